@@ -5,10 +5,12 @@
 use std::path::{Path, PathBuf};
 use mr_data::mrd::MRData;
 use seq_lib::one_pulse::OnePulseParams;
-use seq_lib::pulse_sequence::Initialize;
+use seq_lib::pulse_sequence::{Build, Initialize};
+use seq_tools::rf_power::RfPowerEstimate;
 use utils;
 use ndarray::{s,Array6,Order};
 use seq_lib::rfcal::RfCalParams;
+use seq_lib::protocol::ScanCalibration;
 use serde::{Serialize,Deserialize};
 use crate::build;
 use crate::args::NewAdjArgs;
@@ -53,6 +55,15 @@ impl AdjustmentResults {
         let s = utils::read_to_string(file_path,"json");
         serde_json::from_str(&s).expect("unable to parse json")
     }
+    /// rf power of a sequence using the rf calibration as the reference for B1 and flip angle
+    pub fn rf_power_estimate(&self,to_build:&dyn Build) -> RfPowerEstimate {
+        to_build.rf_power_estimate(self.rf_dac_seconds)
+    }
+    /// replace the observe frequency and rf calibration of a scan calibration with these results
+    pub fn calibrate(&self,calibration:&mut ScanCalibration) {
+        calibration.obs_freq_offset = self.obs_freq_offset;
+        calibration.rf_dac_seconds = Some(self.rf_dac_seconds);
+    }
 }
 
 
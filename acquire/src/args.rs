@@ -10,13 +10,17 @@ pub struct SeqLibArgs {
 #[derive(clap::Subcommand,Debug)]
 pub enum Action {
     NewConfig(NewConfigArgs),
-    New(NewArgs),
+    New(NewSequenceArgs),
     NewSimulation(NewArgs),
     NewDiffusionExperiment(NewDiffusionExperimentArgs),
+    NewInversionRecoveryExperiment(NewInversionRecoveryExperimentArgs),
+    NewProtocol(NewProtocolArgs),
     NewScout(NewArgs),
     NewSetup(NewArgs),
     NewAdjustment(NewAdjArgs),
     ApplySetup(ApplySetupArgs),
+    TimingDiagram(TimingDiagramArgs),
+    Plan(PlanArgs),
     ListSequences,
 }
 
@@ -27,10 +31,36 @@ pub struct NewDiffusionExperimentArgs {
     pub b_table:PathBuf
 }
 
+#[derive(clap::Args,Debug)]
+pub struct NewInversionRecoveryExperimentArgs {
+    pub alias:String,
+    pub destination:PathBuf,
+    // inversion times in ms, one per line
+    pub ti_table:PathBuf
+}
+
+#[derive(clap::Args,Debug)]
+pub struct NewProtocolArgs {
+    pub protocol:PathBuf,
+    pub destination:PathBuf,
+    // adjustment results that replace the protocol's observe frequency and rf calibration
+    #[clap(short, long)]
+    pub adjustment_results:Option<PathBuf>
+}
+
 #[derive(clap::Args,Debug)]
 pub struct NewArgs {
     pub alias:String,
-    pub destination:PathBuf
+    pub destination:PathBuf,
+}
+
+#[derive(clap::Args,Debug)]
+pub struct NewSequenceArgs {
+    pub alias:String,
+    pub destination:PathBuf,
+    // adjustment results used to estimate rf power before the scan is built
+    #[clap(short, long)]
+    pub adjustment_results:Option<PathBuf>
 }
 
 #[derive(clap::Args,Debug)]
@@ -51,4 +81,20 @@ pub struct ApplySetupArgs {
 pub struct NewAdjArgs {
     pub alias:String,
     pub destination:PathBuf,
-}
\ No newline at end of file
+}
+#[derive(clap::Args,Debug)]
+pub struct TimingDiagramArgs {
+    pub alias:String,
+    pub destination:PathBuf,
+    // driver value of the view to draw
+    #[clap(short, long)]
+    pub view:Option<u32>
+}
+
+#[derive(clap::Args,Debug)]
+pub struct PlanArgs {
+    pub alias:String,
+    // b-table of a diffusion series, to time every scan of the series
+    #[clap(short, long)]
+    pub b_table:Option<PathBuf>
+}
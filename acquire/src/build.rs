@@ -3,8 +3,9 @@ use std::f32::consts::PI;
 use std::fs::{create_dir_all, File};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::exit;
 use cs_table::cs_table::CSTable;
-use seq_lib::pulse_sequence::{Build, SequenceParameters, DiffusionWeighted, CompressedSense, Setup, DWSequenceParameters, Initialize, AcqDims, ScoutConfig, AdjustmentParameters};
+use seq_lib::pulse_sequence::{Build, SequenceParameters, DiffusionWeighted, CompressedSense, Setup, DWSequenceParameters, IRSequenceParameters, Initialize, AcqDims, ScoutConfig, AdjustmentParameters};
 use headfile::headfile::Headfile;
 use dyn_clone::clone_box;
 use encoding::all::ISO_8859_1;
@@ -12,16 +13,25 @@ use encoding::{DecoderTrap, EncoderTrap, Encoding};
 use glob::glob;
 use regex::Regex;
 use seq_lib::fse_dti::FseDtiParams;
-use crate::args::{ApplySetupArgs, NewAdjArgs, NewArgs, NewConfigArgs, NewDiffusionExperimentArgs};
+use crate::args::{ApplySetupArgs, NewAdjArgs, NewArgs, NewConfigArgs, NewSequenceArgs, NewDiffusionExperimentArgs, NewInversionRecoveryExperimentArgs, NewProtocolArgs, PlanArgs, TimingDiagramArgs};
 use std::fs::copy;
 use seq_lib::one_pulse::OnePulseParams;
 use seq_lib::rfcal::RfCalParams;
 use seq_lib::scout::ScoutParams;
 use seq_lib::se_2d::Se2DParams;
 use seq_lib::se_dti::SeDtiParams;
+use seq_lib::ute::UteParams;
+use seq_lib::gre::GreParams;
+use seq_lib::mgre::MgreParams;
+use seq_lib::ir_se::IrSeParams;
+use seq_lib::mse::MseParams;
+use seq_lib::protocol::{Experiment, Protocol, PROTOCOL_FILENAME};
+use seq_lib::timing::hms;
 use seq_tools::ppl::Orientation;
 use utils;
 use crate::scout::ScoutViewSettings;
+use crate::adjustment::AdjustmentResults;
+use seq_tools::scanner::ScannerProfile;
 
 const SEQUENCE_LIB:&str = r"C:/workstation/civm_scan/sequence_library";
 //const SEQUENCE_LIB:&str = "/Users/Wyatt/sequence_library";
@@ -29,6 +39,7 @@ const SEQUENCE_LIB:&str = r"C:/workstation/civm_scan/sequence_library";
 //const SEQUENCE_LIB:&str = r"C:\Users\waust\OneDrive\Desktop\test_data\seq_lib";
 pub const HEADFILE_NAME:&str = "meta";
 pub const HEADFILE_EXT:&str = "txt";
+pub const RF_POWER_FILENAME:&str = "rf_power";
 
 const BUILD:bool = true;
 
@@ -41,6 +52,9 @@ pub enum Sequence {
     Se2D,
     OnePulse,
     RfCal,
+    Ute,
+    IrSe,
+    Mse,
 }
 
 impl Sequence {
@@ -54,6 +68,9 @@ impl Sequence {
             Self::decode(&Self::Se2D),
             Self::decode(&Self::OnePulse),
             Self::decode(&Self::RfCal),
+            Self::decode(&Self::Ute),
+            Self::decode(&Self::IrSe),
+            Self::decode(&Self::Mse),
         ].join("\n")
     }
     pub fn encode(name:&str) -> Self {
@@ -66,6 +83,9 @@ impl Sequence {
             "se_2d" => Self::Se2D,
             "one_pulse" => Self::OnePulse,
             "rf_cal" => Self::RfCal,
+            "ute" => Self::Ute,
+            "ir_se" => Self::IrSe,
+            "mse" => Self::Mse,
             _=> panic!("name not recognized")
         }
     }
@@ -79,6 +99,9 @@ impl Sequence {
             Self::Se2D => String::from("se_2d"),
             Self::OnePulse => String::from("one_pulse"),
             Self::RfCal => String::from("rf_cal"),
+            Self::Ute => String::from("ute"),
+            Self::IrSe => String::from("ir_se"),
+            Self::Mse => String::from("mse"),
         }
     }
 }
@@ -103,6 +126,21 @@ fn load_params(cfg_file:&Path) -> Box<dyn SequenceParameters> {
         Sequence::Se2D => {
             Box::new(Se2DParams::load(&cfg_file))
         }
+        Sequence::Ute => {
+            Box::new(UteParams::load(&cfg_file))
+        }
+        Sequence::GRE => {
+            Box::new(GreParams::load(cfg_file))
+        }
+        Sequence::MGRE => {
+            Box::new(MgreParams::load(cfg_file))
+        }
+        Sequence::IrSe => {
+            Box::new(IrSeParams::load(cfg_file))
+        }
+        Sequence::Mse => {
+            Box::new(MseParams::load(cfg_file))
+        }
         _=> panic!("not yet implemented")
     }
 }
@@ -145,6 +183,16 @@ pub fn load_dw_params(cfg_file:&Path) -> Box<dyn DWSequenceParameters> {
     }
 }
 
+pub fn load_ir_params(cfg_file:&Path) -> Box<dyn IRSequenceParameters> {
+    let cfg_str = read_to_string(cfg_file);
+    match find_seq_name_from_config(&cfg_str) {
+        Sequence::IrSe => {
+            Box::new(IrSeParams::load(cfg_file))
+        },
+        _=> panic!("not yet implemented")
+    }
+}
+
 pub fn new_simulation(args:&NewArgs) {
     let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
     let mut params = load_params(&cfg_file);
@@ -153,15 +201,55 @@ pub fn new_simulation(args:&NewArgs) {
 }
 
 
-pub fn new(args:&NewArgs) {
+pub fn new(args:&NewSequenceArgs) {
     let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
     let params = load_params(&cfg_file);
+    if !is_valid(params.as_ref()) {
+        exit(1)
+    }
     if !args.destination.exists() {
         create_dir_all(&args.destination).expect(&format!("unable to create directory: {:?}",args.destination));
     }
+    if let Some(results) = &args.adjustment_results {
+        check_rf_power(params.as_ref(),results,&args.destination);
+    }
     build(params,&args.destination,BUILD);
 }
 
+/** Prints every parameter that can't be built along with the nearest value that can */
+pub fn is_valid(sequence_params:&dyn SequenceParameters) -> bool {
+    match sequence_params.validate() {
+        Ok(_) => true,
+        Err(errors) => {
+            println!("invalid parameters for {}:",sequence_params.name());
+            errors.iter().for_each(|e| println!("    {}",e));
+            false
+        }
+    }
+}
+
+// defaults are validated so a config that can't be built is never written
+fn write_valid_default<P:SequenceParameters + Initialize>(params_file:&Path) {
+    if !is_valid(&P::default()) {
+        exit(1)
+    }
+    P::write_default(params_file);
+}
+
+pub fn check_rf_power(sequence_params:&dyn SequenceParameters,adjustment_results:&Path,work_dir:&Path) {
+    let adj = AdjustmentResults::from_file(adjustment_results);
+    check_calibrated_rf_power(sequence_params,adj.rf_dac_seconds,work_dir);
+}
+
+pub fn check_calibrated_rf_power(sequence_params:&dyn SequenceParameters,rf_dac_seconds:f32,work_dir:&Path) {
+    let estimate = sequence_params.instantiate().rf_power_estimate(rf_dac_seconds);
+    estimate.to_file(&work_dir.join(RF_POWER_FILENAME).with_extension("json"));
+    match ScannerProfile::active().rf_limits.check(&estimate) {
+        Ok(_) => println!("rf power: B1rms = {:.3} uT, duty cycle = {:.4}",estimate.b1_rms_ut,estimate.duty_cycle),
+        Err(e) => panic!("{}",e)
+    }
+}
+
 pub fn new_setup(args:&NewArgs) {
     let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
     let params = load_params(&cfg_file);
@@ -174,6 +262,36 @@ pub fn new_adjustment(args:&NewAdjArgs) {
     build_adj(params,&args.destination,BUILD);
 }
 
+pub fn timing_diagram(args:&TimingDiagramArgs) {
+    let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
+    let params = load_params(&cfg_file);
+    create_dir_all(&args.destination).expect("trouble building directory");
+    let label = format!("{}_timing",params.name());
+    params.instantiate().timing_diagram_export(&args.destination,&label,args.view.unwrap_or(0));
+}
+
+pub fn plan(args:&PlanArgs) {
+    let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
+    let mut params = load_params(&cfg_file);
+    println!("{}",params.timing_limits());
+    if !is_valid(params.as_ref()) {
+        exit(1)
+    }
+    // the number of repetitions comes from the table length when it is built
+    if params.is_cs() {
+        match params.cs_table() {
+            Some(table) if table.exists() => params.set_cs_table(),
+            _ => println!("cs table not found. Using the configured number of repetitions")
+        }
+    }
+    let scan_time = params.scan_time();
+    println!("scan time: {}",scan_time);
+    if let Some(b_table) = &args.b_table {
+        let n_scans = read_b_table(b_table).len();
+        println!("diffusion series of {} scans: {}",n_scans,hms(n_scans as f32*scan_time.total));
+    }
+}
+
 pub fn new_config(args:&NewConfigArgs){
     let seq = Sequence::encode(&args.name);
     let path_out = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
@@ -183,16 +301,16 @@ pub fn new_config(args:&NewConfigArgs){
     }
     match seq {
         Sequence::FseDti => {
-            FseDtiParams::write_default(&path_out);
+            write_valid_default::<FseDtiParams>(&path_out);
         },
         Sequence::SeDti => {
-            SeDtiParams::write_default(&path_out);
+            write_valid_default::<SeDtiParams>(&path_out);
         }
         Sequence::Scout => {
-            ScoutParams::write_default(&path_out);
+            write_valid_default::<ScoutParams>(&path_out);
         }
         Sequence::Se2D => {
-            Se2DParams::write_default(&path_out);
+            write_valid_default::<Se2DParams>(&path_out);
         }
         Sequence::OnePulse => {
             OnePulseParams::write_default(&path_out);
@@ -200,6 +318,21 @@ pub fn new_config(args:&NewConfigArgs){
         Sequence::RfCal => {
             RfCalParams::write_default(&path_out);
         }
+        Sequence::Ute => {
+            write_valid_default::<UteParams>(&path_out);
+        }
+        Sequence::GRE => {
+            write_valid_default::<GreParams>(&path_out);
+        }
+        Sequence::MGRE => {
+            write_valid_default::<MgreParams>(&path_out);
+        }
+        Sequence::IrSe => {
+            write_valid_default::<IrSeParams>(&path_out);
+        }
+        Sequence::Mse => {
+            write_valid_default::<MseParams>(&path_out);
+        }
         _=> panic!("not yet implemented")
     }
 }
@@ -219,6 +352,25 @@ pub fn new_diffusion_experiment(args:&NewDiffusionExperimentArgs) {
     build_diffusion_experiment(params, &args.destination, b_table, BUILD);
 }
 
+pub fn new_inversion_recovery_experiment(args:&NewInversionRecoveryExperimentArgs) {
+    let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
+    let ti_table = Path::new(&args.ti_table);
+    if !ti_table.exists() {
+        println!("cannot find specified inversion time table {:?}",ti_table);
+        return
+    }
+    let params = load_ir_params(&cfg_file);
+    build_inversion_recovery_experiment(params, &args.destination, ti_table, BUILD);
+}
+
+pub fn new_protocol(args:&NewProtocolArgs) {
+    let mut protocol = Protocol::from_file(&args.protocol);
+    if let Some(results) = &args.adjustment_results {
+        AdjustmentResults::from_file(results).calibrate(&mut protocol.calibration);
+    }
+    build_protocol(&protocol,&args.destination,BUILD);
+}
+
 pub fn new_scout_experiment(args:&NewArgs) {
     let cfg_file = Path::new(SEQUENCE_LIB).join(&args.alias).with_extension("json");
     let params = load_scout_params(&cfg_file);
@@ -398,6 +550,10 @@ pub fn build(sequence_params:Box<dyn SequenceParameters>,work_dir:&Path,build:bo
     params.mrd_to_kspace_params().to_file(&work_dir.join("mrd_to_kspace"));
     let h = Headfile::new(&work_dir.join(HEADFILE_NAME).with_extension(HEADFILE_EXT));
     h.append(&params.acq_params().to_hash());
+    if params.acq_params().n_echos > 1 {
+        h.append(&params.echo_params().to_hash());
+    }
+    h.append(&to_build.orientation_params().to_hash());
     to_build.param_export(&work_dir);
 }
 
@@ -442,15 +598,20 @@ pub fn build_scout_experiment(sequence_params:Box<dyn ScoutConfig>, view_setting
         s.mrd_to_kspace_params().to_file(&dir.join("mrd_to_kspace"));
         let h = Headfile::new(&dir.join(HEADFILE_NAME).with_extension(HEADFILE_EXT));
         h.append(&s.acq_params().to_hash());
+        h.append(&to_build.orientation_params().to_hash());
         to_build.ppl_export(&dir,&label,false,build);
         to_build.param_export(&dir);
     });
 }
 
 pub fn build_diffusion_experiment(sequence_params:Box<dyn DWSequenceParameters>, work_dir:&Path, b_table:&Path, build:bool) {
+    build_diffusion_series(sequence_params,work_dir,&read_b_table(b_table),build);
+}
+
+/** One experiment per b-table entry, numbered in table order */
+pub fn build_diffusion_series(sequence_params:Box<dyn DWSequenceParameters>, work_dir:&Path, b_table:&[(f32,f32,f32,f32)], build:bool) {
     let mut s = clone_box(&*sequence_params);
     let b_val = s.b_value();
-    let b_table = read_b_table(b_table);
     let n = b_table.len();
     let w = ((n-1) as f32).log10().floor() as usize + 1;
     let formatter = |index:usize| format!("m{:0width$ }",index,width=w);
@@ -468,6 +629,7 @@ pub fn build_diffusion_experiment(sequence_params:Box<dyn DWSequenceParameters>,
         let h = Headfile::new(&dir.join(HEADFILE_NAME).with_extension(HEADFILE_EXT));
         h.append(&s.acq_params().to_hash());
         h.append(&s.diffusion_params().to_hash());
+        h.append(&to_build.orientation_params().to_hash());
         to_build.ppl_export(&dir,&label,false,build);
         to_build.param_export(&dir);
         match s.is_cs() {
@@ -480,6 +642,113 @@ pub fn build_diffusion_experiment(sequence_params:Box<dyn DWSequenceParameters>,
     })
 }
 
+/** One experiment per inversion time in the table, numbered in table order */
+pub fn build_inversion_recovery_experiment(sequence_params:Box<dyn IRSequenceParameters>, work_dir:&Path, ti_table:&Path, build:bool) {
+    let mut s = clone_box(&*sequence_params);
+    let ti_table = read_ti_table(ti_table);
+    if ti_table.is_empty() {
+        panic!("inversion time table has no entries")
+    }
+    // every inversion time is checked before any experiment directory is made
+    let invalid:Vec<f32> = ti_table.iter().filter(|ti_ms|{
+        s.set_inversion_time(1E-3*(*ti_ms));
+        !is_valid(s.as_ref())
+    }).cloned().collect();
+    if !invalid.is_empty() {
+        panic!("inversion times of {:?} ms can't be built",invalid)
+    }
+    let n = ti_table.len();
+    let w = ((n-1) as f32).log10().floor() as usize + 1;
+    let formatter = |index:usize| format!("m{:0width$ }",index,width=w);
+    ti_table.iter().enumerate().for_each(|(index,ti_ms)| {
+        s.set_inversion_time(1E-3*ti_ms);
+        s.set_cs_table();
+        let label = formatter(index);
+        let dir = work_dir.join(&label);
+        create_dir_all(&dir).expect("trouble building directory");
+        let mut to_build = s.instantiate();
+        s.mrd_to_kspace_params().to_file(&dir.join("mrd_to_kspace"));
+        let h = Headfile::new(&dir.join(HEADFILE_NAME).with_extension(HEADFILE_EXT));
+        h.append(&s.acq_params().to_hash());
+        h.append(&s.inversion_params().to_hash());
+        h.append(&to_build.orientation_params().to_hash());
+        to_build.ppl_export(&dir,&label,false,build);
+        to_build.param_export(&dir);
+        if s.is_cs() {
+            let table = &s.cs_table().unwrap();
+            copy(table,dir.join("cs_table")).expect("unable to copy cs table to destination");
+        }
+    })
+}
+
+/** Every experiment of a protocol in its own directory, in protocol order, on the calibrated observe frequency.
+When the protocol has an rf calibration, acquisitions are scaled to it and checked against the rf power limits */
+pub fn build_protocol(protocol:&Protocol, work_dir:&Path, build:bool) {
+    create_dir_all(work_dir).expect("trouble building directory");
+    protocol.to_file(&work_dir.join(PROTOCOL_FILENAME));
+    let calibration = &protocol.calibration;
+    protocol.experiments.iter().enumerate().for_each(|(index,experiment)| {
+        let dir = work_dir.join(protocol.experiment_dir(index));
+        create_dir_all(&dir).expect("trouble building directory");
+        match experiment {
+            Experiment::Scout(_) => {
+                let mut params = experiment.scout_params().unwrap();
+                calibration.apply(params.as_mut());
+                if let Some(rf_dac_seconds) = calibration.rf_dac_seconds {
+                    check_calibrated_rf_power(params.as_ref(),rf_dac_seconds,&dir);
+                }
+                build_scout_experiment(params,&ScoutViewSettings::default(),&dir,build);
+            }
+            Experiment::Adjustment{freq_cal,rf_cal} => {
+                // the frequency calibration finds the offset, so it runs on the base frequency
+                build_adj(Box::new(freq_cal.clone()),&dir.join("freq"),build);
+                let mut rf_cal = rf_cal.clone();
+                calibration.apply_adj(&mut rf_cal);
+                build_adj(Box::new(rf_cal),&dir.join("rf"),build);
+            }
+            Experiment::Setup(sequence) => {
+                let mut params = sequence.params();
+                calibration.apply(params.as_mut());
+                if let Some(rf_dac_seconds) = calibration.rf_dac_seconds {
+                    check_calibrated_rf_power(params.as_ref(),rf_dac_seconds,&dir);
+                }
+                build_setup(params,&dir,build);
+            }
+            Experiment::Diffusion{sequence,b_table} => {
+                let mut params = sequence.dw_params().unwrap_or_else(|| panic!("{} is not a diffusion weighted sequence",sequence.params().name()));
+                calibration.apply(params.as_mut());
+                if let Some(rf_dac_seconds) = calibration.rf_dac_seconds {
+                    check_calibrated_rf_power(params.as_ref(),rf_dac_seconds,&dir);
+                }
+                build_diffusion_series(params,&dir,b_table,build);
+            }
+            Experiment::Anatomical(sequence) => {
+                let mut params = sequence.params();
+                calibration.apply(params.as_mut());
+                if let Some(rf_dac_seconds) = calibration.rf_dac_seconds {
+                    check_calibrated_rf_power(params.as_ref(),rf_dac_seconds,&dir);
+                }
+                self::build(params,&dir,build);
+            }
+        }
+        let ppr_vars = calibration.ppr_vars();
+        if !ppr_vars.is_empty() {
+            find_files(&dir,".ppr",experiment.search_depth() as u16).iter().for_each(|ppr| {
+                write_ppr(ppr,&update_ppr(&read_ppr(ppr),&ppr_vars));
+            });
+        }
+    })
+}
+
+/** Inversion times in ms, one per line. Lines starting with # are ignored */
+pub fn read_ti_table(ti_table:&Path) -> Vec<f32> {
+    let mut f = File::open(ti_table).expect("inversion time table not found");
+    let mut file_string = String::new();
+    f.read_to_string(&mut file_string).expect("trouble reading from file");
+    file_string.lines().map(|line| line.trim()).filter(|line| !line.starts_with('#') && !line.is_empty())
+        .map(|line| line.parse().unwrap_or_else(|_| panic!("unable to parse {}",line))).collect()
+}
+
 pub fn read_b_table(b_table:&Path) -> Vec<(f32,f32,f32,f32)>{
     let mut f = File::open(b_table).expect("b_vec table not found");
     let mut file_string = String::new();
@@ -1,5 +1,5 @@
 use clap::Parser;
-use acquire::build::{apply_setup, new, new_adjustment, new_config, new_diffusion_experiment, new_scout_experiment, new_setup, new_simulation, Sequence};
+use acquire::build::{apply_setup, new, new_adjustment, new_config, new_diffusion_experiment, new_inversion_recovery_experiment, new_protocol, new_scout_experiment, new_setup, new_simulation, plan, timing_diagram, Sequence};
 use acquire::args::*;
 
 fn main(){
@@ -10,11 +10,15 @@ fn main(){
         NewConfig(args) => new_config(&args),
         New(args) => new(&args),
         NewDiffusionExperiment(args) => new_diffusion_experiment(&args),
+        NewInversionRecoveryExperiment(args) => new_inversion_recovery_experiment(args),
+        NewProtocol(args) => new_protocol(args),
         NewScout(args) => new_scout_experiment(&args),
         NewSetup(args) => new_setup(&args),
         ApplySetup(args) => apply_setup(&args),
         NewSimulation(args) => new_simulation(&args),
         NewAdjustment(args) => new_adjustment(&args),
+        TimingDiagram(args) => timing_diagram(&args),
+        Plan(args) => plan(args),
         _=> {}
     }
 }
\ No newline at end of file
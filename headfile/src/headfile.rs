@@ -11,12 +11,22 @@ use utils;
 
 pub trait AcqHeadfile {
     fn acq_params(&self) -> AcqHeadfileParams;
+    /** Echo time of every echo in the acquisition. Single echo acquisitions only have te */
+    fn echo_params(&self) -> MultiEchoHeadfileParams {
+        MultiEchoHeadfileParams {
+            te_ms: vec![self.acq_params().te_ms]
+        }
+    }
 }
 
 pub trait DWHeadfile:AcqHeadfile {
     fn diffusion_params(&self) -> DWHeadfileParams;
 }
 
+pub trait IRHeadfile:AcqHeadfile {
+    fn inversion_params(&self) -> IRHeadfileParams;
+}
+
 
 pub struct AcqHeadfileParams {
     pub dim_x:i32,
@@ -33,11 +43,42 @@ pub struct AcqHeadfileParams {
     pub S_PSDname:String,
 }
 
+pub struct OrientationHeadfileParams {
+    // base matrix angles in tenths of a degree
+    pub base_matrix:(i16,i16,i16),
+    // rotation from the logical (read,phase,slice) axes to the magnet axes
+    pub rotation:[[f32;3];3],
+}
+
 pub struct DWHeadfileParams {
     pub bvalue:f32,
     pub bval_dir:(f32,f32,f32)
 }
 
+pub struct IRHeadfileParams {
+    pub ti_ms:f32,
+}
+
+pub struct MultiEchoHeadfileParams {
+    pub te_ms:Vec<f32>,
+}
+
+pub struct T1MapHeadfileParams {
+    // inversion times of the source volumes in the order they were fit
+    pub ti_ms:Vec<f32>,
+    pub source_volumes:Vec<String>,
+    // fraction of the peak signal below which voxels are not fit
+    pub mask_threshold:f32,
+}
+
+pub struct T2MapHeadfileParams {
+    // echo times of the source volumes in the order they were fit
+    pub te_ms:Vec<f32>,
+    pub source_volumes:Vec<String>,
+    // fraction of the peak signal below which voxels are not fit
+    pub mask_threshold:f32,
+}
+
 
 #[derive(Clone,Serialize,Deserialize,Debug)]
 pub struct ReconHeadfile {
@@ -265,6 +306,17 @@ impl AcqHeadfileParams {
     }
 }
 
+impl OrientationHeadfileParams {
+    pub fn to_hash(&self) -> HashMap<String,String> {
+        let mut h = HashMap::<String,String>::new();
+        let base_matrix = format!("3:1,{} {} {}",self.base_matrix.0,self.base_matrix.1,self.base_matrix.2);
+        let r:Vec<String> = self.rotation.iter().flat_map(|row| row.iter().map(|x| x.to_string())).collect();
+        h.insert(String::from("orient_base_matrix"),base_matrix);
+        h.insert(String::from("orient_rotation"),format!("3:3,{}",r.join(" ")));
+        h
+    }
+}
+
 impl DWHeadfileParams {
     pub fn to_hash(&self) -> HashMap<String,String> {
         let mut h = HashMap::<String,String>::new();
@@ -275,6 +327,49 @@ impl DWHeadfileParams {
     }
 }
 
+impl IRHeadfileParams {
+    pub fn to_hash(&self) -> HashMap<String,String> {
+        let mut h = HashMap::<String,String>::new();
+        h.insert(String::from("ti"),self.ti_ms.to_string());
+        h
+    }
+}
+
+impl MultiEchoHeadfileParams {
+    pub fn to_hash(&self) -> HashMap<String,String> {
+        let mut h = HashMap::<String,String>::new();
+        let te:Vec<String> = self.te_ms.iter().map(|te| te.to_string()).collect();
+        h.insert(String::from("echo_times"),format!("{}:1,{}",te.len(),te.join(" ")));
+        h
+    }
+}
+
+impl T1MapHeadfileParams {
+    pub fn to_hash(&self) -> HashMap<String,String> {
+        let mut h = HashMap::<String,String>::new();
+        let ti:Vec<String> = self.ti_ms.iter().map(|ti| ti.to_string()).collect();
+        h.insert(String::from("t1map_ti"),format!("{}:1,{}",ti.len(),ti.join(" ")));
+        h.insert(String::from("t1map_source_volumes"),format!("{}:1,{}",self.source_volumes.len(),self.source_volumes.join(" ")));
+        h.insert(String::from("t1map_model"),String::from("|a - b*exp(-ti/t1)|"));
+        h.insert(String::from("t1map_units"),String::from("ms"));
+        h.insert(String::from("t1map_mask_threshold"),self.mask_threshold.to_string());
+        h
+    }
+}
+
+impl T2MapHeadfileParams {
+    pub fn to_hash(&self) -> HashMap<String,String> {
+        let mut h = HashMap::<String,String>::new();
+        let te:Vec<String> = self.te_ms.iter().map(|te| te.to_string()).collect();
+        h.insert(String::from("t2map_te"),format!("{}:1,{}",te.len(),te.join(" ")));
+        h.insert(String::from("t2map_source_volumes"),format!("{}:1,{}",self.source_volumes.len(),self.source_volumes.join(" ")));
+        h.insert(String::from("t2map_model"),String::from("s0*exp(-te/t2)"));
+        h.insert(String::from("t2map_units"),String::from("ms"));
+        h.insert(String::from("t2map_mask_threshold"),self.mask_threshold.to_string());
+        h
+    }
+}
+
 pub struct Headfile{
     file:PathBuf
 }
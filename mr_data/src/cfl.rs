@@ -287,6 +287,22 @@ pub fn fft2(slice:&Array2<Complex<f32>>,fftshift:bool) -> Array2<Complex<f32>> {
     slice
 }
 
+/** 1-D fft with the zero frequency in the middle of the array. The inverse is normalized by n */
+pub fn fft_centered(x:&[Complex<f32>],inverse:bool) -> Vec<Complex<f32>> {
+    let n = x.len();
+    let mut buffer = x.to_vec();
+    buffer.rotate_left(n/2);
+    let mut planner = FftPlanner::<f32>::new();
+    let fft = match inverse {
+        true => planner.plan_fft_inverse(n),
+        false => planner.plan_fft_forward(n)
+    };
+    fft.process(&mut buffer);
+    if inverse {buffer.iter_mut().for_each(|v| *v /= n as f32)}
+    buffer.rotate_right(n/2);
+    buffer
+}
+
 pub fn kspace2d_to_image(slice:&Array2<Complex<f32>>) -> Array2<f32> {
     complex_slice_to_magnitude(&fft2(slice,true))
 }
@@ -347,11 +363,11 @@ fn test_filter() {
     _fermi_filter(&mut vol,0.15,0.75);
 
     println!("performing fft ..");
-    let vol = fft3_axis(vol,2);
+    let vol = fft3_axis(vol,2,true);
     println!("performing fft ..");
-    let vol = fft3_axis(vol,1);
+    let vol = fft3_axis(vol,1,true);
     println!("performing fft ..");
-    let mut vol = fft3_axis(vol,0);
+    let mut vol = fft3_axis(vol,0,true);
 
 
 
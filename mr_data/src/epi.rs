@@ -0,0 +1,185 @@
+/*
+    Epi reshape and Nyquist ghost correction. Every acquisition of an epi train is cut into its lobes.
+    Each lobe is regridded from its ramp sampled k-space positions onto the uniform grid of the flat
+    top, with lobes of negative read polarity reversed on the way. The phase difference between the
+    even and odd navigator echoes is modeled as a constant and a linear term along the readout in
+    image space, and removed from every echo of negative polarity.
+ */
+
+use std::path::Path;
+use ndarray::{Array2, Array3, s};
+use num_complex::Complex;
+use seq_tools::epi::EpiReconParams;
+use crate::cfl::fft_centered;
+use crate::mrd::MRData;
+
+/** Phase error of negative polarity echoes relative to positive ones. The phase at readout
+pixel x is constant + linear*(x - n/2) */
+#[derive(Clone,Copy,Debug)]
+pub struct GhostCorrection {
+    pub constant:f32,
+    pub linear:f32,
+}
+
+impl GhostCorrection {
+    /** Estimate from navigator lobes (already reversed and regridded) of alternating polarity */
+    pub fn from_navigators(navigators:&Array2<Complex<f32>>) -> Option<Self> {
+        let n_nav = navigators.shape()[0];
+        let n_read = navigators.shape()[1];
+        if n_nav < 2 {return None}
+        let mut even = vec![Complex::<f32>::new(0.0,0.0);n_read];
+        let mut odd = vec![Complex::<f32>::new(0.0,0.0);n_read];
+        for i in 0..n_nav {
+            let x = fft_centered(&navigators.slice(s![i,..]).to_vec(),false);
+            let acc = if i % 2 == 0 {&mut even} else {&mut odd};
+            acc.iter_mut().zip(x.iter()).for_each(|(a,x)| *a += x);
+        }
+        // the phase of c is the phase of the even echoes minus the odd ones
+        let c:Vec<Complex<f32>> = even.iter().zip(odd.iter()).map(|(e,o)| e*o.conj()).collect();
+        let linear = c.windows(2).map(|w| w[1]*w[0].conj()).sum::<Complex<f32>>().arg();
+        let center = (n_read/2) as f32;
+        let constant = c.iter().enumerate()
+            .map(|(x,c)| c*Complex::from_polar(1.0,-linear*(x as f32 - center))).sum::<Complex<f32>>().arg();
+        Some(Self {
+            constant,
+            linear
+        })
+    }
+    /** Remove the phase error from a negative polarity echo in k-space */
+    pub fn apply(&self,line:&[Complex<f32>]) -> Vec<Complex<f32>> {
+        let center = (line.len()/2) as f32;
+        let mut x = fft_centered(line,false);
+        x.iter_mut().enumerate().for_each(|(i,v)|{
+            *v *= Complex::from_polar(1.0,self.constant + self.linear*(i as f32 - center))
+        });
+        fft_centered(&x,true)
+    }
+}
+
+/** Linear interpolation of a lobe sampled at increasing k onto n_read points spaced by one k-space step
+centered on the lobe. Grid points outside of the sampled range are zero */
+pub fn regrid_lobe(samples:&[Complex<f32>],k:&[f32],n_read:usize) -> Vec<Complex<f32>> {
+    let k_center = (k[0] + k[k.len()-1])/2.0;
+    (0..n_read).map(|j|{
+        let kj = k_center + j as f32 - (n_read/2) as f32;
+        let i = k.partition_point(|k| *k <= kj);
+        if i == 0 || i == k.len() {
+            return if (kj - k[k.len()-1]).abs() < 1E-6 {samples[k.len()-1]} else {Complex::new(0.0,0.0)}
+        }
+        let w = (kj - k[i-1])/(k[i] - k[i-1]);
+        samples[i-1]*(1.0 - w) + samples[i]*w
+    }).collect()
+}
+
+/** Every lobe (navigators then echoes) of one acquisition on the uniform readout grid */
+pub fn epi_lobes(acq:&[Complex<f32>],params:&EpiReconParams) -> Array2<Complex<f32>> {
+    if acq.len() != params.n_samples() {
+        panic!("expected {} samples for the epi train, found {}",params.n_samples(),acq.len())
+    }
+    let k = params.lobe_k();
+    let k_end = k[k.len()-1];
+    let n_lobes = params.n_navigators + params.n_echoes;
+    let mut out = Array2::<Complex<f32>>::zeros((n_lobes,params.n_read));
+    for lobe in 0..n_lobes {
+        let offset = params.lobe_offset(lobe);
+        let mut samples = acq[offset..offset + params.samples_per_lobe()].to_vec();
+        // negative lobes run backwards through k-space
+        let line = match lobe % 2 {
+            0 => regrid_lobe(&samples,&k,params.n_read),
+            _ => {
+                samples.reverse();
+                let k_rev:Vec<f32> = k.iter().rev().map(|k| k_end - k).collect();
+                regrid_lobe(&samples,&k_rev,params.n_read)
+            }
+        };
+        out.slice_mut(s![lobe,..]).iter_mut().zip(line).for_each(|(o,v)| *o = v);
+    }
+    out
+}
+
+/** K-space (echo,read) of one acquisition with ghost correction from the navigators if there are any */
+pub fn epi_to_kspace(acq:&[Complex<f32>],params:&EpiReconParams) -> Array2<Complex<f32>> {
+    let lobes = epi_lobes(acq,params);
+    let navigators = lobes.slice(s![0..params.n_navigators,..]).to_owned();
+    let correction = GhostCorrection::from_navigators(&navigators);
+    let mut kspace = lobes.slice(s![params.n_navigators..,..]).to_owned();
+    if let Some(correction) = correction {
+        for echo in 0..params.n_echoes {
+            if (params.n_navigators + echo) % 2 == 1 {
+                let corrected = correction.apply(&kspace.slice(s![echo,..]).to_vec());
+                kspace.slice_mut(s![echo,..]).iter_mut().zip(corrected).for_each(|(k,v)| *k = v);
+            }
+        }
+    }
+    kspace
+}
+
+/** K-space (acquisition,echo,read) for every epi train in an mrd file */
+pub fn epi_mrd_to_kspace(mrd:&Path,params:&EpiReconParams) -> Array3<Complex<f32>> {
+    let raw = MRData::new(mrd).complex_stream();
+    let n = params.n_samples();
+    if !raw.len().is_multiple_of(n) {panic!("mrd holds {} samples, which is not a multiple of the epi train length {}",raw.len(),n)}
+    let n_acq = raw.len()/n;
+    let mut out = Array3::<Complex<f32>>::zeros((n_acq,params.n_echoes,params.n_read));
+    raw.chunks(n).enumerate().for_each(|(i,acq)|{
+        out.slice_mut(s![i,..,..]).assign(&epi_to_kspace(acq,params));
+    });
+    out
+}
+
+#[test]
+fn test(){
+    use std::f32::consts::PI;
+    // simulated ramp sampled train of a 2-D object with a phase error on the negative echoes
+    let params = EpiReconParams {
+        n_read:32,
+        n_echoes:32,
+        n_navigators:3,
+        ramp_samples:6,
+        flat_samples:28,
+        gap_samples:10,
+    };
+    let n = params.n_read;
+    let object = |x:usize,y:usize| if (10..22).contains(&x) && (8..18).contains(&y) {1.0} else {0.0};
+    let (a,b) = (0.6,0.15);
+    let k = params.lobe_k();
+    let k_end = k[k.len()-1];
+    let signal = |kx:f32,ky:f32,negative:bool| -> Complex<f32> {
+        let mut sum = Complex::new(0.0,0.0);
+        for y in 0..n {
+            for x in 0..n {
+                let (xc,yc) = (x as f32 - (n/2) as f32,y as f32 - (n/2) as f32);
+                let error = if negative {-(a + b*xc)} else {0.0};
+                sum += Complex::from_polar(object(x,y),error + 2.0*PI*(kx*xc + ky*yc)/n as f32);
+            }
+        }
+        sum
+    };
+    let mut acq = vec![Complex::new(0.0,0.0);params.n_samples()];
+    for lobe in 0..params.n_navigators + params.n_echoes {
+        let ky = match lobe < params.n_navigators {
+            true => 0.0,
+            false => (lobe - params.n_navigators) as f32 - (params.n_echoes/2) as f32
+        };
+        let negative = lobe % 2 == 1;
+        for (i,ki) in k.iter().enumerate() {
+            let kx = if negative {k_end - ki} else {*ki} - k_end/2.0;
+            acq[params.lobe_offset(lobe) + i] = signal(kx,ky,negative);
+        }
+    }
+
+    let ghost_ratio = |kspace:&Array2<Complex<f32>>| -> f32 {
+        let img = crate::cfl::kspace2d_to_image(kspace);
+        let (mut ghost,mut total) = (0.0,0.0);
+        img.indexed_iter().for_each(|((y,x),v)|{
+            total += v;
+            if object(x,y) == 0.0 {ghost += v}
+        });
+        ghost/total
+    };
+    let corrected = epi_to_kspace(&acq,&params);
+    let uncorrected = epi_lobes(&acq,&params).slice(s![params.n_navigators..,..]).to_owned();
+    let c = GhostCorrection::from_navigators(&epi_lobes(&acq,&params).slice(s![0..3,..]).to_owned()).unwrap();
+    assert!((c.constant - a).abs() < 0.05 && (c.linear - b).abs() < 0.01,"{:?}",c);
+    assert!(ghost_ratio(&corrected) < 0.5*ghost_ratio(&uncorrected),"{} vs {}",ghost_ratio(&corrected),ghost_ratio(&uncorrected));
+}
@@ -1,2 +1,5 @@
 pub mod mrd;
 pub mod cfl;
+pub mod epi;
+pub mod nufft;
+pub mod relaxometry;
\ No newline at end of file
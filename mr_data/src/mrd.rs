@@ -2,7 +2,7 @@ use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
-use seq_lib::pulse_sequence::{AcqDims, MrdFormat, MrdToKspaceParams};
+use seq_lib::pulse_sequence::{AcqDims, MrdFormat, MrdToKspaceParams, K_COORDS_FILENAME};
 //use acquire::build::acq_dims;
 use cs_table::cs_table::CSTable;
 use byteorder::{LittleEndian,ByteOrder};
@@ -12,6 +12,7 @@ use ndarray::iter::Axes;
 use ndarray::Order::RowMajor;
 use num_complex::Complex;
 use crate::cfl;
+use crate::nufft;
 
 
 const OFFSET_TO_DATA:usize = 512;
@@ -42,7 +43,15 @@ fn test(){
 pub fn cs_mrd_to_kspace(mrd:&Path,cs_table:&Path,cfl_base:&Path,params:&MrdToKspaceParams) {
     match params.mrd_format {
         MrdFormat::FseCSVol => fse_raw_to_cfl(mrd,cs_table,cfl_base,params),
-        MrdFormat::StandardCSVol => se_raw_to_vol(mrd,cs_table,cfl_base,params),
+        MrdFormat::StandardCSVol if params.n_objects == 1 => se_raw_to_vol(mrd,cs_table,cfl_base,params),
+        // a volume manager reconstructs one k-space volume, so every echo needs its own
+        MrdFormat::StandardCSVol | MrdFormat::BipolarCSVol => panic!(
+            "{} echos can't be formatted to a single k-space volume. Reconstruct them as a multi-echo recon with cs_mrd_echo_to_kspace",
+            params.n_objects
+        ),
+        MrdFormat::StandardSlice => slice_raw_to_cfl(mrd,cfl_base,params),
+        // sample coordinates are written next to the mrd with the other recon resources
+        MrdFormat::NonCartesian => nufft::non_cartesian_raw_to_kspace_cfl(mrd,&mrd.with_file_name(K_COORDS_FILENAME),cfl_base,params),
         _=> panic!("not yet implemented")
     }
 }
@@ -71,21 +80,52 @@ pub fn se_raw_to_vol(mrd:&Path,cs_table:&Path,cfl_out_base_name:&Path,params:&Mr
     cfl::write_cfl_vol(&vol,cfl_out_base_name);
 }
 
-fn multi_echo_raw_to_cfl(mrd:&Path,cs_table:&Path,cfl_out_base_name:&Path,params:&MrdToKspaceParams) {
-    let fname = cfl_out_base_name.file_name().expect(&format!("cannot determine base name from {:?}",cfl_out_base_name)).to_str().unwrap();
-    let n = params.n_objects;
-    let w = ((n-1) as f32).log10().floor() as usize + 1;
-    let formatter = |index:usize| format!("m{:0width$ }",index,width=w);
-    for i in 0..n {
-        let postfix = formatter(i);
-        let qualified_name = format!("{}_{}",fname,postfix);
-        let cfl = cfl_out_base_name.with_file_name(qualified_name);
-        let formatted = format_multi_echo_raw(mrd,params.n_read,params.n_views,params.dummy_excitations,i);
-        let vol = zero_fill(&formatted,cs_table,(params.n_read,params.n_phase1,params.n_phase2),params.dummy_excitations,params.view_acceleration);
-        cfl::write_cfl_vol(&vol,&cfl);
+pub fn slice_raw_to_cfl(mrd:&Path,cfl_base:&Path,params:&MrdToKspaceParams) {
+    let vol = slice_raw_to_vol(mrd,params);
+    cfl::write_cfl_vol(&vol,cfl_base);
+}
+
+/** K-space stack (slice,phase,read) of a 2-D acquisition, with slices in position order. Every slice
+of the slice loop is stored as its own set of echos */
+pub fn slice_raw_to_vol(mrd:&Path,params:&MrdToKspaceParams) -> Array3<Complex<f32>> {
+    let n_echos = MRData::new(mrd).n_echos() as usize;
+    if !n_echos.is_multiple_of(params.n_slices) {panic!("{} echos cannot be split evenly over {} slices",n_echos,params.n_slices)}
+    let echos_per_slice = n_echos/params.n_slices;
+    let mut vol = Array3::<Complex<f32>>::zeros((params.n_slices,params.n_views,params.n_read));
+    params.slice_order.acquisition_order(params.n_slices).iter().enumerate().for_each(|(i,position)|{
+        let kspace = format_multi_echo_raw(mrd,params.n_read,params.n_views,params.dummy_excitations,i*echos_per_slice);
+        vol.slice_mut(s![*position,..,..]).assign(&kspace);
+    });
+    vol
+}
+
+/** Writes the k-space volume of a single echo from a multi-echo acquisition. This lets every echo
+be reconstructed independently */
+pub fn cs_mrd_echo_to_kspace(mrd:&Path,cs_table:&Path,cfl_base:&Path,params:&MrdToKspaceParams,echo_index:usize) {
+    if echo_index >= params.n_objects {
+        panic!("echo index {} is out of range for {} echos",echo_index,params.n_objects);
     }
+    let vol = match params.mrd_format {
+        MrdFormat::StandardCSVol => multi_echo_raw_to_vol(mrd,cs_table,params,echo_index,false),
+        MrdFormat::BipolarCSVol => multi_echo_raw_to_vol(mrd,cs_table,params,echo_index,true),
+        _=> panic!("single echo formatting is only implemented for 3-D compressed sensing volumes")
+    };
+    cfl::write_cfl_vol(&vol,cfl_base);
+}
+
+fn multi_echo_raw_to_vol(mrd:&Path,cs_table:&Path,params:&MrdToKspaceParams,echo_index:usize,bipolar:bool) -> Array3<Complex<f32>> {
+    let mut formatted = format_multi_echo_raw(mrd,params.n_read,params.n_views,params.dummy_excitations,echo_index);
+    if bipolar && echo_index % 2 == 1 {
+        formatted = reverse_read(&formatted);
+    }
+    zero_fill(&formatted,cs_table,(params.n_read,params.n_phase1,params.n_phase2),params.dummy_excitations,params.view_acceleration)
 }
 
+/** Reverses the read direction of every view (view,read). Sample n/2 stays at the center of the line */
+fn reverse_read(array:&Array2::<Complex<f32>>) -> Array2::<Complex<f32>> {
+    let n_read = array.shape()[1];
+    Array2::from_shape_fn(array.raw_dim(),|(view,read)| array[[view,(n_read - read) % n_read]])
+}
 
 fn format_fse_raw(mrd:&Path,n_read:usize,n_views:usize,n_dummy_excitations:usize) -> Array2::<Complex<f32>> {
     let mrd = MRData::new(mrd);
@@ -0,0 +1,249 @@
+/*
+    Non-uniform fft by Kaiser-Bessel gridding. Samples are spread onto an oversampled Cartesian grid
+    with a separable Kaiser-Bessel kernel, transformed to image space, cropped to the image matrix
+    and divided by the transform of the kernel (deapodization). Sample density is compensated with
+    weights found by the iterative method of Pipe and Menon (1999).
+    K-space coordinates are (read,phase,slice) in cycles per field of view, so the Cartesian grid
+    points of the image matrix fall on integers from -n/2 to n/2 - 1. Image volumes are ordered
+    (slice,phase,read) like the rest of the reconstruction.
+ */
+
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use ndarray::{Array3, Axis};
+use num_complex::Complex;
+use seq_lib::pulse_sequence::MrdToKspaceParams;
+use crate::cfl;
+use crate::mrd::MRData;
+
+pub const DCF_ITERATIONS:usize = 10;
+
+#[derive(Clone,Debug)]
+pub struct Nufft {
+    // image matrix (read,phase,slice)
+    pub matrix:[usize;3],
+    pub oversampling:f32,
+    // kernel width in oversampled grid points
+    pub width:f32,
+    beta:f32,
+    grid:[usize;3],
+    deapodization:[Vec<f32>;3],
+}
+
+impl Nufft {
+    pub fn new(matrix:[usize;3]) -> Self {
+        Self::with_kernel(matrix,2.0,4.0)
+    }
+    pub fn with_kernel(matrix:[usize;3],oversampling:f32,width:f32) -> Self {
+        // kernel shape with minimal aliasing for the oversampling ratio (Beatty et al. 2005)
+        let beta = PI*((width/oversampling).powi(2)*(oversampling - 0.5).powi(2) - 0.8).sqrt();
+        let grid = matrix.map(|n| match n {
+            1 => 1,
+            n => 2*((n as f32*oversampling/2.0).ceil() as usize)
+        });
+        let mut nufft = Self {
+            matrix,
+            oversampling,
+            width,
+            beta,
+            grid,
+            deapodization:[vec![],vec![],vec![]],
+        };
+        nufft.deapodization = [0,1,2].map(|axis| nufft.axis_deapodization(axis));
+        nufft
+    }
+    /** Smallest even image matrix holding every coordinate */
+    pub fn matrix_for(coords:&[[f32;3]]) -> [usize;3] {
+        [0,1,2].map(|c|{
+            let k_max = coords.iter().map(|k| k[c].abs()).fold(0.0,f32::max);
+            if k_max == 0.0 {1} else {2*(k_max.ceil() as usize)}
+        })
+    }
+    fn kernel(&self,u:f32) -> f32 {
+        let x = 2.0*u/self.width;
+        if x.abs() > 1.0 {return 0.0}
+        bessel_i0(self.beta*(1.0 - x*x).sqrt())
+    }
+    // grid points and kernel weights a sample touches along one axis
+    fn neighbors(&self,axis:usize,k:f32) -> Vec<(usize,f32)> {
+        let n_grid = self.grid[axis];
+        if n_grid == 1 {return vec![(0,1.0)]}
+        let g = k*n_grid as f32/self.matrix[axis] as f32 + (n_grid/2) as f32;
+        let lo = (g - self.width/2.0).ceil() as i64;
+        let hi = (g + self.width/2.0).floor() as i64;
+        (lo..=hi).map(|j| (j.rem_euclid(n_grid as i64) as usize,self.kernel(g - j as f32))).collect()
+    }
+    fn axis_deapodization(&self,axis:usize) -> Vec<f32> {
+        let (n,n_grid) = (self.matrix[axis],self.grid[axis]);
+        let mut line = vec![Complex::<f32>::new(0.0,0.0);n_grid];
+        self.neighbors(axis,0.0).iter().for_each(|(j,w)| line[*j] += w);
+        let image = cfl::fft_centered(&line,true);
+        let start = n_grid/2 - n/2;
+        image[start..start + n].iter().map(|v| v.re).collect()
+    }
+    /** Spread weighted samples onto the oversampled grid (slice,phase,read) */
+    pub fn grid(&self,samples:&[Complex<f32>],coords:&[[f32;3]],weights:&[f32]) -> Array3<Complex<f32>> {
+        if samples.len() != coords.len() || samples.len() != weights.len() {
+            panic!("{} samples, {} coordinates and {} weights don't match",samples.len(),coords.len(),weights.len())
+        }
+        let mut grid = Array3::<Complex<f32>>::zeros((self.grid[2],self.grid[1],self.grid[0]));
+        for ((sample,k),w) in samples.iter().zip(coords.iter()).zip(weights.iter()) {
+            let [r,p,s] = [0,1,2].map(|c| self.neighbors(c,k[c]));
+            for (is,ws) in s.iter() {
+                for (ip,wp) in p.iter() {
+                    for (ir,wr) in r.iter() {
+                        grid[[*is,*ip,*ir]] += sample*(w*ws*wp*wr);
+                    }
+                }
+            }
+        }
+        grid
+    }
+    /** Kernel weighted values of the oversampled grid at every coordinate */
+    pub fn interpolate(&self,grid:&Array3<Complex<f32>>,coords:&[[f32;3]]) -> Vec<Complex<f32>> {
+        coords.iter().map(|k|{
+            let [r,p,s] = [0,1,2].map(|c| self.neighbors(c,k[c]));
+            let mut v = Complex::<f32>::new(0.0,0.0);
+            for (is,ws) in s.iter() {
+                for (ip,wp) in p.iter() {
+                    for (ir,wr) in r.iter() {
+                        v += grid[[*is,*ip,*ir]]*(ws*wp*wr);
+                    }
+                }
+            }
+            v
+        }).collect()
+    }
+    /** Weights that make the gridded sample density uniform */
+    pub fn density_compensation(&self,coords:&[[f32;3]],iterations:usize) -> Vec<f32> {
+        let mut weights = vec![1.0f32;coords.len()];
+        for _ in 0..iterations {
+            let ones = vec![Complex::<f32>::new(1.0,0.0);coords.len()];
+            let density = self.interpolate(&self.grid(&ones,coords,&weights),coords);
+            weights.iter_mut().zip(density.iter()).for_each(|(w,d)| if d.re > 0.0 {*w /= d.re});
+        }
+        weights
+    }
+    /** Image (slice,phase,read) from density compensated samples */
+    pub fn adjoint(&self,samples:&[Complex<f32>],coords:&[[f32;3]],weights:&[f32]) -> Array3<Complex<f32>> {
+        let mut grid = self.grid(samples,coords,weights);
+        for axis in 0..3 {
+            if grid.shape()[axis] == 1 {continue}
+            grid.lanes_mut(Axis(axis)).into_iter().for_each(|mut lane|{
+                let x = cfl::fft_centered(&lane.to_vec(),true);
+                lane.iter_mut().zip(x).for_each(|(l,x)| *l = x);
+            });
+        }
+        let start = [0,1,2].map(|c| self.grid[c]/2 - self.matrix[c]/2);
+        Array3::from_shape_fn((self.matrix[2],self.matrix[1],self.matrix[0]),|(s,p,r)|{
+            let d = self.deapodization[0][r]*self.deapodization[1][p]*self.deapodization[2][s];
+            grid[[s + start[2],p + start[1],r + start[0]]]/d
+        })
+    }
+}
+
+/** K-space coordinate list with one (read,phase,slice) sample per line, in cycles per field of view */
+pub fn load_coordinates(coords_file:&Path) -> Vec<[f32;3]> {
+    let mut s = String::new();
+    let mut f = File::open(coords_file).unwrap_or_else(|_| panic!("cannot open file {:?}",coords_file));
+    f.read_to_string(&mut s).expect("cannot read from file");
+    s.lines().filter(|line| !line.trim().is_empty()).map(|line|{
+        let k:Vec<f32> = line.split_whitespace().map(|v| v.parse().unwrap_or_else(|_| panic!("cannot parse coordinate {}",line))).collect();
+        if k.len() != 3 {panic!("expected 3 coordinates per line, found {}",line)}
+        [k[0],k[1],k[2]]
+    }).collect()
+}
+
+/** Gridding reconstruction of every view in an mrd file to an image volume */
+pub fn non_cartesian_raw_to_vol(mrd:&Path,coords_file:&Path,params:&MrdToKspaceParams) -> Array3<Complex<f32>> {
+    let coords = load_coordinates(coords_file);
+    let n_samples = params.n_read*params.n_views;
+    if coords.len() != n_samples {
+        panic!("expected {} coordinates for {} views of {} samples, found {}",n_samples,params.n_views,params.n_read,coords.len())
+    }
+    let raw = MRData::new(mrd).complex_stream();
+    let start = params.n_read*params.dummy_excitations;
+    if raw.len() < start + n_samples {panic!("mrd holds {} samples, expected at least {}",raw.len(),start + n_samples)}
+    let nufft = Nufft::new(image_matrix(params));
+    let needed = Nufft::matrix_for(&coords);
+    if (0..3).any(|c| needed[c] > nufft.matrix[c]) {
+        panic!("coordinates need an image matrix of {:?}, but the mrd_to_kspace params give {:?}",needed,nufft.matrix)
+    }
+    let weights = nufft.density_compensation(&coords,DCF_ITERATIONS);
+    nufft.adjoint(&raw[start..start + n_samples],&coords,&weights)
+}
+
+/** Image matrix (read,phase,slice) of a non-cartesian acquisition. Views are n_read samples long,
+so the matrix is held by n_phase1 in plane and n_phase2 through plane */
+pub fn image_matrix(params:&MrdToKspaceParams) -> [usize;3] {
+    [params.n_phase1,params.n_phase1,params.n_phase2]
+}
+
+/** Cartesian k-space (slice,phase,read) of the gridded image, for reconstruction like any fully sampled volume */
+pub fn non_cartesian_raw_to_kspace(mrd:&Path,coords_file:&Path,params:&MrdToKspaceParams) -> Array3<Complex<f32>> {
+    let mut vol = non_cartesian_raw_to_vol(mrd,coords_file,params);
+    for axis in 0..3 {
+        if vol.shape()[axis] == 1 {continue}
+        vol.lanes_mut(Axis(axis)).into_iter().for_each(|mut lane|{
+            let k = cfl::fft_centered(&lane.to_vec(),false);
+            lane.iter_mut().zip(k).for_each(|(l,k)| *l = k);
+        });
+    }
+    vol
+}
+
+pub fn non_cartesian_raw_to_kspace_cfl(mrd:&Path,coords_file:&Path,cfl_base:&Path,params:&MrdToKspaceParams) {
+    let vol = non_cartesian_raw_to_kspace(mrd,coords_file,params);
+    cfl::write_cfl_vol(&vol,cfl_base);
+}
+
+pub fn non_cartesian_raw_to_cfl(mrd:&Path,coords_file:&Path,cfl_base:&Path,params:&MrdToKspaceParams) {
+    let vol = non_cartesian_raw_to_vol(mrd,coords_file,params);
+    cfl::write_cfl_vol(&vol,cfl_base);
+}
+
+// modified bessel function of the first kind, order 0
+fn bessel_i0(x:f32) -> f32 {
+    let q = (x/2.0).powi(2);
+    let (mut term,mut sum) = (1.0f32,1.0f32);
+    for k in 1..50 {
+        term *= q/(k*k) as f32;
+        sum += term;
+        if term < 1E-8*sum {break}
+    }
+    sum
+}
+
+#[test]
+fn test(){
+    // point source off center sampled by center-out radial spokes
+    let (n_spokes,n_read) = (200,16);
+    let x0 = [5.0,-3.0];
+    let coords:Vec<[f32;3]> = (0..n_spokes).flat_map(|spoke|{
+        let theta = 2.0*PI*spoke as f32/n_spokes as f32;
+        (0..=n_read).map(move |i| [i as f32*theta.cos(),i as f32*theta.sin(),0.0])
+    }).collect();
+    let samples:Vec<Complex<f32>> = coords.iter()
+        .map(|k| Complex::from_polar(1.0,-2.0*PI*(k[0]*x0[0] + k[1]*x0[1])/(2*n_read) as f32)).collect();
+    let matrix = Nufft::matrix_for(&coords);
+    assert_eq!(matrix,[32,32,1]);
+    let nufft = Nufft::new(matrix);
+    let weights = nufft.density_compensation(&coords,DCF_ITERATIONS);
+    // density falls off as 1/|k| along spokes in 2-D
+    assert!(weights[n_read - 1] > 5.0*weights[1]);
+    let img = nufft.adjoint(&samples,&coords,&weights);
+    let (peak,_) = img.indexed_iter().fold(((0,0,0),0.0),|(idx,max),(i,v)| if v.norm() > max {(i,v.norm())} else {(idx,max)});
+    assert_eq!(peak,(0,(16.0 + x0[1]) as usize,(16.0 + x0[0]) as usize));
+
+    // fully sampled Cartesian data reproduces the point exactly up to the kernel error
+    let coords:Vec<[f32;3]> = (0..32*32).map(|i| [(i%32) as f32 - 16.0,(i/32) as f32 - 16.0,0.0]).collect();
+    let samples:Vec<Complex<f32>> = coords.iter()
+        .map(|k| Complex::from_polar(1.0,-2.0*PI*(k[0]*x0[0] + k[1]*x0[1])/32.0)).collect();
+    let nufft = Nufft::new([32,32,1]);
+    let img = nufft.adjoint(&samples,&coords,&vec![1.0;coords.len()]);
+    let total:f32 = img.iter().map(|v| v.norm_sqr()).sum();
+    let at_peak = img[[0,(16.0 + x0[1]) as usize,(16.0 + x0[0]) as usize]].norm_sqr();
+    assert!(at_peak/total > 0.99,"{}",at_peak/total);
+}
@@ -0,0 +1,240 @@
+/*
+    Voxel-wise relaxation time fitting for series of magnitude images. T1 is fit from an inversion
+    recovery series with the magnitude model |a - b*exp(-ti/t1)|. The polarity of the signal is
+    restored by testing sign flips around the signal minimum, and the fit is reduced to a 1-D search
+    over t1 with the linear parameters a and b solved in closed form.
+    T2 is fit from a multi-echo series with the mono-exponential model s0*exp(-te/t2) using a log-linear
+    least squares fit weighted by the signal squared.
+*/
+use std::path::Path;
+use ndarray::{Array3, Zip};
+use num_complex::Complex;
+use crate::cfl;
+
+// search range for t1 in milliseconds
+const T1_MIN_MS:f32 = 1.0;
+const T1_MAX_MS:f32 = 10000.0;
+// longest t2 reported in milliseconds. Slower decays are not resolved by a typical echo train
+const T2_MAX_MS:f32 = 5000.0;
+const GOLDEN_SECTION_ITERATIONS:usize = 60;
+
+#[derive(Clone,Copy,Debug)]
+pub struct T1Fit {
+    pub t1:f32,
+    pub a:f32,
+    pub b:f32,
+    pub residual:f32,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct T2Fit {
+    pub t2:f32,
+    pub s0:f32,
+    pub residual:f32,
+}
+
+/** Fit |a - b*exp(-ti/t1)| to a magnitude signal. Returns None if there are fewer than 3 samples */
+pub fn fit_t1(ti_ms:&[f32],signal:&[f32]) -> Option<T1Fit> {
+    if ti_ms.len() != signal.len() {
+        panic!("number of inversion times ({}) doesn't match number of signal samples ({})",ti_ms.len(),signal.len());
+    }
+    if ti_ms.len() < 3 {
+        return None
+    }
+    let mut order:Vec<usize> = (0..ti_ms.len()).collect();
+    order.sort_by(|a,b| ti_ms[*a].partial_cmp(&ti_ms[*b]).unwrap());
+    let ti:Vec<f64> = order.iter().map(|i| ti_ms[*i] as f64).collect();
+    let mag:Vec<f64> = order.iter().map(|i| signal[*i].abs() as f64).collect();
+
+    // the zero crossing is either just before or just after the minimum magnitude sample
+    let i_min = mag.iter().enumerate().min_by(|a,b| a.1.partial_cmp(b.1).unwrap()).map(|(i,_)| i).unwrap();
+    let mut best:Option<T1Fit> = None;
+    for n_negated in [i_min,i_min+1] {
+        let s:Vec<f64> = mag.iter().enumerate().map(|(i,m)| if i < n_negated {-m} else {*m}).collect();
+        let fit = fit_signed(&ti,&s);
+        match best {
+            Some(b) if b.residual <= fit.residual => {}
+            _ => best = Some(fit)
+        }
+    }
+    best
+}
+
+/** Fit s0*exp(-te/t2) to a magnitude signal. Returns None if there are fewer than 2 samples with signal or the signal doesn't decay */
+pub fn fit_t2(te_ms:&[f32],signal:&[f32]) -> Option<T2Fit> {
+    if te_ms.len() != signal.len() {
+        panic!("number of echo times ({}) doesn't match number of signal samples ({})",te_ms.len(),signal.len());
+    }
+    let samples:Vec<(f64,f64)> = te_ms.iter().zip(signal.iter())
+        .filter(|(_,s)| **s > 0.0)
+        .map(|(te,s)| (*te as f64,*s as f64)).collect();
+    if samples.len() < 2 {
+        return None
+    }
+    // weights of s^2 undo the noise amplification of the log transform at low signal
+    let (mut sw,mut swx,mut swy,mut swxx,mut swxy) = (0.0,0.0,0.0,0.0,0.0);
+    for (te,s) in samples.iter() {
+        let w = s*s;
+        let y = s.ln();
+        sw += w;
+        swx += w*te;
+        swy += w*y;
+        swxx += w*te*te;
+        swxy += w*te*y;
+    }
+    let det = sw*swxx - swx*swx;
+    if det.abs() < f64::EPSILON {
+        return None
+    }
+    let slope = (sw*swxy - swx*swy)/det;
+    if slope >= 0.0 {
+        return None
+    }
+    let t2 = -1.0/slope;
+    if t2 > T2_MAX_MS as f64 {
+        return None
+    }
+    let s0 = ((swy - slope*swx)/sw).exp();
+    let residual = samples.iter().map(|(te,s)| (s - s0*(-te/t2).exp()).powi(2)).sum::<f64>();
+    Some(T2Fit {
+        t2:t2 as f32,
+        s0:s0 as f32,
+        residual:residual as f32,
+    })
+}
+
+/** Fit a T1 map from a series of magnitude volumes. Voxels below mask_threshold*max of the longest TI volume are set to 0 */
+pub fn t1_map(series:&[Array3<f32>],ti_ms:&[f32],mask_threshold:f32) -> Array3<f32> {
+    if series.len() != ti_ms.len() {
+        panic!("number of volumes ({}) doesn't match number of inversion times ({})",series.len(),ti_ms.len());
+    }
+    let i_longest = ti_ms.iter().enumerate().max_by(|a,b| a.1.partial_cmp(b.1).unwrap()).map(|(i,_)| i).unwrap();
+    fit_map(series,i_longest,mask_threshold,|signal| fit_t1(ti_ms,signal).map(|fit| fit.t1))
+}
+
+/** Fit a T2 map from a series of magnitude volumes. Voxels below mask_threshold*max of the shortest TE volume are set to 0 */
+pub fn t2_map(series:&[Array3<f32>],te_ms:&[f32],mask_threshold:f32) -> Array3<f32> {
+    if series.len() != te_ms.len() {
+        panic!("number of volumes ({}) doesn't match number of echo times ({})",series.len(),te_ms.len());
+    }
+    let i_shortest = te_ms.iter().enumerate().min_by(|a,b| a.1.partial_cmp(b.1).unwrap()).map(|(i,_)| i).unwrap();
+    fit_map(series,i_shortest,mask_threshold,|signal| fit_t2(te_ms,signal).map(|fit| fit.t2))
+}
+
+/** Fit a T1 map from a series of cfl images, writing the result (in ms) to a cfl */
+pub fn t1_map_from_cfl(images:&[&Path],ti_ms:&[f32],mask_threshold:f32,cfl_base_out:&Path) -> Array3<f32> {
+    let t1 = t1_map(&load_magnitude_series(images),ti_ms,mask_threshold);
+    cfl::write_cfl_vol(&t1.mapv(|x| Complex::new(x,0.0)),cfl_base_out);
+    t1
+}
+
+/** Fit a T2 map from a series of cfl images, writing the result (in ms) to a cfl */
+pub fn t2_map_from_cfl(images:&[&Path],te_ms:&[f32],mask_threshold:f32,cfl_base_out:&Path) -> Array3<f32> {
+    let t2 = t2_map(&load_magnitude_series(images),te_ms,mask_threshold);
+    cfl::write_cfl_vol(&t2.mapv(|x| Complex::new(x,0.0)),cfl_base_out);
+    t2
+}
+
+fn load_magnitude_series(images:&[&Path]) -> Vec<Array3<f32>> {
+    images.iter().map(|cfl_base|{
+        let dims = cfl::get_dims(cfl_base);
+        if dims.len() != 3 {panic!("expecting 3-D image data for relaxometry. Found {}-D",dims.len())}
+        Array3::from_shape_vec((dims[2],dims[1],dims[0]),cfl::to_magnitude(cfl_base)).expect("raw floats cannot fit into shape")
+    }).collect()
+}
+
+// fits every voxel with signal above mask_threshold*max of the mask volume. Voxels that can't be fit are 0
+fn fit_map<F>(series:&[Array3<f32>],mask_index:usize,mask_threshold:f32,fit:F) -> Array3<f32>
+where F:Fn(&[f32]) -> Option<f32>
+{
+    let shape = series[0].dim();
+    series.iter().for_each(|vol| if vol.dim() != shape {
+        panic!("all volumes in the series must have the same dimensions");
+    });
+    let max = series[mask_index].iter().fold(0.0f32,|acc,x| acc.max(*x));
+    let threshold = mask_threshold*max;
+
+    let mut map = Array3::<f32>::zeros(shape);
+    Zip::indexed(&mut map).for_each(|idx,value|{
+        if series[mask_index][idx] < threshold {
+            return
+        }
+        let signal:Vec<f32> = series.iter().map(|vol| vol[idx]).collect();
+        if let Some(fitted) = fit(&signal) {
+            *value = fitted;
+        }
+    });
+    map
+}
+
+// golden section search over ln(t1) with a and b solved by linear least squares
+fn fit_signed(ti:&[f64],s:&[f64]) -> T1Fit {
+    let gr = (5f64.sqrt() - 1.0)/2.0;
+    let mut lo = (T1_MIN_MS as f64).ln();
+    let mut hi = (T1_MAX_MS as f64).ln();
+    let mut x1 = hi - gr*(hi - lo);
+    let mut x2 = lo + gr*(hi - lo);
+    let mut f1 = linear_fit(ti,s,x1.exp()).2;
+    let mut f2 = linear_fit(ti,s,x2.exp()).2;
+    for _ in 0..GOLDEN_SECTION_ITERATIONS {
+        if f1 < f2 {
+            hi = x2;
+            x2 = x1;
+            f2 = f1;
+            x1 = hi - gr*(hi - lo);
+            f1 = linear_fit(ti,s,x1.exp()).2;
+        }else {
+            lo = x1;
+            x1 = x2;
+            f1 = f2;
+            x2 = lo + gr*(hi - lo);
+            f2 = linear_fit(ti,s,x2.exp()).2;
+        }
+    }
+    let t1 = ((lo + hi)/2.0).exp();
+    let (a,b,residual) = linear_fit(ti,s,t1);
+    T1Fit {
+        t1:t1 as f32,
+        a:a as f32,
+        b:b as f32,
+        residual:residual as f32,
+    }
+}
+
+// solves s = a - b*e for fixed t1 where e = exp(-ti/t1). Returns (a,b,sum of squared residuals)
+fn linear_fit(ti:&[f64],s:&[f64],t1:f64) -> (f64,f64,f64) {
+    let n = ti.len() as f64;
+    let e:Vec<f64> = ti.iter().map(|t| (-t/t1).exp()).collect();
+    let se:f64 = e.iter().sum();
+    let see:f64 = e.iter().map(|x| x*x).sum();
+    let ss:f64 = s.iter().sum();
+    let sse:f64 = s.iter().zip(e.iter()).map(|(s,e)| s*e).sum();
+    let det = n*see - se*se;
+    if det.abs() < f64::EPSILON {
+        return (ss/n,0.0,f64::MAX)
+    }
+    let a = (see*ss - se*sse)/det;
+    let b = (se*ss - n*sse)/det;
+    let residual = s.iter().zip(e.iter()).map(|(s,e)| (s - a + b*e).powi(2)).sum();
+    (a,b,residual)
+}
+
+#[test]
+fn test(){
+    let ti = [50.0,200.0,500.0,1000.0,2000.0,4000.0];
+    let (t1,a,b) = (1200.0f32,1000.0f32,1900.0f32);
+    let signal:Vec<f32> = ti.iter().map(|t:&f32| (a - b*(-t/t1).exp()).abs()).collect();
+    let fit = fit_t1(&ti,&signal).unwrap();
+    println!("{:?}",fit);
+    assert!((fit.t1 - t1).abs()/t1 < 0.01);
+    assert!((fit.a - a).abs()/a < 0.01);
+    assert!((fit.b - b).abs()/b < 0.01);
+
+    let te = [10.0,20.0,30.0,40.0,50.0,60.0,70.0,80.0];
+    let (t2,s0) = (35.0f32,1500.0f32);
+    let signal:Vec<f32> = te.iter().map(|t:&f32| s0*(-t/t2).exp()).collect();
+    let fit = fit_t2(&te,&signal).unwrap();
+    println!("{:?}",fit);
+    assert!((fit.t2 - t2).abs()/t2 < 0.01);
+    assert!((fit.s0 - s0).abs()/s0 < 0.01);
+}
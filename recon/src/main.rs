@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::fs::{create_dir, create_dir_all};
 use std::io::{stdin, stdout, Write};
 use clap::Parser;
@@ -9,6 +10,8 @@ use recon::recon_config::{Config, ConfigFile, ProjectSettings, RemoteSystem, Vol
 use recon::slurm::{BatchScript, get_job_state};
 use recon::vol_manager::{VolumeManager, VolumeManagerState};
 use utils::m_number_formatter;
+use headfile::headfile::{Headfile, T1MapHeadfileParams, T2MapHeadfileParams};
+use mr_data::{cfl, relaxometry};
 
 #[derive(clap::Parser,Debug)]
 pub struct ReconArgs {
@@ -32,6 +35,10 @@ pub enum ReconAction {
     NewProjectTemplate(TemplateConfigArgs),
     /// interact with a single volume manager
     VolumeManager(VolumeManagerCmd),
+    /// fit a T1 map from a completed inversion recovery series
+    T1Map(RelaxometryArgs),
+    /// fit a T2 map from a completed multi-echo series
+    T2Map(RelaxometryArgs),
 }
 
 #[derive(clap::Args,Debug)]
@@ -83,6 +90,14 @@ pub struct WaitForCompletionArgs {
     refresh_period:Option<f32>,
 }
 
+#[derive(Clone,clap::Args,Debug)]
+pub struct RelaxometryArgs {
+    run_number:String,
+    /// fraction of the peak signal (longest TI or shortest TE volume) below which voxels are not fit (defaults to 0.05)
+    #[clap(long)]
+    mask_threshold:Option<f32>,
+}
+
 #[derive(Clone,clap::Args,Debug)]
 pub struct RunnoArgs {
     run_number:String,
@@ -161,6 +176,8 @@ fn main() {
         ReconAction::New(args) => recon(args),
         ReconAction::Cancel(args) => cancel(args),
         ReconAction::WaitForCompletion(args) => wait_for_completion(args),
+        ReconAction::T1Map(args) => t1_map(args),
+        ReconAction::T2Map(args) => t2_map(args),
     }
 }
 
@@ -223,7 +240,13 @@ impl VolumeManagerCollection {
                 &settings.specimen_id,
                 &settings.raw_data_base_dir
             ),
-            _=> panic!("{:?} not yet implemented for volume manager collection!",collection_type)
+            MultiEcho => VolumeManagerConfig::new_multi_echo_config(
+                &settings.project_settings,
+                &settings.civm_id,
+                &settings.run_number,
+                &settings.specimen_id,
+                &settings.raw_data_base_dir
+            ),
         };
 
         // trim configs down if a subset is specified
@@ -539,6 +562,107 @@ fn status(args:RunnoArgs) {
 }
 
 
+const DEFAULT_RELAXOMETRY_MASK_THRESHOLD:f32 = 0.05;
+fn t1_map(args:RelaxometryArgs) {
+    let work_dir = work_dir_big_disk(&args.run_number);
+    let mut vms = completed_volume_managers(&work_dir);
+
+    // inversion times are recorded in the headfile of each volume
+    let ti_ms = |vm:&VolumeManager| -> f32 {
+        let h = Headfile::open(&vm.headfile()).to_hash();
+        let ti = h.get("ti").unwrap_or_else(|| panic!("ti not found in headfile for {}. Is this an inversion recovery series?",vm.name()));
+        ti.parse().unwrap_or_else(|_| panic!("cannot parse ti {} for {}",ti,vm.name()))
+    };
+    vms.sort_by(|a,b| ti_ms(a).partial_cmp(&ti_ms(b)).unwrap());
+    let ti:Vec<f32> = vms.iter().map(ti_ms).collect();
+    let images = image_data(&vms);
+    let image_refs:Vec<&Path> = images.iter().map(|p| p.as_path()).collect();
+
+    let name = format!("{}_t1map",args.run_number);
+    let mask_threshold = args.mask_threshold.unwrap_or(DEFAULT_RELAXOMETRY_MASK_THRESHOLD);
+    println!("fitting T1 from {} volumes ...",vms.len());
+    let cfl_base = relaxometry_cfl(&work_dir,&name);
+    relaxometry::t1_map_from_cfl(&image_refs,&ti,mask_threshold,&cfl_base);
+    let t1_params = T1MapHeadfileParams {
+        ti_ms:ti,
+        source_volumes:vms.iter().map(|vm| vm.name()).collect(),
+        mask_threshold,
+    };
+    write_relaxometry_map(&work_dir,&name,&vms[0],&cfl_base,&t1_params.to_hash());
+}
+
+fn t2_map(args:RelaxometryArgs) {
+    let work_dir = work_dir_big_disk(&args.run_number);
+    let mut vms = completed_volume_managers(&work_dir);
+
+    // every echo headfile carries the full list of echo times
+    let te_ms = |vm:&VolumeManager| -> f32 {
+        let echo = vm.config().vm_settings.echo_index.unwrap_or_else(|| panic!("{} is not part of a multi-echo recon",vm.name()));
+        let h = Headfile::open(&vm.headfile()).to_hash();
+        let echo_times = h.get("echo_times").unwrap_or_else(|| panic!("echo_times not found in headfile for {}. Is this a multi-echo series?",vm.name()));
+        let te = headfile_array(echo_times);
+        *te.get(echo).unwrap_or_else(|| panic!("no echo time found for echo {} of {}",echo,vm.name()))
+    };
+    vms.sort_by(|a,b| te_ms(a).partial_cmp(&te_ms(b)).unwrap());
+    let te:Vec<f32> = vms.iter().map(te_ms).collect();
+    let images = image_data(&vms);
+    let image_refs:Vec<&Path> = images.iter().map(|p| p.as_path()).collect();
+
+    let name = format!("{}_t2map",args.run_number);
+    let mask_threshold = args.mask_threshold.unwrap_or(DEFAULT_RELAXOMETRY_MASK_THRESHOLD);
+    println!("fitting T2 from {} echoes ...",vms.len());
+    let cfl_base = relaxometry_cfl(&work_dir,&name);
+    relaxometry::t2_map_from_cfl(&image_refs,&te,mask_threshold,&cfl_base);
+    let t2_params = T2MapHeadfileParams {
+        te_ms:te,
+        source_volumes:vms.iter().map(|vm| vm.name()).collect(),
+        mask_threshold,
+    };
+    write_relaxometry_map(&work_dir,&name,&vms[0],&cfl_base,&t2_params.to_hash());
+}
+
+fn completed_volume_managers(work_dir:&Path) -> Vec<VolumeManager> {
+    let vm_collection = VolumeManagerCollection::from_work_dir(work_dir).unwrap_or_else(|| panic!("no volume manager configs found in {:?}",work_dir));
+    vm_collection.vm_config_files.iter().map(|cfg|{
+        let vm = VolumeManager::read(cfg).unwrap_or_else(|| panic!("volume manager state not found for {:?}",cfg));
+        if !vm.is_done() {
+            panic!("{} is not done reconstructing. Wait for the series to complete before fitting",vm.name());
+        }
+        vm
+    }).collect()
+}
+
+fn image_data(vms:&[VolumeManager]) -> Vec<PathBuf> {
+    vms.iter().map(|vm| vm.image_data().unwrap_or_else(|| panic!("image data not found for {}",vm.name()))).collect()
+}
+
+// parses a headfile array of the form n:1,a b c
+fn headfile_array(value:&str) -> Vec<f32> {
+    let (_,elements) = value.split_once(',').unwrap_or(("",value));
+    elements.split_whitespace().map(|e| e.parse().unwrap_or_else(|_| panic!("cannot parse {} in {}",e,value))).collect()
+}
+
+fn relaxometry_cfl(work_dir:&Path,name:&str) -> PathBuf {
+    let output_dir = work_dir.join(name);
+    create_dir_all(&output_dir).unwrap_or_else(|_| panic!("cannot create dir: {:?}",output_dir));
+    output_dir.join(format!("{}_imspace",name))
+}
+
+/** Writes a relaxation time map (ms) to civm raw with the headfile of the basis volume and the fit parameters */
+fn write_relaxometry_map(work_dir:&Path,name:&str,basis:&VolumeManager,cfl_base:&Path,map_params:&HashMap<String,String>) {
+    let image_dir = work_dir.join(name).join(format!("{}images",name));
+    // relaxation times are written in ms without scaling
+    let settings = basis.config();
+    let raw_prefix = format!("{}{}",settings.project_settings.scanner_settings.image_code,settings.project_settings.scanner_settings.image_tag);
+    cfl::to_civm_raw_u16(cfl_base,&image_dir,name,&raw_prefix,1.0,(true,true,true));
+
+    let headfile = image_dir.join(name).with_extension("headfile");
+    std::fs::copy(basis.headfile(),&headfile).expect("cannot copy headfile");
+    Headfile::open(&headfile).append(map_params);
+    println!("{} written to {:?}",name,image_dir);
+}
+
+
 const DEFAULT_TIME_TO_WAIT:f32 = 2.0; //minutes
 fn wait_for_completion(args:WaitForCompletionArgs){
     let bg = std::env::var("BIGGUS_DISKUS").expect("BIGGUS_DISKUS must be set on this workstation");
@@ -166,6 +166,8 @@ impl Config for ReconSettings {
 pub struct ProjectSettings {
     pub project_code:String,
     pub dti_vols:Option<usize>,
+    /// number of echoes reconstructed from a single raw file in multi-echo recons
+    pub n_echoes:Option<usize>,
     pub archive_info: ArchiveInfo,
     pub recon_settings:ReconSettings,
     pub scanner_settings:ScannerSettings,
@@ -183,6 +185,7 @@ impl Config for ProjectSettings {
         Self {
             project_code: String::from("20.5xfad.01"),
             dti_vols: Some(67),
+            n_echoes: None,
             archive_info: ArchiveInfo::default(),
             recon_settings: ReconSettings::default(),
             scanner_settings: ScannerSettings::default(),
@@ -219,6 +222,8 @@ pub struct VolumeManagerSettings {
     //pub work_dir:PathBuf,
     //pub m_number:String,
     pub volume_index:Option<usize>,
+    /// echo reconstructed by this volume manager when several volumes share a raw file
+    pub echo_index:Option<usize>,
     pub engine_work_dir:PathBuf,
     pub resource_dir:PathBuf,
     pub is_scale_dependent:bool,
@@ -229,6 +234,7 @@ impl VolumeManagerSettings {
     pub fn new(resource_directory:&Path,is_scale_setter:bool,is_scale_dependent:bool,vol_index:Option<usize>) -> Self {
         Self {
             volume_index: vol_index,
+            echo_index: None,
             engine_work_dir: PathBuf::from("/privateShares/wa41"),
             resource_dir: resource_directory.to_owned(),
             is_scale_dependent,
@@ -249,6 +255,17 @@ impl VolumeManagerSettings {
     pub fn new_single_volume_settings(resource_base_dir:&Path) -> Self {
         VolumeManagerSettings::new(resource_base_dir,false,false,None)
     }
+    pub fn new_multi_echo_settings(resource_base_dir:&Path,n_echoes:usize) -> Vec<Self> {
+        let mut vms:Vec<VolumeManagerSettings> = (0..n_echoes).map(|echo_index|{
+            let mut s = VolumeManagerSettings::new(resource_base_dir,false,true,None);
+            s.echo_index = Some(echo_index);
+            s
+        }).collect();
+        // the first echo has the most signal, so it sets the scale for the rest
+        vms[0].is_scale_setter = true;
+        vms[0].is_scale_dependent = false;
+        vms
+    }
 }
 
 
@@ -315,10 +332,33 @@ impl VolumeManagerConfig {
         }]
     }
 
+    pub fn new_multi_echo_config(project_settings:&Path,civm_id:&str,run_number:&str,spec_id:&str,resource_dir:&Path) -> Vec<Self> {
+        let p = ProjectSettings::from_file(project_settings);
+        let r = RunSettings {
+            run_number: run_number.to_string(),
+            civm_id: civm_id.to_string(),
+            spec_id: spec_id.to_string()
+        };
+        let n_echoes = p.n_echoes.expect("n_echoes must be set in the project settings for a multi-echo recon");
+        let vms = VolumeManagerSettings::new_multi_echo_settings(resource_dir,n_echoes);
+        vms.iter().map(|s| VolumeManagerConfig{
+            project_settings:p.clone(),
+            vm_settings:s.clone(),
+            run_settings:r.clone(),
+            slurm_disabled:false,
+            send_to_engine:true,
+        }).collect()
+    }
+
     pub fn m_number(&self) -> String {
-        let i = self.vm_settings.volume_index.unwrap_or(0);
-        let n = self.project_settings.dti_vols.unwrap_or(1);
-        utils::m_number(i,n)
+        match self.vm_settings.echo_index {
+            Some(echo) => utils::m_number(echo,self.project_settings.n_echoes.unwrap_or(1)),
+            None => {
+                let i = self.vm_settings.volume_index.unwrap_or(0);
+                let n = self.project_settings.dti_vols.unwrap_or(1);
+                utils::m_number(i,n)
+            }
+        }
     }
 
     pub fn name(&self) -> String {
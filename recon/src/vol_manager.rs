@@ -11,9 +11,9 @@ use crate::bart_wrapper::{bart_pics};
 use crate::slurm::{self,BatchScript, JobState};
 use std::process::{Command, exit};
 use std::time::Duration;
-use seq_lib::pulse_sequence::MrdToKspaceParams;
+use seq_lib::pulse_sequence::{MrdFormat, MrdToKspaceParams, K_COORDS_FILENAME};
 //use crate::config::{ProjectSettings, Recon};
-use mr_data::mrd::{fse_raw_to_cfl, cs_mrd_to_kspace};
+use mr_data::mrd::{fse_raw_to_cfl, cs_mrd_to_kspace, cs_mrd_echo_to_kspace};
 use headfile::headfile::{ReconHeadfile, Headfile, ArchiveTag};
 use acquire::build::{HEADFILE_NAME,HEADFILE_EXT};
 use clap::Parser;
@@ -52,6 +52,7 @@ pub enum ResourceError {
     MrdNotFound,
     MrdNotComplete,
     KspaceConfigNotFound,
+    KCoordsNotFound,
     FetchError,
     Unknown,
 }
@@ -138,6 +139,10 @@ impl VolumeManagerResources {
                 let kspace_config = utils::get_first_match(&res_dir, "*.mtk").ok_or(ResourceError::KspaceConfigNotFound)?;
                 let meta = utils::get_first_match(&res_dir, "meta.txt");
                 let pulse_program = utils::get_first_match(&res_dir,"*.ppl");
+                // non-cartesian k-space is gridded from the coordinates fetched with the mrd
+                if let MrdFormat::NonCartesian = MrdToKspaceParams::from_file(&kspace_config).mrd_format {
+                    utils::get_first_match(&res_dir,K_COORDS_FILENAME).ok_or(ResourceError::KCoordsNotFound)?;
+                }
                 Ok(Self {
                     cs_table,
                     raw_mrd,
@@ -318,6 +323,14 @@ impl VolumeManager {
         self.work_dir().join(format!("{}images",self.name()))
     }
 
+    pub fn image_data(&self) -> Option<PathBuf> {
+        self.image_data.clone()
+    }
+
+    pub fn headfile(&self) -> PathBuf {
+        self.image_dir().join(self.name()).with_extension("headfile")
+    }
+
     pub fn launch_with_slurm_later(config:&Path,seconds_later:u32) -> u32 {
         let mut vm = VolumeManager::open(config);
         let mut bs = Self::slurm_batch_script(config);
@@ -478,7 +491,10 @@ impl VolumeManager {
                 match &self.resources {
                     Some(res) => {
                         let mtk = MrdToKspaceParams::from_file(&res.kspace_config);
-                        cs_mrd_to_kspace(&res.raw_mrd, &res.cs_table, &self.kspace_file(), &mtk);
+                        match settings.vm_settings.echo_index {
+                            Some(echo) => cs_mrd_echo_to_kspace(&res.raw_mrd, &res.cs_table, &self.kspace_file(), &mtk, echo),
+                            None => cs_mrd_to_kspace(&res.raw_mrd, &res.cs_table, &self.kspace_file(), &mtk)
+                        }
                         self.kspace_data = Some(self.kspace_file());
                         self.state = Reconstructing;
                         StateAdvance::Succeeded
@@ -22,6 +22,8 @@ pub enum Action {
     RunScan,
     /// finds all pprs nested in the parent directory and runs them
     RunDirectory(RunDirectoryArgs),
+    /// runs every experiment of a built protocol in protocol order
+    RunProtocol(PathArgs),
     /// abort the scan
     Abort,
     /// Run a ppr in setup mode
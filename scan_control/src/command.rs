@@ -8,6 +8,8 @@ use regex::Regex;
 use glob::glob;
 use chrono::{DateTime,Local};
 use utils;
+use seq_lib::protocol::{Experiment, Protocol, PROTOCOL_FILENAME};
+use seq_lib::pulse_sequence::LUT_FILENAME;
 
 use crate::args::*;
 
@@ -131,6 +133,43 @@ pub fn run_directory(args:RunDirectoryArgs){
     println!("acquisition complete");
 }
 
+/** Runs the experiments of a protocol directory built by acquire, in protocol order. Setup experiments
+run in setup mode until the operator stops them */
+pub fn run_protocol(protocol_dir:&Path) {
+    let protocol = Protocol::from_file(&protocol_dir.join(PROTOCOL_FILENAME));
+    let n = protocol.experiments.len();
+    protocol.experiments.iter().enumerate().for_each(|(index,experiment)| {
+        let dir = protocol_dir.join(protocol.experiment_dir(index));
+        println!("running experiment {} of {} ({}) ...",index+1,n,experiment.kind());
+        let cs_table = match experiment.is_cs() {
+            true => Some(LUT_FILENAME.to_string()),
+            false => None
+        };
+        match experiment {
+            Experiment::Setup(_) => {
+                let pat = dir.join("*.ppr");
+                let ppr = glob(pat.to_str().unwrap()).expect("failed to read glob pattern").flatten().next()
+                    .unwrap_or_else(|| panic!("no setup ppr found in {:?}",dir));
+                setup_ppr(RunDirectoryArgs{
+                    path:ppr,
+                    cs_table,
+                    depth_to_search:None
+                });
+                thread::sleep(time::Duration::from_secs(2));
+                // block until the operator stops the setup
+                while let Status::SetupInProgress | Status::Running = scan_status() {
+                    thread::sleep(time::Duration::from_secs(2));
+                }
+            }
+            _ => run_directory(RunDirectoryArgs{
+                path:dir,
+                cs_table,
+                depth_to_search:Some(experiment.search_depth())
+            })
+        }
+    });
+    println!("protocol complete");
+}
 
 //196095
 pub fn upload_table(path_to_table:&Path){
@@ -35,6 +35,9 @@ fn main(){
         Action::RunDirectory(args) => {
             run_directory(args)
         }
+        Action::RunProtocol(path_str) => {
+            run_protocol(Path::new(&path_str.path))
+        }
         Action::SetupPPR(args) => {
             setup_ppr(args);
         }
@@ -6,20 +6,26 @@ use seq_tools::grad_cal;
 use seq_tools::acq_event::{AcqEvent, SpectralWidth};
 use seq_tools::event_block::{Event, EventQueue, GradEventType};
 use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin, Origin};
-use seq_tools::execution::ExecutionBlock;
+use seq_tools::execution::{ExecutionBlock, RfRole};
 use seq_tools::gradient_event::GradEvent;
 use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
-use seq_tools::ppl::{GradClock, Orientation, PhaseUnit,BaseFrequency};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
 use seq_tools::pulse::{CompositeHardpulse, HalfSin, Hardpulse, Pulse, Trapezoid};
 use seq_tools::rf_event::RfEvent;
 use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
 use seq_tools::_utils::{sec_to_clock};
-use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat};
+use crate::preparation::{self, PreparationParams};
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
 use serde_json;
 use serde::{Serialize,Deserialize};
 use cs_table::cs_table::CSTable;
 use headfile::headfile::{AcqHeadfileParams, DWHeadfileParams, DWHeadfile, AcqHeadfile};
 
+// the acquisition starts this long before each echo (seconds)
+const ACQ_LEAD:f32 = 38E-6;
+
 
 impl Setup for FseDtiParams {
     fn set_mode(&mut self) {
@@ -115,12 +121,14 @@ impl Initialize for FseDtiParams {
             echo_time: 13.98E-3,
             echo_spacing: 7.2E-3,
             obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
             rep_time: 80E-3,
             n_averages: 1,
             n_repetitions: 2000,
             view_acceleration : 2,
             setup_mode: false,
-            grad_off: false
+            grad_off: false,
+            preparation: None
         }
     }
     fn load(params_file: &Path) -> Self {
@@ -182,7 +190,9 @@ impl MrdToKspace for FseDtiParams {
             n_views,
             view_acceleration: self.view_acceleration as usize,
             dummy_excitations: 20,
-            n_objects: 1
+            n_objects: 1,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
         }
     }
 }
@@ -196,8 +206,62 @@ impl SequenceParameters for FseDtiParams {
         let mut f = File::create(params_file).expect("cannot create file");
         f.write_all(str.as_bytes()).expect("trouble writing to file");
     }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_time = validation::placement("echo_time",self.echo_time,Self::default().echo_time,|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            // the acquisition can't start before the excitation
+            (params.echo_time >= ACQ_LEAD).then(|| params.instantiate())
+        });
+        if echo_time.as_ref().is_some_and(|e| e.nearest.is_some()) {
+            return validation::collect(vec![echo_time])
+        }
+        let echo_spacing = validation::placement("echo_spacing",self.echo_spacing,Self::default().echo_spacing,|echo_spacing|{
+            let mut params = self.clone();
+            params.echo_spacing = echo_spacing;
+            // the acquisition can't start before the excitation
+            (params.echo_time >= ACQ_LEAD).then(|| params.instantiate())
+        });
+        if echo_spacing.is_some() || echo_time.is_some() {
+            // a longer echo time can't fix events of the echo train that are placed on top of each other
+            return validation::collect(vec![echo_spacing.or(echo_time)])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_time = timing::min_value(|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            // the acquisition can't start before the excitation
+            (params.echo_time >= ACQ_LEAD).then(|| params.instantiate())
+        },&[Self::default().echo_time,self.echo_time]);
+        let echo_time = self.echo_time.max(min_echo_time.unwrap_or(0.0));
+        let min_echo_spacing = timing::min_value(|echo_spacing|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            params.echo_spacing = echo_spacing;
+            // the acquisition can't start before the excitation
+            (params.echo_time >= ACQ_LEAD).then(|| params.instantiate())
+        },&[Self::default().echo_spacing,self.echo_spacing]);
+        let mut shortest = self.clone();
+        TimingLimits {
+            min_echo_time,
+            min_echo_spacing,
+            min_rep_time:min_echo_time.zip(min_echo_spacing).map(|(echo_time,echo_spacing)|{
+                shortest.echo_time = echo_time;
+                shortest.echo_spacing = echo_spacing;
+                timing::min_rep_time(shortest.instantiate().as_ref())
+            })
+        }
+    }
     fn instantiate(&self) -> Box<dyn Build> {
-        Box::new(FseDti::new(self.clone()))
+        preparation::prepare(Box::new(FseDti::new(self.clone())),&self.preparation)
     }
 }
 
@@ -211,7 +275,8 @@ impl Build for FseDti {
             n_averages: self.params.n_averages,
             n_repetitions: self.params.n_repetitions,
             rep_time: self.params.rep_time,
-            base_frequency: BaseFrequency::civm9p4t(self.params.obs_freq_offset),
+            obs_freq_offset: self.params.obs_freq_offset,
+            rf_dac_seconds: self.params.rf_dac_seconds,
             orientation: Orientation::CivmStandard,
             //orientation: Orientation::Ortho2,
             grad_clock: GradClock::CPS20,
@@ -248,12 +313,16 @@ pub struct FseDtiParams {
         echo_time: f32,
         echo_spacing: f32,
         obs_freq_offset: f32,
+        #[serde(default)]
+        rf_dac_seconds: Option<f32>,
         rep_time: f32,
         n_averages: u16,
         n_repetitions: u32,
         view_acceleration : u16,
         setup_mode: bool,
         grad_off: bool,
+        // preparation modules run ahead of the sequence every repetition
+        preparation: Option<Vec<PreparationParams>>,
     }
 
 #[derive(Clone)]
@@ -469,7 +538,7 @@ impl FseDti {
                 RfStateType::Static(0)
             );
 
-            let refocus1 = RfEvent::new(
+            let mut refocus1 = RfEvent::new(
                 "refocus1",
                 2,
                 w.refocus.clone(),
@@ -477,8 +546,9 @@ impl FseDti {
                 RfStateType::Adjustable(400, Some(PhaseCycleStrategy::CycleCPMG(2))),
                 //RfStateType::Driven(RfDriver::new(DriverVar::Repetition,RfDriverType::PhaseCycle3D(PhaseCycleStrategy::CycleCPMG(1)),None)),
             );
+            refocus1.set_role(RfRole::Refocus);
 
-            let refocus2 = RfEvent::new(
+            let mut refocus2 = RfEvent::new(
                 "refocus2",
                 3,
                 w.refocus.clone(),
@@ -486,8 +556,9 @@ impl FseDti {
                 RfStateType::Adjustable(120, None),
                 //RfStateType::Driven(RfDriver::new(DriverVar::Repetition,RfDriverType::PhaseCycle3D(PhaseCycleStrategy::CycleCPMG(1)),None)),
             );
+            refocus2.set_role(RfRole::Refocus);
 
-            let refocus3 = RfEvent::new(
+            let mut refocus3 = RfEvent::new(
                 "refocus3",
                 4,
                 w.refocus.clone(),
@@ -495,6 +566,7 @@ impl FseDti {
                 RfStateType::Adjustable(80, None),
                 //RfStateType::Driven(RfDriver::new(DriverVar::Repetition,RfDriverType::PhaseCycle3D(PhaseCycleStrategy::CycleCPMG(1)),None)),
             );
+            refocus3.set_role(RfRole::Refocus);
 
             let phase_encode1 = GradEvent::new(
                 (Some(w.phase_encode), Some(w.phase_encode), Some(w.phase_encode)),
@@ -597,15 +669,15 @@ impl FseDti {
             let excitation = Event::new(self.events.excitation.as_reference(), Origin);
             let refocus1 = Event::new(self.events.refocus1.as_reference(), ExactFromOrigin(sec_to_clock(tau)));
             let readout1 = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te)));
-            let acquire1 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te - 38E-6)));
+            let acquire1 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te - ACQ_LEAD)));
 
             let refocus2 = Event::new(self.events.refocus2.as_reference(), ExactFromOrigin(sec_to_clock(tau2 + adj)));
             let readout2 = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te + 1.0 * te2)));
-            let acquire2 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te + 1.0 * te2 - 38E-6)));
+            let acquire2 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te + 1.0 * te2 - ACQ_LEAD)));
 
             let refocus3 = Event::new(self.events.refocus3.as_reference(), ExactFromOrigin(sec_to_clock(te + 2.0 * te2 - te2 / 2.0 + adj)));
             let readout3 = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te + 2.0 * te2)));
-            let acquire3 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te + 2.0 * te2 - 38E-6)));
+            let acquire3 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te + 2.0 * te2 - ACQ_LEAD)));
 
             let phase_encode1 = Event::new(self.events.phase_encode1.as_reference(), Before(readout1.clone(), 0));
             let phase_encode2 = Event::new(self.events.phase_encode2.as_reference(), Before(readout2.clone(), 0));
@@ -0,0 +1,481 @@
+/*
+    3-D spoiled gradient echo. A non-selective hard pulse is followed by a phase encode and read
+    prephase, a single readout, a rewind of the phase encodes and a gradient spoiler. Transverse
+    magnetization left over at the end of the repetition is removed by quadratic rf spoiling, where
+    the excitation and receiver phase follow the quadratic phase cycle of the repetition counter.
+    Phase encodes come from the compressed sensing table.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{Read, Write};
+use seq_tools::grad_cal;
+use seq_tools::acq_event::{AcqEvent, SpectralWidth};
+use seq_tools::event_block::{Event, EventQueue, GradEventType};
+use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin, Origin};
+use seq_tools::execution::ExecutionBlock;
+use seq_tools::gradient_event::GradEvent;
+use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
+use seq_tools::pulse::{Hardpulse, Pulse, Trapezoid};
+use seq_tools::rf_event::RfEvent;
+use seq_tools::rf_state::{PhaseCycleStrategy, RfDriver, RfDriverType, RfStateType, RF_SPOIL_SEED_DEG};
+use seq_tools::_utils::sec_to_clock;
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, CompressedSense, Simulate, AcqDimensions, AcqDims, Initialize, MrdToKspace, MrdToKspaceParams, MrdFormat, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
+use serde_json;
+use serde::{Serialize,Deserialize};
+use cs_table::cs_table::CSTable;
+use headfile::headfile::{AcqHeadfile, AcqHeadfileParams};
+
+impl Setup for GreParams {
+    fn set_mode(&mut self) {
+        self.setup_mode = true;
+    }
+    fn set_repetitions(&mut self) {
+        self.n_repetitions = 2000;
+    }
+}
+
+impl CompressedSense for GreParams {
+    fn is_cs(&self) -> bool {
+        true
+    }
+    fn set_cs_table(&mut self) {
+        let n_reps = CSTable::open(
+            &self.cs_table().unwrap(),
+            self.samples.1 as i16,
+            self.samples.2 as i16,
+        ).n_views() as u32/self.view_acceleration as u32;
+        self.n_repetitions = n_reps;
+    }
+    fn cs_table(&self) -> Option<PathBuf> {
+        Some(self.cs_table.clone())
+    }
+}
+
+impl Simulate for GreParams {
+    fn set_sim_repetitions(&mut self) {
+        self.n_repetitions = 2;
+    }
+}
+
+impl AcqDimensions for GreParams {
+    fn acq_dims(&self) -> AcqDims {
+        AcqDims {
+            n_read: self.samples.0 as i32,
+            n_phase1: self.samples.1 as i32,
+            n_phase2: self.samples.2 as i32,
+            n_slices: 1,
+            n_echos: 1,
+            n_experiments: 1
+        }
+    }
+}
+
+impl AcqHeadfile for GreParams {
+    fn acq_params(&self) -> AcqHeadfileParams {
+        AcqHeadfileParams {
+            dim_x: self.samples.0 as i32,
+            dim_y: self.samples.1 as i32,
+            dim_z: self.samples.2 as i32,
+            fovx_mm: self.fov.0,
+            fovy_mm: self.fov.1,
+            fovz_mm: self.fov.2,
+            te_ms: 1E3*self.echo_time,
+            tr_us: 1E6*self.rep_time,
+            alpha: self.flip_angle,
+            bw: self.spectral_width.hertz() as f32 /2.0,
+            n_echos: 1,
+            S_PSDname: self.name()
+        }
+    }
+}
+
+impl Initialize for GreParams {
+    fn default() -> Self {
+        GreParams {
+            name: "gre".to_string(),
+            cs_table: Path::new(r"C:\workstation\data\petableCS_stream\stream_CS480_8x_pa18_pb54").to_owned(),
+            fov: (19.7, 12.0, 12.0),
+            samples: (788, 480, 480),
+            sample_discards: 0,
+            spectral_width: SpectralWidth::SW200kH,
+            flip_angle: 20.0,
+            rf_duration: 140E-6,
+            ramp_time: 140E-6,
+            phase_encode_time: 550E-6,
+            spoil_duration: 600E-6,
+            echo_time: 5E-3,
+            obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
+            rep_time: 20E-3,
+            rf_spoiling: true,
+            n_averages: 1,
+            n_repetitions: 2000,
+            view_acceleration: 1,
+            setup_mode: false,
+            grad_off: false
+        }
+    }
+    fn load(params_file: &Path) -> Self {
+        let mut f = File::open(params_file).expect("cannot open file");
+        let mut json_str = String::new();
+        f.read_to_string(&mut json_str).expect("trouble reading file");
+        serde_json::from_str(&json_str).expect("cannot deserialize string")
+    }
+    fn write_default(params_file: &Path){
+        let params = Self::default();
+        let str = serde_json::to_string_pretty(&params).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+}
+
+impl MrdToKspace for GreParams {
+    fn mrd_to_kspace_params(&self) -> MrdToKspaceParams {
+        let table_compression = 8;
+        let n_views = (self.samples.1 as usize*self.samples.2 as usize)/table_compression;
+        MrdToKspaceParams {
+            mrd_format:MrdFormat::StandardCSVol,
+            n_read: self.samples.0 as usize,
+            n_phase1: self.samples.1 as usize,
+            n_phase2: self.samples.2 as usize,
+            n_views,
+            view_acceleration: self.view_acceleration as usize,
+            dummy_excitations: 0,
+            n_objects: 1,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
+        }
+    }
+}
+
+impl SequenceParameters for GreParams {
+
+    fn name(&self) -> String {
+        String::from("gre")
+    }
+    fn write(&self,params_file: &Path){
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_time = validation::placement("echo_time",self.echo_time,Self::default().echo_time,|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        });
+        if echo_time.is_some() {
+            return validation::collect(vec![echo_time])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_time = timing::min_value(|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        },&[Self::default().echo_time,self.echo_time]);
+        let mut shortest = self.clone();
+        TimingLimits {
+            min_echo_time,
+            min_echo_spacing:None,
+            min_rep_time:min_echo_time.map(|echo_time|{
+                shortest.echo_time = echo_time;
+                timing::min_rep_time(shortest.instantiate().as_ref())
+            })
+        }
+    }
+    fn instantiate(&self) -> Box<dyn Build> {
+        Box::new(Gre::new(self.clone()))
+    }
+}
+
+impl Build for Gre {
+    fn place_events(&self) -> EventQueue {
+        self.place_events()
+    }
+    fn base_params(&self) -> PPLBaseParams {
+        PPLBaseParams {
+            n_averages: self.params.n_averages,
+            n_repetitions: self.params.n_repetitions,
+            rep_time: self.params.rep_time,
+            obs_freq_offset: self.params.obs_freq_offset,
+            rf_dac_seconds: self.params.rf_dac_seconds,
+            orientation: Orientation::CivmStandard,
+            grad_clock: GradClock::CPS20,
+            phase_unit: PhaseUnit::Min,
+            view_acceleration: self.params.view_acceleration,
+            waveform_sample_period_us: 2
+        }
+    }
+    fn param_export(&self, filepath: &Path) {
+        let params = self.params.clone();
+        let name = params.name.clone();
+        params.write(&filepath.join(name).with_extension("json"));
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GreParams {
+    name: String,
+    cs_table: PathBuf,
+    fov: (f32, f32, f32),
+    samples: (u16, u16, u16),
+    sample_discards: u16,
+    spectral_width: SpectralWidth,
+    // nominal flip angle (deg) used for the starting rf power
+    flip_angle: f32,
+    rf_duration: f32,
+    ramp_time: f32,
+    phase_encode_time: f32,
+    spoil_duration: f32,
+    echo_time: f32,
+    obs_freq_offset: f32,
+    #[serde(default)]
+    rf_dac_seconds: Option<f32>,
+    rep_time: f32,
+    rf_spoiling: bool,
+    n_averages: u16,
+    n_repetitions: u32,
+    view_acceleration: u16,
+    setup_mode: bool,
+    grad_off: bool,
+}
+
+#[derive(Clone)]
+pub struct Gre {
+    params: GreParams,
+    events: GreEvents,
+}
+
+#[derive(Clone)]
+pub struct GreEvents {
+    excitation: RfEvent<Hardpulse>,
+    phase_encode: GradEvent<Trapezoid>,
+    readout: GradEvent<Trapezoid>,
+    acquire: AcqEvent,
+    rewinder: GradEvent<Trapezoid>,
+    spoiler: GradEvent<Trapezoid>,
+}
+
+struct Waveforms {
+    excitation: Hardpulse,
+    phase_encode: Trapezoid,
+    readout: Trapezoid,
+    spoiler: Trapezoid,
+}
+
+struct GradMatrices {
+    phase_encode: Matrix,
+    readout: Matrix,
+    rewinder: Matrix,
+    spoiler: Matrix,
+}
+
+impl Gre {
+
+    pub fn new(params: GreParams) -> Gre {
+        let events = Self::events(&params);
+        Self {
+            events,
+            params
+        }
+    }
+
+    /** Excitation and receiver phase. Both follow the same quadratic cycle so the spoiling phase
+    drops out of the received signal */
+    fn rf_phase(params: &GreParams) -> RfStateType {
+        match params.rf_spoiling {
+            true => RfStateType::Driven(RfDriver::new(DriverVar::Repetition, RfDriverType::PhaseCycle3D(PhaseCycleStrategy::QuadraticSpoil(RF_SPOIL_SEED_DEG)), None)),
+            false => RfStateType::Static(0)
+        }
+    }
+
+    // a dac of 400 is close to a 90 for the default pulse duration. The power adjustment takes it from here
+    fn rf_dac(params: &GreParams) -> i16 {
+        (400.0*params.flip_angle/90.0).round() as i16
+    }
+
+    fn waveforms(params: &GreParams) -> Waveforms {
+        let n_read = params.samples.0;
+        let read_sample_time_sec = params.spectral_width.sample_time(n_read + params.sample_discards);
+        let excitation = Hardpulse::new(params.rf_duration);
+        let readout = Trapezoid::new(params.ramp_time, read_sample_time_sec);
+        let phase_encode = Trapezoid::new(params.ramp_time, params.phase_encode_time);
+        let spoiler = Trapezoid::new(params.ramp_time, params.spoil_duration);
+        Waveforms {
+            excitation,
+            phase_encode,
+            readout,
+            spoiler
+        }
+    }
+
+    fn gradient_matrices(params: &GreParams) -> GradMatrices {
+        let waveforms = Self::waveforms(params);
+        let mat_count = Matrix::new_tracker();
+        let non_adjustable = (false, false, false);
+
+        /* READOUT */
+        let read_grad_dac = params.spectral_width.fov_to_dac(params.fov.0);
+        let readout = Matrix::new_static("read_mat", DacValues::new(Some(read_grad_dac), None, None), non_adjustable, params.grad_off, &mat_count);
+
+        /* PHASE ENCODING */
+        let lut = vec![240; 230400];
+        let phase_encode_strategy = EncodeStrategy::LUT(Dimension::_3D, lut);
+        let pe_driver = MatrixDriver::new(DriverVar::Repetition, MatrixDriverType::PhaseEncode(phase_encode_strategy), Some(0));
+        let read_pre_phase_dac = waveforms.phase_encode.magnitude_net(0.5 * waveforms.readout.power_net(read_grad_dac as f32)) as i16;
+        let (phase_grad_step, slice_grad_step) = match params.setup_mode {
+            false => (waveforms.phase_encode.magnitude_net(1.0 / params.fov.1), waveforms.phase_encode.magnitude_net(1.0 / params.fov.2)),
+            true => (0.0, 0.0)
+        };
+        let phase_multiplier = grad_cal::grad_to_dac(phase_grad_step) as f32;
+        let slice_multiplier = grad_cal::grad_to_dac(slice_grad_step) as f32;
+        let transform = LinTransform::new((None, Some(phase_multiplier), Some(slice_multiplier)), (None, None, None));
+        let phase_encode = Matrix::new_driven(
+            "c_pe_mat",
+            pe_driver,
+            transform,
+            DacValues::new(Some(-read_pre_phase_dac), None, None),
+            (true, false, false),
+            params.grad_off,
+            &mat_count
+        );
+
+        /* REWINDER */
+        let re_trans = LinTransform::new((None, Some(-1.0), Some(-1.0)), (None, None, None));
+        let rewinder = phase_encode.derive("c_re_mat", re_trans, non_adjustable, params.grad_off, &mat_count);
+
+        /* SPOILER */
+        let spoiler = Matrix::new_static("spoiler_mat", DacValues::new(Some(read_grad_dac), Some(read_grad_dac), Some(read_grad_dac)), non_adjustable, params.grad_off, &mat_count);
+
+        GradMatrices {
+            phase_encode,
+            readout,
+            rewinder,
+            spoiler
+        }
+    }
+
+    fn events(params: &GreParams) -> GreEvents {
+        let w = Self::waveforms(params);
+        let m = Self::gradient_matrices(params);
+
+        let excitation = RfEvent::new(
+            "excitation",
+            1,
+            w.excitation,
+            RfStateType::Adjustable(Self::rf_dac(params), None),
+            Self::rf_phase(params)
+        );
+
+        let phase_encode = GradEvent::new(
+            (Some(w.phase_encode), Some(w.phase_encode), Some(w.phase_encode)),
+            &m.phase_encode,
+            GradEventType::Blocking,
+            "phase_encode"
+        );
+
+        let readout = GradEvent::new(
+            (Some(w.readout), None, None),
+            &m.readout,
+            GradEventType::NonBlocking,
+            "readout"
+        );
+
+        let acquire = AcqEvent::new(
+            "acquire",
+            params.spectral_width.clone(),
+            params.samples.0,
+            params.sample_discards,
+            Self::rf_phase(params)
+        );
+
+        let rewinder = GradEvent::new(
+            (None, Some(w.phase_encode), Some(w.phase_encode)),
+            &m.rewinder,
+            GradEventType::Blocking,
+            "rewind"
+        );
+
+        let spoiler = GradEvent::new(
+            (Some(w.spoiler), Some(w.spoiler), Some(w.spoiler)),
+            &m.spoiler,
+            GradEventType::Blocking,
+            "spoiler"
+        );
+
+        GreEvents {
+            excitation,
+            phase_encode,
+            readout,
+            acquire,
+            rewinder,
+            spoiler,
+        }
+    }
+
+    fn place_events(&self) -> EventQueue {
+        let te = self.params.echo_time;
+
+        let excitation = Event::new(self.events.excitation.as_reference(), Origin);
+        let readout = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te)));
+        let acquire = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te)));
+        let phase_encode = Event::new(self.events.phase_encode.as_reference(), Before(readout.clone(), 0));
+        let rewinder = Event::new(self.events.rewinder.as_reference(), After(acquire.clone(), 0));
+        let spoiler = Event::new(self.events.spoiler.as_reference(), After(rewinder.clone(), 0));
+
+        EventQueue::new(
+            &vec![
+                excitation,
+                phase_encode,
+                readout,
+                acquire,
+                rewinder,
+                spoiler,
+            ]
+        )
+    }
+}
+
+#[test]
+fn test(){
+    use seq_tools::rf_state::PHASE_UNITS_PER_CYCLE;
+    let mut params = GreParams::default();
+    params.samples = (128, 64, 64);
+    params.echo_time = 4E-3;
+    let gre = Gre::new(params.clone());
+    let queue = gre.place_events();
+    let center = |label:&str| queue.events().iter().find(|e| e.borrow().unique_label() == label).unwrap().borrow().center();
+    // the echo is one echo time after the center of the excitation
+    assert_eq!(center("acquire") - center("excitation"), sec_to_clock(params.echo_time));
+    assert_eq!(center("readout"), center("acquire"));
+
+    // the receiver follows the excitation phase, which steps up by the spoiling seed every repetition
+    let seed = (RF_SPOIL_SEED_DEG*PHASE_UNITS_PER_CYCLE as f32/360.0).round() as i32;
+    let phase = |n:u32| gre.events.excitation.phase_value(n).unwrap().unwrap() as i32;
+    for n in [1, 2, 3, 1000] {
+        assert_eq!(gre.events.acquire.phase_value(n).unwrap().unwrap() as i32, phase(n));
+        let second_difference = phase(n + 1) - 2*phase(n) + phase(n - 1);
+        assert_eq!(second_difference.rem_euclid(PHASE_UNITS_PER_CYCLE), seed, "repetition {}", n);
+    }
+    params.rf_spoiling = false;
+    let gre = Gre::new(params.clone());
+    assert_eq!(gre.events.excitation.phase_value(7).unwrap(), Some(0));
+
+    let mtk = params.mrd_to_kspace_params();
+    assert!(matches!(mtk.mrd_format, MrdFormat::StandardCSVol));
+    assert_eq!((mtk.n_read, mtk.n_phase1, mtk.n_phase2), (128, 64, 64));
+    assert_eq!(mtk.n_views, 64*64/8);
+    assert_eq!(mtk.n_objects, 1);
+}
@@ -0,0 +1,564 @@
+/*
+    3-D inversion recovery spin echo. A composite 180 inverts the longitudinal magnetization one
+    inversion time before the excitation, and a crusher removes any transverse magnetization the
+    inversion leaves behind. The spin echo readout follows with crushers on either side of the
+    refocusing pulse and a spoiler at the end of the repetition. A T1 series is built by sweeping
+    the inversion time over a table of experiments.
+    Phase encodes come from the compressed sensing table.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{Read, Write};
+use seq_tools::{grad_cal, _utils};
+use seq_tools::acq_event::{AcqEvent, SpectralWidth};
+use seq_tools::event_block::{Event, EventQueue, GradEventType};
+use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin, Origin};
+use seq_tools::execution::{ExecutionBlock, RfRole};
+use seq_tools::gradient_event::GradEvent;
+use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
+use seq_tools::ppl_function::MIN_DELAY_CLOCKS;
+use seq_tools::pulse::{CompositeHardpulse, Hardpulse, Pulse, Trapezoid};
+use seq_tools::rf_event::RfEvent;
+use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
+use seq_tools::_utils::sec_to_clock;
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, CompressedSense, InversionRecovery, IRSequenceParameters, Simulate, AcqDimensions, AcqDims, Initialize, MrdToKspace, MrdToKspaceParams, MrdFormat, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
+use serde_json;
+use serde::{Serialize,Deserialize};
+use cs_table::cs_table::CSTable;
+use headfile::headfile::{AcqHeadfile, AcqHeadfileParams, IRHeadfile, IRHeadfileParams};
+
+impl Setup for IrSeParams {
+    fn set_mode(&mut self) {
+        self.setup_mode = true;
+    }
+    fn set_repetitions(&mut self) {
+        self.n_repetitions = 2000;
+    }
+}
+
+impl InversionRecovery for IrSeParams {
+    fn inversion_time(&self) -> f32 {
+        self.inversion_time
+    }
+    fn set_inversion_time(&mut self, inversion_time: f32) {
+        self.inversion_time = inversion_time;
+    }
+}
+
+impl CompressedSense for IrSeParams {
+    fn is_cs(&self) -> bool {
+        true
+    }
+    fn set_cs_table(&mut self) {
+        let n_reps = CSTable::open(
+            &self.cs_table().unwrap(),
+            self.samples.1 as i16,
+            self.samples.2 as i16,
+        ).n_views() as u32/self.view_acceleration as u32;
+        self.n_repetitions = n_reps;
+    }
+    fn cs_table(&self) -> Option<PathBuf> {
+        Some(self.cs_table.clone())
+    }
+}
+
+impl Simulate for IrSeParams {
+    fn set_sim_repetitions(&mut self) {
+        self.n_repetitions = 2;
+    }
+}
+
+impl AcqDimensions for IrSeParams {
+    fn acq_dims(&self) -> AcqDims {
+        AcqDims {
+            n_read: self.samples.0 as i32,
+            n_phase1: self.samples.1 as i32,
+            n_phase2: self.samples.2 as i32,
+            n_slices: 1,
+            n_echos: 1,
+            n_experiments: 1
+        }
+    }
+}
+
+impl AcqHeadfile for IrSeParams {
+    fn acq_params(&self) -> AcqHeadfileParams {
+        AcqHeadfileParams {
+            dim_x: self.samples.0 as i32,
+            dim_y: self.samples.1 as i32,
+            dim_z: self.samples.2 as i32,
+            fovx_mm: self.fov.0,
+            fovy_mm: self.fov.1,
+            fovz_mm: self.fov.2,
+            te_ms: 1E3*self.echo_time,
+            tr_us: 1E6*self.rep_time,
+            alpha: 90.0,
+            bw: self.spectral_width.hertz() as f32 /2.0,
+            n_echos: 1,
+            S_PSDname: self.name()
+        }
+    }
+}
+
+impl IRHeadfile for IrSeParams {
+    fn inversion_params(&self) -> IRHeadfileParams {
+        IRHeadfileParams {
+            ti_ms: 1E3*self.inversion_time
+        }
+    }
+}
+
+impl Initialize for IrSeParams {
+    fn default() -> Self {
+        IrSeParams {
+            name: "ir_se".to_string(),
+            cs_table: Path::new(r"C:\workstation\data\petableCS_stream\stream_CS480_8x_pa18_pb54").to_owned(),
+            fov: (19.7, 12.0, 12.0),
+            samples: (394, 240, 240),
+            sample_discards: 0,
+            spectral_width: SpectralWidth::SW200kH,
+            rf_90_duration: 140E-6,
+            rf_180_duration: 280E-6,
+            inversion_duration: 560E-6,
+            crush_duration: 500E-6,
+            spoil_duration: 600E-6,
+            ramp_time: 140E-6,
+            phase_encode_time: 550E-6,
+            echo_time: 10E-3,
+            inversion_time: 500E-3,
+            obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
+            rep_time: 3.0,
+            n_averages: 1,
+            n_repetitions: 2000,
+            view_acceleration: 1,
+            setup_mode: false,
+            grad_off: false
+        }
+    }
+    fn load(params_file: &Path) -> Self {
+        let mut f = File::open(params_file).expect("cannot open file");
+        let mut json_str = String::new();
+        f.read_to_string(&mut json_str).expect("trouble reading file");
+        serde_json::from_str(&json_str).expect("cannot deserialize string")
+    }
+    fn write_default(params_file: &Path){
+        let params = Self::default();
+        let str = serde_json::to_string_pretty(&params).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+}
+
+impl IRSequenceParameters for IrSeParams {}
+
+impl MrdToKspace for IrSeParams {
+    fn mrd_to_kspace_params(&self) -> MrdToKspaceParams {
+        let table_compression = 8;
+        let n_views = (self.samples.1 as usize*self.samples.2 as usize)/table_compression;
+        MrdToKspaceParams {
+            mrd_format:MrdFormat::StandardCSVol,
+            n_read: self.samples.0 as usize,
+            n_phase1: self.samples.1 as usize,
+            n_phase2: self.samples.2 as usize,
+            n_views,
+            view_acceleration: self.view_acceleration as usize,
+            dummy_excitations: 0,
+            n_objects: 1,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
+        }
+    }
+}
+
+impl SequenceParameters for IrSeParams {
+
+    fn name(&self) -> String {
+        String::from("ir_se")
+    }
+    fn write(&self,params_file: &Path){
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let inversion_time = validation::minimum("inversion_time",self.inversion_time,IrSe::min_inversion_time(self),"the inversion crusher must end before the excitation");
+        if inversion_time.is_some() {
+            return validation::collect(vec![inversion_time])
+        }
+        let echo_time = validation::placement("echo_time",self.echo_time,Self::default().echo_time,|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        });
+        if echo_time.is_some() {
+            return validation::collect(vec![echo_time])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        // the inversion time is raised to its minimum so the sequence can be built
+        let inversion_time = self.inversion_time.max(IrSe::min_inversion_time(self));
+        let min_echo_time = timing::min_value(|echo_time|{
+            let mut params = self.clone();
+            params.inversion_time = inversion_time;
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        },&[Self::default().echo_time,self.echo_time]);
+        let mut shortest = self.clone();
+        shortest.inversion_time = inversion_time;
+        TimingLimits {
+            min_echo_time,
+            min_echo_spacing:None,
+            min_rep_time:min_echo_time.map(|echo_time|{
+                shortest.echo_time = echo_time;
+                timing::min_rep_time(shortest.instantiate().as_ref())
+            })
+        }
+    }
+    fn instantiate(&self) -> Box<dyn Build> {
+        Box::new(IrSe::new(self.clone()))
+    }
+}
+
+impl Build for IrSe {
+    fn place_events(&self) -> EventQueue {
+        self.place_events()
+    }
+    fn base_params(&self) -> PPLBaseParams {
+        PPLBaseParams {
+            n_averages: self.params.n_averages,
+            n_repetitions: self.params.n_repetitions,
+            rep_time: self.params.rep_time,
+            obs_freq_offset: self.params.obs_freq_offset,
+            rf_dac_seconds: self.params.rf_dac_seconds,
+            orientation: Orientation::CivmStandard,
+            grad_clock: GradClock::CPS20,
+            phase_unit: PhaseUnit::Min,
+            view_acceleration: self.params.view_acceleration,
+            waveform_sample_period_us: 2
+        }
+    }
+    fn param_export(&self, filepath: &Path) {
+        let params = self.params.clone();
+        let name = params.name.clone();
+        params.write(&filepath.join(name).with_extension("json"));
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IrSeParams {
+    name: String,
+    cs_table: PathBuf,
+    fov: (f32, f32, f32),
+    samples: (u16, u16, u16),
+    sample_discards: u16,
+    spectral_width: SpectralWidth,
+    rf_90_duration: f32,
+    rf_180_duration: f32,
+    inversion_duration: f32,
+    crush_duration: f32,
+    spoil_duration: f32,
+    ramp_time: f32,
+    phase_encode_time: f32,
+    echo_time: f32,
+    // time from the center of the inversion to the center of the excitation
+    inversion_time: f32,
+    obs_freq_offset: f32,
+    #[serde(default)]
+    rf_dac_seconds: Option<f32>,
+    rep_time: f32,
+    n_averages: u16,
+    n_repetitions: u32,
+    view_acceleration: u16,
+    setup_mode: bool,
+    grad_off: bool,
+}
+
+#[derive(Clone)]
+pub struct IrSe {
+    params: IrSeParams,
+    events: IrSeEvents,
+}
+
+#[derive(Clone)]
+pub struct IrSeEvents {
+    inversion: RfEvent<CompositeHardpulse>,
+    inversion_crusher: GradEvent<Trapezoid>,
+    excitation: RfEvent<Hardpulse>,
+    crusher1: GradEvent<Trapezoid>,
+    refocus: RfEvent<CompositeHardpulse>,
+    crusher2: GradEvent<Trapezoid>,
+    phase_encode: GradEvent<Trapezoid>,
+    readout: GradEvent<Trapezoid>,
+    acquire: AcqEvent,
+    spoiler: GradEvent<Trapezoid>,
+}
+
+struct Waveforms {
+    inversion: CompositeHardpulse,
+    excitation: Hardpulse,
+    refocus: CompositeHardpulse,
+    crusher: Trapezoid,
+    phase_encode: Trapezoid,
+    readout: Trapezoid,
+    spoiler: Trapezoid,
+}
+
+struct GradMatrices {
+    inversion_crusher: Matrix,
+    crusher: Matrix,
+    phase_encode: Matrix,
+    readout: Matrix,
+    spoiler: Matrix,
+}
+
+impl IrSe {
+
+    pub fn new(params: IrSeParams) -> IrSe {
+        let min_ti = Self::min_inversion_time(&params);
+        if params.inversion_time < min_ti {
+            panic!("inversion time of {} ms is too short. It needs to be at least {} ms",1E3*params.inversion_time,1E3*min_ti)
+        }
+        let events = Self::events(&params);
+        Self {
+            events,
+            params
+        }
+    }
+
+    /** Shortest inversion time that leaves room for the inversion crusher before the excitation */
+    pub fn min_inversion_time(params: &IrSeParams) -> f32 {
+        let events = Self::events(params);
+        let inversion = Event::new(events.inversion.as_reference(), Origin);
+        let crusher = Event::new(events.inversion_crusher.as_reference(), After(inversion.clone(), 0));
+        let excitation = Event::new(events.excitation.as_reference(), Origin);
+        let clocks = crusher.borrow().block_end() - excitation.borrow().block_start() + MIN_DELAY_CLOCKS;
+        _utils::clock_to_sec(clocks)
+    }
+
+    fn waveforms(params: &IrSeParams) -> Waveforms {
+        let n_read = params.samples.0;
+        let read_sample_time_sec = params.spectral_width.sample_time(n_read + params.sample_discards);
+        let inversion = CompositeHardpulse::new_180(params.inversion_duration);
+        let excitation = Hardpulse::new(params.rf_90_duration);
+        let refocus = CompositeHardpulse::new_180(params.rf_180_duration);
+        let crusher = Trapezoid::new(params.ramp_time, params.crush_duration);
+        let readout = Trapezoid::new(params.ramp_time, read_sample_time_sec);
+        let phase_encode = Trapezoid::new(params.ramp_time, params.phase_encode_time);
+        let spoiler = Trapezoid::new(params.ramp_time, params.spoil_duration);
+        Waveforms {
+            inversion,
+            excitation,
+            refocus,
+            crusher,
+            phase_encode,
+            readout,
+            spoiler
+        }
+    }
+
+    fn gradient_matrices(params: &IrSeParams) -> GradMatrices {
+        let waveforms = Self::waveforms(params);
+        let mat_count = Matrix::new_tracker();
+        let non_adjustable = (false, false, false);
+
+        /* READOUT */
+        let read_grad_dac = params.spectral_width.fov_to_dac(params.fov.0);
+        let readout = Matrix::new_static("read_mat", DacValues::new(Some(read_grad_dac), None, None), non_adjustable, params.grad_off, &mat_count);
+
+        /* PHASE ENCODING */
+        let lut = vec![240; 230400];
+        let phase_encode_strategy = EncodeStrategy::LUT(Dimension::_3D, lut);
+        let pe_driver = MatrixDriver::new(DriverVar::Repetition, MatrixDriverType::PhaseEncode(phase_encode_strategy), Some(0));
+        let read_pre_phase_dac = waveforms.phase_encode.magnitude_net(0.5 * waveforms.readout.power_net(read_grad_dac as f32)) as i16;
+        let (phase_grad_step, slice_grad_step) = match params.setup_mode {
+            false => (waveforms.phase_encode.magnitude_net(1.0 / params.fov.1), waveforms.phase_encode.magnitude_net(1.0 / params.fov.2)),
+            true => (0.0, 0.0)
+        };
+        let phase_multiplier = grad_cal::grad_to_dac(phase_grad_step) as f32;
+        let slice_multiplier = grad_cal::grad_to_dac(slice_grad_step) as f32;
+        let transform = LinTransform::new((None, Some(phase_multiplier), Some(slice_multiplier)), (None, None, None));
+        let phase_encode = Matrix::new_driven(
+            "c_pe_mat",
+            pe_driver,
+            transform,
+            DacValues::new(Some(-read_pre_phase_dac), None, None),
+            (true, false, false),
+            params.grad_off,
+            &mat_count
+        );
+
+        /* CRUSHERS */
+        let crusher = Matrix::new_static("crusher_mat", DacValues::new(None, None, Some(read_grad_dac)), non_adjustable, params.grad_off, &mat_count);
+        let inversion_crusher = Matrix::new_static("inv_crusher_mat", DacValues::new(Some(read_grad_dac), Some(read_grad_dac), Some(read_grad_dac)), non_adjustable, params.grad_off, &mat_count);
+
+        /* SPOILER */
+        let spoiler = Matrix::new_static("spoiler_mat", DacValues::new(Some(read_grad_dac), Some(read_grad_dac), Some(read_grad_dac)), non_adjustable, params.grad_off, &mat_count);
+
+        GradMatrices {
+            inversion_crusher,
+            crusher,
+            phase_encode,
+            readout,
+            spoiler
+        }
+    }
+
+    fn events(params: &IrSeParams) -> IrSeEvents {
+        let w = Self::waveforms(params);
+        let m = Self::gradient_matrices(params);
+
+        let mut inversion = RfEvent::new(
+            "inversion",
+            3,
+            w.inversion,
+            RfStateType::Adjustable(800, None),
+            RfStateType::Static(0)
+        );
+        inversion.set_role(RfRole::Preparation);
+
+        let inversion_crusher = GradEvent::new(
+            (Some(w.spoiler), Some(w.spoiler), Some(w.spoiler)),
+            &m.inversion_crusher,
+            GradEventType::Blocking,
+            "inv_crusher"
+        );
+
+        let excitation = RfEvent::new(
+            "excitation",
+            1,
+            w.excitation,
+            RfStateType::Adjustable(400, None),
+            RfStateType::Static(0)
+        );
+
+        let crusher1 = GradEvent::new(
+            (None, None, Some(w.crusher)),
+            &m.crusher,
+            GradEventType::Blocking,
+            "crusher1"
+        );
+
+        let mut refocus = RfEvent::new(
+            "refocus",
+            2,
+            w.refocus,
+            RfStateType::Adjustable(800, None),
+            RfStateType::Adjustable(400, Some(PhaseCycleStrategy::CycleCPMG(2))),
+        );
+        refocus.set_role(RfRole::Refocus);
+
+        let crusher2 = GradEvent::new(
+            (None, None, Some(w.crusher)),
+            &m.crusher,
+            GradEventType::Blocking,
+            "crusher2"
+        );
+
+        let phase_encode = GradEvent::new(
+            (Some(w.phase_encode), Some(w.phase_encode), Some(w.phase_encode)),
+            &m.phase_encode,
+            GradEventType::Blocking,
+            "phase_encode"
+        );
+
+        let readout = GradEvent::new(
+            (Some(w.readout), None, None),
+            &m.readout,
+            GradEventType::NonBlocking,
+            "readout"
+        );
+
+        let acquire = AcqEvent::new(
+            "acquire",
+            params.spectral_width.clone(),
+            params.samples.0,
+            params.sample_discards,
+            RfStateType::Static(0)
+        );
+
+        let spoiler = GradEvent::new(
+            (Some(w.spoiler), Some(w.spoiler), Some(w.spoiler)),
+            &m.spoiler,
+            GradEventType::Blocking,
+            "spoiler"
+        );
+
+        IrSeEvents {
+            inversion,
+            inversion_crusher,
+            excitation,
+            crusher1,
+            refocus,
+            crusher2,
+            phase_encode,
+            readout,
+            acquire,
+            spoiler,
+        }
+    }
+
+    fn place_events(&self) -> EventQueue {
+        let te = self.params.echo_time;
+        let ti = self.params.inversion_time;
+
+        let inversion = Event::new(self.events.inversion.as_reference(), ExactFromOrigin(-sec_to_clock(ti)));
+        let inversion_crusher = Event::new(self.events.inversion_crusher.as_reference(), After(inversion.clone(), 0));
+
+        let excitation = Event::new(self.events.excitation.as_reference(), Origin);
+        let refocus = Event::new(self.events.refocus.as_reference(), ExactFromOrigin(sec_to_clock(te/2.0)));
+        let crusher1 = Event::new(self.events.crusher1.as_reference(), Before(refocus.clone(), 0));
+        let crusher2 = Event::new(self.events.crusher2.as_reference(), After(refocus.clone(), 0));
+
+        let readout = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te)));
+        let acquire = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te)));
+        let phase_encode = Event::new(self.events.phase_encode.as_reference(), Before(readout.clone(), 0));
+        let spoiler = Event::new(self.events.spoiler.as_reference(), After(acquire.clone(), 0));
+
+        EventQueue::new(
+            &vec![
+                inversion,
+                inversion_crusher,
+                excitation,
+                crusher1,
+                refocus,
+                crusher2,
+                phase_encode,
+                readout,
+                acquire,
+                spoiler,
+            ]
+        )
+    }
+}
+
+#[test]
+fn test(){
+    let mut params = IrSeParams::default();
+    let min_ti = IrSe::min_inversion_time(&params);
+    for ti in [min_ti + 1E-3,2.0] {
+        params.inversion_time = ti;
+        assert!(params.validate().is_ok(),"inversion time of {} s should build",ti);
+        let queue = IrSe::new(params.clone()).place_events();
+        let center = |label:&str| queue.events().iter().find(|e| e.borrow().unique_label() == label).unwrap().borrow().center();
+        // the inversion is one inversion time ahead of the excitation
+        assert_eq!(center("excitation") - center("inversion"),sec_to_clock(ti));
+        assert_eq!(queue.events()[0].borrow().unique_label(),"inversion");
+    }
+    params.inversion_time = 0.5*min_ti;
+    let errors = params.validate().unwrap_err();
+    assert_eq!(errors.len(),1);
+    assert_eq!(errors[0].param,"inversion_time");
+    assert!(errors[0].nearest.unwrap() >= min_ti);
+}
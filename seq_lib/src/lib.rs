@@ -6,4 +6,13 @@ pub mod se_dti;
 pub mod scout;
 pub mod se_2d;
 pub mod one_pulse;
-pub mod rfcal;
\ No newline at end of file
+pub mod rfcal;
+pub mod ute;
+pub mod gre;
+pub mod mgre;
+pub mod ir_se;
+pub mod mse;
+pub mod preparation;
+pub mod protocol;
+pub mod validation;
+pub mod timing;
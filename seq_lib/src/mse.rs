@@ -0,0 +1,528 @@
+/*
+    3-D multi-echo spin echo (CPMG). A train of refocusing pulses at half echo spacing offsets
+    forms one echo per pulse, all sampled with the same phase encode. The phase encode is
+    rewound after every echo so each refocusing pulse sees the same k-space origin, and the
+    refocusing pulses are 90 degrees out of phase with the excitation to keep the train
+    insensitive to flip angle errors. Every echo is reconstructed as its own volume for T2 mapping.
+    Phase encodes come from the compressed sensing table.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{Read, Write};
+use seq_tools::{grad_cal, _utils};
+use seq_tools::acq_event::{AcqEvent, SpectralWidth};
+use seq_tools::event_block::{Event, EventQueue, GradEventType};
+use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin, Origin};
+use seq_tools::execution::{ExecutionBlock, RfRole};
+use seq_tools::gradient_event::GradEvent;
+use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
+use seq_tools::ppl_function::MIN_DELAY_CLOCKS;
+use seq_tools::pulse::{CompositeHardpulse, Hardpulse, Pulse, Trapezoid};
+use seq_tools::rf_event::RfEvent;
+use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
+use seq_tools::_utils::sec_to_clock;
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, CompressedSense, Simulate, AcqDimensions, AcqDims, Initialize, MrdToKspace, MrdToKspaceParams, MrdFormat, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
+use serde_json;
+use serde::{Serialize,Deserialize};
+use cs_table::cs_table::CSTable;
+use headfile::headfile::{AcqHeadfile, AcqHeadfileParams, MultiEchoHeadfileParams};
+
+impl Setup for MseParams {
+    fn set_mode(&mut self) {
+        self.setup_mode = true;
+    }
+    fn set_repetitions(&mut self) {
+        self.n_repetitions = 2000;
+    }
+}
+
+impl CompressedSense for MseParams {
+    fn is_cs(&self) -> bool {
+        true
+    }
+    fn set_cs_table(&mut self) {
+        let n_reps = CSTable::open(
+            &self.cs_table().unwrap(),
+            self.samples.1 as i16,
+            self.samples.2 as i16,
+        ).n_views() as u32/self.view_acceleration as u32;
+        self.n_repetitions = n_reps;
+    }
+    fn cs_table(&self) -> Option<PathBuf> {
+        Some(self.cs_table.clone())
+    }
+}
+
+impl Simulate for MseParams {
+    fn set_sim_repetitions(&mut self) {
+        self.n_repetitions = 2;
+    }
+}
+
+impl AcqDimensions for MseParams {
+    fn acq_dims(&self) -> AcqDims {
+        AcqDims {
+            n_read: self.samples.0 as i32,
+            n_phase1: self.samples.1 as i32,
+            n_phase2: self.samples.2 as i32,
+            n_slices: 1,
+            n_echos: self.n_echoes as i32,
+            n_experiments: 1
+        }
+    }
+}
+
+impl AcqHeadfile for MseParams {
+    fn acq_params(&self) -> AcqHeadfileParams {
+        AcqHeadfileParams {
+            dim_x: self.samples.0 as i32,
+            dim_y: self.samples.1 as i32,
+            dim_z: self.samples.2 as i32,
+            fovx_mm: self.fov.0,
+            fovy_mm: self.fov.1,
+            fovz_mm: self.fov.2,
+            te_ms: 1E3*self.echo_spacing,
+            tr_us: 1E6*self.rep_time,
+            alpha: 90.0,
+            bw: self.spectral_width.hertz() as f32 /2.0,
+            n_echos: self.n_echoes as i32,
+            S_PSDname: self.name()
+        }
+    }
+    fn echo_params(&self) -> MultiEchoHeadfileParams {
+        MultiEchoHeadfileParams {
+            te_ms: Mse::echo_times(self).iter().map(|te| 1E3*te).collect()
+        }
+    }
+}
+
+impl Initialize for MseParams {
+    fn default() -> Self {
+        MseParams {
+            name: "mse".to_string(),
+            cs_table: Path::new(r"C:\workstation\data\petableCS_stream\stream_CS480_8x_pa18_pb54").to_owned(),
+            fov: (19.7, 12.0, 12.0),
+            samples: (394, 240, 240),
+            sample_discards: 0,
+            spectral_width: SpectralWidth::SW200kH,
+            rf_90_duration: 140E-6,
+            rf_180_duration: 280E-6,
+            crush_duration: 300E-6,
+            spoil_duration: 600E-6,
+            ramp_time: 140E-6,
+            phase_encode_time: 550E-6,
+            echo_spacing: 7E-3,
+            n_echoes: 8,
+            obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
+            rep_time: 2.0,
+            n_averages: 1,
+            n_repetitions: 2000,
+            view_acceleration: 1,
+            setup_mode: false,
+            grad_off: false
+        }
+    }
+    fn load(params_file: &Path) -> Self {
+        let mut f = File::open(params_file).expect("cannot open file");
+        let mut json_str = String::new();
+        f.read_to_string(&mut json_str).expect("trouble reading file");
+        serde_json::from_str(&json_str).expect("cannot deserialize string")
+    }
+    fn write_default(params_file: &Path){
+        let params = Self::default();
+        let str = serde_json::to_string_pretty(&params).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+}
+
+impl MrdToKspace for MseParams {
+    fn mrd_to_kspace_params(&self) -> MrdToKspaceParams {
+        let table_compression = 8;
+        let n_views = (self.samples.1 as usize*self.samples.2 as usize)/table_compression;
+        MrdToKspaceParams {
+            mrd_format:MrdFormat::StandardCSVol,
+            n_read: self.samples.0 as usize,
+            n_phase1: self.samples.1 as usize,
+            n_phase2: self.samples.2 as usize,
+            n_views,
+            view_acceleration: self.view_acceleration as usize,
+            dummy_excitations: 0,
+            n_objects: self.n_echoes as usize,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
+        }
+    }
+}
+
+impl SequenceParameters for MseParams {
+
+    fn name(&self) -> String {
+        String::from("mse")
+    }
+    fn write(&self,params_file: &Path){
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_spacing = validation::minimum("echo_spacing",self.echo_spacing,Mse::min_echo_spacing(self),"crushers, encoding and readout must fit around every refocusing pulse");
+        if echo_spacing.is_some() {
+            return validation::collect(vec![echo_spacing])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_spacing = Mse::min_echo_spacing(self);
+        let mut shortest = self.clone();
+        shortest.echo_spacing = min_echo_spacing;
+        TimingLimits {
+            // the first echo is one echo spacing after the excitation
+            min_echo_time:Some(min_echo_spacing),
+            min_echo_spacing:Some(min_echo_spacing),
+            min_rep_time:Some(timing::min_rep_time(shortest.instantiate().as_ref()))
+        }
+    }
+    fn instantiate(&self) -> Box<dyn Build> {
+        Box::new(Mse::new(self.clone()))
+    }
+}
+
+impl Build for Mse {
+    fn place_events(&self) -> EventQueue {
+        self.place_events()
+    }
+    fn base_params(&self) -> PPLBaseParams {
+        PPLBaseParams {
+            n_averages: self.params.n_averages,
+            n_repetitions: self.params.n_repetitions,
+            rep_time: self.params.rep_time,
+            obs_freq_offset: self.params.obs_freq_offset,
+            rf_dac_seconds: self.params.rf_dac_seconds,
+            orientation: Orientation::CivmStandard,
+            grad_clock: GradClock::CPS20,
+            phase_unit: PhaseUnit::Min,
+            view_acceleration: self.params.view_acceleration,
+            waveform_sample_period_us: 2
+        }
+    }
+    fn param_export(&self, filepath: &Path) {
+        let params = self.params.clone();
+        let name = params.name.clone();
+        params.write(&filepath.join(name).with_extension("json"));
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MseParams {
+    name: String,
+    cs_table: PathBuf,
+    fov: (f32, f32, f32),
+    samples: (u16, u16, u16),
+    sample_discards: u16,
+    spectral_width: SpectralWidth,
+    rf_90_duration: f32,
+    rf_180_duration: f32,
+    crush_duration: f32,
+    spoil_duration: f32,
+    ramp_time: f32,
+    phase_encode_time: f32,
+    // time between echoes. The first echo is one echo spacing after the excitation
+    echo_spacing: f32,
+    n_echoes: u16,
+    obs_freq_offset: f32,
+    #[serde(default)]
+    rf_dac_seconds: Option<f32>,
+    rep_time: f32,
+    n_averages: u16,
+    n_repetitions: u32,
+    view_acceleration: u16,
+    setup_mode: bool,
+    grad_off: bool,
+}
+
+#[derive(Clone)]
+pub struct Mse {
+    params: MseParams,
+    events: MseEvents,
+}
+
+#[derive(Clone)]
+pub struct MseEvents {
+    excitation: RfEvent<Hardpulse>,
+    crusher1: GradEvent<Trapezoid>,
+    refocus: RfEvent<CompositeHardpulse>,
+    crusher2: GradEvent<Trapezoid>,
+    phase_encode: GradEvent<Trapezoid>,
+    readout: GradEvent<Trapezoid>,
+    acquire: AcqEvent,
+    rewinder: GradEvent<Trapezoid>,
+    spoiler: GradEvent<Trapezoid>,
+}
+
+struct Waveforms {
+    excitation: Hardpulse,
+    refocus: CompositeHardpulse,
+    crusher: Trapezoid,
+    phase_encode: Trapezoid,
+    readout: Trapezoid,
+    spoiler: Trapezoid,
+}
+
+struct GradMatrices {
+    crusher: Matrix,
+    phase_encode: Matrix,
+    readout: Matrix,
+    rewinder: Matrix,
+    spoiler: Matrix,
+}
+
+impl Mse {
+
+    pub fn new(params: MseParams) -> Mse {
+        let min_spacing = Self::min_echo_spacing(&params);
+        if params.echo_spacing < min_spacing {
+            panic!("echo spacing of {} ms is too short. It needs to be at least {} ms",1E3*params.echo_spacing,1E3*min_spacing)
+        }
+        let events = Self::events(&params);
+        Self {
+            events,
+            params
+        }
+    }
+
+    /** Echo time of every echo in the train */
+    pub fn echo_times(params: &MseParams) -> Vec<f32> {
+        (1..=params.n_echoes).map(|echo| echo as f32*params.echo_spacing).collect()
+    }
+
+    /** Shortest echo spacing that fits the crushers, encoding and readout on both sides of a refocusing pulse */
+    pub fn min_echo_spacing(params: &MseParams) -> f32 {
+        let events = Self::events(params);
+        // refocusing pulse to the center of the echo
+        let refocus = Event::new(events.refocus.as_reference(), Origin);
+        let crusher1 = Event::new(events.crusher1.as_reference(), Before(refocus.clone(), 0));
+        let crusher2 = Event::new(events.crusher2.as_reference(), After(refocus.clone(), 0));
+        let phase_encode = Event::new(events.phase_encode.as_reference(), After(crusher2.clone(), 0));
+        let readout = Event::new(events.readout.as_reference(), After(phase_encode.clone(), 0));
+        let to_echo = readout.borrow().center() - refocus.borrow().center();
+        // center of the echo to the next refocusing pulse
+        let acquire = Event::new(events.acquire.as_reference(), Origin);
+        let rewinder = Event::new(events.rewinder.as_reference(), After(acquire.clone(), 0));
+        let from_echo = rewinder.borrow().block_end() - acquire.borrow().center() + refocus.borrow().center() - crusher1.borrow().block_start();
+        // excitation to the first refocusing pulse
+        let excitation = Event::new(events.excitation.as_reference(), Origin);
+        let to_refocus = excitation.borrow().block_end() - excitation.borrow().center() + refocus.borrow().center() - crusher1.borrow().block_start();
+        let half_spacing = to_echo.max(from_echo).max(to_refocus) + MIN_DELAY_CLOCKS;
+        _utils::clock_to_sec(2*half_spacing)
+    }
+
+    fn waveforms(params: &MseParams) -> Waveforms {
+        let n_read = params.samples.0;
+        let read_sample_time_sec = params.spectral_width.sample_time(n_read + params.sample_discards);
+        let excitation = Hardpulse::new(params.rf_90_duration);
+        let refocus = CompositeHardpulse::new_180(params.rf_180_duration);
+        let crusher = Trapezoid::new(params.ramp_time, params.crush_duration);
+        let readout = Trapezoid::new(params.ramp_time, read_sample_time_sec);
+        let phase_encode = Trapezoid::new(params.ramp_time, params.phase_encode_time);
+        let spoiler = Trapezoid::new(params.ramp_time, params.spoil_duration);
+        Waveforms {
+            excitation,
+            refocus,
+            crusher,
+            phase_encode,
+            readout,
+            spoiler
+        }
+    }
+
+    fn gradient_matrices(params: &MseParams) -> GradMatrices {
+        let waveforms = Self::waveforms(params);
+        let mat_count = Matrix::new_tracker();
+        let non_adjustable = (false, false, false);
+
+        /* READOUT */
+        let read_grad_dac = params.spectral_width.fov_to_dac(params.fov.0);
+        let readout = Matrix::new_static("read_mat", DacValues::new(Some(read_grad_dac), None, None), non_adjustable, params.grad_off, &mat_count);
+
+        /* PHASE ENCODING */
+        let lut = vec![240; 230400];
+        let phase_encode_strategy = EncodeStrategy::LUT(Dimension::_3D, lut);
+        let pe_driver = MatrixDriver::new(DriverVar::Repetition, MatrixDriverType::PhaseEncode(phase_encode_strategy), Some(0));
+        let read_pre_phase_dac = waveforms.phase_encode.magnitude_net(0.5 * waveforms.readout.power_net(read_grad_dac as f32)) as i16;
+        let (phase_grad_step, slice_grad_step) = match params.setup_mode {
+            false => (waveforms.phase_encode.magnitude_net(1.0 / params.fov.1), waveforms.phase_encode.magnitude_net(1.0 / params.fov.2)),
+            true => (0.0, 0.0)
+        };
+        let phase_multiplier = grad_cal::grad_to_dac(phase_grad_step) as f32;
+        let slice_multiplier = grad_cal::grad_to_dac(slice_grad_step) as f32;
+        let transform = LinTransform::new((None, Some(phase_multiplier), Some(slice_multiplier)), (None, None, None));
+        let phase_encode = Matrix::new_driven(
+            "c_pe_mat",
+            pe_driver,
+            transform,
+            DacValues::new(Some(-read_pre_phase_dac), None, None),
+            (true, false, false),
+            params.grad_off,
+            &mat_count
+        );
+
+        /* REWINDER */
+        // the read prephase is repeated to bring the second half of the readout back to the center
+        let re_trans = LinTransform::new((Some(1.0), Some(-1.0), Some(-1.0)), (None, None, None));
+        let rewinder = phase_encode.derive("c_re_mat", re_trans, (true, false, false), params.grad_off, &mat_count);
+
+        /* CRUSHERS */
+        let crusher = Matrix::new_static("crusher_mat", DacValues::new(None, None, Some(read_grad_dac)), non_adjustable, params.grad_off, &mat_count);
+
+        /* SPOILER */
+        let spoiler = Matrix::new_static("spoiler_mat", DacValues::new(Some(read_grad_dac), Some(read_grad_dac), Some(read_grad_dac)), non_adjustable, params.grad_off, &mat_count);
+
+        GradMatrices {
+            crusher,
+            phase_encode,
+            readout,
+            rewinder,
+            spoiler
+        }
+    }
+
+    fn events(params: &MseParams) -> MseEvents {
+        let w = Self::waveforms(params);
+        let m = Self::gradient_matrices(params);
+
+        let excitation = RfEvent::new(
+            "excitation",
+            1,
+            w.excitation,
+            RfStateType::Adjustable(400, None),
+            RfStateType::Static(0)
+        );
+
+        let crusher1 = GradEvent::new(
+            (None, None, Some(w.crusher)),
+            &m.crusher,
+            GradEventType::Blocking,
+            "crusher1"
+        );
+
+        let mut refocus = RfEvent::new(
+            "refocus",
+            2,
+            w.refocus,
+            RfStateType::Adjustable(800, None),
+            RfStateType::Adjustable(400, Some(PhaseCycleStrategy::CycleCPMG(2))),
+        );
+        refocus.set_role(RfRole::Refocus);
+
+        let crusher2 = GradEvent::new(
+            (None, None, Some(w.crusher)),
+            &m.crusher,
+            GradEventType::Blocking,
+            "crusher2"
+        );
+
+        let phase_encode = GradEvent::new(
+            (Some(w.phase_encode), Some(w.phase_encode), Some(w.phase_encode)),
+            &m.phase_encode,
+            GradEventType::Blocking,
+            "phase_encode"
+        );
+
+        let readout = GradEvent::new(
+            (Some(w.readout), None, None),
+            &m.readout,
+            GradEventType::NonBlocking,
+            "readout"
+        );
+
+        let acquire = AcqEvent::new(
+            "acquire",
+            params.spectral_width.clone(),
+            params.samples.0,
+            params.sample_discards,
+            RfStateType::Static(0)
+        );
+
+        let rewinder = GradEvent::new(
+            (Some(w.phase_encode), Some(w.phase_encode), Some(w.phase_encode)),
+            &m.rewinder,
+            GradEventType::Blocking,
+            "rewind"
+        );
+
+        let spoiler = GradEvent::new(
+            (Some(w.spoiler), Some(w.spoiler), Some(w.spoiler)),
+            &m.spoiler,
+            GradEventType::Blocking,
+            "spoiler"
+        );
+
+        MseEvents {
+            excitation,
+            crusher1,
+            refocus,
+            crusher2,
+            phase_encode,
+            readout,
+            acquire,
+            rewinder,
+            spoiler,
+        }
+    }
+
+    fn place_events(&self) -> EventQueue {
+        let esp = self.params.echo_spacing;
+        let excitation = Event::new(self.events.excitation.as_reference(), Origin);
+        let mut events = vec![excitation];
+        let mut last_rewinder = None;
+        for te in Self::echo_times(&self.params) {
+            let refocus = Event::new(self.events.refocus.as_reference(), ExactFromOrigin(sec_to_clock(te - esp/2.0)));
+            let crusher1 = Event::new(self.events.crusher1.as_reference(), Before(refocus.clone(), 0));
+            let crusher2 = Event::new(self.events.crusher2.as_reference(), After(refocus.clone(), 0));
+            let readout = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te)));
+            let acquire = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te)));
+            let phase_encode = Event::new(self.events.phase_encode.as_reference(), Before(readout.clone(), 0));
+            let rewinder = Event::new(self.events.rewinder.as_reference(), After(acquire.clone(), 0));
+            events.extend([crusher1, refocus, crusher2, phase_encode, readout, acquire, rewinder.clone()]);
+            last_rewinder = Some(rewinder);
+        }
+        let spoiler = Event::new(self.events.spoiler.as_reference(), After(last_rewinder.expect("at least one echo is required"), 0));
+        events.push(spoiler);
+        EventQueue::new(&events)
+    }
+}
+
+#[test]
+fn test(){
+    let mut params = MseParams::default();
+    params.samples = (128, 64, 64);
+    params.n_echoes = 4;
+    params.echo_spacing = Mse::min_echo_spacing(&params);
+    let k_step = 1E3/params.fov.0;
+    let pe_tolerance = 0.01*1E3/params.fov.1;
+    let mse = Mse::new(params.clone());
+    // a view away from the center of k-space
+    let traj = mse.k_space_trajectory(1000);
+    assert_eq!(traj.readouts.len(), 4);
+    let first = &traj.readouts[0].k;
+    for (echo, readout) in traj.readouts.iter().enumerate() {
+        // every echo is centered in read and samples the same phase encode as the first
+        let k = &readout.k;
+        assert!(k[k.len()/2][0].abs() < k_step, "echo {} centered at {}", echo, k[k.len()/2][0]);
+        assert!((k[k.len()/2][1] - first[first.len()/2][1]).abs() < pe_tolerance, "echo {} has phase encode {}", echo, k[k.len()/2][1]);
+        assert!((k[k.len()/2][2] - first[first.len()/2][2]).abs() < pe_tolerance, "echo {} has slice encode {}", echo, k[k.len()/2][2]);
+    }
+    assert_eq!(params.mrd_to_kspace_params().n_objects, 4);
+}
@@ -9,7 +9,7 @@ use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin,
 use seq_tools::execution::ExecutionBlock;
 use seq_tools::gradient_event::GradEvent;
 use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
-use seq_tools::ppl::{GradClock, Orientation, PhaseUnit,BaseFrequency};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
 use seq_tools::pulse::{CompositeHardpulse, HalfSin, Hardpulse, Pulse, Trapezoid};
 use seq_tools::rf_event::RfEvent;
 use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
@@ -84,7 +84,8 @@ impl Build for OnePulse {
             n_averages: 1,
             n_repetitions: self.params.n_repetitions,
             rep_time: self.params.rep_time,
-            base_frequency: BaseFrequency::civm9p4t(0.0),
+            obs_freq_offset: self.params.obs_freq_offset as f32,
+            rf_dac_seconds: None,
             orientation: CivmStandard,
             grad_clock: GradClock::CPS20,
             phase_unit: PhaseUnit::Min,
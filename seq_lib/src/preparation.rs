@@ -0,0 +1,438 @@
+/*
+    Magnetization preparation modules that run ahead of a sequence's own events every repetition.
+    A module places its events backward from the event that follows it, so any number of modules can
+    be chained in front of a sequence without the sequence knowing about them. Each module brings its
+    own rf and gradient execution blocks, and with them its header adjustments and the time it adds to
+    the repetition.
+    Off-resonant pulses (fat saturation, magnetization transfer, saturation bands) use their own
+    frequency buffers, and the spoiler that follows them switches the transmitter back to the base
+    frequency before the sequence continues.
+ */
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+use dyn_clone::DynClone;
+use serde::{Serialize,Deserialize};
+use seq_tools::{grad_cal, _utils};
+use seq_tools::event_block::{Event, EventQueue, GradEventType};
+use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin};
+use seq_tools::execution::{ExecutionBlock, RfRole};
+use seq_tools::gradient_event::GradEvent;
+use seq_tools::gradient_matrix::{DacValues, Matrix};
+use seq_tools::ppl::Adjustment;
+use seq_tools::ppl_function::MIN_DELAY_CLOCKS;
+use seq_tools::pulse::{CompositeHardpulse, GaussianPulse, HammingSincPulse, SliceSelective, Trapezoid};
+use seq_tools::rf_event::RfEvent;
+use seq_tools::rf_state::RfStateType;
+use seq_tools::scanner::ScannerProfile;
+use seq_tools::trajectory::KSpaceTrajectory;
+use crate::pulse_sequence::{Build, PPLBaseParams};
+
+// rf and matrix uids of the modules start above the ones used by the sequences
+const RF_UID_START:u8 = 10;
+const MATRIX_UID_START:u8 = 200;
+// chemical shift of the main fat resonance from water
+const FAT_SHIFT_PPM:f32 = -3.5;
+
+/** Where a module is placed in the chain, and what it needs to know about the sequence it prepares */
+pub struct PreparationContext {
+    index:usize,
+    n_slices:u16,
+    excitation_center:i32,
+    mat_count:Rc<RefCell<u8>>,
+}
+
+impl PreparationContext {
+    fn new(index:usize,n_slices:u16,excitation_center:i32,mat_count:&Rc<RefCell<u8>>) -> Self {
+        Self {
+            index,
+            n_slices,
+            excitation_center,
+            mat_count:mat_count.clone()
+        }
+    }
+    // labels are unique to the module position so the same module can run more than once
+    fn label(&self,name:&str) -> String {
+        format!("prep{}_{}",self.index,name)
+    }
+    fn rf_uid(&self) -> u8 {
+        RF_UID_START + self.index as u8
+    }
+    // the frequency offset is the same for every slice of the slice loop
+    fn frequency_offsets(&self,offset_hz:f32) -> Vec<f32> {
+        vec![offset_hz;self.n_slices as usize]
+    }
+}
+
+pub trait Preparation:DynClone {
+    /** Events of the module in execution order, placed to finish before next */
+    fn place(&self,next:&Rc<RefCell<Event>>,context:&PreparationContext) -> Vec<Rc<RefCell<Event>>>;
+    /** Time (sec) the module adds in front of next */
+    fn duration(&self,next:&Rc<RefCell<Event>>,context:&PreparationContext) -> f32 {
+        let start = self.place(next,context)[0].borrow().block_start();
+        _utils::clock_to_sec(next.borrow().block_start() - start)
+    }
+    /** Header adjustments (scroll bars) the module adds to the sequence */
+    fn header_adjustments(&self,next:&Rc<RefCell<Event>>,context:&PreparationContext) -> Vec<Adjustment> {
+        EventQueue::new(&self.place(next,context)).ppl_user_adjustments().unwrap_or_default()
+    }
+}
+
+dyn_clone::clone_trait_object!(Preparation);
+
+#[derive(Clone,Serialize,Deserialize)]
+pub enum PreparationParams {
+    SpectralSaturation(SpectralSaturation),
+    Inversion(Inversion),
+    SaturationBand(SaturationBand),
+}
+
+impl PreparationParams {
+    pub fn instantiate(&self) -> Box<dyn Preparation> {
+        match self {
+            PreparationParams::SpectralSaturation(module) => Box::new(module.clone()),
+            PreparationParams::Inversion(module) => Box::new(module.clone()),
+            PreparationParams::SaturationBand(module) => Box::new(module.clone()),
+        }
+    }
+}
+
+/** Wrap a sequence with preparation modules if there are any to run */
+pub fn prepare(sequence:Box<dyn Build>,preparation:&Option<Vec<PreparationParams>>) -> Box<dyn Build> {
+    match preparation {
+        Some(modules) if !modules.is_empty() => {
+            Box::new(Prepared::new(sequence,modules.iter().map(|m| m.instantiate()).collect()))
+        }
+        _ => sequence
+    }
+}
+
+/** Chain modules in front of first. The first module in the list runs first */
+pub fn prepend(modules:&[Box<dyn Preparation>],first:&Rc<RefCell<Event>>,n_slices:u16,excitation_center:i32) -> Vec<Rc<RefCell<Event>>> {
+    let mat_count = Rc::new(RefCell::new(MATRIX_UID_START));
+    let mut next = first.clone();
+    let mut placed = Vec::<Vec<Rc<RefCell<Event>>>>::new();
+    for (index,module) in modules.iter().enumerate().rev() {
+        let context = PreparationContext::new(index,n_slices,excitation_center,&mat_count);
+        let events = module.place(&next,&context);
+        if events.is_empty() {panic!("preparation module {} has no events",index)}
+        next = events[0].clone();
+        placed.push(events);
+    }
+    placed.into_iter().rev().flatten().collect()
+}
+
+// spoiler on all axes that dephases whatever transverse magnetization the module leaves behind
+fn spoiler(name:&str,ramp_time:f32,duration:f32,strength_hz_per_mm:f32,context:&PreparationContext) -> GradEvent<Trapezoid> {
+    let w = Trapezoid::new(ramp_time,duration);
+    let dac = grad_cal::grad_to_dac(strength_hz_per_mm);
+    let matrix = Matrix::new_static(&context.label(&format!("{}_mat",name)),DacValues::new(Some(dac),Some(dac),Some(dac)),(false,false,false),false,&context.mat_count);
+    GradEvent::new((Some(w),Some(w),Some(w)),&matrix,GradEventType::Blocking,&context.label(name))
+}
+
+/*
+    Spectrally selective saturation. A long gaussian pulse tips only the spins near its frequency offset,
+    then a spoiler dephases them so they don't contribute to the following excitation. Fat saturation
+    (CHESS) sits on the fat resonance and magnetization transfer sits far off resonance on the broad line
+    of bound water.
+ */
+#[derive(Clone,Serialize,Deserialize)]
+pub struct SpectralSaturation {
+    name:String,
+    pulse_duration:f32,
+    time_bandwidth:f32,
+    frequency_offset_hz:f32,
+    rf_power:i16,
+    ramp_time:f32,
+    spoil_duration:f32,
+    spoil_strength_hz_per_mm:f32,
+}
+
+impl SpectralSaturation {
+    /** CHESS pulse on the fat resonance at the field strength of the active scanner profile */
+    pub fn fat_sat() -> Self {
+        Self {
+            name:"fat_sat".to_string(),
+            pulse_duration:2.5E-3,
+            time_bandwidth:2.7,
+            frequency_offset_hz:FAT_SHIFT_PPM*1E-6*ScannerProfile::active().larmor_hz(),
+            rf_power:300,
+            ramp_time:140E-6,
+            spoil_duration:1E-3,
+            spoil_strength_hz_per_mm:10E3,
+        }
+    }
+    /** Narrow band pulse far enough off resonance to leave free water untouched */
+    pub fn magnetization_transfer() -> Self {
+        Self {
+            name:"mt".to_string(),
+            pulse_duration:8E-3,
+            time_bandwidth:2.0,
+            frequency_offset_hz:6E3,
+            rf_power:600,
+            ramp_time:140E-6,
+            spoil_duration:1E-3,
+            spoil_strength_hz_per_mm:10E3,
+        }
+    }
+    pub fn set_frequency_offset(&mut self,offset_hz:f32) {
+        self.frequency_offset_hz = offset_hz;
+    }
+}
+
+impl Preparation for SpectralSaturation {
+    fn place(&self,next:&Rc<RefCell<Event>>,context:&PreparationContext) -> Vec<Rc<RefCell<Event>>> {
+        let mut pulse = RfEvent::new(
+            &context.label(&self.name),
+            context.rf_uid(),
+            GaussianPulse::new(self.pulse_duration,self.time_bandwidth),
+            RfStateType::Adjustable(self.rf_power,None),
+            RfStateType::Static(0)
+        );
+        pulse.set_role(RfRole::Preparation);
+        pulse.set_frequency_offsets(context.frequency_offsets(self.frequency_offset_hz));
+        let mut spoiler = spoiler(&format!("{}_spoil",self.name),self.ramp_time,self.spoil_duration,self.spoil_strength_hz_per_mm,context);
+        spoiler.restore_base_frequency();
+
+        let spoiler = Event::new(spoiler.as_reference(),Before(next.clone(),0));
+        let pulse = Event::new(pulse.as_reference(),Before(spoiler.clone(),0));
+        vec![pulse,spoiler]
+    }
+}
+
+/*
+    Non-selective inversion. A composite 180 inverts the longitudinal magnetization one inversion time
+    before the center of the sequence's excitation, and a crusher removes any transverse magnetization
+    the inversion leaves behind.
+ */
+#[derive(Clone,Serialize,Deserialize)]
+pub struct Inversion {
+    pulse_duration:f32,
+    // time from the center of the inversion to the center of the excitation
+    inversion_time:f32,
+    rf_power:i16,
+    ramp_time:f32,
+    crush_duration:f32,
+    crush_strength_hz_per_mm:f32,
+}
+
+impl Inversion {
+    pub fn new(inversion_time:f32) -> Self {
+        Self {
+            pulse_duration:560E-6,
+            inversion_time,
+            rf_power:800,
+            ramp_time:140E-6,
+            crush_duration:600E-6,
+            crush_strength_hz_per_mm:10E3,
+        }
+    }
+}
+
+impl Preparation for Inversion {
+    fn place(&self,next:&Rc<RefCell<Event>>,context:&PreparationContext) -> Vec<Rc<RefCell<Event>>> {
+        let mut pulse = RfEvent::new(
+            &context.label("inversion"),
+            context.rf_uid(),
+            CompositeHardpulse::new_180(self.pulse_duration),
+            RfStateType::Adjustable(self.rf_power,None),
+            RfStateType::Static(0)
+        );
+        pulse.set_role(RfRole::Preparation);
+        let crusher = spoiler("inv_crusher",self.ramp_time,self.crush_duration,self.crush_strength_hz_per_mm,context);
+
+        let center = context.excitation_center - _utils::sec_to_clock(self.inversion_time);
+        let pulse = Event::new(pulse.as_reference(),ExactFromOrigin(center));
+        let crusher = Event::new(crusher.as_reference(),After(pulse.clone(),0));
+        let crusher_end = crusher.borrow().block_start() + crusher.borrow().execution.time_to_end();
+        let overlap = crusher_end.max(crusher.borrow().block_end()) + MIN_DELAY_CLOCKS - next.borrow().block_start();
+        if overlap > 0 {
+            panic!("inversion time of {} ms is too short. It needs to be at least {} ms",1E3*self.inversion_time,1E3*(self.inversion_time + _utils::clock_to_sec(overlap)))
+        }
+        vec![pulse,crusher]
+    }
+}
+
+#[derive(Clone,Copy,Serialize,Deserialize)]
+pub enum SaturationAxis {
+    Read,
+    Phase,
+    Slice,
+}
+
+/*
+    Spatial saturation band. A sinc pulse under a gradient on one axis saturates a slab of the given
+    thickness and position (mm from isocenter along the axis), then a spoiler dephases it. Used to null
+    signal outside the field of view that would otherwise alias or carry flow artifacts.
+ */
+#[derive(Clone,Serialize,Deserialize)]
+pub struct SaturationBand {
+    axis:SaturationAxis,
+    thickness_mm:f32,
+    position_mm:f32,
+    pulse_duration:f32,
+    lobes:u16,
+    rf_power:i16,
+    ramp_time:f32,
+    spoil_duration:f32,
+    spoil_strength_hz_per_mm:f32,
+}
+
+impl SaturationBand {
+    pub fn new(axis:SaturationAxis,thickness_mm:f32,position_mm:f32) -> Self {
+        Self {
+            axis,
+            thickness_mm,
+            position_mm,
+            pulse_duration:1E-3,
+            lobes:3,
+            rf_power:400,
+            ramp_time:140E-6,
+            spoil_duration:1E-3,
+            spoil_strength_hz_per_mm:10E3,
+        }
+    }
+    fn pulse(&self) -> HammingSincPulse {
+        HammingSincPulse::new(self.pulse_duration,self.lobes)
+    }
+    // gradient strength (dac) that selects the band thickness
+    fn select_dac(&self) -> i16 {
+        grad_cal::grad_to_dac(self.pulse().bandwidth()/self.thickness_mm)
+    }
+    // transmit frequency offset (Hz) that moves the band to its position
+    fn frequency_offset(&self) -> f32 {
+        grad_cal::dac_to_hz_per_mm(self.select_dac())*self.position_mm
+    }
+}
+
+impl Preparation for SaturationBand {
+    fn place(&self,next:&Rc<RefCell<Event>>,context:&PreparationContext) -> Vec<Rc<RefCell<Event>>> {
+        let mut pulse = RfEvent::new(
+            &context.label("sat_band"),
+            context.rf_uid(),
+            self.pulse(),
+            RfStateType::Adjustable(self.rf_power,None),
+            RfStateType::Static(0)
+        );
+        pulse.set_role(RfRole::Preparation);
+        pulse.set_frequency_offsets(context.frequency_offsets(self.frequency_offset()));
+
+        // the plateau covers the pulse with half a pulse to spare on either side
+        let w = Trapezoid::new(self.ramp_time,2.0*self.pulse_duration);
+        let dac = Some(self.select_dac());
+        let dacs = match self.axis {
+            SaturationAxis::Read => DacValues::new(dac,None,None),
+            SaturationAxis::Phase => DacValues::new(None,dac,None),
+            SaturationAxis::Slice => DacValues::new(None,None,dac),
+        };
+        let frames = match self.axis {
+            SaturationAxis::Read => (Some(w),None,None),
+            SaturationAxis::Phase => (None,Some(w),None),
+            SaturationAxis::Slice => (None,None,Some(w)),
+        };
+        let matrix = Matrix::new_static(&context.label("sat_band_mat"),dacs,(false,false,false),false,&context.mat_count);
+        let select = GradEvent::new(frames,&matrix,GradEventType::NonBlocking,&context.label("sat_band_sel"));
+        let mut spoiler = spoiler("sat_band_spoil",self.ramp_time,self.spoil_duration,self.spoil_strength_hz_per_mm,context);
+        spoiler.restore_base_frequency();
+
+        let spoiler = Event::new(spoiler.as_reference(),Before(next.clone(),0));
+        let select = Event::new(select.as_reference(),Before(spoiler.clone(),0));
+        // the center of the select gradient already includes its hardware start delay
+        let pulse = Event::new(pulse.as_reference(),ExactFromOrigin(select.borrow().center()));
+        vec![select,pulse,spoiler]
+    }
+}
+
+/** A sequence run behind a chain of preparation modules */
+pub struct Prepared {
+    sequence:Box<dyn Build>,
+    modules:Vec<Box<dyn Preparation>>,
+}
+
+impl Prepared {
+    pub fn new(sequence:Box<dyn Build>,modules:Vec<Box<dyn Preparation>>) -> Self {
+        Self {
+            sequence,
+            modules
+        }
+    }
+    /** Time (sec) the modules add to every repetition */
+    pub fn preparation_time(&self) -> f32 {
+        let events = self.sequence.place_events().events();
+        let prep = self.prepend(&events);
+        match prep.first() {
+            Some(first) => _utils::clock_to_sec(events[0].borrow().block_start() - first.borrow().block_start()),
+            None => 0.0
+        }
+    }
+    fn prepend(&self,events:&[Rc<RefCell<Event>>]) -> Vec<Rc<RefCell<Event>>> {
+        // inversion times are measured to the first excitation of the sequence
+        let excitation_center = events.iter().find(|e| e.borrow().execution.rf_role() == Some(RfRole::Excitation))
+            .map(|e| e.borrow().center()).unwrap_or(0);
+        prepend(&self.modules,&events[0],self.sequence.n_slices(),excitation_center)
+    }
+}
+
+impl Build for Prepared {
+    fn place_events(&self) -> EventQueue {
+        let events = self.sequence.place_events().events();
+        let mut v = self.prepend(&events);
+        v.extend(events);
+        EventQueue::new(&v)
+    }
+    fn base_params(&self) -> PPLBaseParams {
+        self.sequence.base_params()
+    }
+    fn lut(&self) -> Option<Vec<i16>> {
+        self.sequence.lut()
+    }
+    fn n_slices(&self) -> u16 {
+        self.sequence.n_slices()
+    }
+    // the modules spoil everything they excite, so the encoding comes from the sequence alone
+    fn k_space_trajectory(&self,driver_value:u32) -> KSpaceTrajectory {
+        self.sequence.k_space_trajectory(driver_value)
+    }
+    fn param_export(&self,filepath:&Path) {
+        self.sequence.param_export(filepath)
+    }
+}
+
+#[test]
+fn test(){
+    use crate::pulse_sequence::{Initialize, SequenceParameters};
+    use crate::se_dti::SeDtiParams;
+    let modules:Vec<Box<dyn Preparation>> = vec![
+        Box::new(Inversion::new(300E-3)),
+        Box::new(SpectralSaturation::fat_sat()),
+    ];
+    let prepared = Prepared::new(SeDtiParams::default().instantiate(),modules.clone());
+    let sequence_events = SeDtiParams::default().instantiate().place_events().events();
+    let first = &sequence_events[0];
+    let prep = prepend(&modules,first,1,0);
+    // the modules run in order and finish before the sequence starts
+    let labels:Vec<String> = prep.iter().map(|e| e.borrow().unique_label()).collect();
+    assert_eq!(labels,vec!["prep0_inversion","prep0_inv_crusher","prep1_fat_sat","prep1_fat_sat_spoil"]);
+    for pair in prep.windows(2) {
+        assert!(pair[0].borrow().block_end() <= pair[1].borrow().block_start());
+    }
+    assert!(prep.last().unwrap().borrow().block_end() <= first.borrow().block_start());
+    // the inversion is one inversion time ahead of the excitation
+    assert_eq!(prep[0].borrow().center(),-_utils::sec_to_clock(300E-3));
+    // the module scroll bars show up with the sequence's own
+    let context = PreparationContext::new(1,1,0,&Matrix::new_tracker());
+    assert!(!modules[1].header_adjustments(first,&context).is_empty());
+    let adjustments = prepared.place_events().ppl_user_adjustments().unwrap();
+    let sequence_adjustments = SeDtiParams::default().instantiate().place_events().ppl_user_adjustments().unwrap();
+    assert!(adjustments.len() > sequence_adjustments.len());
+    assert!(prepared.preparation_time() > 0.3);
+
+    // fat sits 3.5 ppm below water, about 1400 hz at 9.4T
+    assert!((SpectralSaturation::fat_sat().frequency_offset_hz + 1400.0).abs() < 5.0);
+    // a saturation band pulse is centered on its select gradient
+    let band:Vec<Box<dyn Preparation>> = vec![Box::new(SaturationBand::new(SaturationAxis::Read,2.0,5.0))];
+    let prep = prepend(&band,first,1,0);
+    assert_eq!(prep[0].borrow().center(),prep[1].borrow().center());
+    assert!(prep[1].borrow().block_end() <= prep[2].borrow().block_start());
+}
@@ -1,38 +1,233 @@
-
-
-
 /*
     An acquisition is the basic unit of data collection represented with a ppr file
     An experiment is closely related series of acquisitions such as:
         diffsuion series, t1 map series, localizer series (orthoganol views)
     A protocol is a specific queue of experiments or acquisitions
+
+    A protocol is written to a single json file holding the parameters of every experiment in the
+    order they are run, along with the scan calibration they share. The observe frequency and rf
+    calibration are applied to every experiment when the protocol is built. Each experiment is built to
+    its own directory under the protocol directory, named by its position in the queue.
  */
 
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use serde::{Serialize,Deserialize};
+use serde_json;
+use crate::pulse_sequence::{AdjustmentParameters, DWSequenceParameters, ScoutConfig, SequenceParameters};
+use crate::fse_dti::FseDtiParams;
+use crate::se_dti::SeDtiParams;
+use crate::se_2d::Se2DParams;
+use crate::scout::ScoutParams;
+use crate::gre::GreParams;
+use crate::mgre::MgreParams;
+use crate::ir_se::IrSeParams;
+use crate::mse::MseParams;
+use crate::ute::UteParams;
+use crate::one_pulse::OnePulseParams;
+use crate::rfcal::RfCalParams;
 
+// the protocol is copied to the protocol directory under this name when it is built
+pub const PROTOCOL_FILENAME:&str = "protocol";
 
-// sequences that implement this trait may return a path to a setup ppr
-// that needs to adjusted before continuing
-pub trait Setup {
-    fn setup(&self) -> Option<PathBuf>;
+// struct that holds shared information about scan/specimen setup
+// (rf base frequency, rf power)
+#[derive(Clone,Default,Serialize,Deserialize)]
+pub struct ScanCalibration {
+    // observe frequency offset from the base frequency (Hz)
+    pub obs_freq_offset:f32,
+    // rf dac-seconds of a 90 degree hard pulse, found by the rf calibration
+    pub rf_dac_seconds:Option<f32>,
+    // shim settings by ppr variable name, written to every ppr that declares them
+    pub shims:Option<BTreeMap<String,i32>>,
 }
 
+impl ScanCalibration {
+    /** Set the observe frequency offset of a sequence, and scale its rf powers to the rf calibration */
+    pub fn apply<P:SequenceParameters + ?Sized>(&self,params:&mut P) {
+        params.set_freq_offset(self.obs_freq_offset);
+        if let Some(rf_dac_seconds) = self.rf_dac_seconds {
+            params.set_rf_dac_seconds(rf_dac_seconds);
+        }
+    }
+    /** Adjustments find the rf calibration, so only the observe frequency is applied */
+    pub fn apply_adj<P:AdjustmentParameters + ?Sized>(&self,params:&mut P) {
+        params.set_freq_offset(self.obs_freq_offset);
+    }
+    /** ppr variables to overwrite in every acquisition */
+    pub fn ppr_vars(&self) -> HashMap<String,String> {
+        match &self.shims {
+            Some(shims) => shims.iter().map(|(var,value)| (var.clone(),value.to_string())).collect(),
+            None => HashMap::new()
+        }
+    }
+}
 
-// an acquisition is something that needs to get exported and built
-pub trait Acquisition {
-    // needs to look at a scan calibration before export
-    // may need to point to a setup ppr to inherit settings from
-    fn export(&self,path:&Path);
-    fn get_setup(&self,path:&Path);
+/** Parameters of any imaging sequence, tagged by sequence */
+#[derive(Clone,Serialize,Deserialize)]
+pub enum SequenceConfig {
+    FseDti(FseDtiParams),
+    SeDti(SeDtiParams),
+    Se2D(Se2DParams),
+    Gre(GreParams),
+    Mgre(MgreParams),
+    IrSe(IrSeParams),
+    Mse(MseParams),
+    Ute(UteParams),
 }
 
-// things like running a diffusion scan go here
-pub trait Experiment {
+impl SequenceConfig {
+    pub fn params(&self) -> Box<dyn SequenceParameters> {
+        match self {
+            SequenceConfig::FseDti(p) => Box::new(p.clone()),
+            SequenceConfig::SeDti(p) => Box::new(p.clone()),
+            SequenceConfig::Se2D(p) => Box::new(p.clone()),
+            SequenceConfig::Gre(p) => Box::new(p.clone()),
+            SequenceConfig::Mgre(p) => Box::new(p.clone()),
+            SequenceConfig::IrSe(p) => Box::new(p.clone()),
+            SequenceConfig::Mse(p) => Box::new(p.clone()),
+            SequenceConfig::Ute(p) => Box::new(p.clone()),
+        }
+    }
+    /** Diffusion weighted parameters, if the sequence is diffusion weighted */
+    pub fn dw_params(&self) -> Option<Box<dyn DWSequenceParameters>> {
+        match self {
+            SequenceConfig::FseDti(p) => Some(Box::new(p.clone())),
+            SequenceConfig::SeDti(p) => Some(Box::new(p.clone())),
+            _ => None
+        }
+    }
+}
 
+#[derive(Clone,Serialize,Deserialize)]
+pub enum Experiment {
+    // orthogonal localizer views
+    Scout(ScoutParams),
+    // frequency calibration followed by an rf calibration
+    Adjustment{freq_cal:OnePulseParams,rf_cal:RfCalParams},
+    // continuous setup mode for manual adjustment, no data is saved
+    Setup(SequenceConfig),
+    // one acquisition per entry of the b-table (b-value scale, x, y, z)
+    Diffusion{sequence:SequenceConfig,b_table:Vec<(f32,f32,f32,f32)>},
+    // a single acquisition
+    Anatomical(SequenceConfig),
 }
 
+impl Experiment {
+    pub fn kind(&self) -> String {
+        match self {
+            Experiment::Scout(_) => String::from("scout"),
+            Experiment::Adjustment{..} => String::from("adjustment"),
+            Experiment::Setup(_) => String::from("setup"),
+            Experiment::Diffusion{..} => String::from("dti"),
+            Experiment::Anatomical(_) => String::from("anatomical"),
+        }
+    }
+    /** Directory levels between the experiment directory and its pprs */
+    pub fn search_depth(&self) -> u8 {
+        match self {
+            Experiment::Scout(_) | Experiment::Adjustment{..} | Experiment::Diffusion{..} => 1,
+            Experiment::Setup(_) | Experiment::Anatomical(_) => 0,
+        }
+    }
+    pub fn is_cs(&self) -> bool {
+        match self {
+            Experiment::Setup(s) | Experiment::Anatomical(s) | Experiment::Diffusion{sequence:s,..} => s.params().is_cs(),
+            _ => false
+        }
+    }
+    pub fn scout_params(&self) -> Option<Box<dyn ScoutConfig>> {
+        match self {
+            Experiment::Scout(p) => Some(Box::new(p.clone())),
+            _ => None
+        }
+    }
+}
 
-// struct that holds shared information about scan/specimen setup
-// (rf base frequency, rf power)
-pub struct ScanCalibration {
+#[derive(Clone,Serialize,Deserialize)]
+pub struct Protocol {
+    pub name:String,
+    pub calibration:ScanCalibration,
+    pub experiments:Vec<Experiment>,
+}
 
-}
\ No newline at end of file
+impl Protocol {
+    pub fn new(name:&str) -> Self {
+        Self {
+            name:name.to_string(),
+            calibration:ScanCalibration::default(),
+            experiments:vec![]
+        }
+    }
+    pub fn push(&mut self,experiment:Experiment) {
+        self.experiments.push(experiment);
+    }
+    /** Directory of an experiment relative to the protocol directory */
+    pub fn experiment_dir(&self,index:usize) -> String {
+        format!("{:02}_{}",index,self.experiments[index].kind())
+    }
+    pub fn to_file(&self,file_path:&Path) {
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        let mut f = File::create(file_path.with_extension("json")).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn from_file(file_path:&Path) -> Self {
+        let mut f = File::open(file_path.with_extension("json")).unwrap_or_else(|_| panic!("cannot open file {:?}",file_path));
+        let mut json_str = String::new();
+        f.read_to_string(&mut json_str).expect("trouble reading file");
+        serde_json::from_str(&json_str).expect("cannot deserialize protocol")
+    }
+}
+
+#[test]
+fn test(){
+    use crate::pulse_sequence::Initialize;
+    use seq_tools::scanner::ScannerProfile;
+    use crate::pulse_sequence::Build;
+    let mut protocol = Protocol::new("dti_protocol");
+    protocol.calibration.obs_freq_offset = 120.0;
+    protocol.calibration.rf_dac_seconds = Some(1.25*ScannerProfile::active().reference_rf_dac_seconds);
+    protocol.calibration.shims = Some(BTreeMap::from([(String::from("shim_z"),-35)]));
+    protocol.push(Experiment::Scout(ScoutParams::default()));
+    protocol.push(Experiment::Adjustment{freq_cal:OnePulseParams::default(),rf_cal:RfCalParams::default()});
+    protocol.push(Experiment::Setup(SequenceConfig::SeDti(SeDtiParams::default())));
+    protocol.push(Experiment::Diffusion{sequence:SequenceConfig::SeDti(SeDtiParams::default()),b_table:vec![(0.0,1.0,0.0,0.0),(1.0,1.0,0.0,0.0)]});
+    protocol.push(Experiment::Anatomical(SequenceConfig::Mgre(MgreParams::default())));
+
+    let file = std::env::temp_dir().join("protocol_test");
+    protocol.to_file(&file);
+    let loaded = Protocol::from_file(&file);
+    let dirs:Vec<String> = (0..loaded.experiments.len()).map(|i| loaded.experiment_dir(i)).collect();
+    assert_eq!(dirs,vec!["00_scout","01_adjustment","02_setup","03_dti","04_anatomical"]);
+    assert!(loaded.experiments[3].is_cs());
+    assert_eq!(loaded.calibration.ppr_vars().get("shim_z"),Some(&String::from("-35")));
+    match &loaded.experiments[3] {
+        Experiment::Diffusion{sequence,b_table} => {
+            assert!(sequence.dw_params().is_some());
+            assert_eq!(b_table.len(),2);
+        }
+        _ => panic!("experiment order not preserved")
+    }
+    std::fs::remove_file(file.with_extension("json")).expect("cannot remove file");
+
+    // every child is exported on the calibrated observe frequency, with rf powers scaled to the rf calibration
+    let observe = format!(":OBSERVE_FREQUENCY \"{}\", {:.1},",ScannerProfile::active().nucleus,ScannerProfile::active().base_freq_hz + 120.0);
+    let mut scout = loaded.experiments[0].scout_params().unwrap();
+    loaded.calibration.apply(scout.as_mut());
+    let mut se_2d = SequenceConfig::Se2D(Se2DParams::default()).params();
+    loaded.calibration.apply(se_2d.as_mut());
+    let uncalibrated = [ScoutParams::default().instantiate(),Se2DParams::default().instantiate()];
+    for ((name,mut to_build),reference) in [("scout",scout.instantiate()),("se_2d",se_2d.instantiate())].into_iter().zip(uncalibrated) {
+        let dacs = |b:&dyn Build| -> Vec<i16> {b.rf_power_estimate(1.0).pulses.iter().map(|p| p.dac).collect()};
+        let expected:Vec<i16> = dacs(reference.as_ref()).iter().map(|dac| (*dac as f32*1.25).round() as i16).collect();
+        assert_eq!(dacs(to_build.as_ref()),expected,"{} rf powers are not scaled to the rf calibration",name);
+        let dir = std::env::temp_dir().join(format!("protocol_test_{}",name));
+        std::fs::create_dir_all(&dir).expect("cannot create directory");
+        to_build.ppl_export(&dir,name,false,false);
+        let ppr = std::fs::read_to_string(dir.join(name).with_extension("ppr")).expect("cannot read ppr");
+        assert!(ppr.contains(&observe),"{} is not on the calibrated frequency",name);
+        std::fs::remove_dir_all(&dir).expect("cannot remove directory");
+    }
+}
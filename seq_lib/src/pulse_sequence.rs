@@ -6,12 +6,26 @@ use std::path::{Path, PathBuf};
 use seq_tools::event_block::EventQueue;
 use seq_tools::seqframe::SeqFrame;
 use build_sequence::build_directory::{Config,build_directory};
-use seq_tools::ppl::{BaseFrequency, GradClock, Orientation, PhaseUnit, PPL};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit, PPL};
+use seq_tools::pulseq::{Pulseq, PulseqError, PulseqParams};
+use seq_tools::grad_limits::GradLimitViolation;
+use seq_tools::rf_power::RfPowerEstimate;
+use seq_tools::trajectory::KSpaceTrajectory;
+use seq_tools::moments::{GradientMoments, moments_between};
+use seq_tools::timing_diagram::TimingDiagram;
+use seq_tools::scanner::ScannerProfile;
 use serde_json;
 use serde::{Serialize,Deserialize};
 use seq_tools::grad_cal::{GAMMA, tesla_per_mm_to_dac};
 use dyn_clone::DynClone;
-use headfile::headfile::{AcqHeadfile, DWHeadfile};
+use headfile::headfile::{AcqHeadfile, DWHeadfile, IRHeadfile, OrientationHeadfileParams};
+use crate::validation::ParamError;
+use crate::timing::{ScanTime, TimingLimits};
+
+// the scanner loads its lookup table from this file, whatever the table encodes
+pub const LUT_FILENAME:&str = "cs_table";
+// k-space sample positions of non-Cartesian sequences, written next to the lookup table
+pub const K_COORDS_FILENAME:&str = "k_coords";
 
 
 #[derive(Clone,Serialize,Deserialize)]
@@ -19,7 +33,10 @@ pub struct PPLBaseParams {
     pub n_averages:u16,
     pub n_repetitions:u32,
     pub rep_time:f32,
-    pub base_frequency:BaseFrequency,
+    // observe frequency offset from the base frequency of the scanner profile (hz)
+    pub obs_freq_offset:f32,
+    // rf calibration (dac-seconds of a 90 degree hard pulse) the starting rf powers are scaled to
+    pub rf_dac_seconds:Option<f32>,
     pub orientation:Orientation,
     pub grad_clock:GradClock,
     pub phase_unit:PhaseUnit,
@@ -41,6 +58,11 @@ pub trait DiffusionWeighted {
     fn pulse_duration(&self) -> f32;
 }
 
+pub trait InversionRecovery {
+    fn inversion_time(&self) -> f32;
+    fn set_inversion_time(&mut self,inversion_time:f32);
+}
+
 pub trait CompressedSense{
     fn is_cs(&self) -> bool;
     fn set_cs_table(&mut self);
@@ -68,11 +90,24 @@ pub trait Initialize {
 }
 
 pub trait DWSequenceParameters:SequenceParameters + DiffusionWeighted + DynClone + DWHeadfile {}
+pub trait IRSequenceParameters:SequenceParameters + InversionRecovery + DynClone + IRHeadfile {}
 pub trait SequenceParameters:
 CompressedSense+Simulate+AcqDimensions+DynClone+MrdToKspace+Setup+AcqHeadfile {
     fn name(&self) -> String;
     fn write(&self,params_file:&Path);
+    // observe frequency offset from the base frequency
+    fn set_freq_offset(&mut self,offset_hertz:f32);
+    // rf calibration the rf powers are scaled to when the sequence is exported
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32);
+    // every parameter that can't be built, with the nearest value that can
+    fn validate(&self) -> Result<(),Vec<ParamError>>;
+    // shortest echo timing and rep time the events can be placed with
+    fn timing_limits(&self) -> TimingLimits;
     fn instantiate(&self) -> Box<dyn Build>;
+    // the rep time is repeated for every pass through the view and average loops
+    fn scan_time(&self) -> ScanTime {
+        ScanTime::new(self.instantiate().as_ref(),self.mrd_to_kspace_params().dummy_excitations)
+    }
 }
 
 pub trait AdjustmentParameters {
@@ -114,12 +149,31 @@ pub struct AcqDims {
     pub n_experiments:i32,
 }
 
+#[derive(Clone,Copy,Debug,Default,Serialize,Deserialize)]
+pub enum SliceOrder {
+    #[default]
+    Sequential, // slice positions excited from one end of the stack to the other
+    Interleaved // even positions then odd ones, so neighbors are excited half a rep time apart
+}
+
+impl SliceOrder {
+    /** Slice position for each iteration of the slice loop */
+    pub fn acquisition_order(&self,n_slices:usize) -> Vec<usize> {
+        match self {
+            SliceOrder::Sequential => (0..n_slices).collect(),
+            SliceOrder::Interleaved => (0..n_slices).step_by(2).chain((1..n_slices).step_by(2)).collect()
+        }
+    }
+}
+
 #[derive(Serialize,Deserialize)]
 pub enum MrdFormat {
     FseCSVol, // 3-D accelerated compressed sensing
     StandardCSVol, // 3-D compressed sensing (single or multi-echo)
+    BipolarCSVol, // 3-D compressed sensing multi-echo with every other echo read in reverse
     StandardVol,// 3-D standard imaging (single or multi-echo)
-    StandardSlice // 2-D imaging (single or multi-echo)
+    StandardSlice, // 2-D imaging (single or multi-echo)
+    NonCartesian // gridded from a k-space coordinate list (radial, spiral ...)
 }
 
 // this is an attempt to provide info to unify the reconstruction process for any
@@ -133,7 +187,16 @@ pub struct MrdToKspaceParams {
     pub n_views:usize,
     pub view_acceleration:usize,
     pub dummy_excitations:usize,
-    pub n_objects:usize // for MGRE or any multi-echo data
+    pub n_objects:usize, // for MGRE or any multi-echo data
+    // mtk files written before multi-slice support are single slice
+    #[serde(default = "n_slices_default")]
+    pub n_slices:usize, // slices acquired in every view
+    #[serde(default)]
+    pub slice_order:SliceOrder
+}
+
+fn n_slices_default() -> usize {
+    1
 }
 
 impl MrdToKspaceParams {
@@ -163,6 +226,29 @@ pub trait AcqDimensions {
 pub trait Build {
     fn place_events(&self) -> EventQueue;
     fn base_params(&self) -> PPLBaseParams;
+    // table the scanner steps through with GETLUTENTRY, if the sequence generates its own
+    fn lut(&self) -> Option<Vec<i16>> {
+        None
+    }
+    // slices excited one after the other in every rep time
+    fn n_slices(&self) -> u16 {
+        1
+    }
+    // events with the rf powers scaled from the reference calibration of the profile to the rf calibration
+    fn calibrated_events(&self) -> EventQueue {
+        let q = self.place_events();
+        if let Some(rf_dac_seconds) = self.base_params().rf_dac_seconds {
+            q.scale_rf_power(rf_dac_seconds/ScannerProfile::active().reference_rf_dac_seconds);
+        }
+        q
+    }
+    fn lut_export(&self,filepath:&Path) {
+        if let Some(lut) = self.lut() {
+            let s:Vec<String> = lut.iter().map(|entry| entry.to_string()).collect();
+            let mut f = File::create(filepath.join(LUT_FILENAME)).expect("cannot create file");
+            f.write_all(s.join("\n").as_bytes()).expect("trouble writing to file");
+        }
+    }
     fn seq_file_export(&self,sample_period_us:usize,filepath:&str) {
         let q = self.place_events();
         let (grad_params,rf_params) = q.ppl_seq_params(sample_period_us);
@@ -200,12 +286,22 @@ pub trait Build {
                 (String::from(""),String::from(""))
             }
         };
+        // events are built with the calibration of the active profile, so the limits and ppl use it too
+        let scanner = ScannerProfile::active();
+        // catch gradients the amplifiers can't deliver before they reach the scanner
+        let violations = self.grad_limit_check(scanner);
+        if !violations.is_empty() {
+            let report:Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+            panic!("gradient hardware limits exceeded:\n{}",report.join("\n"))
+        }
         let ppl = PPL::new(
-            &mut self.place_events(),
+            &mut self.calibrated_events(),
             base_params.n_repetitions,
             base_params.n_averages,
+            self.n_slices(),
             base_params.rep_time,
-            base_params.base_frequency.clone(),
+            base_params.obs_freq_offset,
+            scanner,
             &seq_path_strs.0,
             &seq_path_strs.1,
             base_params.orientation.clone(),
@@ -214,6 +310,7 @@ pub trait Build {
             base_params.view_acceleration,
             sim_mode
         );
+        self.lut_export(filepath);
         let filename = filepath.join(name);
         let ppr_filename = filepath.join(ppr_name).with_extension("ppr");
         let ppr_str = ppl.print_ppr(&filename);
@@ -225,6 +322,66 @@ pub trait Build {
             build_directory(filepath);
         }
     }
+    fn grad_limit_check(&self,scanner:&ScannerProfile) -> Vec<GradLimitViolation> {
+        let base_params = self.base_params();
+        scanner.check_grad_limits(
+            &self.place_events(),
+            base_params.n_repetitions,
+            base_params.view_acceleration,
+            base_params.rep_time,
+            base_params.waveform_sample_period_us
+        )
+    }
+    fn rf_power_estimate(&self,rf_dac_seconds:f32) -> RfPowerEstimate {
+        let base_params = self.base_params();
+        RfPowerEstimate::new(
+            &self.calibrated_events(),
+            base_params.n_repetitions,
+            base_params.n_averages,
+            base_params.view_acceleration,
+            base_params.rep_time,
+            rf_dac_seconds,
+            base_params.waveform_sample_period_us
+        )
+    }
+    fn orientation_params(&self) -> OrientationHeadfileParams {
+        let orientation = self.base_params().orientation;
+        OrientationHeadfileParams {
+            base_matrix:orientation.base_matrix(),
+            rotation:orientation.rotation_matrix()
+        }
+    }
+    fn k_space_trajectory(&self,driver_value:u32) -> KSpaceTrajectory {
+        KSpaceTrajectory::new(&self.place_events(),driver_value,self.base_params().waveform_sample_period_us)
+    }
+    fn gradient_moments(&self,from_label:&str,to_label:&str,driver_value:u32) -> GradientMoments {
+        moments_between(&self.place_events(),from_label,to_label,driver_value,self.base_params().waveform_sample_period_us)
+    }
+    fn timing_diagram(&self,driver_value:u32) -> TimingDiagram {
+        let base_params = self.base_params();
+        TimingDiagram::new(&self.place_events(),driver_value,base_params.waveform_sample_period_us,Some(base_params.rep_time))
+    }
+    fn timing_diagram_export(&self,filepath:&Path,name:&str,driver_value:u32) {
+        let diagram = self.timing_diagram(driver_value);
+        diagram.to_svg(&filepath.join(name).with_extension("svg"));
+        diagram.to_json(&filepath.join(name).with_extension("json"));
+    }
+    fn pulseq_export(&self,filepath:&Path,seq_name:&str,rf_dac_seconds:f32) -> Result<(),PulseqError> {
+        let base_params = self.base_params();
+        let params = PulseqParams {
+            name:seq_name.to_string(),
+            n_repetitions:base_params.n_repetitions,
+            n_averages:base_params.n_averages,
+            rep_time:base_params.rep_time,
+            view_acceleration:base_params.view_acceleration,
+            waveform_sample_period_us:base_params.waveform_sample_period_us,
+            phase_unit:base_params.phase_unit,
+            rf_dac_seconds
+        };
+        let seq = Pulseq::new(&self.calibrated_events(),params,ScannerProfile::active())?;
+        seq.write(&filepath.join(seq_name));
+        Ok(())
+    }
     fn param_export(&self,filepath:&Path);
 }
 
@@ -10,7 +10,8 @@ use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin,
 use seq_tools::execution::ExecutionBlock;
 use seq_tools::gradient_event::GradEvent;
 use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
-use seq_tools::ppl::{BaseFrequency, GradClock, Orientation, PhaseUnit, PPL};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit, PPL};
+use seq_tools::scanner::ScannerProfile;
 use seq_tools::pulse::{CompositeHardpulse, Hardpulse, Pulse, Trapezoid};
 use seq_tools::rf_event::RfEvent;
 use seq_tools::rf_state::{PhaseCycleStrategy, RfDriver, RfDriverType, RfStateType};
@@ -25,7 +26,7 @@ fn test(){
     let acceleration = 1;
     let output_dir = Path::new("/mnt/d/dev/rf_cal");
     let me = RfCal::new(mep);
-    let ppl = me.ppl_export(BaseFrequency::civm9p4t(0.0),Orientation::CivmStandard,acceleration,sim_mode);
+    let ppl = me.ppl_export(0.0,Orientation::CivmStandard,acceleration,sim_mode);
     let filename = output_dir.join("rf_cal.ppl");
     let mut outfile = File::create(&filename).expect("cannot create file");
     outfile.write_all(ppl.print().as_bytes()).expect("cannot write to file");
@@ -164,12 +165,12 @@ impl RfCal {
     //     let mut f = File::create(file).expect("cannot create file");
     //     f.write_all(&s.as_bytes()).expect("trouble writing to file");
     // }
-    pub fn ppl_export(&self,base_frequency:BaseFrequency,orientation:Orientation,acceleration:u16,simulation_mode:bool) -> PPL {
+    pub fn ppl_export(&self,obs_freq_offset:f32,orientation:Orientation,acceleration:u16,simulation_mode:bool) -> PPL {
         let averages = 1;
         //let repetitions = (self.params.samples.1 as u32*self.params.samples.2 as u32);
         let repetitions = 2;
         PPL::new(
-            &mut self.place_events(),repetitions,averages,self.params.rep_time,base_frequency,
+            &mut self.place_events(),repetitions,averages,1,self.params.rep_time,obs_freq_offset,ScannerProfile::active(),
             r"d:\dev\rf_cal\civm_grad.seq",r"d:\dev\rf_cal\civm_rf.seq",
             orientation,GradClock::CPS20,PhaseUnit::Min,acceleration,simulation_mode)
     }
@@ -9,12 +9,14 @@ use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin,
 use seq_tools::execution::ExecutionBlock;
 use seq_tools::gradient_event::GradEvent;
 use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
-use seq_tools::ppl::{GradClock, Orientation, PhaseUnit,BaseFrequency};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
 use seq_tools::pulse::{CompositeHardpulse, HalfSin, Hardpulse, Pulse, Trapezoid};
 use seq_tools::rf_event::RfEvent;
 use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
 use seq_tools::_utils::{sec_to_clock};
-use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat, ScoutConfig};
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat, ScoutConfig, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
 use serde_json;
 use serde::{Serialize,Deserialize};
 use cs_table::cs_table::CSTable;
@@ -74,6 +76,7 @@ impl Initialize for ScoutParams {
             phase_encode_time: 550E-6,
             echo_time: 5E-3,
             obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
             rep_time: 50E-3,
             n_averages: 1,
             n_repetitions: 128,
@@ -104,7 +107,9 @@ impl MrdToKspace for ScoutParams {
             n_views: self.samples.1 as usize,
             view_acceleration: 1,
             dummy_excitations: 0,
-            n_objects: 1
+            n_objects: 1,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
         }
     }
 }
@@ -154,6 +159,39 @@ impl SequenceParameters for ScoutParams {
         let mut f = File::create(params_file).expect("cannot create file");
         f.write_all(str.as_bytes()).expect("trouble writing to file");
     }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz as f64;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_time = validation::placement("echo_time",self.echo_time,Self::default().echo_time,|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        });
+        if echo_time.is_some() {
+            return validation::collect(vec![echo_time])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_time = timing::min_value(|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        },&[Self::default().echo_time,self.echo_time]);
+        let mut shortest = self.clone();
+        TimingLimits {
+            min_echo_time,
+            min_echo_spacing:None,
+            min_rep_time:min_echo_time.map(|echo_time|{
+                shortest.echo_time = echo_time;
+                timing::min_rep_time(shortest.instantiate().as_ref())
+            })
+        }
+    }
     fn instantiate(&self) -> Box<dyn Build> {
         Box::new(Scout::new(self.clone()))
     }
@@ -168,7 +206,8 @@ impl Build for Scout {
             n_averages: self.params.n_averages,
             n_repetitions: self.params.samples.1 as u32,
             rep_time: self.params.rep_time,
-            base_frequency: BaseFrequency::civm9p4t(0.0),
+            obs_freq_offset: self.params.obs_freq_offset as f32,
+            rf_dac_seconds: self.params.rf_dac_seconds,
             orientation: self.params.orientation.clone(),
             grad_clock: GradClock::CPS20,
             phase_unit: PhaseUnit::Min,
@@ -201,6 +240,8 @@ pub struct ScoutParams {
     n_repetitions: u32,
     grad_off: bool,
     pub obs_freq_offset: f64,
+    #[serde(default)]
+    pub rf_dac_seconds: Option<f32>,
 }
 
 #[derive(Clone)]
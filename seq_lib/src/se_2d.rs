@@ -3,20 +3,26 @@ use std::rc::Rc;
 use std::path::{Path, PathBuf};
 use std::fs::{File};
 use std::io::{Read, Write};
-use seq_tools::{grad_cal, _utils};
+use seq_tools::{grad_cal, moments, _utils};
 use seq_tools::acq_event::{AcqEvent, SpectralWidth};
 use seq_tools::event_block::{Event, EventQueue, GradEventType};
 use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin, Origin};
-use seq_tools::execution::ExecutionBlock;
+use seq_tools::execution::{ExecutionBlock, RfRole};
 use seq_tools::gradient_event::GradEvent;
 use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
-use seq_tools::ppl::{GradClock, Orientation, PhaseUnit,BaseFrequency};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
 use seq_tools::pulse::{CompositeHardpulse, HalfSin, Hardpulse, Pulse, Trapezoid};
 use seq_tools::rf_event::RfEvent;
+use seq_tools::moments::TrapezoidSolution;
+use seq_tools::scanner::ScannerProfile;
 use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
 use seq_tools::_utils::{sec_to_clock};
-use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat, ScoutConfig};
+use crate::preparation::{self, PreparationParams};
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat, ScoutConfig, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
 use serde_json;
+use spin_sim::slice_profile::SliceProfile;
 use serde::{Serialize,Deserialize};
 use cs_table::cs_table::CSTable;
 use headfile::headfile::{DWHeadfile, DWHeadfileParams, AcqHeadfile, AcqHeadfileParams};
@@ -34,7 +40,7 @@ impl AcqDimensions for Se2DParams {
             n_read: self.samples.0 as i32,
             n_phase1: self.samples.1 as i32,
             n_phase2: 1,
-            n_slices: 1,
+            n_slices: self.n_slices as i32,
             n_echos: 1,
             n_experiments: 1
         }
@@ -46,10 +52,13 @@ impl AcqHeadfile for Se2DParams {
         AcqHeadfileParams {
             dim_x: self.samples.0 as i32,
             dim_y: self.samples.1 as i32,
-            dim_z: 1,
+            dim_z: self.n_slices as i32,
             fovx_mm: self.fov.0,
             fovy_mm: self.fov.1,
-            fovz_mm: self.slice_thickness,
+            fovz_mm: match self.n_slices {
+                1 => self.slice_thickness,
+                n => n as f32*self.slice_spacing
+            },
             te_ms: 1E3*self.echo_time,
             tr_us: 1E6*self.rep_time,
             alpha: 90.0,
@@ -67,6 +76,10 @@ impl Initialize for Se2DParams {
             fov: (19.7, 12.0),
             samples: (210, 128),
             slice_thickness: 1.0,
+            n_slices: 1,
+            slice_spacing: 1.0,
+            slice_offset: 0.0,
+            slice_order: SliceOrder::Interleaved,
             sample_discards: 0,
             orientation: Orientation::CivmStandard,
             spectral_width: SpectralWidth::SW100kH,
@@ -76,10 +89,12 @@ impl Initialize for Se2DParams {
             phase_encode_time: 550E-6,
             echo_time: 10E-3,
             obs_freq_offset: 0.0,
-            rep_time: 50E-3,
+            rf_dac_seconds: None,
+            rep_time: 150E-3,
             n_averages: 1,
             n_repetitions: 128,
-            grad_off: false
+            grad_off: false,
+            preparation: None
         }
     }
     fn load(params_file: &Path) -> Self {
@@ -106,7 +121,9 @@ impl MrdToKspace for Se2DParams {
             n_views: self.samples.1 as usize,
             view_acceleration: 1,
             dummy_excitations: 0,
-            n_objects: 1
+            n_objects: 1,
+            n_slices: self.n_slices as usize,
+            slice_order: self.slice_order
         }
     }
 }
@@ -143,8 +160,41 @@ impl SequenceParameters for Se2DParams {
         let mut f = File::create(params_file).expect("cannot create file");
         f.write_all(str.as_bytes()).expect("trouble writing to file");
     }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz as f64;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_time = validation::placement("echo_time",self.echo_time,Self::default().echo_time,|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        });
+        if echo_time.is_some() {
+            return validation::collect(vec![echo_time])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_time = timing::min_value(|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            Some(params.instantiate())
+        },&[Self::default().echo_time,self.echo_time]);
+        let mut shortest = self.clone();
+        TimingLimits {
+            min_echo_time,
+            min_echo_spacing:None,
+            min_rep_time:min_echo_time.map(|echo_time|{
+                shortest.echo_time = echo_time;
+                timing::min_rep_time(shortest.instantiate().as_ref())
+            })
+        }
+    }
     fn instantiate(&self) -> Box<dyn Build> {
-        Box::new(Se2D::new(self.clone()))
+        preparation::prepare(Box::new(Se2D::new(self.clone())),&self.preparation)
     }
 }
 
@@ -157,7 +207,8 @@ impl Build for Se2D {
             n_averages: self.params.n_averages,
             n_repetitions: self.params.samples.1 as u32,
             rep_time: self.params.rep_time,
-            base_frequency: BaseFrequency::civm9p4t(0.0),
+            obs_freq_offset: self.params.obs_freq_offset as f32,
+            rf_dac_seconds: self.params.rf_dac_seconds,
             orientation: self.params.orientation.clone(),
             grad_clock: GradClock::CPS20,
             phase_unit: PhaseUnit::Min,
@@ -165,6 +216,9 @@ impl Build for Se2D {
             waveform_sample_period_us: 2
         }
     }
+    fn n_slices(&self) -> u16 {
+        self.params.n_slices
+    }
     fn param_export(&self, filepath: &Path) {
         let params = self.params.clone();
         let name = params.name.clone();
@@ -178,6 +232,12 @@ pub struct Se2DParams {
     fov: (f32, f32),
     samples: (u16, u16),
     slice_thickness:f32,
+    n_slices: u16,
+    // center to center distance of neighboring slices (mm)
+    slice_spacing: f32,
+    // position of the center of the slice stack (mm)
+    slice_offset: f32,
+    slice_order: SliceOrder,
     sample_discards: u16,
     orientation:Orientation,
     spectral_width: SpectralWidth,
@@ -191,6 +251,10 @@ pub struct Se2DParams {
     n_repetitions: u32,
     grad_off: bool,
     pub obs_freq_offset: f64,
+    #[serde(default)]
+    pub rf_dac_seconds: Option<f32>,
+    // preparation modules run ahead of the sequence every repetition
+    preparation: Option<Vec<PreparationParams>>,
 }
 
 #[derive(Clone)]
@@ -218,7 +282,6 @@ struct Waveforms {
     phase_encode: Trapezoid,
     readout: Trapezoid,
     slice_sel:Trapezoid,
-    slice_ref:Trapezoid,
     ref_slice_sel:Trapezoid,
 }
 
@@ -249,7 +312,6 @@ impl Se2D {
         let readout = Trapezoid::new(params.ramp_time, read_sample_time_sec);
         let phase_encode = Trapezoid::new(params.ramp_time, params.phase_encode_time);
         let slice_sel = Trapezoid::new(params.ramp_time,2.0*params.rf_duration);
-        let slice_ref = Trapezoid::new(params.ramp_time,params.rf_duration);
         let ref_slice_sel = Trapezoid::new(params.ramp_time,2.0*params.rf_180_duration);
 
         Waveforms {
@@ -258,11 +320,36 @@ impl Se2D {
             phase_encode,
             readout,
             slice_sel,
-            slice_ref,
             ref_slice_sel
         }
     }
 
+    // slice select and refocus slice select dacs from bloch simulated slice profiles, along with the
+    // shortest lobe that rephases the excitation
+    fn slice_select_dacs(params: &Se2DParams) -> (i16,i16,TrapezoidSolution) {
+        let waveforms = Self::waveforms(params);
+        let excitation = SliceProfile::for_thickness(&waveforms.excitation,90.0,params.slice_thickness);
+        let refocus = SliceProfile::for_thickness(&waveforms.refocus,180.0,params.slice_thickness);
+        let grad = excitation.grad_strength_hzpmm;
+        // the rephase area is taken from the end of the pulse, where half the select lobe less half the pulse remains
+        let select_tail = waveforms.slice_sel.power_net(grad)/2.0 - grad*params.rf_duration/2.0;
+        let rephase = moments::solve_trapezoid(excitation.rephase_area() - select_tail,ScannerProfile::active());
+        (grad_cal::grad_to_dac(grad),grad_cal::grad_to_dac(refocus.grad_strength_hzpmm),rephase)
+    }
+
+    /** Slice positions (mm) from one end of the stack to the other */
+    pub fn slice_positions(params: &Se2DParams) -> Vec<f32> {
+        let center = (params.n_slices as f32 - 1.0)/2.0;
+        (0..params.n_slices).map(|i| params.slice_offset + (i as f32 - center)*params.slice_spacing).collect()
+    }
+
+    // rf frequency offsets (Hz) that move the slice selected by a gradient to each position, in slice loop order
+    fn slice_frequency_offsets(params: &Se2DParams,slice_dac:i16) -> Vec<f32> {
+        let positions = Self::slice_positions(params);
+        let hz_per_mm = grad_cal::dac_to_hz_per_mm(slice_dac);
+        params.slice_order.acquisition_order(params.n_slices as usize).iter().map(|i| hz_per_mm*positions[*i]).collect()
+    }
+
     fn gradient_matrices(params: &Se2DParams) -> GradMatrices {
         let waveforms = Self::waveforms(params);
         let mat_count = Matrix::new_tracker();
@@ -298,11 +385,7 @@ impl Se2D {
         let rewinder = phase_encode1.derive("c_re_mat",re_trans,(false, false, false),false,&mat_count);
 
 
-        let grad = 10.0*waveforms.excitation.bandwidth_hz()/params.slice_thickness;
-        let slice_dac = grad_cal::grad_to_dac(grad);
-
-        let grad = 10.0*waveforms.refocus.bandwidth_hz()/params.slice_thickness;
-        let ref_dac = grad_cal::grad_to_dac(grad);
+        let (slice_dac,ref_dac,rephase) = Self::slice_select_dacs(params);
 
         let slice_sel = Matrix::new_static(
             "slice_sel_mat",
@@ -312,9 +395,9 @@ impl Se2D {
             &mat_count
         );
 
-        let slice_ref = slice_sel.derive(
+        let slice_ref = Matrix::new_static(
             "slice_ref_mat",
-            LinTransform::new((None,None,Some(-1.0)),(None,None,None)),
+            DacValues::new(None,None,Some(rephase.dac)),
             (false,false,false),
             false,
             &mat_count
@@ -357,7 +440,7 @@ impl Se2D {
             "ref_slice_sel"
         );
 
-        let excitation = RfEvent::new(
+        let mut excitation = RfEvent::new(
             "excitation",
             1,
             w.excitation,
@@ -365,17 +448,23 @@ impl Se2D {
             RfStateType::Static(0)
         );
 
-        let refocus = RfEvent::new(
+        let mut refocus = RfEvent::new(
             "refocus",
             2,
             w.refocus,
             RfStateType::Adjustable(800, None),
             RfStateType::Adjustable(400,None)
         );
+        refocus.set_role(RfRole::Refocus);
+
+        // both pulses select the same slice under their own gradient strengths
+        let (slice_dac,ref_dac,rephase) = Self::slice_select_dacs(params);
+        excitation.set_frequency_offsets(Self::slice_frequency_offsets(params,slice_dac));
+        refocus.set_frequency_offsets(Self::slice_frequency_offsets(params,ref_dac));
 
 
         let slice_ref = GradEvent::new(
-            (None,None,Some(w.slice_ref)),
+            (None,None,Some(rephase.trapezoid)),
             &m.slice_ref,
             GradEventType::NonBlocking,
             "slice_ref"
@@ -388,12 +477,14 @@ impl Se2D {
             "phase_encode1"
         );
 
-        let readout = GradEvent::new(
+        let mut readout = GradEvent::new(
             (Some(w.readout), None, None),
             &m.readout,
             GradEventType::NonBlocking,
             "readout"
         );
+        // the slice offsets of the refocusing pulse would otherwise shift the receiver too
+        readout.restore_base_frequency();
 
         let acquire = AcqEvent::new(
             "acquire",
@@ -478,3 +569,26 @@ impl Se2D {
         )
     }
 }
+
+#[test]
+fn test(){
+    let mut params = Se2DParams::default();
+    params.n_slices = 5;
+    params.slice_spacing = 1.5;
+    params.slice_offset = 0.5;
+    assert_eq!(Se2D::slice_positions(&params),vec![-2.5,-1.0,0.5,2.0,3.5]);
+    // interleaved slices are excited at positions 0,2,4,1,3 and the offsets follow the loop order
+    let (slice_dac,_,rephase) = Se2D::slice_select_dacs(&params);
+    // the rephase lobe opposes the slice select gradient
+    assert!(slice_dac > 0 && rephase.dac < 0);
+    // the shortest rephase lobe stays within the gradient limits
+    assert!(params.instantiate().grad_limit_check(ScannerProfile::active()).is_empty());
+    let hz_per_mm = grad_cal::dac_to_hz_per_mm(slice_dac);
+    let offsets = Se2D::slice_frequency_offsets(&params,slice_dac);
+    for (offset,position) in offsets.iter().zip([-2.5,0.5,3.5,-1.0,2.0]) {
+        assert!((offset - hz_per_mm*position).abs() < 1E-3*hz_per_mm.abs());
+    }
+    // the receiver is back on the base frequency before every echo is acquired
+    let readout = Se2D::events(&params).readout.block_execution(100).cmd_string();
+    assert!(readout.commands.contains("frequency_buffer(0);"));
+}
@@ -6,20 +6,26 @@ use seq_tools::grad_cal;
 use seq_tools::acq_event::{AcqEvent, SpectralWidth};
 use seq_tools::event_block::{Event, EventQueue, GradEventType};
 use seq_tools::event_block::EventPlacementType::{After, Before, ExactFromOrigin, Origin};
-use seq_tools::execution::ExecutionBlock;
+use seq_tools::execution::{ExecutionBlock, RfRole};
 use seq_tools::gradient_event::GradEvent;
 use seq_tools::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
-use seq_tools::ppl::{GradClock, Orientation, PhaseUnit,BaseFrequency};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
 use seq_tools::pulse::{CompositeHardpulse, HalfSin, Hardpulse, Pulse, Trapezoid};
 use seq_tools::rf_event::RfEvent;
 use seq_tools::rf_state::{PhaseCycleStrategy, RfStateType};
 use seq_tools::_utils::{sec_to_clock};
-use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat };
+use crate::preparation::{self, PreparationParams};
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, DiffusionWeighted, DiffusionPulseShape, CompressedSense, b_val_to_dac, Simulate, AcqDimensions, AcqDims, Initialize, DWSequenceParameters, MrdToKspace, MrdToKspaceParams, MrdFormat, SliceOrder };
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
 use serde_json;
 use serde::{Serialize,Deserialize};
 use cs_table::cs_table::CSTable;
 use headfile::headfile::{DWHeadfile, DWHeadfileParams, AcqHeadfile, AcqHeadfileParams};
 
+// the acquisition starts this long before each echo (seconds)
+const ACQ_LEAD:f32 = 38E-6;
+
 impl Setup for SeDtiParams {
     fn set_mode(&mut self) {
         self.setup_mode = true;
@@ -139,12 +145,14 @@ impl Initialize for SeDtiParams {
             phase_encode_time: 550E-6,
             echo_time: 13.98E-3,
             obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
             rep_time: 80E-3,
             n_averages: 1,
             n_repetitions: 2000,
             view_acceleration : 1,
             setup_mode: false,
-            grad_off: false
+            grad_off: false,
+            preparation: None
         }
     }
     fn load(params_file: &Path) -> Self {
@@ -175,7 +183,9 @@ impl MrdToKspace for SeDtiParams {
             n_views,
             view_acceleration: self.view_acceleration as usize,
             dummy_excitations: 0,
-            n_objects: 1
+            n_objects: 1,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
         }
     }
 }
@@ -190,8 +200,43 @@ impl SequenceParameters for SeDtiParams {
         let mut f = File::create(params_file).expect("cannot create file");
         f.write_all(str.as_bytes()).expect("trouble writing to file");
     }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_time = validation::placement("echo_time",self.echo_time,Self::default().echo_time,|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            // the acquisition can't start before the excitation
+            (params.echo_time >= ACQ_LEAD).then(|| params.instantiate())
+        });
+        if echo_time.is_some() {
+            return validation::collect(vec![echo_time])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_time = timing::min_value(|echo_time|{
+            let mut params = self.clone();
+            params.echo_time = echo_time;
+            // the acquisition can't start before the excitation
+            (params.echo_time >= ACQ_LEAD).then(|| params.instantiate())
+        },&[Self::default().echo_time,self.echo_time]);
+        let mut shortest = self.clone();
+        TimingLimits {
+            min_echo_time,
+            min_echo_spacing:None,
+            min_rep_time:min_echo_time.map(|echo_time|{
+                shortest.echo_time = echo_time;
+                timing::min_rep_time(shortest.instantiate().as_ref())
+            })
+        }
+    }
     fn instantiate(&self) -> Box<dyn Build> {
-        Box::new(SeDti::new(self.clone()))
+        preparation::prepare(Box::new(SeDti::new(self.clone())),&self.preparation)
     }
 }
 
@@ -205,7 +250,8 @@ impl Build for SeDti {
             n_averages: self.params.n_averages,
             n_repetitions: self.params.n_repetitions,
             rep_time: self.params.rep_time,
-            base_frequency: BaseFrequency::civm9p4t(self.params.obs_freq_offset),
+            obs_freq_offset: self.params.obs_freq_offset,
+            rf_dac_seconds: self.params.rf_dac_seconds,
             orientation: Orientation::CivmStandard,
             grad_clock: GradClock::CPS20,
             phase_unit: PhaseUnit::Min,
@@ -240,12 +286,16 @@ pub struct SeDtiParams {
     phase_encode_time: f32,
     echo_time: f32,
     obs_freq_offset: f32,
+    #[serde(default)]
+    rf_dac_seconds: Option<f32>,
     rep_time: f32,
     n_averages: u16,
     n_repetitions: u32,
     view_acceleration : u16,
     setup_mode: bool,
     grad_off: bool,
+    // preparation modules run ahead of the sequence every repetition
+    preparation: Option<Vec<PreparationParams>>,
 }
 
 #[derive(Clone)]
@@ -400,7 +450,7 @@ impl SeDti {
             RfStateType::Static(0)
         );
 
-        let refocus1 = RfEvent::new(
+        let mut refocus1 = RfEvent::new(
             "refocus1",
             2,
             w.refocus.clone(),
@@ -408,6 +458,7 @@ impl SeDti {
             RfStateType::Adjustable(400, Some(PhaseCycleStrategy::CycleCPMG(2))),
             //RfStateType::Driven(RfDriver::new(DriverVar::Repetition,RfDriverType::PhaseCycle3D(PhaseCycleStrategy::CycleCPMG(1)),None)),
         );
+        refocus1.set_role(RfRole::Refocus);
 
         let phase_encode1 = GradEvent::new(
             (Some(w.phase_encode), Some(w.phase_encode), Some(w.phase_encode)),
@@ -472,7 +523,7 @@ impl SeDti {
         let excitation = Event::new(self.events.excitation.as_reference(), Origin);
         let refocus1 = Event::new(self.events.refocus1.as_reference(), ExactFromOrigin(sec_to_clock(tau)));
         let readout1 = Event::new(self.events.readout.as_reference(), ExactFromOrigin(sec_to_clock(te)));
-        let acquire1 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te - 38E-6)));
+        let acquire1 = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(sec_to_clock(te - ACQ_LEAD)));
 
         let phase_encode1 = Event::new(self.events.phase_encode1.as_reference(), Before(readout1.clone(), 0));
 
@@ -0,0 +1,263 @@
+/*
+    Timing solver for pulse sequences. The shortest echo time, echo spacing and rep time are found
+    from the events a sequence actually places, so they follow the event durations and placement
+    rules instead of a separate timing model that can drift from the sequence.
+
+    Timing parameters without a closed form are searched for the smallest value that places every
+    event without overlap and in the same order as a reference value: the value the sequence is
+    designed around, or the configured value. Short values can slide events past each other (both
+    diffusion lobes ahead of the refocusing pulse) without any overlap, and long values can slide
+    them past each other again when other timing is fixed, so events may only be placed in order
+    within a window of the parameter. The search steps up from the shortest value in steps finer
+    than the window before it is refined.
+
+    Sequences are built through a function of the timing parameter that returns None for values the
+    sequence can't be built with (such as an acquisition that would start before the excitation), so
+    every value that is built must place its events without panicking.
+
+    The scan time is the number of passes through the view and average loops times the rep time.
+    Dummy excitations are run inside the view loop, and compressed sensing sequences set the view
+    loop count from the table length and view acceleration, so both are already part of the count.
+ */
+
+use std::fmt;
+use seq_tools::_utils;
+use seq_tools::ppl::FlatLoopStructure;
+use crate::pulse_sequence::Build;
+
+// search resolution of timing parameters in microseconds
+const SEARCH_RESOLUTION_US:i32 = 10;
+// the search steps up by this or 2% of the value (microseconds), whichever is larger
+const SEARCH_STEP_US:i32 = 100;
+// timing parameters are not searched beyond this (seconds)
+const SEARCH_LIMIT:f32 = 1.0;
+
+/** Shortest timing parameters of a sequence. Parameters the sequence doesn't have are None */
+#[derive(Clone,Debug)]
+pub struct TimingLimits {
+    pub min_echo_time:Option<f32>,
+    pub min_echo_spacing:Option<f32>,
+    // rep time of the sequence with the shortest echo timing
+    pub min_rep_time:Option<f32>,
+}
+
+impl fmt::Display for TimingLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |value:Option<f32>| match value {
+            Some(seconds) => format!("{:.3} ms",1E3*seconds),
+            None => String::from("n/a")
+        };
+        writeln!(f,"minimum echo time: {}",ms(self.min_echo_time))?;
+        writeln!(f,"minimum echo spacing: {}",ms(self.min_echo_spacing))?;
+        write!(f,"minimum rep time: {}",ms(self.min_rep_time))
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct ScanTime {
+    pub rep_time:f32,
+    // passes through the view loop, including dummy excitations
+    pub n_repetitions:u32,
+    pub n_averages:u16,
+    // views acquired in every pass through the view loop
+    pub view_acceleration:u16,
+    // passes through the view loop that aren't reconstructed
+    pub dummy_excitations:usize,
+    // seconds
+    pub total:f32,
+}
+
+impl ScanTime {
+    pub fn new(sequence:&dyn Build,dummy_excitations:usize) -> Self {
+        let base_params = sequence.base_params();
+        Self {
+            rep_time:base_params.rep_time,
+            n_repetitions:base_params.n_repetitions,
+            n_averages:base_params.n_averages,
+            view_acceleration:base_params.view_acceleration,
+            dummy_excitations,
+            total:base_params.n_repetitions as f32*base_params.n_averages as f32*base_params.rep_time
+        }
+    }
+}
+
+impl fmt::Display for ScanTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"{} repetitions ({} dummy, {} views each) x {} averages x {:.3} ms = {}",
+               self.n_repetitions,self.dummy_excitations,self.view_acceleration,self.n_averages,1E3*self.rep_time,hms(self.total))
+    }
+}
+
+/** Seconds formatted as hours, minutes and seconds */
+pub fn hms(seconds:f32) -> String {
+    let s = seconds.round() as u64;
+    format!("{}h {:02}m {:02}s",s/3600,(s % 3600)/60,s % 60)
+}
+
+/** Shortest rep time in clock periods that fits the events of every slice with loop execution */
+pub fn min_rep_time_clocks(sequence:&dyn Build) -> i32 {
+    FlatLoopStructure::min_rep_time_clocks(&sequence.place_events(),sequence.n_slices())
+}
+
+/** Shortest rep time rounded up to a tenth of a millisecond. The rep time is split evenly between
+slices and truncated to the microsecond, so every slice gets a microsecond of margin first */
+pub fn min_rep_time(sequence:&dyn Build) -> f32 {
+    let min_us = _utils::clock_to_us(min_rep_time_clocks(sequence)) + sequence.n_slices() as i32;
+    _utils::us_to_sec(100*((min_us + 99)/100))
+}
+
+/** Smallest value of a timing parameter that places every event of the sequence built with it, in
+the order they have with the first of the reference values that places them without overlap */
+pub fn min_value<F>(sequence:F,reference:&[f32]) -> Option<f32>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    let reference = reference_order(&sequence,reference)?;
+    match placeable(&sequence,0,&reference) {
+        true => Some(0.0),
+        false => search(&sequence,0,&reference)
+    }
+}
+
+/** Smallest value larger than an infeasible value that places every event, in the order they
+have with the first of the reference values that places them without overlap. Without one, the
+order is taken from the first larger value that places them without overlap */
+pub fn min_placeable<F>(sequence:&F,infeasible:f32,reference:&[f32]) -> Option<f32>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    let reference = reference_order(sequence,reference).or_else(|| first_order_above(sequence,infeasible))?;
+    search(sequence,_utils::sec_to_us(infeasible.max(0.0)),&reference)
+}
+
+/** Why the events of the sequence built with a value can't be placed. Events must not overlap and
+must run in the reference order, if there is one */
+#[derive(Clone,Debug,PartialEq)]
+pub enum PlacementError {
+    // the two events are placed on top of each other
+    Overlap(String,String),
+    // the first event is placed before the second one
+    Order(String,String),
+    // the sequence can't be built with the value
+    Unbuildable,
+}
+
+/** Checks the placement of the events of the sequence built with a value */
+pub fn check_placement<F>(sequence:&F,value:f32,reference:Option<&[String]>) -> Result<(),PlacementError>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    let (order,overlaps) = placed_events(sequence,value).ok_or(PlacementError::Unbuildable)?;
+    if let Some((first,second,_)) = overlaps.first() {
+        return Err(PlacementError::Overlap(first.clone(),second.clone()))
+    }
+    if let Some(reference) = reference {
+        if let Some((expected,found)) = reference.iter().zip(order.iter()).find(|(expected,found)| expected != found) {
+            return Err(PlacementError::Order(found.clone(),expected.clone()))
+        }
+    }
+    Ok(())
+}
+
+// labels of events in the order they are placed, along with the events that overlap
+type PlacedEvents = (Vec<String>,Vec<(String,String,i32)>);
+
+// placed events of the sequence built with a value. None if the sequence can't be built
+fn placed_events<F>(sequence:&F,value:f32) -> Option<PlacedEvents>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    // event times are never negative
+    if value < 0.0 {return None}
+    let queue = sequence(value)?.place_events();
+    let order = queue.events().iter().map(|event| event.borrow().unique_label()).collect();
+    Some((order,queue.overlaps()))
+}
+
+/** Order of events with the first reference value that places them without overlap */
+pub fn reference_order<F>(sequence:&F,reference:&[f32]) -> Option<Vec<String>>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    reference.iter().find_map(|value| match placed_events(sequence,*value) {
+        Some((order,overlaps)) if overlaps.is_empty() => Some(order),
+        _ => None
+    })
+}
+
+// order of events with the first larger value on the search grid that places them without overlap
+fn first_order_above<F>(sequence:&F,value:f32) -> Option<Vec<String>>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    let limit = _utils::sec_to_us(SEARCH_LIMIT);
+    let mut us = _utils::sec_to_us(value.max(0.0));
+    while us < limit {
+        us = next_step(us).min(limit);
+        if let Some((order,overlaps)) = placed_events(sequence,_utils::us_to_sec(us)) {
+            if overlaps.is_empty() {return Some(order)}
+        }
+    }
+    None
+}
+
+fn next_step(us:i32) -> i32 {
+    us + SEARCH_STEP_US.max(us/50)
+}
+
+// smallest value larger than an infeasible one that places events in order, to the search resolution
+fn search<F>(sequence:&F,infeasible_us:i32,reference:&[String]) -> Option<f32>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    let limit = _utils::sec_to_us(SEARCH_LIMIT);
+    let mut lo = infeasible_us;
+    // step up until the events fit
+    let mut hi = next_step(lo).min(limit);
+    while !placeable(sequence,hi,reference) {
+        if hi >= limit {return None}
+        lo = hi;
+        hi = next_step(hi).min(limit);
+    }
+    while hi - lo > SEARCH_RESOLUTION_US {
+        let mid = (lo + hi)/2;
+        match placeable(sequence,mid,reference) {
+            true => hi = mid,
+            false => lo = mid
+        }
+    }
+    let us = SEARCH_RESOLUTION_US*((hi + SEARCH_RESOLUTION_US - 1)/SEARCH_RESOLUTION_US);
+    Some(_utils::us_to_sec(us))
+}
+
+fn placeable<F>(sequence:&F,us:i32,reference:&[String]) -> bool
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    check_placement(sequence,_utils::us_to_sec(us),Some(reference)).is_ok()
+}
+
+#[test]
+fn test(){
+    use crate::pulse_sequence::{Initialize, SequenceParameters};
+    use crate::gre::GreParams;
+    let params = GreParams::default();
+    let limits = params.timing_limits();
+    println!("{}",limits);
+    let mut json = serde_json::to_value(&params).unwrap();
+    json["echo_time"] = serde_json::json!(limits.min_echo_time.unwrap());
+    json["rep_time"] = serde_json::json!(limits.min_rep_time.unwrap());
+    let shortest:GreParams = serde_json::from_value(json).unwrap();
+    assert!(shortest.validate().is_ok());
+    // the minimum doesn't depend on the configured echo time, and anything shorter can't be placed
+    assert!(shortest.timing_limits().min_echo_time == limits.min_echo_time);
+    json = serde_json::to_value(&shortest).unwrap();
+    json["echo_time"] = serde_json::json!(limits.min_echo_time.unwrap() - 20E-6);
+    assert!(serde_json::from_value::<GreParams>(json).unwrap().validate().is_err());
+
+    // negative values are reported without building the sequence
+    let sequence = |echo_time:f32|{
+        let mut json = serde_json::to_value(&params).unwrap();
+        json["echo_time"] = serde_json::json!(echo_time);
+        Some(serde_json::from_value::<GreParams>(json).unwrap().instantiate())
+    };
+    assert_eq!(check_placement(&sequence,-1E-3,None),Err(PlacementError::Unbuildable));
+
+    let scan_time = params.scan_time();
+    println!("{}",scan_time);
+    let base_params = params.instantiate().base_params();
+    assert_eq!(scan_time.total,base_params.n_repetitions as f32*base_params.n_averages as f32*base_params.rep_time);
+    assert_eq!(hms(3725.0),"1h 02m 05s");
+}
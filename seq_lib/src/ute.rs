@@ -0,0 +1,444 @@
+/*
+    Ultrashort echo time imaging with center-out projections. A non-selective hard pulse is followed
+    as soon as the hardware allows by an acquisition that starts sampling at the first point of the
+    readout ramp. The readout direction is rotated every view by a projection driver, so each
+    repetition samples one spoke from the center of k-space out to the edge. The readout gradient
+    block is played before the rf pulse with a delay long enough that its ramp starts at the first
+    sample, which removes the gradient setup time from the echo time.
+    The image matrix is twice the number of samples per spoke.
+ */
+
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{Read, Write};
+use seq_tools::{grad_cal, _utils};
+use seq_tools::acq_event::{AcqEvent, SpectralWidth};
+use seq_tools::event_block::{Event, EventQueue, GradEventType};
+use seq_tools::event_block::EventPlacementType::{After, ExactFromOrigin, Origin};
+use seq_tools::execution::ExecutionBlock;
+use seq_tools::gradient_event::GradEvent;
+use seq_tools::gradient_matrix::{DacValues, DriverVar, LinTransform, Matrix, MatrixDriver, MatrixDriverType, ProjectionStrategy};
+use seq_tools::ppl::{GradClock, Orientation, PhaseUnit};
+use seq_tools::ppl_function::MIN_DELAY_CLOCKS;
+use seq_tools::pulse::{DelayedTrapezoid, Hardpulse, Trapezoid};
+use seq_tools::rf_event::RfEvent;
+use seq_tools::rf_state::RfStateType;
+use crate::pulse_sequence::{Build, PPLBaseParams, SequenceParameters, Setup, CompressedSense, Simulate, AcqDimensions, AcqDims, Initialize, MrdToKspace, MrdToKspaceParams, MrdFormat, K_COORDS_FILENAME, SliceOrder};
+use crate::validation::{self, ParamError};
+use crate::timing::{self, TimingLimits};
+use serde_json;
+use serde::{Serialize,Deserialize};
+use headfile::headfile::{AcqHeadfile, AcqHeadfileParams};
+
+impl Simulate for UteParams {
+    fn set_sim_repetitions(&mut self) {
+        self.projections = match &self.projections {
+            ProjectionStrategy::Radial2D(_) => ProjectionStrategy::Radial2D(2),
+            ProjectionStrategy::GoldenAngle3D(_) => ProjectionStrategy::GoldenAngle3D(2),
+            ProjectionStrategy::Kooshball(_) => ProjectionStrategy::Kooshball(2),
+            ProjectionStrategy::LUT(lut) => ProjectionStrategy::LUT(lut[0..6].to_vec()),
+        }
+    }
+}
+
+impl AcqDimensions for UteParams {
+    fn acq_dims(&self) -> AcqDims {
+        AcqDims {
+            n_read: self.n_read as i32,
+            n_phase1: self.projections.n_projections() as i32,
+            n_phase2: 1,
+            n_slices: 1,
+            n_echos: 1,
+            n_experiments: 1
+        }
+    }
+}
+
+impl AcqHeadfile for UteParams {
+    fn acq_params(&self) -> AcqHeadfileParams {
+        let dim = 2*self.n_read as i32;
+        let (dim_z,fov_z) = match self.projections {
+            ProjectionStrategy::Radial2D(_) => (1,self.fov),
+            _=> (dim,self.fov)
+        };
+        AcqHeadfileParams {
+            dim_x: dim,
+            dim_y: dim,
+            dim_z,
+            fovx_mm: self.fov,
+            fovy_mm: self.fov,
+            fovz_mm: fov_z,
+            te_ms: 1E3*self.echo_time,
+            tr_us: 1E6*self.rep_time,
+            alpha: 90.0,
+            bw: self.spectral_width.hertz() as f32 /2.0,
+            n_echos: 1,
+            S_PSDname: self.name()
+        }
+    }
+}
+
+impl Initialize for UteParams {
+    fn default() -> Self {
+        UteParams {
+            name: "ute".to_string(),
+            fov: 20.0,
+            n_read: 64,
+            projections: ProjectionStrategy::Kooshball(25736),
+            orientation: Orientation::CivmStandard,
+            spectral_width: SpectralWidth::SW100kH,
+            rf_duration: 20E-6,
+            ramp_time: 100E-6,
+            spoil_duration: 1E-3,
+            echo_time: 150E-6,
+            obs_freq_offset: 0.0,
+            rf_dac_seconds: None,
+            rep_time: 8E-3,
+            n_averages: 1,
+            grad_off: false
+        }
+    }
+    fn load(params_file: &Path) -> Self {
+        let mut f = File::open(params_file).expect("cannot open file");
+        let mut json_str = String::new();
+        f.read_to_string(&mut json_str).expect("trouble reading file");
+        serde_json::from_str(&json_str).expect("cannot deserialize string")
+    }
+    fn write_default(params_file: &Path){
+        let params = Self::default();
+        let str = serde_json::to_string_pretty(&params).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+}
+
+impl MrdToKspace for UteParams {
+    fn mrd_to_kspace_params(&self) -> MrdToKspaceParams {
+        MrdToKspaceParams {
+            mrd_format:MrdFormat::NonCartesian,
+            n_read: self.n_read as usize,
+            // image matrix of the gridding recon, matching the headfile dims
+            n_phase1: 2*self.n_read as usize,
+            n_phase2: match self.projections {
+                ProjectionStrategy::Radial2D(_) => 1,
+                _=> 2*self.n_read as usize
+            },
+            n_views: self.projections.n_projections(),
+            view_acceleration: 1,
+            dummy_excitations: 0,
+            n_objects: 1,
+            n_slices: 1,
+            slice_order: SliceOrder::Sequential
+        }
+    }
+}
+
+impl CompressedSense for UteParams {
+    fn is_cs(&self) -> bool {
+        false
+    }
+
+    fn set_cs_table(&mut self) {
+    }
+
+    fn cs_table(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+impl Setup for UteParams {
+    fn set_mode(&mut self) {
+    }
+
+    fn set_repetitions(&mut self) {
+    }
+}
+
+impl SequenceParameters for UteParams {
+
+    fn name(&self) -> String {
+        String::from("ute")
+    }
+    fn write(&self,params_file: &Path){
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        let mut f = File::create(params_file).expect("cannot create file");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    fn set_freq_offset(&mut self,offset_hertz:f32) {
+        self.obs_freq_offset = offset_hertz;
+    }
+    fn set_rf_dac_seconds(&mut self,rf_dac_seconds:f32) {
+        self.rf_dac_seconds = Some(rf_dac_seconds);
+    }
+    fn validate(&self) -> Result<(),Vec<ParamError>> {
+        let echo_time = validation::minimum("echo_time",self.echo_time,Ute::min_echo_time(self),"the acquisition must start after the end of the excitation");
+        if echo_time.is_some() {
+            return validation::collect(vec![echo_time])
+        }
+        validation::collect(vec![validation::rep_time(self.instantiate().as_ref())])
+    }
+    fn timing_limits(&self) -> TimingLimits {
+        let min_echo_time = Ute::min_echo_time(self);
+        let mut shortest = self.clone();
+        shortest.echo_time = min_echo_time;
+        TimingLimits {
+            min_echo_time:Some(min_echo_time),
+            min_echo_spacing:None,
+            min_rep_time:Some(timing::min_rep_time(shortest.instantiate().as_ref()))
+        }
+    }
+    fn instantiate(&self) -> Box<dyn Build> {
+        Box::new(Ute::new(self.clone()))
+    }
+}
+
+impl Build for Ute {
+    fn place_events(&self) -> EventQueue {
+        self.place_events()
+    }
+    fn base_params(&self) -> PPLBaseParams {
+        PPLBaseParams {
+            n_averages: self.params.n_averages,
+            n_repetitions: self.params.projections.n_projections() as u32,
+            rep_time: self.params.rep_time,
+            obs_freq_offset: self.params.obs_freq_offset,
+            rf_dac_seconds: self.params.rf_dac_seconds,
+            orientation: self.params.orientation.clone(),
+            grad_clock: GradClock::CPS20,
+            phase_unit: PhaseUnit::Min,
+            view_acceleration: 1,
+            waveform_sample_period_us: 2
+        }
+    }
+    fn lut(&self) -> Option<Vec<i16>> {
+        Some(self.params.projections.lut())
+    }
+    fn param_export(&self, filepath: &Path) {
+        let params = self.params.clone();
+        let name = params.name.clone();
+        params.write(&filepath.join(name).with_extension("json"));
+        let lines:Vec<String> = self.k_coords().iter().map(|k| format!("{} {} {}",k[0],k[1],k[2])).collect();
+        let mut f = File::create(filepath.join(K_COORDS_FILENAME)).expect("cannot create file");
+        f.write_all(lines.join("\n").as_bytes()).expect("trouble writing to file");
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UteParams {
+    name: String,
+    // isotropic field of view (mm)
+    fov: f32,
+    // samples per spoke from the center of k-space out
+    n_read: u16,
+    projections: ProjectionStrategy,
+    orientation: Orientation,
+    spectral_width: SpectralWidth,
+    rf_duration: f32,
+    ramp_time: f32,
+    spoil_duration: f32,
+    // rf center to the first sample
+    echo_time: f32,
+    rep_time: f32,
+    n_averages: u16,
+    grad_off: bool,
+    pub obs_freq_offset: f32,
+    #[serde(default)]
+    pub rf_dac_seconds: Option<f32>,
+}
+
+#[derive(Clone)]
+pub struct Ute {
+    params: UteParams,
+    events: UteEvents,
+}
+
+#[derive(Clone)]
+pub struct UteEvents {
+    excitation: RfEvent<Hardpulse>,
+    readout: GradEvent<DelayedTrapezoid>,
+    readout_delay: f32,
+    acquire: AcqEvent,
+    spoiler: GradEvent<Trapezoid>,
+}
+
+struct GradMatrices {
+    readout: Matrix,
+    spoiler: Matrix,
+}
+
+impl Ute {
+
+    pub fn new(params: UteParams) -> Ute {
+        let events = Self::events(&params);
+        Self {
+            events,
+            params
+        }
+    }
+
+    /** Shortest echo time the rf and acquisition blocks allow */
+    pub fn min_echo_time(params: &UteParams) -> f32 {
+        let excitation = Self::excitation(params);
+        let acquire = Self::acquire(params);
+        let clocks = excitation.block_duration() - excitation.time_to_center() + MIN_DELAY_CLOCKS + acquire.time_to_start();
+        _utils::clock_to_sec(clocks)
+    }
+
+    /** Position of every sample of every spoke (read,phase,slice) in cycles per field of view */
+    pub fn k_coords(&self) -> Vec<[f32;3]> {
+        // every spoke has the radial profile of the first one
+        let traj = self.k_space_trajectory(0);
+        let radii:Vec<f32> = traj.readouts[0].k.iter()
+            .map(|k| 1E-3*self.params.fov*(k[0].powi(2) + k[1].powi(2) + k[2].powi(2)).sqrt()).collect();
+        (0..self.params.projections.n_projections()).flat_map(|view|{
+            let dir = self.params.projections.direction(view);
+            radii.iter().map(move |r| dir.map(|d| r*d)).collect::<Vec<[f32;3]>>()
+        }).collect()
+    }
+
+    fn excitation(params: &UteParams) -> RfEvent<Hardpulse> {
+        RfEvent::new(
+            "excitation",
+            1,
+            Hardpulse::new(params.rf_duration),
+            RfStateType::Adjustable(400, None),
+            RfStateType::Static(0)
+        )
+    }
+
+    fn acquire(params: &UteParams) -> AcqEvent {
+        AcqEvent::new(
+            "acquire",
+            params.spectral_width.clone(),
+            params.n_read,
+            0,
+            RfStateType::Static(0)
+        )
+    }
+
+    fn gradient_matrices(params: &UteParams) -> GradMatrices {
+        let mat_count = Matrix::new_tracker();
+        let non_adjustable = (false, false, false);
+        let identity = LinTransform::new((None, None, None), (None, None, None));
+
+        /* READOUT */
+        // the ramp is sampled, so the spoke reaches the edge of k-space half a ramp after the plateau
+        let sample_time = params.spectral_width.sample_time(params.n_read);
+        if sample_time <= params.ramp_time {
+            panic!("readout ramp of {} s is longer than the sampling window of {} s",params.ramp_time,sample_time);
+        }
+        let grad = params.n_read as f32/(params.fov*(sample_time - params.ramp_time/2.0));
+        let read_dac = grad_cal::grad_to_dac(grad);
+        let driver = MatrixDriver::new(DriverVar::Repetition, MatrixDriverType::Projection(params.projections.clone()), None);
+        let readout = Matrix::new_driven(
+            "read_mat",
+            driver,
+            identity,
+            DacValues::new(Some(read_dac), None, None),
+            non_adjustable,
+            params.grad_off,
+            &mat_count
+        );
+
+        /* SPOILER */
+        // continues along the spoke
+        let spoiler = readout.derive("spoil_mat", identity, non_adjustable, params.grad_off, &mat_count);
+
+        GradMatrices {
+            readout,
+            spoiler
+        }
+    }
+
+    fn events(params: &UteParams) -> UteEvents {
+        let m = Self::gradient_matrices(params);
+        let excitation = Self::excitation(params);
+        let acquire = Self::acquire(params);
+
+        let min_te = Self::min_echo_time(params);
+        if params.echo_time < min_te {
+            panic!("echo time of {} s is too short. The minimum is {} s",params.echo_time,min_te);
+        }
+
+        let sample_time = params.spectral_width.sample_time(params.n_read);
+        let plateau = sample_time - params.ramp_time;
+        let readout_frame = |delay:f32| Some(DelayedTrapezoid::new(delay, params.ramp_time, plateau));
+        let readout_event = |delay:f32| GradEvent::new(
+            (readout_frame(delay), readout_frame(delay), readout_frame(delay)),
+            &m.readout,
+            GradEventType::NonBlocking,
+            "readout"
+        );
+
+        // the readout block ends just before the rf block, and its ramp starts at the echo time
+        let gradient_start = -excitation.time_to_center() - MIN_DELAY_CLOCKS - readout_event(0.0).block_duration() + readout_event(0.0).time_to_start();
+        let readout_delay = _utils::clock_to_sec(_utils::sec_to_clock(params.echo_time) - gradient_start);
+        let readout = readout_event(readout_delay);
+
+        let spoil = Trapezoid::new(params.ramp_time, params.spoil_duration);
+        let spoiler = GradEvent::new(
+            (Some(spoil), Some(spoil), Some(spoil)),
+            &m.spoiler,
+            GradEventType::Blocking,
+            "spoiler"
+        );
+
+        UteEvents {
+            excitation,
+            readout,
+            readout_delay,
+            acquire,
+            spoiler
+        }
+    }
+
+    fn place_events(&self) -> EventQueue {
+        let step = self.base_params().waveform_sample_period_us;
+        let excitation = Event::new(self.events.excitation.as_reference(), Origin);
+        // only the readout block has to fit before the rf block. The waveform plays through it at zero
+        let readout_center = excitation.borrow().block_start() - MIN_DELAY_CLOCKS - self.events.readout.block_duration() + self.events.readout.time_to_center();
+        let readout = Event::new(self.events.readout.as_reference(), ExactFromOrigin(readout_center));
+
+        // sampling starts with the first rendered sample of the ramp
+        let delay_samples = _utils::sec_to_samples(self.events.readout_delay, step);
+        let sample_start = readout.borrow().waveform_start() + _utils::us_to_clock((delay_samples*step) as i32);
+        let acq_center = sample_start - self.events.acquire.time_to_start() + self.events.acquire.time_to_center();
+        let acquire = Event::new(self.events.acquire.as_reference(), ExactFromOrigin(acq_center));
+        let spoiler = Event::new(self.events.spoiler.as_reference(), After(acquire.clone(), 0));
+
+        EventQueue::new(
+            &vec![excitation, readout, acquire, spoiler]
+        )
+    }
+}
+
+#[test]
+fn test(){
+    let mut params = UteParams::default();
+    params.projections = ProjectionStrategy::GoldenAngle3D(16);
+    let ute = Ute::new(params.clone());
+    assert!(Ute::min_echo_time(&params) <= params.echo_time);
+
+    // spokes start at the center of k-space and run out along the projection direction
+    let k_max = 1E3*params.n_read as f32/params.fov;
+    for view in [0,5] {
+        let traj = ute.k_space_trajectory(view);
+        let k = &traj.readouts[0].k;
+        let (first,last) = (k[0],k[k.len()-1]);
+        assert!(first.iter().map(|k| k.abs()).sum::<f32>() < 0.02*k_max,"first sample at {:?}",first);
+        let dir = params.projections.direction(view as usize);
+        let r = (last[0].powi(2) + last[1].powi(2) + last[2].powi(2)).sqrt();
+        let cos = (0..3).map(|c| last[c]*dir[c]).sum::<f32>()/r;
+        assert!(cos > 0.999,"spoke {} is off its projection direction",view);
+        assert!((r - k_max).abs() < 0.05*k_max,"spoke {} reaches {} cycles/m, expected {}",view,r,k_max);
+    }
+    assert_eq!(ute.lut().unwrap().len(),3*16);
+    let coords = ute.k_coords();
+    assert_eq!(coords.len(),16*params.n_read as usize);
+    let r_max = coords.iter().map(|k| (k[0].powi(2) + k[1].powi(2) + k[2].powi(2)).sqrt()).fold(0.0,f32::max);
+    assert!((r_max - params.n_read as f32).abs() < 0.05*params.n_read as f32);
+    // every sample lands inside the image matrix the gridding recon is given
+    let mtk = params.mrd_to_kspace_params();
+    let half = [mtk.n_phase1,mtk.n_phase1,mtk.n_phase2].map(|n| n as f32/2.0);
+    assert!(coords.iter().all(|k| (0..3).all(|c| k[c].abs() <= half[c])));
+}
@@ -0,0 +1,132 @@
+/*
+    Parameter validation for pulse sequences. A sequence is checked by placing its events without
+    exporting anything, so an impossible parameter set is reported as the parameter at fault, the
+    constraint it breaks and the closest value that satisfies it instead of a panic during ppl export
+    or a pulse program that fails on the console.
+
+    Timing parameters that push events into each other are reported with the smallest larger value
+    that places every event without overlap, found by the timing solver.
+ */
+
+use std::fmt;
+use seq_tools::_utils;
+use crate::pulse_sequence::Build;
+use crate::timing::{self, PlacementError};
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Constraint {
+    // why events can't be placed
+    Placement(PlacementError),
+    // the events, loop execution and calculation don't fit in the rep time
+    RepTime,
+    // a sequence specific lower bound, with a description of what sets it
+    Minimum(String),
+}
+
+#[derive(Clone,Debug)]
+pub struct ParamError {
+    pub param:String,
+    pub constraint:Constraint,
+    pub value:f32,
+    // closest value that satisfies the constraint, if one was found
+    pub nearest:Option<f32>,
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let constraint = match &self.constraint {
+            Constraint::Placement(PlacementError::Overlap(first,second)) => format!("{} overlaps {}",first,second),
+            Constraint::Placement(PlacementError::Order(first,second)) => format!("{} is placed before {}",first,second),
+            Constraint::Placement(PlacementError::Unbuildable) => String::from("events can't be placed"),
+            Constraint::RepTime => String::from("events don't fit in the rep time"),
+            Constraint::Minimum(reason) => reason.clone(),
+        };
+        match self.nearest {
+            Some(nearest) => write!(f,"{} = {}: {}. Nearest feasible value is {}",self.param,self.value,constraint,nearest),
+            None => write!(f,"{} = {}: {}. No feasible value was found",self.param,self.value,constraint),
+        }
+    }
+}
+
+/** Collects the errors that were found */
+pub fn collect(errors:Vec<Option<ParamError>>) -> Result<(),Vec<ParamError>> {
+    let errors:Vec<ParamError> = errors.into_iter().flatten().collect();
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors)
+    }
+}
+
+/** Error if value is below a minimum set by the sequence */
+pub fn minimum(param:&str,value:f32,min:f32,reason:&str) -> Option<ParamError> {
+    match value < min {
+        true => Some(ParamError {
+            param:param.to_string(),
+            constraint:Constraint::Minimum(reason.to_string()),
+            value,
+            nearest:Some(min)
+        }),
+        false => None
+    }
+}
+
+/** Error if the events of the sequence built with the value of a timing parameter can't be placed.
+The nearest value is the smallest larger value that places every event in the order they have with
+the design value */
+pub fn placement<F>(param:&str,value:f32,design:f32,sequence:F) -> Option<ParamError>
+where F:Fn(f32) -> Option<Box<dyn Build>>
+{
+    let reference = timing::reference_order(&sequence,&[design]);
+    let constraint = match timing::check_placement(&sequence,value,reference.as_deref()) {
+        Ok(_) => return None,
+        Err(e) => Constraint::Placement(e)
+    };
+    Some(ParamError {
+        param:param.to_string(),
+        constraint,
+        value,
+        nearest:timing::min_placeable(&sequence,value,&[design])
+    })
+}
+
+/** Error if the rep time of the sequence is too short for its events. The sequence is expected to
+have no overlapping events */
+pub fn rep_time(sequence:&dyn Build) -> Option<ParamError> {
+    let rep_time = sequence.base_params().rep_time;
+    let slices = sequence.n_slices();
+    let min_clocks = timing::min_rep_time_clocks(sequence);
+    // the rep time is split evenly between slices and truncated to the microsecond
+    let per_slice = _utils::sec_to_clock(rep_time/slices as f32);
+    match per_slice*(slices as i32) < min_clocks {
+        true => Some(ParamError {
+            param:String::from("rep_time"),
+            constraint:Constraint::RepTime,
+            value:rep_time,
+            nearest:Some(timing::min_rep_time(sequence))
+        }),
+        false => None
+    }
+}
+
+#[test]
+fn test(){
+    use crate::pulse_sequence::{Initialize, SequenceParameters};
+    use crate::gre::GreParams;
+    let with = |field:&str,value:f32| -> GreParams {
+        let mut json = serde_json::to_value(GreParams::default()).unwrap();
+        json[field] = serde_json::json!(value);
+        serde_json::from_value(json).unwrap()
+    };
+    assert!(GreParams::default().validate().is_ok());
+
+    let errors = with("echo_time",0.5E-3).validate().unwrap_err();
+    println!("{}",errors[0]);
+    assert_eq!(errors[0].param,"echo_time");
+    assert!(matches!(errors[0].constraint,Constraint::Placement(_)));
+    assert!(with("echo_time",errors[0].nearest.unwrap()).validate().is_ok());
+
+    let errors = with("rep_time",1E-3).validate().unwrap_err();
+    println!("{}",errors[0]);
+    assert_eq!(errors[0].constraint,Constraint::RepTime);
+    assert!(with("rep_time",errors[0].nearest.unwrap()).validate().is_ok());
+}
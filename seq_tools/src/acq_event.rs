@@ -1,10 +1,10 @@
 use crate::execution::{BlockExecution, ExecutionBlock, PlotTrace, WaveformData, EventType};
 use crate::command_string::CommandString;
-use crate::rf_state::{RfState, RfStateType};
+use crate::rf_state::{RfState, RfStateError, RfStateType};
 use crate::{ppl_function, _utils};
 use crate::pulse_function::{Function,FunctionParams};
 use crate::ppl::Adjustment;
-use crate::grad_cal;
+use crate::scanner::{ReceiverBandwidth, ScannerProfile};
 use crate::pulse::Trapezoid;
 use crate::gradient_event::GradEvent;
 use std::cell::RefCell;
@@ -33,39 +33,52 @@ pub enum SpectralWidth {
     SW200kH,
     SW133kH,
     SW80kH,
+    // any other bandwidth in the receiver table of the scanner profile (hz)
+    Custom(i32),
 }
 
 impl SpectralWidth {
+    /** Uses the named variants where they exist so that parameter files stay readable */
+    pub fn from_hertz(hertz:i32) -> Self {
+        [SpectralWidth::SW200kH,SpectralWidth::SW133kH,SpectralWidth::SW100kH,SpectralWidth::SW80kH]
+            .into_iter().find(|sw| sw.hertz() == hertz).unwrap_or(SpectralWidth::Custom(hertz))
+    }
     pub fn hertz(&self) -> i32 {
         match self {
             SpectralWidth::SW100kH => 100_000,
             SpectralWidth::SW200kH => 200_000,
             SpectralWidth::SW133kH => 133_333,
-            SpectralWidth::SW80kH => 80_000
+            SpectralWidth::SW80kH => 80_000,
+            SpectralWidth::Custom(hertz) => *hertz
         }
     }
+    /** Digitizer setting in the receiver table of the active scanner profile */
+    pub fn receiver_bandwidth(&self) -> &'static ReceiverBandwidth {
+        self.receiver_bandwidth_for(ScannerProfile::active())
+    }
+    pub fn receiver_bandwidth_for<'a>(&self,scanner:&'a ScannerProfile) -> &'a ReceiverBandwidth {
+        scanner.receiver_bandwidth(self.hertz())
+    }
+    pub fn sample_period_clocks(&self) -> i32 {
+        self.receiver_bandwidth().sample_period_clocks
+    }
     // delay required after sampling is complete, but before control is returned
     pub fn post_delay(&self) -> i32 {
-        let sample_rate = self.hertz();
-        // delay is longer for sample rates less than or equal to 100kH
-        if sample_rate <= 100_000 {3582} else {3582}
+        self.receiver_bandwidth().post_delay_clocks
     }
     pub fn fov_to_dac(&self,fov_mm:f32) -> i16 {
-        let grad_hz_per_mm = self.hertz() as f32/fov_mm;
-        grad_cal::grad_to_dac(grad_hz_per_mm)
+        self.fov_to_dac_for(fov_mm,ScannerProfile::active())
+    }
+    pub fn fov_to_dac_for(&self,fov_mm:f32,scanner:&ScannerProfile) -> i16 {
+        // the digitizer rate can differ slightly from the nominal bandwidth
+        let grad_hz_per_mm = self.receiver_bandwidth_for(scanner).sample_rate()/fov_mm;
+        scanner.grad_to_dac(grad_hz_per_mm)
     }
     pub fn sample_time(&self,n_samples:u16) -> f32 {
-        let sample_period = 1.0/self.hertz() as f32;
-        sample_period*n_samples as f32
+        _utils::clock_to_sec(self.sample_period_clocks())*n_samples as f32
     }
-
     pub fn ppr_string(&self) -> String {
-        match self {
-            SpectralWidth::SW200kH => format!("{}, {}, \"200  KHz   5 µs\"",50,25),
-            SpectralWidth::SW100kH => format!("{}, {}, \"100  KHz  10 µs\"",100,23),
-            SpectralWidth::SW133kH => format!("{}, {}, \"133  KHz 7.5 µs\"",75,24),
-            SpectralWidth::SW80kH => format!("{}, {}, \"80.0 KHz 12.5 µs\"",125,22),
-        }
+        self.receiver_bandwidth().ppr_string()
     }
 }
 
@@ -101,7 +114,7 @@ impl AcqEvent{
         SAMPLE_PERIOD_VAR.to_string()
     }
     pub fn sample_period_clocks(&self) -> i32 {
-        10_000_000/self.sample_rate.hertz()
+        self.sample_rate.sample_period_clocks()
     }
     pub fn sample_time_clocks(&self) -> i32 {
         // /sec_to_clock(self.sample_rate.sample_time(self.n_samples()+self.n_discards()))
@@ -186,6 +199,9 @@ impl ExecutionBlock for AcqEvent {
     fn render_magnitude(&self, time_step_us: usize, driver_value: u32) -> WaveformData {
         self.render_normalized(time_step_us)
     }
+    fn phase_value(&self, driver_value: u32) -> Result<Option<i16>,RfStateError> {
+        self.phase_state.phase_value(driver_value).map(Some)
+    }
 }
 
 // #[test]
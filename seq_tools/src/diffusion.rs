@@ -123,7 +123,7 @@ fn find_bvalue(diff_pulse:&Box<dyn Pulse>,pulse1_center:f32,pulse2_center:f32,ec
 }
 
 
-fn resample(t:&Vec<f32>,x:&Vec<f32>,tq:&Vec<f32>) -> Vec<f32> {
+pub fn resample(t:&Vec<f32>,x:&Vec<f32>,tq:&Vec<f32>) -> Vec<f32> {
     let xq:Vec<f32> = tq.iter().map(|t_val| interp1(&t,&x,*t_val)).collect();
     xq
 }
@@ -0,0 +1,376 @@
+/*
+    Echo-planar readout. A single gradient event plays a train of bipolar readout lobes with triangular
+    phase encode blips between them, and a single acquisition samples the whole train, ramps included.
+    Navigator lobes without blips are played first for Nyquist ghost correction. They are followed by
+    a gap in which the phase encode is prewound to the edge of k-space. All timing is kept on a raster
+    common to the digitizer dwell and the gradient waveform sample period, so every lobe holds the same
+    number of samples. The read prewinder is left to the sequence (see read_prewind_area).
+ */
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use crate::_utils;
+use crate::acq_event::{AcqEvent, SpectralWidth};
+use crate::event_block::GradEventType;
+use crate::execution::ExecutionBlock;
+use crate::gradient_event::GradEvent;
+use crate::gradient_frame::GradFrame;
+use crate::gradient_matrix::{DacValues, Matrix};
+use crate::moments;
+use crate::ppl_function::MIN_DELAY_CLOCKS;
+use crate::pulse::Pulse;
+use crate::pulse_function::{Function, FunctionParams};
+use crate::rf_state::RfStateType;
+use crate::scanner::ScannerProfile;
+
+/** Piece of an epi gradient waveform with a normalized value and a duration in clock ticks */
+#[derive(Clone,Copy,Debug)]
+pub enum EpiSegment {
+    Up(f32,i32),
+    Down(f32,i32),
+    Flat(f32,i32),
+}
+
+impl EpiSegment {
+    fn clocks(&self) -> i32 {
+        match self {
+            EpiSegment::Up(_,c) | EpiSegment::Down(_,c) | EpiSegment::Flat(_,c) => *c
+        }
+    }
+    fn area(&self) -> f32 {
+        let t = _utils::clock_to_sec(self.clocks());
+        match self {
+            EpiSegment::Up(v,_) | EpiSegment::Down(v,_) => v*t/2.0,
+            EpiSegment::Flat(v,_) => v*t
+        }
+    }
+}
+
+/** Gradient waveform of one channel of an epi train. The read and phase channels share this type so
+they can be played from one gradient event */
+#[derive(Clone,Debug)]
+pub struct EpiTrain {
+    segments:Vec<EpiSegment>
+}
+
+impl EpiTrain {
+    pub fn new(segments:Vec<EpiSegment>) -> Self {
+        Self {
+            segments:segments.into_iter().filter(|s| s.clocks() > 0).collect()
+        }
+    }
+    pub fn duration_clocks(&self) -> i32 {
+        self.segments.iter().map(|s| s.clocks()).sum()
+    }
+    fn area(&self) -> f32 {
+        self.segments.iter().map(|s| s.area()).sum()
+    }
+}
+
+impl Pulse for EpiTrain {
+    fn duration(&self) -> f32 {
+        _utils::clock_to_sec(self.duration_clocks())
+    }
+    fn function(&self, time_step_us: usize) -> Vec<Function> {
+        let step_clocks = _utils::us_to_clock(time_step_us as i32);
+        self.segments.iter().map(|s|{
+            let n = (s.clocks() as f32/step_clocks as f32).round() as usize;
+            match s {
+                EpiSegment::Up(v,_) => Function::RampUp(FunctionParams::new(n,*v)),
+                EpiSegment::Down(v,_) => Function::RampDown(FunctionParams::new(n,*v)),
+                EpiSegment::Flat(v,_) => Function::Plateau(FunctionParams::new(n,*v)),
+            }
+        }).collect()
+    }
+    fn power_net(&self, magnitude: f32) -> f32 {
+        self.area()*magnitude
+    }
+    fn magnitude_net(&self, power_net: f32) -> f32 {
+        power_net/self.area()
+    }
+    fn power_abs(&self, magnitude: f32) -> f32 {
+        self.segments.iter().map(|s| s.area().abs()).sum::<f32>()*magnitude.abs()
+    }
+}
+
+impl GradFrame for EpiTrain {}
+
+#[derive(Clone,Serialize,Deserialize)]
+pub struct EpiReadout {
+    pub spectral_width:SpectralWidth,
+    pub n_read:u16,
+    pub n_echoes:u16,
+    pub n_navigators:u16,
+    pub ramp_sampling:bool,
+    // all durations are in clock ticks
+    pub lead_in_clocks:i32,
+    pub ramp_clocks:i32,
+    pub flat_clocks:i32,
+    pub prewind_ramp_clocks:i32,
+    pub prewind_plateau_clocks:i32,
+    pub read_dac:i16,
+    pub phase_dac:i16,
+    // normalized amplitudes on the phase channel
+    pub blip_value:f32,
+    pub prewind_value:f32,
+}
+
+impl EpiReadout {
+    /** Fastest train for the field of view and matrix within the gradient limits of the active scanner
+    profile. With ramp sampling the flat top is shortened by one ramp since the ramps contribute k-space */
+    pub fn new(fov_read_mm:f32,fov_phase_mm:f32,n_read:u16,n_echoes:u16,n_navigators:u16,spectral_width:SpectralWidth,ramp_sampling:bool,time_step_us:usize) -> Self {
+        let scanner = ScannerProfile::active();
+        let limits = &scanner.grad_limits;
+        let dwell = spectral_width.receiver_bandwidth_for(scanner).sample_period_clocks;
+        let step = _utils::us_to_clock(time_step_us as i32);
+        let raster = dwell/gcd(dwell,step)*step;
+        let round_up = |clocks:f32| ((clocks/raster as f32 - 1E-3).ceil().max(1.0) as i32)*raster;
+
+        // the acquisition block can only start once the gradient block has returned control, so
+        // sampling starts a lead-in after the start of the waveform
+        let lead_in_clocks = round_up(acq_lead_in() as f32);
+
+        let read_amp = spectral_width.receiver_bandwidth_for(scanner).sample_rate()/fov_read_mm;
+        let read_dac = scanner.grad_to_dac(read_amp);
+        let ramp_clocks = round_up(read_amp/limits.max_slew_hz_per_mm_per_us*10.0);
+        let flat_clocks = match ramp_sampling {
+            true => round_up((n_read as i32*dwell - ramp_clocks) as f32),
+            false => round_up((n_read as i32*dwell) as f32)
+        };
+
+        // blips are triangles over the ramp down and ramp up between lobes
+        let blip_area = 1.0/fov_phase_mm;
+        let blip_amp = blip_area/_utils::clock_to_sec(ramp_clocks);
+        if blip_amp > limits.max_amplitude_hz_per_mm {
+            panic!("phase encode blips of {} hz/mm exceed the gradient limit. Increase the phase field of view",blip_amp)
+        }
+        // move to the first line of k-space such that echo n_echoes/2 is at the center
+        let prewind_area = -((n_echoes/2) as f32)*blip_area;
+        let prewind = moments::solve_trapezoid(prewind_area,scanner).trapezoid;
+        let prewind_ramp_clocks = round_up(prewind.ramp_time*1E7);
+        let prewind_plateau_clocks = if prewind.plateau_time > 0.0 {round_up(prewind.plateau_time*1E7)} else {0};
+        let prewind_amp = prewind_area.abs()/_utils::clock_to_sec(prewind_ramp_clocks + prewind_plateau_clocks);
+
+        let peak = blip_amp.max(prewind_amp);
+        Self {
+            spectral_width,
+            n_read,
+            n_echoes,
+            n_navigators,
+            ramp_sampling,
+            lead_in_clocks,
+            ramp_clocks,
+            flat_clocks,
+            prewind_ramp_clocks,
+            prewind_plateau_clocks,
+            read_dac,
+            phase_dac:scanner.grad_to_dac(peak),
+            blip_value:blip_amp/peak,
+            prewind_value:-prewind_amp/peak,
+        }
+    }
+    pub fn lobe_clocks(&self) -> i32 {
+        2*self.ramp_clocks + self.flat_clocks
+    }
+    pub fn gap_clocks(&self) -> i32 {
+        2*self.prewind_ramp_clocks + self.prewind_plateau_clocks
+    }
+    pub fn duration_clocks(&self) -> i32 {
+        self.lead_in_clocks + self.sampled_clocks()
+    }
+    // time the acquisition samples for
+    fn sampled_clocks(&self) -> i32 {
+        (self.n_navigators + self.n_echoes) as i32*self.lobe_clocks() + self.gap_clocks()
+    }
+    pub fn echo_spacing(&self) -> f32 {
+        _utils::clock_to_sec(self.lobe_clocks())
+    }
+    /** Time from the start of the train to the center of k-space */
+    pub fn time_to_k_center(&self) -> f32 {
+        let lobes = (self.n_navigators + self.n_echoes/2) as i32;
+        _utils::clock_to_sec(self.lead_in_clocks + self.gap_clocks() + lobes*self.lobe_clocks() + self.lobe_clocks()/2)
+    }
+    /** Center of the acquisition event for a train whose waveform starts at train_waveform_start */
+    pub fn acq_center(&self,train_waveform_start:i32) -> i32 {
+        let sample_clocks = self.n_samples() as i32*self.spectral_width.sample_period_clocks();
+        train_waveform_start + self.lead_in_clocks + sample_clocks/2
+    }
+    pub fn n_samples(&self) -> u16 {
+        let n = self.sampled_clocks()/self.spectral_width.sample_period_clocks();
+        if n > u16::MAX as i32 {panic!("epi train needs {} samples, which is more than a single acquisition can take",n)}
+        n as u16
+    }
+    /** Read prewinder area (hz*s/mm) that puts the start of the first lobe at the edge of k-space */
+    pub fn read_prewind_area(&self) -> f32 {
+        let amp = ScannerProfile::active().dac_to_hz_per_mm(self.read_dac);
+        -amp*_utils::clock_to_sec(self.ramp_clocks + self.flat_clocks)/2.0
+    }
+    fn lobe(&self,lobe_index:u16) -> Vec<EpiSegment> {
+        let v = if lobe_index.is_multiple_of(2) {1.0} else {-1.0};
+        vec![EpiSegment::Up(v,self.ramp_clocks),EpiSegment::Flat(v,self.flat_clocks),EpiSegment::Down(v,self.ramp_clocks)]
+    }
+    pub fn read_frame(&self) -> EpiTrain {
+        let mut s = vec![EpiSegment::Flat(0.0,self.lead_in_clocks)];
+        (0..self.n_navigators).for_each(|i| s.extend(self.lobe(i)));
+        s.push(EpiSegment::Flat(0.0,self.gap_clocks()));
+        (self.n_navigators..self.n_navigators + self.n_echoes).for_each(|i| s.extend(self.lobe(i)));
+        EpiTrain::new(s)
+    }
+    pub fn phase_frame(&self) -> EpiTrain {
+        let p = self.prewind_value;
+        let b = self.blip_value;
+        let mut s = vec![
+            EpiSegment::Flat(0.0,self.lead_in_clocks + self.n_navigators as i32*self.lobe_clocks()),
+            EpiSegment::Up(p,self.prewind_ramp_clocks),
+            EpiSegment::Flat(p,self.prewind_plateau_clocks),
+            EpiSegment::Down(p,self.prewind_ramp_clocks),
+            EpiSegment::Flat(0.0,self.ramp_clocks + self.flat_clocks),
+        ];
+        for _ in 1..self.n_echoes {
+            s.extend([EpiSegment::Up(b,self.ramp_clocks),EpiSegment::Down(b,self.ramp_clocks),EpiSegment::Flat(0.0,self.flat_clocks)]);
+        }
+        s.push(EpiSegment::Flat(0.0,self.ramp_clocks));
+        EpiTrain::new(s)
+    }
+    /** Gradient event of the train. The acquisition from acq_event is placed with acq_center */
+    pub fn grad_event(&self,label:&str,uid_tracker:&Rc<RefCell<u8>>) -> GradEvent<EpiTrain> {
+        let matrix = Matrix::new_static(&format!("{}_mat",label),DacValues::new(Some(self.read_dac),Some(self.phase_dac),None),(false,false,false),false,uid_tracker);
+        GradEvent::new((Some(self.read_frame()),Some(self.phase_frame()),None),&matrix,GradEventType::NonBlocking,label)
+    }
+    pub fn acq_event(&self,label:&str,phase:RfStateType) -> AcqEvent {
+        AcqEvent::new(label,self.spectral_width.clone(),self.n_samples(),0,phase)
+    }
+    pub fn recon_params(&self) -> EpiReconParams {
+        let dwell = self.spectral_width.sample_period_clocks();
+        EpiReconParams {
+            n_read:self.n_read as usize,
+            n_echoes:self.n_echoes as usize,
+            n_navigators:self.n_navigators as usize,
+            ramp_samples:(self.ramp_clocks/dwell) as usize,
+            flat_samples:(self.flat_clocks/dwell) as usize,
+            gap_samples:(self.gap_clocks()/dwell) as usize,
+        }
+    }
+}
+
+/** Layout of the samples of an epi acquisition needed to build k-space from it. Sample counts are per
+lobe ramp, per lobe flat top and for the phase prewind gap after the navigators */
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct EpiReconParams {
+    pub n_read:usize,
+    pub n_echoes:usize,
+    pub n_navigators:usize,
+    pub ramp_samples:usize,
+    pub flat_samples:usize,
+    pub gap_samples:usize,
+}
+
+impl EpiReconParams {
+    pub fn samples_per_lobe(&self) -> usize {
+        2*self.ramp_samples + self.flat_samples
+    }
+    pub fn n_samples(&self) -> usize {
+        (self.n_navigators + self.n_echoes)*self.samples_per_lobe() + self.gap_samples
+    }
+    /** Offset of the first sample of a lobe in the acquisition. Navigators come first */
+    pub fn lobe_offset(&self,lobe_index:usize) -> usize {
+        let gap = if lobe_index >= self.n_navigators {self.gap_samples} else {0};
+        lobe_index*self.samples_per_lobe() + gap
+    }
+    /** K-space position of each sample of a positive lobe, in units of the k-space step of the flat top */
+    pub fn lobe_k(&self) -> Vec<f32> {
+        let (r,f) = (self.ramp_samples as f32,self.flat_samples as f32);
+        (0..self.samples_per_lobe()).map(|i|{
+            let t = i as f32;
+            if r == 0.0 {return t}
+            match t {
+                t if t < r => t*t/(2.0*r),
+                t if t < r + f => r/2.0 + t - r,
+                t => {
+                    let d = t - r - f;
+                    r/2.0 + f + d - d*d/(2.0*r)
+                }
+            }
+        }).collect()
+    }
+    pub fn to_file(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn from_file(file_path:&Path) -> Self {
+        let mut f = File::open(file_path).unwrap_or_else(|_| panic!("cannot open file {:?}",file_path));
+        let mut in_str = String::new();
+        f.read_to_string(&mut in_str).expect("trouble reading file");
+        serde_json::from_str(&in_str).expect("cannot deserialize struct")
+    }
+}
+
+// clocks from the start of a two channel gradient waveform to the earliest acquisition sample, allowing
+// for the shortest delay between blocks
+fn acq_lead_in() -> i32 {
+    let tracker = Matrix::new_tracker();
+    let matrix = Matrix::new_static("lead_in",DacValues::new(Some(0),Some(0),None),(false,false,false),false,&tracker);
+    let frame = EpiTrain::new(vec![]);
+    let grad = GradEvent::new((Some(frame.clone()),Some(frame),None),&matrix,GradEventType::NonBlocking,"lead_in");
+    let acq = AcqEvent::new("lead_in",SpectralWidth::SW100kH,8,0,RfStateType::Static(0));
+    grad.block_duration() - grad.time_to_start() + MIN_DELAY_CLOCKS + acq.time_to_start()
+}
+
+fn gcd(a:i32,b:i32) -> i32 {
+    if b == 0 {a} else {gcd(b,a%b)}
+}
+
+#[test]
+fn test(){
+    use crate::event_block::{Event, EventPlacementType, EventQueue};
+    use crate::moments::GradientGrid;
+    use crate::ppl::{GradClock, Orientation, PhaseUnit, PPL};
+    use crate::pulse::Hardpulse;
+    use crate::rf_event::RfEvent;
+    use crate::trajectory::KSpaceTrajectory;
+
+    let (fov,n) = (25.6,64);
+    let epi = EpiReadout::new(fov,fov,n,n,3,SpectralWidth::SW200kH,true,2);
+    let dwell = SpectralWidth::SW200kH.sample_period_clocks();
+    assert_eq!(epi.lobe_clocks()%dwell,0);
+    assert_eq!(epi.read_frame().duration_clocks(),epi.duration_clocks());
+    assert_eq!(epi.phase_frame().duration_clocks(),epi.duration_clocks());
+    let recon = epi.recon_params();
+    assert_eq!(recon.n_samples(),epi.n_samples() as usize);
+    assert_eq!(epi.lead_in_clocks%dwell,0);
+    // ramp samples contribute to the lobe area
+    let k = recon.lobe_k();
+    assert!(k[k.len()-1] >= n as f32 - 1.0);
+
+    // the train plays inside an event queue and gives a ppl
+    let tracker = Matrix::new_tracker();
+    let excitation = RfEvent::new("excitation",1,Hardpulse::new(100E-6),RfStateType::Static(500),RfStateType::Static(0));
+    let pre = moments::solve_trapezoid(epi.read_prewind_area(),ScannerProfile::active());
+    let pre_matrix = Matrix::new_static("pre_mat",DacValues::new(Some(pre.dac),None,None),(false,false,false),false,&tracker);
+    let prewind = GradEvent::new((Some(pre.trapezoid),None,None),&pre_matrix,GradEventType::Blocking,"prewind");
+    let ex = Event::new(excitation.as_reference(),EventPlacementType::Origin);
+    let pw = Event::new(prewind.as_reference(),EventPlacementType::After(ex.clone(),0));
+    let train = Event::new(epi.grad_event("epi",&tracker).as_reference(),EventPlacementType::After(pw.clone(),0));
+    let acq = Event::new(epi.acq_event("acq",RfStateType::Static(0)).as_reference(),EventPlacementType::ExactFromOrigin(epi.acq_center(train.borrow().waveform_start())));
+    let mut q = EventQueue::new(&vec![ex,pw,train.clone(),acq]);
+
+    // the echo at n/2 crosses the center of k-space in both directions
+    let traj = KSpaceTrajectory::new(&q,0,2);
+    let samples = &traj.readouts[0].k;
+    let dk = 1.0/(fov*1E-3);
+    let center = recon.lobe_offset(recon.n_navigators + n as usize/2) + recon.samples_per_lobe()/2;
+    assert!(samples[center][0].abs() < 2.0*dk,"kx at echo center = {}",samples[center][0]);
+    assert!(samples[center][1].abs() < 2.0*dk,"ky at echo center = {}",samples[center][1]);
+    // the grid of the trajectory agrees with the train's own timing
+    let grid = GradientGrid::new(&q,0,2);
+    assert!(grid.g.len() as f32*grid.dt >= epi.duration_clocks() as f32*1E-7);
+
+    let ppl = PPL::new(&mut q,1,1,1,100E-3,0.0,ScannerProfile::active(),"","",Orientation::CivmStandard,GradClock::CPS20,PhaseUnit::Min,1,false);
+    assert!(ppl.print().contains("epi"));
+}
@@ -111,6 +111,12 @@ impl Event {
     pub fn center(&self) -> i32 {
         self.center
     }
+    pub fn waveform_start(&self) -> i32 {
+        self.block_start() + self.execution.time_to_start()
+    }
+    pub fn unique_label(&self) -> String {
+        self.unique_label.clone()
+    }
 }
 
 /** Queue of sequence events that will be executed in a loop */
@@ -161,6 +167,10 @@ impl EventQueue {
             }
         })
     }
+    /** Ordered references to the events in the queue */
+    pub fn events(&self) -> Vec<Rc<RefCell<Event>>> {
+        self.events.clone()
+    }
     /** Render out waveforms to a vector of EventGraph structures for writing to a file */
     pub fn graphs(&self,time_step_us:usize) -> Vec<EventGraph> {
         self.events.iter().map(|event| event.borrow().event_graph_normalized(time_step_us)).collect()
@@ -193,6 +203,26 @@ impl EventQueue {
         }
         self.events[last_event_idx].as_ref().borrow_mut().set_post_delay(makeup);
     }
+    /** Scale the power of every rf event. Used to bring starting rf powers to an rf calibration */
+    pub fn scale_rf_power(&self,scale:f32) {
+        self.events.iter().for_each(|event| event.as_ref().borrow_mut().execution.scale_rf_power(scale));
+    }
+    /** Neighboring events that run into each other, with the overlap in clock periods */
+    pub fn overlaps(&self) -> Vec<(String,String,i32)> {
+        self.events.windows(2).filter_map(|pair|{
+            let overlap = pair[0].borrow().block_end() - pair[1].borrow().block_start();
+            match overlap > 0 {
+                true => Some((pair[0].borrow().unique_label.clone(),pair[1].borrow().unique_label.clone(),overlap)),
+                false => None
+            }
+        }).collect()
+    }
+    /** Shortest rep time in clock periods that fits every event along with loop execution */
+    pub fn min_rep_time_clocks(&self,loop_time:i32,calc_time:i32) -> i32 {
+        let first_event_start = self.events[0].borrow().block_start();
+        let last_event_end = self.events[self.events.len()-1].borrow().block_end();
+        last_event_end - first_event_start + loop_time + calc_time
+    }
     /** Sets the rep time and all other event post-delay properties */
     pub fn set_rep_time(&mut self,rep_time:f32,loop_time:i32,calc_time:i32){
         self.set_post_delay();
@@ -231,8 +261,8 @@ impl EventQueue {
     // if there are 4 acq events, there is assumed to be 4 echos, so the view count needs to be incrementd
     // by 4 at the end of the loop. We also need to check the condition that the total number of view is a multple
     // of the number of echos in the view loop
-    pub fn flat_loop_structure(&mut self,repetitions:u32,averages:u16,rep_time:f32,acceleration:u16) -> FlatLoopStructure {
-        FlatLoopStructure::new(repetitions,averages,rep_time,self,acceleration)
+    pub fn flat_loop_structure(&mut self,repetitions:u32,averages:u16,slices:u16,rep_time:f32,acceleration:u16) -> FlatLoopStructure {
+        FlatLoopStructure::new(repetitions,averages,slices,rep_time,self,acceleration)
     }
     pub fn ppl_user_adjustments(&self) -> Option<Vec<Adjustment>> {
         let mut scrollbars = Vec::<Adjustment>::new();
@@ -1,7 +1,7 @@
 use std::cell::RefCell;
 use crate::acq_event::{AcqEvent, SpectralWidth};
 use crate::pulse::{CompositeHardpulse, Hardpulse, Trapezoid};
-use crate::rf_state::{RfDriver, RfDriverType, RfStateType};
+use crate::rf_state::{RfDriver, RfDriverType, RfStateError, RfStateType};
 use crate::rf_event::RfEvent;
 use crate::command_string::CommandString;
 use crate::gradient_event::GradEvent;
@@ -15,7 +15,7 @@ use std::path::Path;
 use std::process::Command;
 use std::rc::Rc;
 use crate::event_block::{Event, EventPlacementType, GradEventType, EventQueue};
-use crate::ppl::{FlatLoopStructure, CalcBlock, Header, DspRoutine, BaseFrequency, Adjustment, PPL, Orientation, GradClock, VIEW_LOOP_COUNTER_VAR, PhaseUnit};
+use crate::ppl::{FlatLoopStructure, CalcBlock, Header, DspRoutine, Adjustment, PPL, Orientation, GradClock, VIEW_LOOP_COUNTER_VAR, PhaseUnit};
 use crate::ppl_function::acquire;
 use crate::seqframe::SeqFrame;
 
@@ -26,6 +26,17 @@ pub enum EventType {
     Acq(SpectralWidth,u16,u16)
 }
 
+/** What an rf pulse does to the magnetization that forms the signal */
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum RfRole {
+    // tips longitudinal magnetization into the transverse plane
+    Excitation,
+    // refocuses transverse magnetization (k-space is conjugated)
+    Refocus,
+    // inversion or saturation before the excitation. its transverse signal is spoiled
+    Preparation,
+}
+
 #[derive(Clone,Debug,Serialize)]
 pub enum WaveformData {
     Rf(PlotTrace,PlotTrace),
@@ -113,4 +124,26 @@ pub trait ExecutionBlock {
     fn blocking(&self) -> bool;
     fn seq_params(&self,sample_period_us:usize) -> Option<String>;
     fn render_magnitude(&self,time_step_us:usize,driver_value:u32) -> WaveformData;
+    // rf or receiver phase of the block for a driver value (in phase units)
+    fn phase_value(&self,_driver_value:u32) -> Result<Option<i16>,RfStateError> {
+        Ok(None)
+    }
+    // gradient matrix dac values of the block for a driver value
+    fn grad_dac_values(&self,_driver_value:u32) -> Option<DacValues> {
+        None
+    }
+    // rf amplifier dac value of the block for a driver value
+    fn rf_dac_value(&self,_driver_value:u32) -> Option<i16> {
+        None
+    }
+    // absolute area of the rf pulse at some magnitude (magnitude*seconds)
+    fn rf_power_abs(&self,_magnitude:f32) -> Option<f32> {
+        None
+    }
+    // role of an rf block in forming the signal
+    fn rf_role(&self) -> Option<RfRole> {
+        None
+    }
+    // scale the rf amplifier dac of the block
+    fn scale_rf_power(&mut self,_scale:f32) {}
 }
\ No newline at end of file
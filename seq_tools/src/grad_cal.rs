@@ -1,6 +1,8 @@
 use std::f32::consts::PI;
+use crate::scanner::ScannerProfile;
 
-// these values must match the parfilio file values to output correct gradient strengths
+// civm 9.4T defaults. The values in use come from the active scanner profile, and
+// must match the parfilio file values to output correct gradient strengths
 pub const GRAD_MAX_READ:u32 = 101857;
 pub const GRAD_MAX_PHASE:u32 = 92456;
 pub const GRAD_MAX_SLICE:u32 = 112634;
@@ -8,17 +10,22 @@ pub const GAMMA_BAR:f32 = 42.58e6;
 pub const GAMMA:f32 = 2.0*PI*GAMMA_BAR;
 pub const GRAD_MIN:u32 = GRAD_MAX_PHASE;
 
+// conversions with the active profile, used while building sequence events. Code that is handed a
+// profile converts with the methods of that profile instead
+
 pub fn grad_to_dac(grad_hz_per_mm:f32) -> i16 {
-    let fraction = grad_hz_per_mm/GRAD_MIN as f32;
-    if fraction >= 1.0 {panic!("max gradient strength exceeded. {} hz/mm > {} hz/mm",grad_hz_per_mm,GRAD_MIN)}
-    let dac = i16::MAX as f32 * fraction;
-    dac as i16
+    ScannerProfile::active().grad_to_dac(grad_hz_per_mm)
 }
 
 // dac -> Hz/mm
 pub fn dac_to_grad(grad_dac:i16) -> u32 {
     let fraction = grad_dac as f32 / i16::MAX as f32;
-    (GRAD_MIN as f32 * fraction) as u32
+    (ScannerProfile::active().grad_min() as f32 * fraction) as u32
+}
+
+// dac -> Hz/mm (signed)
+pub fn dac_to_hz_per_mm(grad_dac:i16) -> f32 {
+    ScannerProfile::active().dac_to_hz_per_mm(grad_dac)
 }
 
 pub fn dac_to_hz_per_meter(grad_dac:i16) -> f32 {
@@ -0,0 +1,221 @@
+/*
+    Gradient hardware limits. Events in a placed event queue are checked against the peak amplitude,
+    slew rate and rms duty cycle that the gradient amplifiers can deliver. Every view of driven matrices
+    is considered because phase encoding gradients are only at full strength for the outer views.
+ */
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use serde_json;
+use crate::_utils;
+use crate::event_block::EventQueue;
+use crate::execution::{EventType, WaveformData, PlotTrace};
+use crate::grad_cal;
+use crate::gradient_event::Channel;
+use crate::scanner::ScannerProfile;
+
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct GradHardwareProfile {
+    // peak gradient strength per channel
+    pub max_amplitude_hz_per_mm:f32,
+    // peak rate of change of the gradient per channel
+    pub max_slew_hz_per_mm_per_us:f32,
+    // rms gradient strength over a repetition as a fraction of the max amplitude
+    pub max_rms_duty:f32,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum GradLimit {
+    Amplitude,
+    Slew,
+    RmsDuty,
+}
+
+#[derive(Clone,Debug)]
+pub struct GradLimitViolation {
+    pub limit:GradLimit,
+    // event label. For rms duty this is the event with the largest contribution
+    pub label:String,
+    pub view:u32,
+    pub channel:Channel,
+    pub value:f32,
+    pub max:f32,
+}
+
+// normalized waveform properties of a single channel of a gradient event
+#[derive(Clone,Copy)]
+struct ChannelStats {
+    peak:f32,
+    slew_per_us:f32,
+    energy_sec:f32,
+}
+
+impl ChannelStats {
+    fn from_trace(trace:&PlotTrace,time_step_us:usize) -> Self {
+        let peak = trace.y.iter().fold(0.0f32,|acc,y| acc.max(y.abs()));
+        let slew_per_us = trace.y.windows(2).fold(0.0f32,|acc,y| acc.max((y[1] - y[0]).abs()))/time_step_us as f32;
+        let energy_sec = trace.y.iter().map(|y| y*y).sum::<f32>()*_utils::us_to_sec(time_step_us as i32);
+        Self {
+            peak,
+            slew_per_us,
+            energy_sec
+        }
+    }
+}
+
+impl Channel {
+    fn index(&self) -> usize {
+        match self {
+            Channel::Read => 0,
+            Channel::Phase => 1,
+            Channel::Slice => 2,
+        }
+    }
+    fn name(&self) -> &str {
+        match self {
+            Channel::Read => "read",
+            Channel::Phase => "phase",
+            Channel::Slice => "slice",
+        }
+    }
+}
+
+impl fmt::Display for GradLimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.limit {
+            GradLimit::Amplitude => write!(f,"{} exceeds peak amplitude on {} channel for view {}: {:.1} hz/mm > {:.1} hz/mm",
+                                           self.label,self.channel.name(),self.view,self.value,self.max),
+            GradLimit::Slew => write!(f,"{} exceeds slew rate on {} channel for view {}: {:.1} hz/mm/us > {:.1} hz/mm/us",
+                                      self.label,self.channel.name(),self.view,self.value,self.max),
+            GradLimit::RmsDuty => write!(f,"rms duty cycle exceeded on {} channel for view {} (largest contributor is {}): {:.3} > {:.3}",
+                                         self.channel.name(),self.view,self.label,self.value,self.max),
+        }
+    }
+}
+
+impl GradHardwareProfile {
+    pub fn civm9p4t() -> Self {
+        Self {
+            max_amplitude_hz_per_mm:grad_cal::GRAD_MIN as f32,
+            // full scale in 100 us
+            max_slew_hz_per_mm_per_us:grad_cal::GRAD_MIN as f32/100.0,
+            max_rms_duty:0.5,
+        }
+    }
+    pub fn to_file(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn from_file(file_path:&Path) -> Self {
+        let mut f = File::open(file_path).unwrap_or_else(|_| panic!("cannot open file {:?}",file_path));
+        let mut in_str = String::new();
+        f.read_to_string(&mut in_str).expect("trouble reading file");
+        serde_json::from_str(&in_str).expect("cannot deserialize struct")
+    }
+}
+
+impl ScannerProfile {
+    /** Check every view of the event queue against the gradient limits of the profile, with dac values
+    converted by its calibration. An empty vector means the sequence is safe to run */
+    pub fn check_grad_limits(&self,event_queue:&EventQueue,n_repetitions:u32,view_acceleration:u16,rep_time:f32,time_step_us:usize) -> Vec<GradLimitViolation> {
+        let limits = &self.grad_limits;
+        let channels = [Channel::Read,Channel::Phase,Channel::Slice];
+        // waveform shapes don't change over views, so they are only rendered once
+        let grad_events:Vec<(String,[Option<ChannelStats>;3])> = event_queue.events().iter().filter_map(|event|{
+            let e = event.borrow();
+            if e.execution.kind() != EventType::Grad {return None}
+            match e.execution.render_normalized(time_step_us) {
+                WaveformData::Grad(r,p,s) => {
+                    let stats = [r,p,s].map(|trace| trace.map(|t| ChannelStats::from_trace(&t,time_step_us)));
+                    Some((e.unique_label(),stats))
+                }
+                _=> None
+            }
+        }).collect();
+        let max_rms = limits.max_rms_duty*limits.max_amplitude_hz_per_mm;
+        let mut violations = Vec::<GradLimitViolation>::new();
+        for rep in 0..n_repetitions {
+            let driver_val = rep*view_acceleration as u32;
+            // total energy and largest contributor for each channel
+            let mut energy = [0.0f32;3];
+            let mut loudest = [(0.0f32,String::new()),(0.0f32,String::new()),(0.0f32,String::new())];
+            for (event,(label,stats)) in event_queue.events().iter().filter(|e| e.borrow().execution.kind() == EventType::Grad).zip(grad_events.iter()) {
+                let dac = event.borrow().execution.grad_dac_values(driver_val).expect("gradient events must have dac values");
+                let dac = [dac.read,dac.phase,dac.slice];
+                for channel in channels {
+                    let c = channel.index();
+                    let stat = match stats[c] {
+                        Some(stat) => stat,
+                        None => continue
+                    };
+                    let hz_per_mm = self.dac_to_hz_per_mm(dac[c].unwrap_or(0)).abs();
+                    let peak = hz_per_mm*stat.peak;
+                    if peak > limits.max_amplitude_hz_per_mm {
+                        violations.push(GradLimitViolation{limit:GradLimit::Amplitude,label:label.clone(),view:driver_val,channel,value:peak,max:limits.max_amplitude_hz_per_mm});
+                    }
+                    let slew = hz_per_mm*stat.slew_per_us;
+                    if slew > limits.max_slew_hz_per_mm_per_us {
+                        violations.push(GradLimitViolation{limit:GradLimit::Slew,label:label.clone(),view:driver_val,channel,value:slew,max:limits.max_slew_hz_per_mm_per_us});
+                    }
+                    let e = hz_per_mm.powi(2)*stat.energy_sec;
+                    energy[c] += e;
+                    if e > loudest[c].0 {
+                        loudest[c] = (e,label.clone());
+                    }
+                }
+            }
+            for channel in channels {
+                let c = channel.index();
+                let rms = (energy[c]/rep_time).sqrt();
+                if rms > max_rms {
+                    violations.push(GradLimitViolation{limit:GradLimit::RmsDuty,label:loudest[c].1.clone(),view:driver_val,channel,value:rms/limits.max_amplitude_hz_per_mm,max:limits.max_rms_duty});
+                }
+            }
+        }
+        violations
+    }
+}
+
+#[test]
+fn test(){
+    use crate::event_block::{Event, EventPlacementType, GradEventType};
+    use crate::execution::ExecutionBlock;
+    use crate::gradient_event::GradEvent;
+    use crate::gradient_matrix::{DacValues, DriverVar, EncodeStrategy, Dimension, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+    use crate::pulse::Trapezoid;
+
+    let n_views = 64;
+    let tracker = Matrix::new_tracker();
+    let driver = MatrixDriver::new(DriverVar::Repetition,MatrixDriverType::PhaseEncode(EncodeStrategy::FullySampled(Dimension::_2D,n_views,None)),None);
+    // the outer views of the phase encode are just over full scale in 100 us
+    let pe_matrix = Matrix::new_driven("pe",driver,LinTransform::new((None,Some(-1000.0),None),(None,None,None)),DacValues::new(None,None,None),(false,false,false),false,&tracker);
+    let ro_matrix = Matrix::new_static("ro",DacValues::new(Some(30000),None,None),(false,false,false),false,&tracker);
+    let phase_encode = GradEvent::new((None,Some(Trapezoid::new(100E-6,500E-6)),None),&pe_matrix,GradEventType::Blocking,"phase_encode");
+    let readout = GradEvent::new((Some(Trapezoid::new(100E-6,1.28E-3)),None,None),&ro_matrix,GradEventType::NonBlocking,"readout");
+    let pe = Event::new(phase_encode.as_reference(),EventPlacementType::Origin);
+    let ro = Event::new(readout.as_reference(),EventPlacementType::After(pe.clone(),0));
+    let q = EventQueue::new(&vec![pe,ro]);
+
+    let scanner = ScannerProfile::civm9p4t();
+    assert!(scanner.check_grad_limits(&q,n_views as u32,1,50E-3,2).is_empty());
+
+    let limited = ScannerProfile {
+        grad_limits:GradHardwareProfile {
+            max_amplitude_hz_per_mm:scanner.dac_to_hz_per_mm(31500),
+            ..GradHardwareProfile::civm9p4t()
+        },
+        ..scanner.clone()
+    };
+    let violations = limited.check_grad_limits(&q,n_views as u32,1,50E-3,2);
+    // only the first view is driven past 31500 dac
+    assert_eq!(violations.len(),1);
+    assert!(violations.iter().all(|v| v.limit == GradLimit::Amplitude && v.label == "phase_encode" && v.channel == Channel::Phase));
+    assert_eq!(violations[0].view,0);
+    // a very short rep time concentrates the gradient energy
+    let violations = scanner.check_grad_limits(&q,n_views as u32,1,1E-3,2);
+    assert!(violations.iter().any(|v| v.limit == GradLimit::RmsDuty && v.label == "readout"));
+}
@@ -25,7 +25,7 @@ const READ_MASK:&str = "0x0002";
 const PHASE_MASK:&str = "0x0020";
 const SLICE_MASK:&str = "0x0200";
 
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,Debug,PartialEq)]
 pub enum Channel{
     Read,
     Phase,
@@ -50,9 +50,11 @@ pub struct GradEvent<GF> where GF:GradFrame{
     kind: GradEventType,
     matrix:Matrix,
     label:String,
+    // switch the transmitter back to the base frequency buffer, undoing an off-resonant rf event
+    restores_base_frequency:bool,
 }
 
-impl<GF> GradEvent<GF> where GF:GradFrame + Copy {
+impl<GF> GradEvent<GF> where GF:GradFrame + Clone {
     pub fn new(grad_frames:(Option<GF>,Option<GF>,Option<GF>), matrix:&Matrix, event_type: GradEventType, label:&str) -> GradEvent<GF> {
         GradEvent{
             read_frame:grad_frames.0,
@@ -60,9 +62,14 @@ impl<GF> GradEvent<GF> where GF:GradFrame + Copy {
             slice_frame:grad_frames.2,
             kind:event_type,
             matrix:matrix.clone(),
-            label:label.to_string()
+            label:label.to_string(),
+            restores_base_frequency:false
         }
     }
+    /** Select the base frequency buffer when this event runs, for events following an rf event with frequency offsets */
+    pub fn restore_base_frequency(&mut self) {
+        self.restores_base_frequency = true;
+    }
     fn list_label(&self,channel:Channel) -> Option<String>{
         match channel {
             Channel::Read => if self.read_frame.is_some() {Some(format!("{}_read",self.label))} else {None},
@@ -72,15 +79,15 @@ impl<GF> GradEvent<GF> where GF:GradFrame + Copy {
     }
     fn channel_seq_frame(&self,channel:Channel,sample_period_us:usize) -> Option<SeqFrame>{
         match channel {
-            Channel::Read => match self.read_frame {
+            Channel::Read => match &self.read_frame {
                 Some(frame) => Some(frame.grad_seq_frame(&self.list_label(channel).unwrap(),sample_period_us)),
                 None => None
             }
-            Channel::Phase => match self.phase_frame {
+            Channel::Phase => match &self.phase_frame {
                 Some(frame) => Some(frame.grad_seq_frame(&self.list_label(channel).unwrap(),sample_period_us)),
                 None => None
             }
-            Channel::Slice => match self.slice_frame {
+            Channel::Slice => match &self.slice_frame {
                 Some(frame) => Some(frame.grad_seq_frame(&self.list_label(channel).unwrap(),sample_period_us)),
                 None => None
             }
@@ -167,15 +174,15 @@ impl<GF> GradEvent<GF> where GF:GradFrame + Copy {
     }
     pub fn frame_duration_clocks(&self,chann:Channel) -> Option<i32> {
         match chann {
-            Channel::Read => match self.read_frame {
+            Channel::Read => match &self.read_frame {
                 Some(frame) => Some(_utils::sec_to_clock(frame.duration())),
                 None => None
             }
-            Channel::Phase => match self.phase_frame {
+            Channel::Phase => match &self.phase_frame {
                 Some(frame) => Some(_utils::sec_to_clock(frame.duration())),
                 None => None
             }
-            Channel::Slice => match self.slice_frame {
+            Channel::Slice => match &self.slice_frame {
                 Some(frame) => Some(_utils::sec_to_clock(frame.duration())),
                 None => None
             }
@@ -199,7 +206,7 @@ impl<GF> GradEvent<GF> where GF:GradFrame + Copy {
     }
 }
 
-impl<GF: 'static> ExecutionBlock for GradEvent<GF> where GF:GradFrame + Copy{
+impl<GF: 'static> ExecutionBlock for GradEvent<GF> where GF:GradFrame + Clone{
     fn block_duration(&self) -> i32 {
         TIME_BLOCK_1 + TIME_BLOCK_2
     }
@@ -213,17 +220,22 @@ impl<GF: 'static> ExecutionBlock for GradEvent<GF> where GF:GradFrame + Copy{
         self.time_to_start() + self.max_waveform_duration()/2
     }
     fn block_execution(&self,post_delay_clocks:i32) -> BlockExecution{
-        let cmd = CommandString::new_hardware_exec(
-            &vec![
-                ppl_function::start_timer(),
-                self.select_matrix(),
-                self.set_list(),
-                ppl_function::wait_timer(TIME_BLOCK_1),
-                ppl_function::start_timer(),
-                ppl_function::grad_start(&self.channel_mask()),
-                ppl_function::wait_timer(TIME_BLOCK_2),
-            ].join("\n")
-        );
+        let mut cmd_str = vec![
+            ppl_function::start_timer(),
+            self.select_matrix(),
+            self.set_list(),
+        ];
+        // the buffer switch is covered by the time block
+        if self.restores_base_frequency {
+            cmd_str.push(ppl_function::select_freq_buffer("0"));
+        }
+        cmd_str.extend(vec![
+            ppl_function::wait_timer(TIME_BLOCK_1),
+            ppl_function::start_timer(),
+            ppl_function::grad_start(&self.channel_mask()),
+            ppl_function::wait_timer(TIME_BLOCK_2),
+        ]);
+        let cmd = CommandString::new_hardware_exec(&cmd_str.join("\n"));
         BlockExecution::new(cmd,post_delay_clocks)
     }
     fn block_header_adjustments(&self) -> Option<Vec<Adjustment>> {
@@ -314,19 +326,19 @@ impl<GF: 'static> ExecutionBlock for GradEvent<GF> where GF:GradFrame + Copy{
         self.label.clone()
     }
     fn render_normalized(&self, time_step_us:usize) -> WaveformData {
-        let r = match self.read_frame {
+        let r = match &self.read_frame {
             Some(frame) =>{
                 Some(frame.render_normalized(time_step_us))
             }
             None => None
         };
-        let p = match self.phase_frame {
+        let p = match &self.phase_frame {
             Some(frame) =>{
                 Some(frame.render_normalized(time_step_us))
             }
             None => None
         };
-        let s = match self.slice_frame {
+        let s = match &self.slice_frame {
             Some(frame) =>{
                 Some(frame.render_normalized(time_step_us))
             }
@@ -336,19 +348,19 @@ impl<GF: 'static> ExecutionBlock for GradEvent<GF> where GF:GradFrame + Copy{
     }
     fn render_magnitude(&self,time_step_us:usize,driver_value:u32) -> WaveformData {
         let dac = self.matrix.dac_vals(driver_value);
-        let r = match self.read_frame {
+        let r = match &self.read_frame {
             Some(frame) =>{
                 Some(frame.render_magnitude(time_step_us,dac.read.unwrap_or(0)))
             }
             None => None
         };
-        let p = match self.phase_frame {
+        let p = match &self.phase_frame {
             Some(frame) =>{
                 Some(frame.render_magnitude(time_step_us,dac.phase.unwrap_or(0)))
             }
             None => None
         };
-        let s = match self.slice_frame {
+        let s = match &self.slice_frame {
             Some(frame) =>{
                 Some(frame.render_magnitude(time_step_us,dac.slice.unwrap_or(0)))
             }
@@ -359,6 +371,9 @@ impl<GF: 'static> ExecutionBlock for GradEvent<GF> where GF:GradFrame + Copy{
     fn kind(&self) -> EventType {
         EventType::Grad
     }
+    fn grad_dac_values(&self, driver_value: u32) -> Option<DacValues> {
+        Some(self.matrix.dac_vals(driver_value))
+    }
     fn seq_params(&self, sample_period_us: usize) -> Option<String> {
         self.seq_params(sample_period_us)
     }
@@ -1,4 +1,4 @@
-use crate::pulse::{HalfSin, Pulse, Trapezoid};
+use crate::pulse::{ArbitraryWaveform, DelayedTrapezoid, HalfSin, Pulse, Trapezoid};
 use crate::pulse_function::Function;
 use crate::seqframe::{self, SeqFrame, FrameType, SeqFrameExpression};
 use crate::_utils;
@@ -20,7 +20,9 @@ pub trait GradFrame:Pulse {
 }
 
 impl GradFrame for Trapezoid {}
+impl GradFrame for DelayedTrapezoid {}
 impl GradFrame for HalfSin {}
+impl GradFrame for ArbitraryWaveform {}
 
 #[test]
 fn test(){
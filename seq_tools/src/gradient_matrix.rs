@@ -10,9 +10,11 @@
  */
 
 use std::cell::RefCell;
+use std::f32::consts::PI;
 use std::rc::Rc;
+use serde::{Deserialize, Serialize};
 use crate::command_string::{CommandString, Command};
-use crate::ppl::{AVERAGES_LOOP_COUNTER_VAR, Adjustment, VIEW_LOOP_COUNTER_VAR};
+use crate::ppl::{AVERAGES_LOOP_COUNTER_VAR, Adjustment, SLICE_LOOP_COUNTER_VAR, VIEW_LOOP_COUNTER_VAR};
 
 pub const LONG_TEMPVAL_VAR_NAME:&str = "tempval_long";
 pub const LUT_TEMPVAL_VAR_NAME_1:&str = "lut_tempval_1";
@@ -230,9 +232,94 @@ impl EncodeStrategy {
 
 }
 
+/** Readout directions that change every view. Directions are stored in the scanner LUT as
+(read,phase,slice) triplets scaled to i16::MAX, and the readout amplitude is rotated onto all three
+channels. The ppl has no trig functions, so directions are always calculated here */
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum ProjectionStrategy {
+    Radial2D(usize), // spokes evenly spaced around the full circle in the read-phase plane
+    GoldenAngle3D(usize), // 2-D golden means over the sphere (Chan et al. 2009)
+    Kooshball(usize), // spiral of evenly spaced spokes over the sphere (Wong and Roos 1994)
+    LUT(Vec<i16>),
+}
+
+impl ProjectionStrategy {
+    pub fn n_projections(&self) -> usize {
+        match self {
+            ProjectionStrategy::Radial2D(n) |
+            ProjectionStrategy::GoldenAngle3D(n) |
+            ProjectionStrategy::Kooshball(n) => *n,
+            ProjectionStrategy::LUT(lut) => lut.len()/3
+        }
+    }
+    /** Unit readout direction (read,phase,slice) of a view */
+    pub fn direction(&self,view:usize) -> [f32;3] {
+        let i = view as f32;
+        match self {
+            ProjectionStrategy::Radial2D(n) => {
+                let theta = 2.0*PI*i/(*n as f32);
+                [theta.cos(),theta.sin(),0.0]
+            }
+            ProjectionStrategy::GoldenAngle3D(_) => {
+                let (phi1,phi2) = (0.4656,0.6823);
+                let z = 2.0*(i*phi1).fract() - 1.0;
+                let azimuth = 2.0*PI*(i*phi2).fract();
+                let r = (1.0 - z*z).max(0.0).sqrt();
+                [r*azimuth.cos(),r*azimuth.sin(),z]
+            }
+            ProjectionStrategy::Kooshball(n) => {
+                let n = *n as f32;
+                let z = (2.0*i + 1.0 - n)/n;
+                let azimuth = (n*PI).sqrt()*z.asin();
+                let r = (1.0 - z*z).max(0.0).sqrt();
+                [r*azimuth.cos(),r*azimuth.sin(),z]
+            }
+            ProjectionStrategy::LUT(lut) => {
+                [0,1,2].map(|c| lut[3*view + c] as f32/i16::MAX as f32)
+            }
+        }
+    }
+    fn lut_entry(&self,view:usize,channel:usize) -> i16 {
+        match self {
+            ProjectionStrategy::LUT(lut) => lut[3*view + channel],
+            _=> (self.direction(view)[channel]*i16::MAX as f32).round() as i16
+        }
+    }
+    /** Table to be loaded on the scanner */
+    pub fn lut(&self) -> Vec<i16> {
+        (0..self.n_projections()).flat_map(|view| [0,1,2].map(|c| self.lut_entry(view,c))).collect()
+    }
+    pub fn dac_value(&self,driver_val:u32,trans:LinTransform,default_dac:DacValues,echo_index:usize) -> DacValues {
+        let view = driver_val as usize + echo_index;
+        let amplitude = default_dac.read.unwrap_or(0) as i32;
+        // integer math to match the ppl
+        let d = [0,1,2].map(|c| Some((self.lut_entry(view,c) as i32*amplitude/i16::MAX as i32) as i16));
+        DacValues::new(trans.read.transform(d[0]),trans.phase.transform(d[1]),trans.slice.transform(d[2]))
+    }
+    pub fn print(&self,driver_var:&str,matrix:&Matrix,trans:LinTransform,default_dac:DacValues,echo_index:usize) -> String {
+        let vars = matrix.var_names();
+        let amplitude = default_dac.read.unwrap_or(0);
+        let channels = [(&vars.0,trans.read),(&vars.1,trans.phase),(&vars.2,trans.slice)];
+        let mut out_str = vec![format!("{} = ({}+{})*3L;",LUT_INDEX_VAR_NAME,driver_var,echo_index)];
+        for (c,(var,coeffs)) in channels.iter().enumerate() {
+            if c > 0 {
+                out_str.push(format!("{} = {} + 1L;",LUT_INDEX_VAR_NAME,LUT_INDEX_VAR_NAME));
+            }
+            out_str.push(format!("GETLUTENTRY({},{})",LUT_INDEX_VAR_NAME,LUT_TEMPVAL_VAR_NAME_1));
+            out_str.push(format!("{}={};",LONG_TEMPVAL_VAR_NAME,LUT_TEMPVAL_VAR_NAME_1));
+            out_str.push(format!("{}=({}*{}L)/{}L;",LONG_TEMPVAL_VAR_NAME,LONG_TEMPVAL_VAR_NAME,amplitude,i16::MAX));
+            out_str.push(format!("{}={};",var,LONG_TEMPVAL_VAR_NAME));
+            out_str.push(coeffs.transform_string(var,var,LONG_TEMPVAL_VAR_NAME));
+        }
+        out_str.join("\n")
+    }
+}
+
 #[derive(Clone,Debug)]
 pub enum MatrixDriverType{
     PhaseEncode(EncodeStrategy),
+    // the default read dac is the readout amplitude
+    Projection(ProjectionStrategy),
 }
 
 #[derive(Clone,Debug)]
@@ -244,14 +331,16 @@ pub struct MatrixDriver{
 
 pub enum DriverVar {
     Repetition,
-    Average
+    Average,
+    Slice
 }
 
 impl DriverVar {
     pub fn varname(&self) -> String {
         match self {
             DriverVar::Repetition => String::from(VIEW_LOOP_COUNTER_VAR),
-            DriverVar::Average => String::from(AVERAGES_LOOP_COUNTER_VAR)
+            DriverVar::Average => String::from(AVERAGES_LOOP_COUNTER_VAR),
+            DriverVar::Slice => String::from(SLICE_LOOP_COUNTER_VAR)
         }
     }
 }
@@ -266,7 +355,8 @@ impl MatrixDriver {
     }
     fn render(&self,trans:LinTransform,matrix:&Matrix,default_dac:DacValues) -> String {
         match &self.kind {
-            MatrixDriverType::PhaseEncode(strategy) => strategy.print(&self.driver_var,matrix,trans,default_dac,self.echo_index)
+            MatrixDriverType::PhaseEncode(strategy) => strategy.print(&self.driver_var,matrix,trans,default_dac,self.echo_index),
+            MatrixDriverType::Projection(strategy) => strategy.print(&self.driver_var,matrix,trans,default_dac,self.echo_index)
         }
     }
 }
@@ -477,6 +567,9 @@ impl Matrix {
                     MatrixDriverType::PhaseEncode(strategy) => {
                         strategy.dac_value(driver_value, &self, *transform, *dac_values, driver.echo_index)
                     }
+                    MatrixDriverType::Projection(strategy) => {
+                        strategy.dac_value(driver_value, *transform, *dac_values, driver.echo_index)
+                    }
                 }
             }
             MatrixType::Derived(parent, transform) => {
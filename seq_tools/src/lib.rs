@@ -23,4 +23,12 @@ pub mod ppl;
 pub mod event_block;
 pub mod grad_cal;
 pub mod diffusion;
-pub mod pe_table;
\ No newline at end of file
+pub mod pe_table;
+pub mod pulseq;
+pub mod grad_limits;
+pub mod rf_power;
+pub mod trajectory;
+pub mod moments;
+pub mod scanner;
+pub mod timing_diagram;
+pub mod epi;
@@ -0,0 +1,203 @@
+/*
+    Gradient moments and trapezoid design. Gradients of a placed event queue are rendered onto a common
+    time grid for a driver value so that zeroth (area) and first moments can be found between any two
+    events. Rewinders and crushers are solved as the shortest trapezoid that reaches a target area
+    within the amplitude and slew limits of the gradient hardware.
+    Gradients are in Hz/mm, so m0 is in Hz*s/mm (cycles/mm) and m1 is in Hz*s^2/mm.
+ */
+
+use std::fmt;
+use crate::_utils;
+use crate::event_block::EventQueue;
+use crate::execution::{EventType, WaveformData};
+use crate::grad_cal;
+use crate::scanner::ScannerProfile;
+use crate::pulse::Trapezoid;
+
+// trapezoid timing is rounded up to this raster
+const GRAD_RASTER:f32 = 10E-6;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct GradientMoments {
+    pub m0:[f32;3],
+    pub m1:[f32;3],
+}
+
+impl fmt::Display for GradientMoments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f,"m0 (read,phase,slice) = ({:.4},{:.4},{:.4}) hz*s/mm, m1 = ({:.4e},{:.4e},{:.4e}) hz*s^2/mm",
+               self.m0[0],self.m0[1],self.m0[2],self.m1[0],self.m1[1],self.m1[2])
+    }
+}
+
+/** Gradient waveforms (read, phase, slice) of an event queue on a uniform time grid */
+pub struct GradientGrid {
+    // time of the first grid point relative to the origin of the event queue
+    pub t0:f32,
+    pub dt:f32,
+    pub g:Vec<[f32;3]>,
+}
+
+impl GradientGrid {
+    pub fn new(event_queue:&EventQueue,driver_value:u32,time_step_us:usize) -> Self {
+        let dt = _utils::us_to_sec(time_step_us as i32);
+        let events = event_queue.events();
+        // gradient waveforms can play out past the end of their block
+        let t0 = events.iter().map(|e| _utils::clock_to_sec(e.borrow().block_start())).fold(f32::MAX,f32::min);
+        let t_end = events.iter().map(|e|{
+            let e = e.borrow();
+            _utils::clock_to_sec(e.block_end().max(e.block_start() + e.execution.time_to_end()))
+        }).fold(f32::MIN,f32::max);
+        let n = ((t_end - t0)/dt).ceil() as usize + 2;
+        let mut grid = Self {
+            t0,
+            dt,
+            g:vec![[0.0f32;3];n]
+        };
+        let hz_per_mm_per_dac = grad_cal::dac_to_hz_per_mm(1);
+        for event in events.iter() {
+            let e = event.borrow();
+            if e.execution.kind() != EventType::Grad {continue}
+            let graph = e.event_graph_dynamic(time_step_us,driver_value);
+            if let WaveformData::Grad(r,p,s) = graph.wave_data {
+                for (c,trace) in [r,p,s].iter().enumerate() {
+                    if let Some(trace) = trace {
+                        trace.x.iter().zip(trace.y.iter()).for_each(|(x,y)|{
+                            let i = grid.index(graph.waveform_start + x);
+                            grid.g[i][c] += y*hz_per_mm_per_dac;
+                        });
+                    }
+                }
+            }
+        }
+        grid
+    }
+    /** Grid index nearest to a time relative to the event queue origin */
+    pub fn index(&self,t:f32) -> usize {
+        (((t - self.t0)/self.dt).round().max(0.0) as usize).min(self.g.len() - 1)
+    }
+    /** Moments between two times. The first moment is taken about t_start */
+    pub fn moments(&self,t_start:f32,t_end:f32) -> GradientMoments {
+        let (i0,i1) = (self.index(t_start),self.index(t_end));
+        let mut m = GradientMoments{m0:[0.0;3],m1:[0.0;3]};
+        for i in i0..i1 {
+            let t = (i - i0) as f32*self.dt;
+            for c in 0..3 {
+                m.m0[c] += self.g[i][c]*self.dt;
+                m.m1[c] += self.g[i][c]*t*self.dt;
+            }
+        }
+        m
+    }
+}
+
+/** Gradient moments between the centers of two events, found by unique label. Rf events are ignored, so
+the moments are those of the gradients alone */
+pub fn moments_between(event_queue:&EventQueue,from_label:&str,to_label:&str,driver_value:u32,time_step_us:usize) -> GradientMoments {
+    let center = |label:&str| {
+        let event = event_queue.events().into_iter().find(|e| e.borrow().unique_label() == label)
+            .unwrap_or_else(|| panic!("event {} not found in event queue",label));
+        let c = event.borrow().center();
+        _utils::clock_to_sec(c)
+    };
+    let grid = GradientGrid::new(event_queue,driver_value,time_step_us);
+    grid.moments(center(from_label),center(to_label))
+}
+
+/** Area of a crusher that dephases a slice by n_cycles */
+pub fn crusher_area(n_cycles:f32,slice_thickness_mm:f32) -> f32 {
+    n_cycles/slice_thickness_mm
+}
+
+#[derive(Clone,Copy)]
+pub struct TrapezoidSolution {
+    pub trapezoid:Trapezoid,
+    pub dac:i16,
+}
+
+fn round_up_to_raster(t:f32) -> f32 {
+    (t/GRAD_RASTER - 1E-3).ceil().max(1.0)*GRAD_RASTER
+}
+
+/** Shortest trapezoid with the target area (hz*s/mm) within the gradient limits of the scanner.
+Negative areas give a negative dac */
+pub fn solve_trapezoid(target_area:f32,scanner:&ScannerProfile) -> TrapezoidSolution {
+    let area = target_area.abs();
+    let max_amp = scanner.grad_limits.max_amplitude_hz_per_mm;
+    let slew_per_sec = scanner.grad_limits.max_slew_hz_per_mm_per_us*1E6;
+    let full_ramp = max_amp/slew_per_sec;
+    let (ramp,plateau) = match area <= max_amp*full_ramp {
+        // triangle
+        true => (round_up_to_raster((area/slew_per_sec).sqrt()),0.0),
+        false => {
+            let ramp = round_up_to_raster(full_ramp);
+            // round the plateau such that the amplitude doesn't increase past the limit
+            (ramp,round_up_to_raster(area/max_amp - ramp).max(0.0))
+        }
+    };
+    // timing is rounded up, so the amplitude can only pass the limit by float error
+    let amp = (area/(ramp + plateau)).min(max_amp);
+    TrapezoidSolution {
+        trapezoid:Trapezoid::new(ramp,plateau),
+        dac:scanner.grad_to_dac(amp)*target_area.signum() as i16
+    }
+}
+
+/** Trapezoid with fixed timing that reaches the target area (hz*s/mm) */
+pub fn solve_trapezoid_timing(target_area:f32,ramp_time:f32,plateau_time:f32,scanner:&ScannerProfile) -> TrapezoidSolution {
+    let trapezoid = Trapezoid::new(ramp_time,plateau_time);
+    let amp = target_area/(ramp_time + plateau_time);
+    let dac = scanner.grad_to_dac(amp.abs())*amp.signum() as i16;
+    TrapezoidSolution {
+        trapezoid,
+        dac
+    }
+}
+
+/** Shortest trapezoid that nulls the zeroth moment */
+pub fn rewinder(moments:&GradientMoments,channel:usize,scanner:&ScannerProfile) -> TrapezoidSolution {
+    solve_trapezoid(-moments.m0[channel],scanner)
+}
+
+#[test]
+fn test(){
+    use crate::event_block::{Event, EventPlacementType, GradEventType};
+    use crate::execution::ExecutionBlock;
+    use crate::gradient_event::GradEvent;
+    use crate::gradient_matrix::{DacValues, Matrix};
+    use crate::pulse::Pulse;
+
+    let scanner = ScannerProfile::civm9p4t();
+    let tracker = Matrix::new_tracker();
+    let ro_dac = 10000;
+    let ro_matrix = Matrix::new_static("ro",DacValues::new(Some(ro_dac),None,None),(false,false,false),false,&tracker);
+    let readout = GradEvent::new((Some(Trapezoid::new(100E-6,1E-3)),None,None),&ro_matrix,GradEventType::Blocking,"readout");
+    let ro = Event::new(readout.as_reference(),EventPlacementType::Origin);
+    let q = EventQueue::new(&vec![ro.clone()]);
+    let grid = GradientGrid::new(&q,0,2);
+    let t0 = _utils::clock_to_sec(ro.borrow().waveform_start());
+    let t1 = t0 + Trapezoid::new(100E-6,1E-3).duration();
+    let m = grid.moments(t0,t1);
+    let expected = grad_cal::dac_to_hz_per_mm(ro_dac)*Trapezoid::new(100E-6,1E-3).power_net(1.0);
+    assert!((m.m0[0] - expected).abs() < 0.01*expected,"{} != {}",m.m0[0],expected);
+    // a symmetric waveform has its first moment at its center
+    assert!((m.m1[0]/m.m0[0] - (t1 - t0)/2.0).abs() < 20E-6);
+
+    // the rewinder cancels the area within the hardware limits
+    let rewind = rewinder(&m,0,&scanner);
+    let area = scanner.dac_to_hz_per_mm(rewind.dac)*rewind.trapezoid.power_net(1.0);
+    assert!((area + m.m0[0]).abs() < 0.01*m.m0[0]);
+    assert!(scanner.dac_to_hz_per_mm(rewind.dac).abs() <= scanner.grad_limits.max_amplitude_hz_per_mm);
+    assert!(scanner.dac_to_hz_per_mm(rewind.dac).abs()/(rewind.trapezoid.ramp_time*1E6) <= scanner.grad_limits.max_slew_hz_per_mm_per_us);
+
+    // small areas are triangles
+    let crusher = solve_trapezoid(crusher_area(2.0,1.0),&scanner);
+    assert_eq!(crusher.trapezoid.plateau_time,0.0);
+    assert!(crusher.dac > 0);
+
+    // an area that needs full scale on the raster is played at full scale
+    let max_amp = scanner.grad_limits.max_amplitude_hz_per_mm;
+    let ramp = round_up_to_raster(max_amp/(scanner.grad_limits.max_slew_hz_per_mm_per_us*1E6));
+    let full = solve_trapezoid(max_amp*(ramp + 1E-3),&scanner);
+    assert_eq!(full.dac.abs(),i16::MAX);
+}
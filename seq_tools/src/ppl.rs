@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::f32::consts::FRAC_PI_2;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
@@ -16,23 +17,10 @@ use crate::seqframe::{FrameType, RF_SEQ_FILE_LABEL, GRAD_SEQ_FILE_LABEL};
 use crate::seqframe::FrameType::Grad;
 use crate::gradient_matrix::{LUT_TEMPVAL_VAR_NAME_1, LUT_TEMPVAL_VAR_NAME_2, LONG_TEMPVAL_VAR_NAME, LUT_INDEX_VAR_NAME};
 use crate::pulse_function::Function;
-use crate::grad_cal;
-
-const CIVM_INCLUDE:&str = r"C:\workstation\SequenceTools\CivmSequenceTools_v1.0\civm_var_20_long.PPH";
-const STD_FN_INCLUDE:&str = r"stdfn_15.pph";
-const GRAD_FN_INCLUDE:&str = r"m3040_20.pph";
-const RF_FN_INCLUDE:&str = r"m3031_15.pph";
-const STD_RF_SEQ:&str = r"c:\smis\seqlib\RFstd.seq";
-const STD_GRAD_SEQ:&str = r"c:\smis\seqlib\g3040_15.seq";
-const LUT_INCLUDE:&str = r"C:\smis\include\lututils.pph";
+use crate::scanner::ScannerProfile;
 
 const CALC_MATRIX:&str = "c_calc_mat";
 
-// 92456, 101857, 92456, 112634
-const GRAD_X:i32 = 101857;
-const GRAD_Y:i32 = 92456;
-const GRAD_Z:i32 = 112634;
-
 const SPECTRAL_WIDTH_VAR:&str = "sample_period";
 const GRAD_STRENGTH_VAR:&str = "grad_var";
 const RECEIVER_MASK_VAR:&str = "rec_sel";
@@ -64,6 +52,9 @@ pub const AVERAGES_LOOP_COUNTER_VAR:&str = "no_completed_averages";
 const NO_AVERAGES_MIN:u32 = 1;
 const NO_AVERAGES_MAX:u32 = 65535;
 
+const SLICE_LOOP_NAME:&str = "slice_loop";
+pub const SLICE_LOOP_COUNTER_VAR:&str = "no_completed_slices";
+
 const FREQ_OFFSET_MIN:i32 = -40000000;
 const FREQ_OFFSET_MAX:i32 = 40000000;
 
@@ -80,6 +71,8 @@ pub enum Orientation {
     Scout0,
     Scout1,
     Scout2,
+    // base matrix rotations about x, y and z in tenths of a degree
+    Custom(i16,i16,i16),
 }
 
 #[derive(Clone,Serialize,Deserialize)]
@@ -117,8 +110,33 @@ impl Orientation {
             Orientation::Scout0 => (0, 0, 900),
             Orientation::Scout1 => (-900, 0, 0),
             Orientation::Scout2 => (-900, -900, 0),
+            Orientation::Custom(x,y,z) => (*x, *y, *z),
         }
     }
+    pub fn from_euler_deg(x:f32,y:f32,z:f32) -> Self {
+        let tenths = |deg:f32| (10.0*deg).round() as i16;
+        Orientation::Custom(tenths(x),tenths(y),tenths(z))
+    }
+    /** Orientation from a rotation matrix R = Rz*Ry*Rx, the same convention as rotation_matrix */
+    pub fn from_rotation(r:&[[f32;3];3]) -> Self {
+        let (x,y,z) = match r[2][0].abs() > 1.0 - 1E-6 {
+            // gimbal lock. The z rotation is folded into x
+            true => ((-r[1][2]).atan2(r[1][1]),(-r[2][0].signum()*FRAC_PI_2),0.0),
+            false => (r[2][1].atan2(r[2][2]),(-r[2][0]).asin(),r[1][0].atan2(r[0][0]))
+        };
+        Self::from_euler_deg(x.to_degrees(),y.to_degrees(),z.to_degrees())
+    }
+    /** Rotation matrix of the base matrix angles, applied about x, then y, then z */
+    pub fn rotation_matrix(&self) -> [[f32;3];3] {
+        let (x,y,z) = self.base_matrix();
+        let (a,b,c) = ((x as f32/10.0).to_radians(),(y as f32/10.0).to_radians(),(z as f32/10.0).to_radians());
+        let (sa,ca,sb,cb,sc,cc) = (a.sin(),a.cos(),b.sin(),b.cos(),c.sin(),c.cos());
+        [
+            [cb*cc, sa*sb*cc - ca*sc, ca*sb*cc + sa*sc],
+            [cb*sc, sa*sb*sc + ca*cc, ca*sb*sc - sa*cc],
+            [-sb, sa*cb, ca*cb]
+        ]
+    }
     pub fn print(&self) -> String {
         let mat = self.base_matrix();
         vec![
@@ -171,19 +189,19 @@ pub struct BaseFrequency {
 
 impl BaseFrequency {
 
-    pub fn civm9p4t(offset:f32) -> Self {
+    pub fn from_scanner(scanner:&ScannerProfile,offset:f32) -> Self {
         Self {
-            base_freq:30171576.0,
+            base_freq:scanner.base_freq_hz,
             obs_offset:offset
         }
     }
-    fn print(&self) -> String {
-                format!("OBSERVE_FREQUENCY \"9.4T 1H\",{},{},{},MHz, kHz, Hz, rx1MHz;",
-                        FREQ_OFFSET_MIN,FREQ_OFFSET_MAX,self.obs_offset)
+    fn print(&self,nucleus:&str) -> String {
+                format!("OBSERVE_FREQUENCY \"{}\",{},{},{},MHz, kHz, Hz, rx1MHz;",
+                        nucleus,FREQ_OFFSET_MIN,FREQ_OFFSET_MAX,self.obs_offset)
     }
-    fn print_ppr(&self) -> String {
-                format!(":OBSERVE_FREQUENCY \"9.4T 1H\", {:.1}, MHz, kHz, Hz, rx1MHz"
-                        ,self.base_freq+self.obs_offset)
+    fn print_ppr(&self,nucleus:&str) -> String {
+                format!(":OBSERVE_FREQUENCY \"{}\", {:.1}, MHz, kHz, Hz, rx1MHz"
+                        ,nucleus,self.base_freq+self.obs_offset)
     }
     pub fn set_freq_buffer(&self) -> String {
         ppl_function::set_base_freq()
@@ -201,7 +219,8 @@ pub struct Header {
     pub echos:u16,
     pub echo_divisor:u16,
     pub averages:u16,
-    pub user_adjustments:Option<Vec<Adjustment>>
+    pub user_adjustments:Option<Vec<Adjustment>>,
+    pub scanner:ScannerProfile,
 }
 
 
@@ -248,16 +267,20 @@ pub struct Includes {
 
 impl Includes {
     pub fn new_default(grad_seqfile:String,rf_seqfile:String) -> Self {
+        Self::new(ScannerProfile::active(),grad_seqfile,rf_seqfile)
+    }
+    pub fn new(scanner:&ScannerProfile,grad_seqfile:String,rf_seqfile:String) -> Self {
         use FrameType::*;
+        let inc = &scanner.includes;
         Self {
             civm_grad:Import::Use(Grad,grad_seqfile,String::from(GRAD_SEQ_FILE_LABEL)),
             civm_rf:Import::Use(Rf,rf_seqfile,String::from(RF_SEQ_FILE_LABEL)),
-            civm_include:Import::Include(String::from(CIVM_INCLUDE)),
-            std_fn:Import::Include(String::from(STD_FN_INCLUDE)),
-            grad_fn:Import::Include(String::from(GRAD_FN_INCLUDE)),
-            rf_fn:Import::Include(String::from(RF_FN_INCLUDE)),
-            std_grad:Import::Use(Grad,String::from(STD_GRAD_SEQ),String::from("grad")),
-            std_rf:Import::Use(Rf,String::from(STD_RF_SEQ),String::from("pf1")),
+            civm_include:Import::Include(inc.civm_include.clone()),
+            std_fn:Import::Include(inc.std_fn.clone()),
+            grad_fn:Import::Include(inc.grad_fn.clone()),
+            rf_fn:Import::Include(inc.rf_fn.clone()),
+            std_grad:Import::Use(Grad,inc.std_grad_seq.clone(),String::from("grad")),
+            std_rf:Import::Use(Rf,inc.std_rf_seq.clone(),String::from("pf1")),
             sys_out:Import::Function(String::from("void systemout(int);")),
             delay32:Import::Function(String::from("void delay32(long);")),
         }
@@ -318,6 +341,9 @@ pub struct Declarations {
 
 impl Declarations {
     pub fn default(event_queue:&EventQueue) -> Self {
+        Self::new(event_queue,ScannerProfile::active())
+    }
+    pub fn new(event_queue:&EventQueue,scanner:&ScannerProfile) -> Self {
         Self {
             block_declarations:event_queue.ppl_declarations().iter().map(|cmd| cmd.commands.clone()).collect(),
             temp_vars:vec![
@@ -328,9 +354,12 @@ impl Declarations {
                 String::from("common int pts_mask;"),
                 format!("long {};",VIEW_LOOP_COUNTER_VAR),
                 format!("int {};",AVERAGES_LOOP_COUNTER_VAR),
-                Import::Include(String::from(LUT_INCLUDE)).print(),
+                format!("int {};",SLICE_LOOP_COUNTER_VAR),
+                Import::Include(scanner.includes.lut_include.clone()).print(),
                 format!("int is16bit;"),
                 format!("is16bit = 1;"),
+                // single slice sequences never enter the slice loop, but may still read the counter
+                format!("{} = 0;",SLICE_LOOP_COUNTER_VAR),
             ]
         }
     }
@@ -382,7 +411,10 @@ impl PPL {
         event_queue:&mut EventQueue,
         repetitions:u32,
         averages:u16,
-        rep_time:f32,base_freq:BaseFrequency,
+        slices:u16,
+        rep_time:f32,
+        obs_freq_offset:f32,
+        scanner:&ScannerProfile,
         grad_seq_file:&str,
         rf_seq_file:&str,
         orientation:Orientation,
@@ -401,22 +433,24 @@ impl PPL {
             header:Header {
             dsp_routine:DspRoutine::Dsp,
             receiver_mask:1,
-            base_frequency:base_freq,
+            base_frequency:BaseFrequency::from_scanner(scanner,obs_freq_offset),
             samples:acq.n_samples,
             spectral_width: acq.sample_rate,
             sample_discards:acq.n_discards,
             repetitions,
-            echos:acq.n_echos,
+            // every slice in the loop acquires its own echos
+            echos:acq.n_echos*slices,
             echo_divisor:1,
             averages,
-            user_adjustments:event_queue.ppl_user_adjustments()
+            user_adjustments:event_queue.ppl_user_adjustments(),
+            scanner:scanner.clone(),
             },
-            includes:Includes::new_default(String::from(grad_seq_file),String::from(rf_seq_file)),
+            includes:Includes::new(scanner,String::from(grad_seq_file),String::from(rf_seq_file)),
             constants:Constants::default(&event_queue),
-            declarations:Declarations::default(&event_queue),
+            declarations:Declarations::new(&event_queue,scanner),
             initializations:Initializations::default(&event_queue),
             setup:Setup{grad_clock,orientation,phase_unit},
-            loop_structure:event_queue.flat_loop_structure(repetitions,averages,rep_time,acceleration),
+            loop_structure:event_queue.flat_loop_structure(repetitions,averages,slices,rep_time,acceleration),
             simulate
         }
     }
@@ -577,7 +611,7 @@ impl Header {
                 self.receiver_mask as u32
             ).print(),
             format!("GRADIENT_STRENGTH {};",GRAD_STRENGTH_VAR),
-            self.base_frequency.print(),
+            self.base_frequency.print(&self.scanner.nucleus),
             PPLNumeric::new(
                 "SPECTRAL_WIDTH",
                 SPECTRAL_WIDTH_VAR,
@@ -650,10 +684,10 @@ impl Header {
                 self.receiver_mask as u32
             ).print_ppr(),
             format!(":GRADIENT_STRENGTH {}, 4, {}, {}, {}, {}",
-                    GRAD_STRENGTH_VAR,grad_cal::GRAD_MIN,grad_cal::GRAD_MAX_READ,
-                    grad_cal::GRAD_MAX_PHASE,grad_cal::GRAD_MAX_SLICE),
-            self.base_frequency.print_ppr(),
-            format!(":SAMPLE_PERIOD {}, {}",SPECTRAL_WIDTH_VAR,self.spectral_width.ppr_string()),
+                    GRAD_STRENGTH_VAR,self.scanner.grad_min(),self.scanner.grad_max_read,
+                    self.scanner.grad_max_phase,self.scanner.grad_max_slice),
+            self.base_frequency.print_ppr(&self.scanner.nucleus),
+            format!(":SAMPLE_PERIOD {}, {}",SPECTRAL_WIDTH_VAR,self.spectral_width.receiver_bandwidth_for(&self.scanner).ppr_string()),
             PPLNumeric::new(
                 "NO_VIEWS",
                 NO_VIEWS_VAR,
@@ -716,7 +750,9 @@ impl Loop {
             Loop::Repetition(_,_) =>
                 Loop::_start(VIEW_LOOP_NAME),
             Loop::Average(_) =>
-                Loop::_start(AVERAGES_LOOP_NAME)
+                Loop::_start(AVERAGES_LOOP_NAME),
+            Loop::Slice(_) =>
+                Loop::_start(SLICE_LOOP_NAME)
         }
     }
     pub fn end(&self) -> String {
@@ -725,6 +761,9 @@ impl Loop {
                 Loop::_end(VIEW_LOOP_NAME,VIEW_LOOP_COUNTER_VAR,NO_VIEWS_VAR,*step),
             Loop::Average(_) =>
                 Loop::_end(AVERAGES_LOOP_NAME,AVERAGES_LOOP_COUNTER_VAR,NO_AVERAGES_VAR,1),
+            // the slice count is fixed when the sequence is built
+            Loop::Slice(n) =>
+                Loop::_end(SLICE_LOOP_NAME,SLICE_LOOP_COUNTER_VAR,&n.to_string(),1),
         }
     }
 
@@ -733,7 +772,9 @@ impl Loop {
             Loop::Repetition(_,_) =>
                 format!("{} = 0;",VIEW_LOOP_COUNTER_VAR),
             Loop::Average(_) =>
-                format!("{} = 0;",AVERAGES_LOOP_COUNTER_VAR)
+                format!("{} = 0;",AVERAGES_LOOP_COUNTER_VAR),
+            Loop::Slice(_) =>
+                format!("{} = 0;",SLICE_LOOP_COUNTER_VAR)
         }
     }
 
@@ -752,7 +793,8 @@ impl Loop {
 
 pub enum Loop {
     Repetition(u32,u16),
-    Average(u16)
+    Average(u16),
+    Slice(u16)
 }
 
 pub struct CalcBlock {
@@ -794,48 +836,73 @@ impl CalcBlock {
 pub struct FlatLoopStructure {
     outer:Loop,
     inner:Loop,
+    // innermost loop over the slices excited in one rep time
+    slices:Option<Loop>,
     rep_time:f32,
     calc_block:CalcBlock,
     exec_block:Vec<CommandString>
 }
 
 impl FlatLoopStructure {
-    pub fn new(repetitions:u32, averages:u16, rep_time:f32, event_queue:&mut EventQueue,acceleration:u16) -> Self {
+    pub fn new(repetitions:u32, averages:u16, slices:u16, rep_time:f32, event_queue:&mut EventQueue,acceleration:u16) -> Self {
+        if slices == 0 {panic!("there must be at least 1 slice")}
         // lock in events in the event queue so timing is accurate
         let calc_block = CalcBlock::new(event_queue.export_calc_blocks());
         let calc_time = calc_block.duration_clocks();
         let loop_time = FlatLoopStructure::loop_waittimer();
-        event_queue.set_rep_time(rep_time,loop_time,calc_time);
+        // the slices share the rep time evenly
+        event_queue.set_rep_time(rep_time/slices as f32,loop_time,calc_time);
         Self {
             outer:Loop::Repetition(repetitions,acceleration),
             inner:Loop::Average(averages),
+            slices:match slices {
+                1 => None,
+                n => Some(Loop::Slice(n))
+            },
             rep_time,
             calc_block,
             exec_block:event_queue.export_exec_blocks() // rep time must be set before exporting execs
         }
     }
-    fn loop_waittimer() -> i32 {
+    pub fn loop_waittimer() -> i32 {
         500
     }
+    /** Shortest rep time in clock periods that fits the event queue once for every slice */
+    pub fn min_rep_time_clocks(event_queue:&EventQueue,slices:u16) -> i32 {
+        let calc_time = CalcBlock::new(vec![]).duration_clocks();
+        slices as i32*event_queue.min_rep_time_clocks(FlatLoopStructure::loop_waittimer(),calc_time)
+    }
 
     pub fn print(&self) -> String {
 
         let exec_string_vec:Vec<String> = self.exec_block.iter().map(|block| block.commands.clone()).collect();
         let exec_string = exec_string_vec.join("\n");
 
-        vec![
+        let (slice_start,slice_end) = match &self.slices {
+            Some(slices) => (vec![slices.init_counter(),slices.start()],vec![slices.end()]),
+            None => (vec![],vec![])
+        };
+
+        let mut out = vec![
             ppl_function::start_timer(),
             self.outer.init_counter(),
             self.outer.start(),
             self.inner.init_counter(),
             self.inner.start(),
+        ];
+        out.extend(slice_start);
+        out.extend(vec![
             ppl_function::wait_timer(FlatLoopStructure::loop_waittimer()),
             self.calc_block.print(),
             exec_string,
             ppl_function::start_timer(),
+        ]);
+        out.extend(slice_end);
+        out.extend(vec![
             self.inner.end(),
             self.outer.end()
-        ].join("\n")
+        ]);
+        out.join("\n")
     }
     pub fn n_reps(&self) -> u32 {
         match self.outer {
@@ -843,6 +910,12 @@ impl FlatLoopStructure {
             _=> panic!("this loop structure must have an outer repetition loop")
         }
     }
+    pub fn n_slices(&self) -> u16 {
+        match self.slices {
+            Some(Loop::Slice(n)) => n,
+            _=> 1
+        }
+    }
     pub fn n_averages(&self) -> u32 {
         match self.outer {
             Loop::Average(n) => n as u32,
@@ -857,7 +930,7 @@ fn test(){
     let h = Header{
         dsp_routine:DspRoutine::Dsp,
         receiver_mask:1,
-        base_frequency:BaseFrequency::civm9p4t(0.0),
+        base_frequency:BaseFrequency::from_scanner(&ScannerProfile::civm9p4t(),0.0),
         samples:788,
         spectral_width: SpectralWidth::SW200kH,
         sample_discards:0,
@@ -865,11 +938,42 @@ fn test(){
         echos:4,
         echo_divisor:1,
         averages:1,
-        user_adjustments:None
+        user_adjustments:None,
+        scanner:ScannerProfile::civm9p4t(),
     };
 
-    println!("{}",h.print())
+    println!("{}",h.print());
+
+    // the observe frequency and digitizer setting come from the profile of the header
+    let mut scanner = ScannerProfile::civm9p4t();
+    scanner.base_freq_hz = 30E6;
+    scanner.receiver_bandwidths.push(crate::scanner::ReceiverBandwidth::new(500,7,3582));
+    let h = Header{
+        base_frequency:BaseFrequency::from_scanner(&scanner,100.0),
+        spectral_width:SpectralWidth::Custom(20_000),
+        scanner,
+        ..h
+    };
+    let ppr = h.print_ppr(Path::new("test.ppl"));
+    assert!(ppr.contains("30000100.0"));
+    assert!(ppr.contains(":SAMPLE_PERIOD sample_period, 500, 7"));
 }
 
 
 
+
+#[test]
+fn orientation_test(){
+    // custom orientations survive a round trip through the rotation matrix
+    for o in [Orientation::CivmStandard,Orientation::Scout2,Orientation::from_euler_deg(12.5,-30.0,71.2),Orientation::Custom(0,900,450)] {
+        let r = o.rotation_matrix();
+        let back = Orientation::from_rotation(&r).rotation_matrix();
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((r[i][j] - back[i][j]).abs() < 1E-3);
+            }
+        }
+    }
+    assert_eq!(Orientation::from_euler_deg(-90.0,0.0,0.0).base_matrix(),Orientation::CivmStandard.base_matrix());
+    assert!(Orientation::Custom(150,-200,0).print().contains("BASEMATRIX_LONG1(150,-200,0)"));
+}
@@ -96,6 +96,17 @@ pub fn set_base_freq() -> String {
     ].join("\n")
 }
 
+pub fn set_offset_freq(buffer:usize,offset_hz:i32) -> String {
+    [
+        format!("frequency_buffer({});",buffer),
+        format!("frequency(MHz, kHz, Hz {} {}, rx1MHz);",if offset_hz < 0 {"-"} else {"+"},offset_hz.abs()),
+    ].join("\n")
+}
+
+pub fn select_freq_buffer(buffer_var:&str) -> String {
+    format!("frequency_buffer({});",buffer_var)
+}
+
 pub fn set_discard_samples(discard_var:&str) -> String {
     format!("discard({});",discard_var)
 }
\ No newline at end of file
@@ -8,10 +8,17 @@
  */
 
 use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use serde_json;
 use crate::execution::PlotTrace;
 use crate::pulse_function::{Function, FunctionParams};
 use crate::_utils;
 use utils;
+use num_complex::Complex;
 
 
 pub trait Pulse {
@@ -101,6 +108,42 @@ impl Pulse for Trapezoid {
     }
 }
 
+/** Trapezoid preceded by a delay at zero. Lets a gradient start ramping after its event block has
+ended, e.g. when an acquisition has to sample the ramp from its very start */
+#[derive(Clone,Copy)]
+pub struct DelayedTrapezoid {
+    pub delay_time:f32,
+    pub trapezoid:Trapezoid,
+}
+
+impl DelayedTrapezoid {
+    pub fn new(delay_time:f32,ramp_time:f32,plateau_time:f32) -> DelayedTrapezoid {
+        assert!(delay_time >= 0.0,"delay time must be positive or 0");
+        DelayedTrapezoid{delay_time,trapezoid:Trapezoid::new(ramp_time,plateau_time)}
+    }
+}
+
+impl Pulse for DelayedTrapezoid {
+    fn duration(&self) -> f32 {
+        self.delay_time + self.trapezoid.duration()
+    }
+    fn function(&self,time_step_us:usize) -> Vec<Function>{
+        let n_delay_samples = _utils::sec_to_samples(self.delay_time, time_step_us);
+        let mut f = vec![Function::Plateau(FunctionParams::new(n_delay_samples,0.0))];
+        f.extend(self.trapezoid.function(time_step_us));
+        f
+    }
+    fn power_net(&self,magnitude:f32) -> f32 {
+        self.trapezoid.power_net(magnitude)
+    }
+    fn magnitude_net(&self,power:f32) -> f32 {
+        self.trapezoid.magnitude_net(power)
+    }
+    fn power_abs(&self,magnitude:f32) -> f32 {
+        self.trapezoid.power_abs(magnitude)
+    }
+}
+
 #[derive(Clone,Copy)]
 pub struct HalfSin {
     pub duration:f32,
@@ -262,3 +305,568 @@ impl Pulse for SincPulse {
         self.power_net(magnitude)
     }
 }
+
+/*
+ An arbitrary waveform is defined by samples on a uniform time grid. Every sample holds its value
+ for one sample period, so the waveform lasts as many periods as it has samples and its area is the
+ sum of the samples times the period, which is also the area of the rendered waveform. Samples are
+ normalized to a maximum unit magnitude. An optional phase waveform (degrees) is carried along for
+ rf pulses. Like the other rf pulses, zero end points are rendered just outside the duration.
+ Gradient waveforms are played exactly as sampled, because the gradient events end with their
+ waveform duration.
+
+ Waveforms designed elsewhere (vendor rf shapes, matlab gradient designs) can be loaded from file.
+ A csv file has one sample per line with an optional second column for phase in degrees. A json
+ file has the same fields as the struct.
+ */
+/** Why a waveform file can't be loaded */
+#[derive(Clone,Debug,PartialEq)]
+pub enum WaveformError {
+    // csv line (from 1) that can't be parsed
+    Parse(usize),
+    // csv line (from 1) with a phase column when the first sample has none, or the other way around
+    MixedPhase(usize),
+    // number of magnitude and phase samples
+    PhaseLength(usize,usize),
+    // number of samples found
+    TooShort(usize),
+    SamplePeriod(f32),
+    // deserialization error of a json file
+    Json(String),
+    // file that can't be read
+    Io(String),
+    // file that isn't csv or json
+    Extension(PathBuf),
+}
+
+impl fmt::Display for WaveformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaveformError::Parse(line) => write!(f,"cannot parse line {}",line),
+            WaveformError::MixedPhase(line) => write!(f,"line {} doesn't have the same columns as the first sample",line),
+            WaveformError::PhaseLength(n_mag,n_phase) => write!(f,"{} magnitude samples don't match {} phase samples",n_mag,n_phase),
+            WaveformError::TooShort(n) => write!(f,"arbitrary waveforms need at least 2 samples. Found {}",n),
+            WaveformError::SamplePeriod(period) => write!(f,"sample period must be positive. Found {}",period),
+            WaveformError::Json(e) => write!(f,"cannot deserialize waveform: {}",e),
+            WaveformError::Io(e) => write!(f,"cannot read waveform file: {}",e),
+            WaveformError::Extension(path) => write!(f,"unknown waveform file type {:?}. Use csv or json",path),
+        }
+    }
+}
+
+#[derive(Clone,Serialize,Deserialize)]
+pub struct ArbitraryWaveform {
+    sample_period:f32,
+    samples:Vec<f32>,
+    phase:Option<Vec<f32>>,
+    #[serde(default = "end_points_default")]
+    end_points:bool,
+}
+
+fn end_points_default() -> bool {
+    true
+}
+
+impl ArbitraryWaveform {
+    pub fn new(samples:Vec<f32>,sample_period:f32) -> Self {
+        assert!(sample_period > 0.0,"sample period must be positive");
+        assert!(samples.len() > 1,"arbitrary waveforms need at least 2 samples");
+        let max = samples.iter().fold(0.0f32,|acc,x| acc.max(x.abs()));
+        let samples = match max > 0.0 {
+            true => samples.iter().map(|x| x/max).collect(),
+            false => samples
+        };
+        Self {
+            sample_period,
+            samples,
+            phase:None,
+            end_points:true
+        }
+    }
+    /** A gradient waveform without the zero end points of rf pulses */
+    pub fn new_gradient(samples:Vec<f32>,sample_period:f32) -> Self {
+        let mut w = Self::new(samples,sample_period);
+        w.end_points = false;
+        w
+    }
+    pub fn new_with_phase(samples:Vec<f32>,phase_deg:Vec<f32>,sample_period:f32) -> Self {
+        assert_eq!(samples.len(),phase_deg.len(),"magnitude and phase waveforms must be the same length");
+        let mut w = Self::new(samples,sample_period);
+        w.phase = Some(phase_deg);
+        w
+    }
+    fn read_file(file_path:&Path) -> Result<String,WaveformError> {
+        let mut f = File::open(file_path).map_err(|e| WaveformError::Io(format!("{:?}: {}",file_path,e)))?;
+        let mut s = String::new();
+        f.read_to_string(&mut s).map_err(|e| WaveformError::Io(format!("{:?}: {}",file_path,e)))?;
+        Ok(s)
+    }
+    pub fn from_csv(file_path:&Path,sample_period:f32) -> Result<Self,WaveformError> {
+        let s = Self::read_file(file_path)?;
+        let mut samples = Vec::<f32>::new();
+        let mut phase = Vec::<f32>::new();
+        for (i,line) in s.lines().enumerate() {
+            let cols:Vec<&str> = line.split(|c:char| c == ',' || c.is_whitespace()).filter(|c| !c.is_empty()).collect();
+            if cols.is_empty() {continue}
+            let values:Vec<f32> = match cols.iter().map(|c| c.parse::<f32>()).collect() {
+                Ok(values) => values,
+                // a header line is allowed before any samples
+                Err(_) if samples.is_empty() => continue,
+                Err(_) => return Err(WaveformError::Parse(i+1))
+            };
+            // every sample has a phase or none of them do
+            if !samples.is_empty() && (values.len() > 1) != !phase.is_empty() {
+                return Err(WaveformError::MixedPhase(i+1))
+            }
+            samples.push(values[0]);
+            if values.len() > 1 {
+                phase.push(values[1]);
+            }
+        }
+        match phase.len() {
+            0 => Self::validated(samples,None,sample_period),
+            _ => Self::validated(samples,Some(phase),sample_period)
+        }
+    }
+    pub fn from_json(file_path:&Path) -> Result<Self,WaveformError> {
+        let s = Self::read_file(file_path)?;
+        let w:Self = serde_json::from_str(&s).map_err(|e| WaveformError::Json(e.to_string()))?;
+        // run through the constructors to normalize
+        let mut validated = Self::validated(w.samples,w.phase,w.sample_period)?;
+        validated.end_points = w.end_points;
+        Ok(validated)
+    }
+    /** Loads a waveform by file extension. The sample period is only used for csv files */
+    pub fn from_file(file_path:&Path,sample_period:f32) -> Result<Self,WaveformError> {
+        match file_path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(file_path),
+            Some("csv") | Some("txt") => Self::from_csv(file_path,sample_period),
+            _=> Err(WaveformError::Extension(file_path.to_owned()))
+        }
+    }
+    // checks loaded samples before they are passed to the constructors
+    fn validated(samples:Vec<f32>,phase:Option<Vec<f32>>,sample_period:f32) -> Result<Self,WaveformError> {
+        if sample_period <= 0.0 {
+            return Err(WaveformError::SamplePeriod(sample_period))
+        }
+        if samples.len() < 2 {
+            return Err(WaveformError::TooShort(samples.len()))
+        }
+        match phase {
+            Some(phase) if phase.len() != samples.len() => Err(WaveformError::PhaseLength(samples.len(),phase.len())),
+            Some(phase) => Ok(Self::new_with_phase(samples,phase,sample_period)),
+            None => Ok(Self::new(samples,sample_period))
+        }
+    }
+    pub fn sample_period(&self) -> f32 {
+        self.sample_period
+    }
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.clone()
+    }
+    pub fn end_points(&self) -> bool {
+        self.end_points
+    }
+    // samples held for their period, rendered every time step over the duration. Times are compared
+    // in double precision so a time step equal to the sample period returns the samples unchanged
+    fn resample(&self,waveform:&[f32],time_step_us:usize) -> Vec<f32> {
+        let period_us = self.sample_period as f64*1E6;
+        let n = (waveform.len() as f64*period_us/time_step_us as f64).round() as usize;
+        (0..n).map(|i| {
+            let index = ((i*time_step_us) as f64/period_us + 1E-6).floor() as usize;
+            waveform[index.min(waveform.len() - 1)]
+        }).collect()
+    }
+    // phase waveform in degrees resampled to the time step
+    pub fn phase(&self,time_step_us:usize) -> Option<Vec<f32>> {
+        self.phase.as_ref().map(|phase| self.resample(phase,time_step_us))
+    }
+    fn area(&self) -> f32 {
+        self.samples.iter().sum::<f32>()*self.sample_period
+    }
+    fn area_abs(&self) -> f32 {
+        self.samples.iter().map(|x| x.abs()).sum::<f32>()*self.sample_period
+    }
+}
+
+impl Pulse for ArbitraryWaveform {
+    fn duration(&self) -> f32 {
+        self.samples.len() as f32*self.sample_period
+    }
+    fn function(&self, time_step_us: usize) -> Vec<Function> {
+        let samples = crate::pulse_function::plateau_runs(&self.resample(&self.samples,time_step_us));
+        if !self.end_points {
+            return samples
+        }
+        let end_point = FunctionParams::new(1,0.0);
+        let mut functions = vec![Function::Plateau(end_point)];
+        functions.extend(samples);
+        functions.push(Function::Plateau(end_point));
+        functions
+    }
+    fn power_net(&self, magnitude: f32) -> f32 {
+        self.area()*magnitude
+    }
+    fn magnitude_net(&self, power_net: f32) -> f32 {
+        power_net/self.area()
+    }
+    fn power_abs(&self, magnitude: f32) -> f32 {
+        self.area_abs()*magnitude.abs()
+    }
+}
+
+fn n_function_samples(duration:f32,time_step_us:usize) -> usize {
+    (duration/_utils::us_to_sec(time_step_us as i32)).floor() as usize
+}
+
+// pulse area in normalized units found from a rendered waveform
+fn rendered_area(pulse:&dyn Pulse,abs:bool) -> f32 {
+    let w = pulse.render(2);
+    match abs {
+        true => utils::trapz(&utils::abs(&w),Some(2.0E-6)),
+        false => utils::trapz(&w,Some(2.0E-6))
+    }
+}
+
+/*
+ A gaussian pulse truncated to its duration. The width of the gaussian is set by the
+ time-bandwidth product, where the bandwidth is the full-width half-max of its spectrum.
+ */
+#[derive(Clone,Copy)]
+pub struct GaussianPulse {
+    duration:f32,
+    time_bandwidth:f32,
+}
+
+impl GaussianPulse {
+    pub fn new(duration:f32,time_bandwidth:f32) -> Self {
+        assert!(duration > 0.0,"duration must be positive");
+        assert!(time_bandwidth > 0.0,"time-bandwidth product must be positive");
+        Self {
+            duration,
+            time_bandwidth
+        }
+    }
+    // standard deviation as a fraction of the pulse duration (fwhm of the spectrum is 2*sqrt(2*ln2)/(2*pi*sigma))
+    fn sigma(&self) -> f32 {
+        (2.0*(2.0*2.0f32.ln()).sqrt())/(2.0*PI*self.time_bandwidth)
+    }
+}
+
+impl SliceSelective for GaussianPulse{}
+impl Pulse for GaussianPulse {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+    fn function(&self, time_step_us: usize) -> Vec<Function> {
+        let p = FunctionParams::new(n_function_samples(self.duration,time_step_us),1.0);
+        let end_point = FunctionParams::new(1,0.0);
+        vec![
+            Function::Plateau(end_point),
+            Function::Gaussian(self.sigma(),p),
+            Function::Plateau(end_point)
+        ]
+    }
+    fn power_net(&self, magnitude: f32) -> f32 {
+        rendered_area(self,false)*magnitude
+    }
+    fn magnitude_net(&self, power_net: f32) -> f32 {
+        power_net/rendered_area(self,false)
+    }
+    fn power_abs(&self, magnitude: f32) -> f32 {
+        rendered_area(self,true)*magnitude.abs()
+    }
+}
+
+/*
+ A sinc pulse with a hamming window applied to suppress the ringing of the slice profile
+ caused by truncating the side lobes.
+ */
+#[derive(Clone,Copy)]
+pub struct HammingSincPulse {
+    duration:f32,
+    n_lobes:u16,
+}
+
+impl HammingSincPulse {
+    pub fn new(duration:f32,lobes:u16) -> Self {
+        assert!(duration > 0.0,"duration must be positive");
+        let lobes = if lobes.is_multiple_of(2) {lobes+1} else {lobes};
+        Self {
+            duration,
+            n_lobes:lobes
+        }
+    }
+}
+
+impl SliceSelective for HammingSincPulse{}
+impl Pulse for HammingSincPulse {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+    fn function(&self, time_step_us: usize) -> Vec<Function> {
+        let p = FunctionParams::new(n_function_samples(self.duration,time_step_us),1.0);
+        let end_point = FunctionParams::new(1,0.0);
+        vec![
+            Function::Plateau(end_point),
+            Function::HammingSinc(self.n_lobes,p),
+            Function::Plateau(end_point)
+        ]
+    }
+    fn power_net(&self, magnitude: f32) -> f32 {
+        rendered_area(self,false)*magnitude
+    }
+    fn magnitude_net(&self, power_net: f32) -> f32 {
+        power_net/rendered_area(self,false)
+    }
+    fn power_abs(&self, magnitude: f32) -> f32 {
+        rendered_area(self,true)*magnitude.abs()
+    }
+}
+
+/*
+ Shinnar-Le Roux pulse designed from a hamming windowed sinc beta polynomial. Alpha is the minimum
+ phase polynomial that satisfies |a|^2 + |b|^2 = 1 and the rf is recovered with the inverse SLR transform.
+ Unlike a small tip angle design, the slice profile holds its shape for large flip angles.
+ */
+#[derive(Clone)]
+pub struct SlrPulse {
+    time_bandwidth:f32,
+    flip_angle_deg:f32,
+    waveform:ArbitraryWaveform,
+}
+
+const SLR_DESIGN_SAMPLES:usize = 256;
+
+impl SlrPulse {
+    pub fn new(duration:f32,time_bandwidth:f32,flip_angle_deg:f32) -> Self {
+        assert!(duration > 0.0,"duration must be positive");
+        assert!(time_bandwidth > 0.0,"time-bandwidth product must be positive");
+        assert!(flip_angle_deg > 0.0 && flip_angle_deg <= 180.0,"flip angle must be between 0 and 180 degrees");
+        let rf = slr_design(SLR_DESIGN_SAMPLES,time_bandwidth,flip_angle_deg.to_radians());
+        Self {
+            time_bandwidth,
+            flip_angle_deg,
+            waveform:ArbitraryWaveform::new(rf,duration/SLR_DESIGN_SAMPLES as f32)
+        }
+    }
+    pub fn time_bandwidth(&self) -> f32 {
+        self.time_bandwidth
+    }
+    pub fn flip_angle_deg(&self) -> f32 {
+        self.flip_angle_deg
+    }
+}
+
+// hamming windowed sinc with unit dc gain (msinc)
+fn windowed_sinc(n:usize,n_cycles:f32) -> Vec<f32> {
+    let h = n as f32/2.0;
+    let w:Vec<f32> = (0..n).map(|i| {
+        let x = (i as f32 - h)/h;
+        let arg = 2.0*PI*n_cycles*x + 1E-5;
+        (arg.sin()/arg)*(0.54 + 0.46*(PI*x).cos())
+    }).collect();
+    let sum:f32 = w.iter().sum();
+    w.iter().map(|x| x/sum).collect()
+}
+
+// returns the real part of the rf in radians per sample
+fn slr_design(n:usize,time_bandwidth:f32,flip_angle:f32) -> Vec<f32> {
+    let b:Vec<f32> = windowed_sinc(n,time_bandwidth/4.0).iter().map(|x| x*(flip_angle/2.0).sin()).collect();
+    let b = utils::real_to_complex(&b);
+    let a = min_phase_alpha(&b);
+    inverse_slr(a,b)
+}
+
+fn ifft(x:&[Complex<f32>]) -> Vec<Complex<f32>> {
+    let n = x.len();
+    let conj:Vec<_> = x.iter().map(|c| c.conj()).collect();
+    utils::fft(&conj,n).iter().map(|c| c.conj()/n as f32).collect()
+}
+
+// minimum phase alpha polynomial from the magnitude of alpha using the folded cepstrum
+fn min_phase_alpha(b:&[Complex<f32>]) -> Vec<Complex<f32>> {
+    let n = b.len();
+    let n_pad = 16*n;
+    let mut bp = b.to_vec();
+    bp.resize(n_pad,b[0]*0.0);
+    let bf = utils::fft(&bp,n_pad);
+    let log_am:Vec<f32> = bf.iter().map(|c| 0.5*(1.0 - c.norm_sqr()).max(1E-12).ln()).collect();
+    let mut cep = ifft(&utils::real_to_complex(&log_am));
+    for (i,c) in cep.iter_mut().enumerate() {
+        if i > 0 && i < n_pad/2 {*c *= 2.0}
+        else if i > n_pad/2 {*c *= 0.0}
+    }
+    let af:Vec<_> = utils::fft(&cep,n_pad).iter().map(|c| c.exp()).collect();
+    let mut a = ifft(&af);
+    a.truncate(n);
+    a
+}
+
+// peels off one hard pulse rotation at a time, starting from the end of the pulse
+fn inverse_slr(mut a:Vec<Complex<f32>>,mut b:Vec<Complex<f32>>) -> Vec<f32> {
+    let n = a.len();
+    let mut rf = vec![0.0;n];
+    for j in (0..n).rev() {
+        let ratio = b[0]/a[0];
+        let cj = (1.0/(1.0 + ratio.norm_sqr())).sqrt();
+        let sj = (ratio*cj).conj();
+        let phi = 2.0*sj.norm().atan2(cj);
+        rf[j] = phi*sj.arg().cos();
+        if j > 0 {
+            let at:Vec<_> = a.iter().zip(b.iter()).map(|(a,b)| *a*cj + *b*sj).collect();
+            let bt:Vec<_> = a.iter().zip(b.iter()).map(|(a,b)| -(*a*sj.conj()) + *b*cj).collect();
+            a = at[0..j].to_vec();
+            b = bt[1..j+1].to_vec();
+        }
+    }
+    rf
+}
+
+impl SliceSelective for SlrPulse{}
+impl Pulse for SlrPulse {
+    fn duration(&self) -> f32 {
+        self.waveform.duration()
+    }
+    fn function(&self, time_step_us: usize) -> Vec<Function> {
+        self.waveform.function(time_step_us)
+    }
+    fn power_net(&self, magnitude: f32) -> f32 {
+        self.waveform.power_net(magnitude)
+    }
+    fn magnitude_net(&self, power_net: f32) -> f32 {
+        self.waveform.magnitude_net(power_net)
+    }
+    fn power_abs(&self, magnitude: f32) -> f32 {
+        self.waveform.power_abs(magnitude)
+    }
+}
+
+/*
+ Hyperbolic secant adiabatic pulse. The amplitude is sech(beta*t) and the frequency is swept by
+ -mu*beta*tanh(beta*t), which is played out as a phase modulation of mu*ln(sech(beta*t)).
+ Above the adiabatic threshold the inversion is insensitive to B1.
+ */
+#[derive(Clone,Copy)]
+pub struct HyperbolicSecant {
+    duration:f32,
+    // argument of sech at the ends of the pulse
+    beta_extent:f32,
+    mu:f32,
+}
+
+impl HyperbolicSecant {
+    pub fn new(duration:f32,beta_extent:f32,mu:f32) -> Self {
+        assert!(duration > 0.0,"duration must be positive");
+        assert!(beta_extent > 0.0,"beta must be positive");
+        assert!(mu > 0.0,"mu must be positive");
+        Self {
+            duration,
+            beta_extent,
+            mu
+        }
+    }
+    // modulation angular frequency in rad/s
+    pub fn beta(&self) -> f32 {
+        2.0*self.beta_extent/self.duration
+    }
+    pub fn mu(&self) -> f32 {
+        self.mu
+    }
+    // minimum peak B1 in Hz for the pulse to behave adiabatically
+    pub fn adiabatic_threshold_hz(&self) -> f32 {
+        self.mu.sqrt()*self.beta()/(2.0*PI)
+    }
+    // phase modulation in degrees wrapped to [0,360) for each sample of the amplitude function
+    pub fn phase_deg(&self,time_step_us:usize) -> Vec<f32> {
+        let n = n_function_samples(self.duration,time_step_us);
+        let c = (n - 1) as f32/2.0;
+        let mut phase:Vec<f32> = (0..n).map(|i|{
+            let x = self.beta_extent*(i as f32 - c)/c;
+            (self.mu*(1.0/x.cosh()).ln()).to_degrees().rem_euclid(360.0)
+        }).collect();
+        // the phase is held over the zero magnitude end points of the pulse
+        phase.insert(0,phase[0]);
+        phase.push(phase[phase.len()-1]);
+        phase
+    }
+}
+
+impl SliceSelective for HyperbolicSecant {
+    // the pulse is frequency modulated so the bandwidth is the extent of the frequency sweep
+    fn bandwidth(&self) -> f32 {
+        self.mu*self.beta()/PI
+    }
+}
+impl Pulse for HyperbolicSecant {
+    fn duration(&self) -> f32 {
+        self.duration
+    }
+    fn function(&self, time_step_us: usize) -> Vec<Function> {
+        let p = FunctionParams::new(n_function_samples(self.duration,time_step_us),1.0);
+        let end_point = FunctionParams::new(1,0.0);
+        vec![
+            Function::Plateau(end_point),
+            Function::Sech(self.beta_extent,p),
+            Function::Plateau(end_point)
+        ]
+    }
+    fn power_net(&self, magnitude: f32) -> f32 {
+        rendered_area(self,false)*magnitude
+    }
+    fn magnitude_net(&self, power_net: f32) -> f32 {
+        power_net/rendered_area(self,false)
+    }
+    fn power_abs(&self, magnitude: f32) -> f32 {
+        rendered_area(self,true)*magnitude.abs()
+    }
+}
+
+#[test]
+fn test(){
+    // waveforms loaded from file are normalized and keep their phase
+    let dir = std::env::temp_dir();
+    let csv = dir.join("arb_waveform_test.csv");
+    std::fs::write(&csv,"magnitude,phase\n0,0\n2,90\n4,180\n2,270\n").expect("cannot write file");
+    let w = ArbitraryWaveform::from_file(&csv,10E-6).unwrap();
+    assert_eq!(w.samples(),vec![0.0,0.5,1.0,0.5]);
+    assert!((w.duration() - 40E-6).abs() < 1E-9);
+    // the area is the area of the rendered waveform over the full duration
+    assert!((w.power_net(1.0) - 20E-6).abs() < 1E-9);
+    assert!((w.power_net(1.0) - rendered_area(&w,false)).abs() < 1E-8);
+    assert_eq!(w.phase(10).unwrap()[2],180.0);
+    assert_eq!(w.phase(10).unwrap().len(),4);
+    std::fs::write(&csv,"0,0\n2\n4,180\n").expect("cannot write file");
+    assert_eq!(ArbitraryWaveform::from_file(&csv,10E-6).err(),Some(WaveformError::MixedPhase(2)));
+    std::fs::write(&csv,"0\n2\nx\n").expect("cannot write file");
+    assert_eq!(ArbitraryWaveform::from_file(&csv,10E-6).err(),Some(WaveformError::Parse(3)));
+    let json = dir.join("arb_waveform_test.json");
+    std::fs::write(&json,r#"{"sample_period":2E-6,"samples":[0.0,-3.0,1.5,0.0],"phase":null}"#).expect("cannot write file");
+    let w = ArbitraryWaveform::from_file(&json,1.0).unwrap();
+    assert_eq!(w.samples(),vec![0.0,-1.0,0.5,0.0]);
+    assert_eq!(w.render(2).len(),w.n_samples(2));
+    let unknown = dir.join("arb_waveform_test.bin");
+    assert_eq!(ArbitraryWaveform::from_file(&unknown,1.0).err(),Some(WaveformError::Extension(unknown.clone())));
+    assert!(matches!(ArbitraryWaveform::from_file(&dir.join("missing_waveform.csv"),1.0).err(),Some(WaveformError::Io(_))));
+
+    // dc flip angle of the slr design is the sum of the hard pulse rotations for a real pulse
+    for flip in [30.0f32,90.0,180.0] {
+        let rf = slr_design(SLR_DESIGN_SAMPLES,4.0,flip.to_radians());
+        let total:f32 = rf.iter().sum();
+        assert!((total.to_degrees() - flip).abs() < 0.02*flip,"slr flip angle for {} was {}",flip,total.to_degrees());
+    }
+    let slr = SlrPulse::new(2E-3,4.0,90.0);
+    assert_eq!(slr.render(2).len(),slr.n_samples(2));
+    assert!((slr.bandwidth() - 2000.0).abs() < 400.0);
+
+    let g = GaussianPulse::new(2E-3,2.0);
+    assert!((g.bandwidth() - 1000.0).abs() < 100.0);
+    let hs = HammingSincPulse::new(2E-3,3);
+    assert_eq!(hs.render(2)[0],0.0);
+
+    use crate::rf_frame::RfFrame;
+    let sech = HyperbolicSecant::new(8E-3,5.3,5.0);
+    let n_phase:usize = sech.phase_function(2).iter().map(|f| f.n_samples()).sum();
+    assert_eq!(n_phase,sech.n_samples(2));
+    assert!((sech.bandwidth() - 5.0*5.3*2.0/(8E-3*PI)).abs() < 1.0);
+}
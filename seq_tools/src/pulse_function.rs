@@ -19,7 +19,14 @@ pub enum Function {
     RampDown(FunctionParams),
     HalfSin(FunctionParams),
     Plateau(FunctionParams),
-    Sinc(u16,FunctionParams)
+    Sinc(u16,FunctionParams),
+    // gaussian with a standard deviation given as a fraction of the number of samples
+    Gaussian(f32,FunctionParams),
+    HammingSinc(u16,FunctionParams),
+    // hyperbolic secant with the argument at the end points of the function
+    Sech(f32,FunctionParams),
+    // ramp from a start value to the max value
+    Ramp(f32,FunctionParams)
 }
 
 impl Function {
@@ -40,6 +47,18 @@ impl Function {
             Function::Sinc(n_lobes,p) => {
                 sinc(p.max_value, *n_lobes, p.n_samples)
             }
+            Function::Gaussian(sigma,p) => {
+                gaussian(p.max_value, *sigma, p.n_samples)
+            }
+            Function::HammingSinc(n_lobes,p) => {
+                hamming_sinc(p.max_value, *n_lobes, p.n_samples)
+            }
+            Function::Sech(extent,p) => {
+                sech(p.max_value, *extent, p.n_samples)
+            }
+            Function::Ramp(start,p) => {
+                ramp(*start, p.max_value, p.n_samples)
+            }
         }
     }
     pub fn n_samples(&self) -> usize {
@@ -56,6 +75,8 @@ pub fn render_function_vector(functions:Vec<Function>) -> Vec<f32> {
 }
 
 fn ramp(start:f32,end:f32,n_samples:usize) -> Vec<f32>{
+    // trapezoids without a plateau have an empty plateau function
+    if n_samples == 0 {return vec![]}
     let step = (end-start)/(n_samples-1) as f32;
     let mut v:Vec<f32> = (0..n_samples).map(|i| start+((i as f32)*step)).collect();
     v.pop();
@@ -84,4 +105,66 @@ fn sinc(amplitude:f32,n_lobes:u16,n_samples:usize) -> Vec<f32> {
             _=> amplitude*(i.sin()/i)
         }
     ).collect()
-}
\ No newline at end of file
+}
+
+fn gaussian(amplitude:f32,sigma:f32,n_samples:usize) -> Vec<f32> {
+    //"{}*exp(-((Ñ-{c})*(Ñ-{c}))/{2*s*s})"
+    let c = (n_samples - 1) as f32/2.0;
+    let s = sigma*n_samples as f32;
+    (0..n_samples).map(|i| amplitude*(-(i as f32 - c).powi(2)/(2.0*s*s)).exp()).collect()
+}
+
+fn hamming_sinc(amplitude:f32,n_lobes:u16,n_samples:usize) -> Vec<f32> {
+    //"{}*(0.54+0.46*cos(PI*((Ñ-({}/2))/({}/2))))*sinc(PI*{}*((Ñ-({}/2))/({}/2)))"
+    let pi = std::f32::consts::PI;
+    let nsamp_over_2 = n_samples as f32/2.0;
+    sinc(amplitude,n_lobes,n_samples).iter().enumerate().map(|(i,s)|
+        s*(0.54 + 0.46*(pi*(i as f32 - nsamp_over_2)/nsamp_over_2).cos())
+    ).collect()
+}
+
+fn sech(amplitude:f32,extent:f32,n_samples:usize) -> Vec<f32> {
+    //"{}*2/(exp({x})+exp(-{x}))" with x = {extent}*((Ñ-{c})/{c})
+    let c = (n_samples - 1) as f32/2.0;
+    (0..n_samples).map(|i| amplitude/(extent*(i as f32 - c)/c).cosh()).collect()
+}
+
+// describes sampled data as a series of plateaus to support arbitrary waveforms
+pub fn plateau_runs(samples:&[f32]) -> Vec<Function> {
+    let mut functions = Vec::<Function>::new();
+    let mut i = 0;
+    while i < samples.len() {
+        let mut n = 1;
+        while i + n < samples.len() && samples[i + n] == samples[i] {
+            n += 1;
+        }
+        functions.push(Function::Plateau(FunctionParams::new(n,samples[i])));
+        i += n;
+    }
+    functions
+}
+
+/** Piecewise linear description of sampled data, where no sample is further than the tolerance from
+its segment. Segments are broken where neighboring samples jump by more than max_jump, so a wrapped
+phase is never ramped across the wrap */
+pub fn linear_runs(samples:&[f32],tolerance:f32,max_jump:f32) -> Vec<Function> {
+    let fits = |i:usize,j:usize| (i..=j).all(|k|{
+        let line = samples[i] + (samples[j] - samples[i])*(k - i) as f32/(j - i).max(1) as f32;
+        (line - samples[k]).abs() <= tolerance
+    });
+    let mut functions = Vec::<Function>::new();
+    let mut i = 0;
+    while i < samples.len() {
+        let mut j = i;
+        while j + 1 < samples.len() && (samples[j + 1] - samples[j]).abs() <= max_jump && fits(i,j + 1) {
+            j += 1;
+        }
+        let params = FunctionParams::new(j - i + 1,samples[j]);
+        match samples[i] == samples[j] {
+            true => functions.push(Function::Plateau(params)),
+            false => functions.push(Function::Ramp(samples[i],params))
+        }
+        i = j + 1;
+    }
+    functions
+}
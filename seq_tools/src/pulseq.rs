@@ -0,0 +1,1102 @@
+/*
+    Pulseq export of an event queue. The loop structure of the pulse program is unrolled such that every
+    view and average gets its own set of blocks. Events with overlapping waveforms are grouped into a single
+    pulseq block, and gradients are written as arbitrary waveforms on the gradient raster. Gradients are written
+    in the logical frame (read,phase,slice) -> (x,y,z)
+    https://pulseq.github.io/specification.pdf (v1.4)
+ */
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use crate::_utils;
+use crate::event_block::EventQueue;
+use crate::execution::{EventType, WaveformData};
+use crate::ppl::{CalcBlock, FlatLoopStructure, PhaseUnit};
+use crate::rf_state::RfStateError;
+use crate::scanner::ScannerProfile;
+
+const PULSEQ_EXT:&str = "seq";
+const PULSEQ_VERSION:(u8,u8,u8) = (1,4,1);
+// block durations and adc timing are resolved to the hardware clock (100 ns)
+const BLOCK_DURATION_RASTER_SEC:f32 = 100E-9;
+const ADC_RASTER_SEC:f32 = 100E-9;
+
+pub struct PulseqParams {
+    pub name:String,
+    pub n_repetitions:u32,
+    pub n_averages:u16,
+    pub rep_time:f32,
+    pub view_acceleration:u16,
+    pub waveform_sample_period_us:usize,
+    pub phase_unit:PhaseUnit,
+    // dac*seconds for a 90 degree hard pulse (from rf calibration)
+    pub rf_dac_seconds:f32,
+}
+
+/** Why a repetition can't be unrolled */
+#[derive(Clone,Debug,PartialEq)]
+pub enum PulseqError {
+    // event label, driver value and why its phase can't be evaluated
+    Phase(String,u32,RfStateError),
+    // blocks overlap after rounding to the gradient raster
+    Overlap,
+    // shortest rep time that fits every event (seconds)
+    RepTime{min:f32},
+    MultipleRf,
+    MultipleAdc,
+    // file that can't be read
+    Io(String),
+    // line or reference of an imported file that can't be parsed
+    Parse(String),
+    // major and minor version of an imported file that isn't pulseq 1.4
+    Version(u32,u32),
+    // part of an imported file that can't be represented as a pulse program
+    Unsupported(String),
+}
+
+impl fmt::Display for PulseqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PulseqError::Phase(label,driver_val,e) => write!(f,"cannot evaluate the phase of {} for driver value {}: {}",label,driver_val,e),
+            PulseqError::Overlap => write!(f,"pulseq blocks overlap after rounding to the gradient raster. Is the raster too coarse?"),
+            PulseqError::RepTime{min} => write!(f,"rep time is too short to fit all events. It needs to be at least {} seconds",min),
+            PulseqError::MultipleRf => write!(f,"more than one rf event overlaps in time. This cannot be represented in pulseq"),
+            PulseqError::MultipleAdc => write!(f,"more than one acquisition overlaps in time. This cannot be represented in pulseq"),
+            PulseqError::Io(e) => write!(f,"cannot read pulseq file: {}",e),
+            PulseqError::Parse(e) => write!(f,"cannot parse pulseq file: {}",e),
+            PulseqError::Version(major,minor) => write!(f,"only pulseq version 1.4 files are supported. Found {}.{}",major,minor),
+            PulseqError::Unsupported(e) => write!(f,"unsupported pulseq feature: {}",e),
+        }
+    }
+}
+
+/* an event in absolute time (clock cycles from the start of the repetition) */
+struct TimedWaveform {
+    start:i32,
+    end:i32,
+    kind:EventType,
+    wave_data:WaveformData,
+    phase:Option<i16>,
+}
+
+#[derive(Clone,Copy,Default)]
+struct Block {
+    duration:i32,
+    rf:u32,
+    gx:u32,
+    gy:u32,
+    gz:u32,
+    adc:u32,
+}
+
+/* look up table for de-duplicating shapes and events. Ids start at 1 */
+struct Library {
+    entries:Vec<String>,
+    ids:HashMap<String,u32>,
+}
+
+impl Library {
+    fn new() -> Self {
+        Self {
+            entries:Vec::<String>::new(),
+            ids:HashMap::<String,u32>::new()
+        }
+    }
+    fn insert(&mut self,entry:String) -> u32 {
+        match self.ids.get(&entry) {
+            Some(id) => *id,
+            None => {
+                self.entries.push(entry.clone());
+                let id = self.entries.len() as u32;
+                self.ids.insert(entry,id);
+                id
+            }
+        }
+    }
+    fn print(&self) -> Vec<String> {
+        self.entries.iter().enumerate().map(|(i,entry)| format!("{} {}",i+1,entry)).collect()
+    }
+}
+
+pub struct Pulseq {
+    params:PulseqParams,
+    // gradient calibration the event dac values are converted with
+    scanner:ScannerProfile,
+    blocks:Vec<Block>,
+    rf:Library,
+    gradients:Library,
+    adc:Library,
+    shapes:Library,
+}
+
+impl Pulseq {
+    pub fn new(event_queue:&EventQueue,params:PulseqParams,scanner:&ScannerProfile) -> Result<Self,PulseqError> {
+        let mut seq = Self {
+            params,
+            scanner:scanner.clone(),
+            blocks:Vec::<Block>::new(),
+            rf:Library::new(),
+            gradients:Library::new(),
+            adc:Library::new(),
+            shapes:Library::new(),
+        };
+        seq.unroll(event_queue)?;
+        Ok(seq)
+    }
+
+    fn raster_clocks(&self) -> i32 {
+        _utils::us_to_clock(self.params.waveform_sample_period_us as i32)
+    }
+
+    fn phase_to_rad(&self,phase:i16) -> f32 {
+        // 400 minimum phase units is 90 degrees
+        (phase as f32)*(self.params.phase_unit.value() as f32)*(PI/2.0)/400.0
+    }
+
+    fn hz_per_rf_dac(&self) -> f32 {
+        // a 90 degree hard pulse has an area of 1/4 cycle
+        0.25/self.params.rf_dac_seconds
+    }
+
+    /** Render the event queue for a driver value with event timing relative to the start of the repetition */
+    fn timed_waveforms(&self,event_queue:&EventQueue,driver_val:u32) -> Result<Vec<TimedWaveform>,PulseqError> {
+        let events = event_queue.events();
+        let first_block_start = events[0].borrow().block_start();
+        // time spent waiting on the loop and calculating the next state before events are executed
+        let pre_delay = FlatLoopStructure::loop_waittimer() + CalcBlock::new(vec![]).duration_clocks();
+        events.iter().map(|event|{
+            let e = event.borrow();
+            let start = e.waveform_start() - first_block_start + pre_delay;
+            let end = start + e.execution.time_to_end() - e.execution.time_to_start();
+            let phase = e.execution.phase_value(driver_val).map_err(|err| PulseqError::Phase(e.execution.label(),driver_val,err))?;
+            Ok(TimedWaveform {
+                start,
+                end,
+                kind:e.execution.kind(),
+                wave_data:e.execution.render_magnitude(self.params.waveform_sample_period_us,driver_val),
+                phase,
+            })
+        }).collect()
+    }
+
+    /** Unroll the view and average loops into pulseq blocks */
+    fn unroll(&mut self,event_queue:&EventQueue) -> Result<(),PulseqError> {
+        let tr = _utils::sec_to_clock(self.params.rep_time);
+        for rep in 0..self.params.n_repetitions {
+            let driver_val = rep*self.params.view_acceleration as u32;
+            let waveforms = self.timed_waveforms(event_queue,driver_val)?;
+            let rep_blocks = self.repetition_blocks(&waveforms,tr)?;
+            for _ in 0..self.params.n_averages {
+                self.blocks.extend(rep_blocks.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /** Group overlapping waveforms into blocks and fill the gaps with delays */
+    fn repetition_blocks(&mut self,waveforms:&[TimedWaveform],tr:i32) -> Result<Vec<Block>,PulseqError> {
+        let raster = self.raster_clocks();
+        let mut order:Vec<usize> = (0..waveforms.len()).collect();
+        order.sort_by_key(|&i| waveforms[i].start);
+
+        // clusters of waveform indices that overlap in time
+        let mut clusters = Vec::<(i32,i32,Vec<usize>)>::new();
+        for i in order {
+            let w = &waveforms[i];
+            match clusters.last_mut() {
+                Some((_,end,members)) if w.start < *end => {
+                    members.push(i);
+                    if w.end > *end {*end = w.end}
+                }
+                _=> clusters.push((w.start,w.end,vec![i]))
+            }
+        }
+        // block durations are rounded up to the gradient raster
+        clusters.iter_mut().for_each(|(start,end,_)|{
+            let n = (*end - *start + raster - 1)/raster;
+            *end = *start + n*raster;
+        });
+
+        let mut blocks = Vec::<Block>::new();
+        let mut t = 0;
+        for (start,end,members) in clusters.iter() {
+            if *start < t {return Err(PulseqError::Overlap)}
+            if *start > t {
+                blocks.push(Block{duration:*start - t,..Default::default()});
+            }
+            blocks.push(self.block(waveforms,*start,*end,members)?);
+            t = *end;
+        }
+        if t > tr {return Err(PulseqError::RepTime{min:_utils::clock_to_sec(t)})}
+        if tr > t {
+            blocks.push(Block{duration:tr - t,..Default::default()});
+        }
+        Ok(blocks)
+    }
+
+    fn block(&mut self,waveforms:&[TimedWaveform],start:i32,end:i32,members:&[usize]) -> Result<Block,PulseqError> {
+        let raster = self.raster_clocks();
+        let n_raster = ((end - start)/raster) as usize;
+        let mut block = Block{duration:end - start,..Default::default()};
+        let mut grads = vec![vec![0.0;n_raster];3];
+        let mut active = [false;3];
+        for &i in members.iter() {
+            let w = &waveforms[i];
+            let offset = w.start - start;
+            match &w.wave_data {
+                WaveformData::Rf(amp,phase) => {
+                    if block.rf != 0 {return Err(PulseqError::MultipleRf)}
+                    block.rf = self.add_rf(&amp.y,&phase.y,offset,w.phase.unwrap_or(0));
+                }
+                WaveformData::Grad(r,p,s) => {
+                    let first_sample = ((offset + raster/2)/raster) as usize;
+                    for (channel,trace) in [r,p,s].iter().enumerate() {
+                        if let Some(trace) = trace {
+                            active[channel] = true;
+                            trace.y.iter().enumerate().for_each(|(j,dac)|{
+                                let idx = (first_sample + j).min(n_raster-1);
+                                // hz/mm -> hz/m
+                                grads[channel][idx] += self.scanner.dac_to_hz_per_mm(*dac as i16)*1000.0;
+                            });
+                        }
+                    }
+                }
+                WaveformData::Acq(_) => {
+                    if block.adc != 0 {return Err(PulseqError::MultipleAdc)}
+                    let (n_samples,dwell_ns) = match &w.kind {
+                        EventType::Acq(sample_rate,n_samples,_) => (*n_samples,100.0*sample_rate.sample_period_clocks() as f32),
+                        _=> panic!("acquisition waveform must come from an acquisition event")
+                    };
+                    let adc = format!("{} {:.0} {} 0 {:.6}",n_samples,dwell_ns,
+                                      _utils::clock_to_us(offset),self.phase_to_rad(w.phase.unwrap_or(0)));
+                    block.adc = self.adc.insert(adc);
+                }
+            }
+        }
+        block.gx = if active[0] {self.add_grad(&grads[0])} else {0};
+        block.gy = if active[1] {self.add_grad(&grads[1])} else {0};
+        block.gz = if active[2] {self.add_grad(&grads[2])} else {0};
+        Ok(block)
+    }
+
+    fn add_shape(&mut self,samples:&[f32]) -> u32 {
+        let s:Vec<String> = samples.iter().map(|x| format!("{:.6}",x)).collect();
+        self.shapes.insert(format!("{}\n{}",samples.len(),s.join("\n")))
+    }
+
+    fn add_rf(&mut self,amplitude_dac:&[f32],phase:&[f32],offset:i32,phase_state:i16) -> u32 {
+        let hz:Vec<f32> = amplitude_dac.iter().map(|dac| dac*self.hz_per_rf_dac()).collect();
+        let peak = hz.iter().fold(0.0f32,|acc,x| acc.max(x.abs()));
+        let (magnitude,phase_shape):(Vec<f32>,Vec<f32>) = hz.iter().zip(phase.iter()).map(|(a,p)|{
+            // the normalized phase trace is in units of 90 degrees. Negative amplitudes are a 180 degree phase shift
+            let mut cycles = p/4.0;
+            if *a < 0.0 {cycles += 0.5}
+            let mag = if peak > 0.0 {a.abs()/peak} else {0.0};
+            (mag,cycles.rem_euclid(1.0))
+        }).unzip();
+        let mag_id = self.add_shape(&magnitude);
+        let phase_id = self.add_shape(&phase_shape);
+        let rf = format!("{:.3} {} {} 0 {} 0 {:.6}",peak,mag_id,phase_id,_utils::clock_to_us(offset),self.phase_to_rad(phase_state));
+        self.rf.insert(rf)
+    }
+
+    fn add_grad(&mut self,hz_per_m:&[f32]) -> u32 {
+        // normalize to the sample with the largest magnitude to keep the shape independent of polarity
+        let peak = hz_per_m.iter().fold(0.0f32,|acc,x| if x.abs() > acc.abs() {*x} else {acc});
+        let shape:Vec<f32> = hz_per_m.iter().map(|x| if peak != 0.0 {x/peak} else {0.0}).collect();
+        let shape_id = self.add_shape(&shape);
+        let grad = format!("{:.3} {:.3} {:.3} {} 0 0",peak,hz_per_m[0],hz_per_m[hz_per_m.len()-1],shape_id);
+        self.gradients.insert(grad)
+    }
+
+    pub fn total_duration(&self) -> f32 {
+        self.blocks.iter().map(|block| block.duration as f64).sum::<f64>() as f32 * BLOCK_DURATION_RASTER_SEC
+    }
+
+    pub fn print(&self) -> String {
+        let raster_sec = _utils::us_to_sec(self.params.waveform_sample_period_us as i32);
+        let mut out = vec![
+            String::from("# Pulseq sequence file"),
+            String::from("# Created by seq_tools"),
+            String::new(),
+            String::from("[VERSION]"),
+            format!("major {}",PULSEQ_VERSION.0),
+            format!("minor {}",PULSEQ_VERSION.1),
+            format!("revision {}",PULSEQ_VERSION.2),
+            String::new(),
+            String::from("[DEFINITIONS]"),
+            format!("AdcRasterTime {:e}",ADC_RASTER_SEC),
+            format!("BlockDurationRaster {:e}",BLOCK_DURATION_RASTER_SEC),
+            format!("GradientRasterTime {:e}",raster_sec),
+            format!("RadiofrequencyRasterTime {:e}",raster_sec),
+            format!("Name {}",self.params.name),
+            format!("TotalDuration {}",self.total_duration()),
+            String::new(),
+            String::from("# Format of blocks:"),
+            String::from("# NUM DUR RF  GX  GY  GZ  ADC  EXT"),
+            String::from("[BLOCKS]"),
+        ];
+        self.blocks.iter().enumerate().for_each(|(i,b)|{
+            out.push(format!("{} {} {} {} {} {} {} 0",i+1,b.duration,b.rf,b.gx,b.gy,b.gz,b.adc));
+        });
+        if !self.rf.entries.is_empty() {
+            out.push(String::new());
+            out.push(String::from("# Format of RF events:"));
+            out.push(String::from("# id amplitude mag_id phase_id time_shape_id delay freq phase"));
+            out.push(String::from("# ..        Hz   ....     ....          ....    us   Hz   rad"));
+            out.push(String::from("[RF]"));
+            out.extend(self.rf.print());
+        }
+        if !self.gradients.entries.is_empty() {
+            out.push(String::new());
+            out.push(String::from("# Format of arbitrary gradients:"));
+            out.push(String::from("# id amplitude first last amp_shape_id time_shape_id delay"));
+            out.push(String::from("# ..      Hz/m  Hz/m Hz/m        ....          ....    us"));
+            out.push(String::from("[GRADIENTS]"));
+            out.extend(self.gradients.print());
+        }
+        if !self.adc.entries.is_empty() {
+            out.push(String::new());
+            out.push(String::from("# Format of ADC events:"));
+            out.push(String::from("# id num dwell delay freq phase"));
+            out.push(String::from("# ..  ..    ns    us   Hz   rad"));
+            out.push(String::from("[ADC]"));
+            out.extend(self.adc.print());
+        }
+        if !self.shapes.entries.is_empty() {
+            out.push(String::new());
+            out.push(String::from("# Sequence Shapes"));
+            out.push(String::from("[SHAPES]"));
+            self.shapes.entries.iter().enumerate().for_each(|(i,shape)|{
+                out.push(String::new());
+                out.push(format!("shape_id {}",i+1));
+                out.push(format!("num_samples {}",shape));
+            });
+        }
+        out.push(String::new());
+        out.join("\n")
+    }
+
+    pub fn write(&self,filepath:&Path) {
+        let mut f = File::create(filepath.with_extension(PULSEQ_EXT)).expect("cannot create file");
+        f.write_all(self.print().as_bytes()).expect("trouble writing to file");
+    }
+}
+
+
+/*
+    Pulseq import. A pulseq file is a flat list of blocks for the entire scan. The file is split into
+    repetitions with the same block structure, and the first repetition becomes the event queue. Gradient
+    amplitudes that change between repetitions (phase encoding) are driven by a look-up table that is
+    written alongside the pulse program. Everything else must be the same for every repetition.
+ */
+
+#[derive(Clone,Copy,PartialEq)]
+struct PulseqBlock {
+    duration:i64,
+    rf:u32,
+    grad:[u32;3],
+    adc:u32,
+}
+
+#[derive(Clone)]
+struct PulseqRf {
+    amplitude:f32,
+    mag_id:u32,
+    phase_id:u32,
+    time_shape_id:u32,
+    delay_us:f32,
+    freq:f32,
+    phase:f32,
+}
+
+#[derive(Clone)]
+enum PulseqGrad {
+    Arbitrary{amplitude:f32,shape_id:u32,time_shape_id:u32,delay_us:f32},
+    Trapezoid{amplitude:f32,rise_us:f32,flat_us:f32,fall_us:f32,delay_us:f32},
+}
+
+#[derive(Clone)]
+struct PulseqAdc {
+    n_samples:u16,
+    dwell_ns:f32,
+    delay_us:f32,
+    freq:f32,
+    phase:f32,
+}
+
+pub struct PulseqReader {
+    block_raster:f32,
+    grad_raster:f32,
+    rf_raster:f32,
+    blocks:Vec<PulseqBlock>,
+    rf:HashMap<u32,PulseqRf>,
+    gradients:HashMap<u32,PulseqGrad>,
+    adc:HashMap<u32,PulseqAdc>,
+    shapes:HashMap<u32,Vec<f32>>,
+}
+
+/* event queue and loop parameters recovered from a pulseq file */
+pub struct PulseqImport {
+    pub event_queue:EventQueue,
+    pub n_repetitions:u32,
+    pub rep_time:f32,
+    // phase encoding look-up table (phase,slice pairs per view) for gradients that change every repetition
+    pub lut:Option<Vec<i16>>,
+}
+
+impl PulseqImport {
+    pub fn write_lut(&self,filepath:&Path) {
+        let lut = self.lut.as_ref().expect("imported sequence does not have a look-up table");
+        let s:Vec<String> = lut.iter().map(|val| val.to_string()).collect();
+        let mut f = File::create(filepath).expect("cannot create file");
+        f.write_all(s.join("\n").as_bytes()).expect("trouble writing to file");
+    }
+}
+
+fn parse_values(line:&str,min_values:usize) -> Result<Vec<f32>,PulseqError> {
+    let v = line.split_whitespace().map(|val| val.parse::<f32>()).collect::<Result<Vec<f32>,_>>()
+        .map_err(|_| PulseqError::Parse(format!("cannot parse value in line: {}",line)))?;
+    if v.len() < min_values {return Err(PulseqError::Parse(format!("expected {} values in line: {}",min_values,line)))}
+    Ok(v)
+}
+
+/* pulseq shapes are run-length encoded derivatives when the number of stored samples is less than num_samples */
+fn decompress_shape(packed:&[f32],n_samples:usize) -> Result<Vec<f32>,PulseqError> {
+    if packed.len() == n_samples {
+        return Ok(packed.to_vec());
+    }
+    let mut derivative = Vec::<f32>::with_capacity(n_samples);
+    let mut i = 0;
+    while i + 1 < packed.len() {
+        if packed[i] != packed[i+1] {
+            derivative.push(packed[i]);
+            i += 1;
+        }else {
+            let n_rep = *packed.get(i+2).ok_or(PulseqError::Parse(String::from("shape ends in the middle of a run")))? as usize + 2;
+            derivative.extend(vec![packed[i];n_rep]);
+            i += 3;
+        }
+    }
+    if i + 1 == packed.len() {
+        derivative.push(packed[i]);
+    }
+    if derivative.len() != n_samples {
+        return Err(PulseqError::Parse(format!("shape has {} samples, expected {}",derivative.len(),n_samples)))
+    }
+    let mut sum = 0.0;
+    Ok(derivative.iter().map(|d| {sum += d; sum}).collect())
+}
+
+impl PulseqReader {
+    pub fn open(filepath:&Path) -> Result<Self,PulseqError> {
+        let s = std::fs::read_to_string(filepath).map_err(|e| PulseqError::Io(format!("{:?}: {}",filepath,e)))?;
+        Self::parse(&s)
+    }
+
+    fn parse(text:&str) -> Result<Self,PulseqError> {
+        let mut reader = Self {
+            block_raster:10E-6,
+            grad_raster:10E-6,
+            rf_raster:1E-6,
+            blocks:Vec::<PulseqBlock>::new(),
+            rf:HashMap::new(),
+            gradients:HashMap::new(),
+            adc:HashMap::new(),
+            shapes:HashMap::new(),
+        };
+        let mut version = (0,0);
+        let mut section = String::new();
+        let mut shape:Option<(u32,usize,Vec<f32>)> = None;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {continue}
+            if line.starts_with('[') {
+                section = line.to_string();
+                continue
+            }
+            match section.as_str() {
+                "[VERSION]" => {
+                    let mut kv = line.split_whitespace();
+                    match (kv.next(),kv.next()) {
+                        (Some("major"),Some(v)) => version.0 = v.parse::<u32>().map_err(|_| PulseqError::Parse(format!("cannot parse version: {}",line)))?,
+                        (Some("minor"),Some(v)) => version.1 = v.parse::<u32>().map_err(|_| PulseqError::Parse(format!("cannot parse version: {}",line)))?,
+                        _=> {}
+                    }
+                }
+                "[DEFINITIONS]" => {
+                    let mut kv = line.split_whitespace();
+                    let key = kv.next().unwrap_or("");
+                    let val = kv.next().map(|v| v.parse::<f32>());
+                    match (key,val) {
+                        ("BlockDurationRaster",Some(Ok(v))) => reader.block_raster = v,
+                        ("GradientRasterTime",Some(Ok(v))) => reader.grad_raster = v,
+                        ("RadiofrequencyRasterTime",Some(Ok(v))) => reader.rf_raster = v,
+                        _=> {}
+                    }
+                }
+                "[BLOCKS]" => {
+                    let v = parse_values(line,7)?;
+                    reader.blocks.push(PulseqBlock{
+                        duration:v[1] as i64,
+                        rf:v[2] as u32,
+                        grad:[v[3] as u32,v[4] as u32,v[5] as u32],
+                        adc:v[6] as u32,
+                    });
+                }
+                "[RF]" => {
+                    let v = parse_values(line,8)?;
+                    reader.rf.insert(v[0] as u32,PulseqRf{
+                        amplitude:v[1],
+                        mag_id:v[2] as u32,
+                        phase_id:v[3] as u32,
+                        time_shape_id:v[4] as u32,
+                        delay_us:v[5],
+                        freq:v[6],
+                        phase:v[7],
+                    });
+                }
+                "[GRADIENTS]" => {
+                    let v = parse_values(line,7)?;
+                    reader.gradients.insert(v[0] as u32,PulseqGrad::Arbitrary{
+                        amplitude:v[1],
+                        shape_id:v[4] as u32,
+                        time_shape_id:v[5] as u32,
+                        delay_us:v[6],
+                    });
+                }
+                "[TRAP]" => {
+                    let v = parse_values(line,6)?;
+                    reader.gradients.insert(v[0] as u32,PulseqGrad::Trapezoid{
+                        amplitude:v[1],
+                        rise_us:v[2],
+                        flat_us:v[3],
+                        fall_us:v[4],
+                        delay_us:v[5],
+                    });
+                }
+                "[ADC]" => {
+                    let v = parse_values(line,6)?;
+                    reader.adc.insert(v[0] as u32,PulseqAdc{
+                        n_samples:v[1] as u16,
+                        dwell_ns:v[2],
+                        delay_us:v[3],
+                        freq:v[4],
+                        phase:v[5],
+                    });
+                }
+                "[SHAPES]" => {
+                    let mut kv = line.split_whitespace();
+                    match (kv.next(),kv.next()) {
+                        (Some("shape_id"),Some(id)) => {
+                            if let Some((id,n,packed)) = shape.take() {
+                                reader.shapes.insert(id,decompress_shape(&packed,n)?);
+                            }
+                            let id = id.parse::<u32>().map_err(|_| PulseqError::Parse(format!("cannot parse shape id: {}",line)))?;
+                            shape = Some((id,0,vec![]));
+                        }
+                        (Some("num_samples"),Some(n)) => {
+                            let s = shape.as_mut().ok_or(PulseqError::Parse(String::from("num_samples found before shape_id")))?;
+                            s.1 = n.parse::<usize>().map_err(|_| PulseqError::Parse(format!("cannot parse number of samples: {}",line)))?;
+                        }
+                        _=> {
+                            let s = shape.as_mut().ok_or(PulseqError::Parse(String::from("shape samples found before shape_id")))?;
+                            s.2.extend(parse_values(line,1)?);
+                        }
+                    }
+                }
+                _=> {/* extensions and signatures are ignored */}
+            }
+        }
+        if let Some((id,n,packed)) = shape.take() {
+            reader.shapes.insert(id,decompress_shape(&packed,n)?);
+        }
+        if version.0 != 1 || version.1 < 4 {
+            return Err(PulseqError::Version(version.0,version.1))
+        }
+        Ok(reader)
+    }
+
+    fn shape(&self,id:u32) -> Result<Vec<f32>,PulseqError> {
+        self.shapes.get(&id).cloned().ok_or(PulseqError::Parse(format!("shape {} not found",id)))
+    }
+
+    fn gradient(&self,id:u32) -> Result<&PulseqGrad,PulseqError> {
+        self.gradients.get(&id).ok_or(PulseqError::Parse(format!("gradient {} not found",id)))
+    }
+
+    /** Largest gradient (hz/m) of a waveform, signed by the amplitude. A missing gradient (id 0) is zero */
+    fn grad_peak(&self,id:u32) -> Result<f32,PulseqError> {
+        if id == 0 {return Ok(0.0)}
+        let amplitude = match self.gradient(id)? {
+            PulseqGrad::Arbitrary{amplitude,..} => *amplitude,
+            PulseqGrad::Trapezoid{amplitude,..} => *amplitude,
+        };
+        let (shape,_) = self.grad_shape(id)?;
+        Ok(amplitude*shape.iter().fold(0.0f32,|acc,x| acc.max(x.abs())))
+    }
+
+    fn block_clocks(&self,duration:i64) -> i32 {
+        (duration as f64*self.block_raster as f64*1E7).round() as i32
+    }
+
+    /** The smallest number of blocks that repeats with the same structure over the whole file. Gradients
+    are left out of the structure because zero-area gradients are often dropped from their blocks */
+    fn blocks_per_repetition(&self) -> usize {
+        let n = self.blocks.len();
+        let structure:Vec<(i64,bool,bool)> = self.blocks.iter().map(|b|
+            (b.duration,b.rf != 0,b.adc != 0)
+        ).collect();
+        (1..=n).find(|&p| n.is_multiple_of(p) && (0..n).all(|i| structure[i] == structure[i%p])).unwrap()
+    }
+
+    /** Gradient samples at unit amplitude on the gradient raster and delay from the block start */
+    fn grad_shape(&self,id:u32) -> Result<(Vec<f32>,f32),PulseqError> {
+        let raster_us = self.grad_raster*1E6;
+        match self.gradient(id)? {
+            PulseqGrad::Arbitrary{shape_id,time_shape_id,delay_us,..} => {
+                let shape = self.shape(*shape_id)?;
+                let shape = match *time_shape_id {
+                    0 => shape,
+                    _ => {
+                        // resample an irregularly sampled waveform to the raster
+                        let t:Vec<f32> = self.shape(*time_shape_id)?.iter().map(|t| t*raster_us).collect();
+                        if t.len() != shape.len() {
+                            return Err(PulseqError::Parse(format!("time shape {} does not match gradient shape {}",time_shape_id,shape_id)))
+                        }
+                        let n = (t[t.len()-1]/raster_us).round() as usize + 1;
+                        let tq:Vec<f32> = (0..n).map(|i| i as f32*raster_us).collect();
+                        crate::diffusion::resample(&t,&shape,&tq)
+                    }
+                };
+                Ok((shape,*delay_us))
+            }
+            PulseqGrad::Trapezoid{rise_us,flat_us,fall_us,delay_us,..} => {
+                let t = vec![0.0,*rise_us,rise_us + flat_us,rise_us + flat_us + fall_us];
+                let a = vec![0.0,1.0,1.0,0.0];
+                let n = (t[3]/raster_us).round() as usize + 1;
+                let tq:Vec<f32> = (0..n).map(|i| i as f32*raster_us).collect();
+                Ok((crate::diffusion::resample(&t,&a,&tq),*delay_us))
+            }
+        }
+    }
+
+    /** Build an event queue from the first repetition of the sequence. Gradient amplitudes and adc dwell
+    times are converted with the calibration and receiver table of the scanner */
+    pub fn event_queue(&self,rf_dac_seconds:f32,phase_unit:PhaseUnit,scanner:&ScannerProfile) -> Result<PulseqImport,PulseqError> {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        use crate::event_block::{Event, EventPlacementType, GradEventType};
+        use crate::execution::ExecutionBlock;
+        use crate::gradient_event::GradEvent;
+        use crate::gradient_matrix::{DacValues, Dimension, DriverVar, EncodeStrategy, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+        use crate::pulse::ArbitraryWaveform;
+        use crate::rf_event::RfEvent;
+        use crate::rf_frame::RF_MAX_DAC;
+        use crate::rf_state::RfStateType;
+        use crate::acq_event::{AcqEvent, SpectralWidth};
+
+        if self.blocks.is_empty() {return Err(PulseqError::Parse(String::from("no blocks found")))}
+        let period = self.blocks_per_repetition();
+        let n_reps = self.blocks.len()/period;
+        let rep_blocks:Vec<Vec<PulseqBlock>> = (0..n_reps).map(|r| self.blocks[r*period..(r+1)*period].to_vec()).collect();
+        let rad_to_phase = |rad:f32| (rad/(PI/2.0)*400.0/phase_unit.value() as f32).round() as i16;
+        let hz_to_rf_dac = |hz:f32| {
+            let dac = (hz*4.0*rf_dac_seconds).round();
+            if dac > RF_MAX_DAC as f32 {return Err(PulseqError::Unsupported(format!("rf amplitude of {} hz exceeds the maximum rf dac",hz)))}
+            Ok(dac as i16)
+        };
+
+        // gradient dac values for each block, channel and repetition
+        let grad_dac = |b:usize,c:usize| -> Result<Vec<i16>,PulseqError> {
+            rep_blocks.iter().map(|blocks| {
+                let peak = self.grad_peak(blocks[b].grad[c])?;
+                // hz/m -> hz/mm
+                if peak.abs()/1000.0 > scanner.grad_min() as f32 {
+                    return Err(PulseqError::Unsupported(format!("gradient amplitude of {} hz/m exceeds full scale",peak)))
+                }
+                Ok(scanner.grad_to_dac(peak/1000.0))
+            }).collect()
+        };
+        // a channel is played in a block if any repetition has a gradient on it
+        let active = |b:usize,c:usize| rep_blocks.iter().any(|blocks| blocks[b].grad[c] != 0);
+
+        /* the first gradient that changes over repetitions on the phase and slice channel defines the look-up table */
+        let mut lut_index:[Option<Vec<i16>>;2] = [None,None];
+        for b in 0..period {
+            for c in 1..3 {
+                if !active(b,c) || lut_index[c-1].is_some() {continue}
+                let dac = grad_dac(b,c)?;
+                let Some(step) = dac.iter().filter(|d| **d != 0).map(|d| d.abs()).min() else {continue};
+                if dac.iter().all(|d| *d == dac[0]) {continue}
+                lut_index[c-1] = Some(dac.iter().map(|d| (*d as f32/step as f32).round() as i16).collect());
+            }
+        }
+        let lut = match lut_index[0].is_some() || lut_index[1].is_some() {
+            true => {
+                let mut lut = Vec::<i16>::with_capacity(2*n_reps);
+                for r in 0..n_reps {
+                    lut.push(lut_index[0].as_ref().map(|l| l[r]).unwrap_or(0));
+                    lut.push(lut_index[1].as_ref().map(|l| l[r]).unwrap_or(0));
+                }
+                Some(lut)
+            }
+            false => None
+        };
+
+        let tracker = Matrix::new_tracker();
+        let mut events = Vec::<Rc<RefCell<Event>>>::new();
+        let mut rf_uid = 1;
+        let mut block_start = 0;
+        let place = |exec:Box<dyn ExecutionBlock>,waveform_start:i32| -> Rc<RefCell<Event>> {
+            let center = waveform_start + exec.time_to_center() - exec.time_to_start();
+            Event::new(exec,EventPlacementType::ExactFromOrigin(center))
+        };
+        for b in 0..period {
+            let block = rep_blocks[0][b];
+            if rep_blocks.iter().any(|blocks| blocks[b].rf != block.rf || blocks[b].adc != block.adc) {
+                return Err(PulseqError::Unsupported(format!("rf and adc events must be the same for every repetition (block {})",b+1)))
+            }
+
+            if block.rf != 0 {
+                let rf = self.rf.get(&block.rf).ok_or(PulseqError::Parse(format!("rf {} not found",block.rf)))?;
+                if rf.freq != 0.0 {return Err(PulseqError::Unsupported(String::from("rf frequency offsets")))}
+                if rf.time_shape_id != 0 {return Err(PulseqError::Unsupported(String::from("rf time shapes")))}
+                let mag = self.shape(rf.mag_id)?;
+                let phase = self.shape(rf.phase_id)?;
+                if phase.len() != mag.len() {
+                    return Err(PulseqError::Parse(format!("rf {} magnitude and phase shapes are different lengths",block.rf)))
+                }
+                // leading and trailing zeros are trimmed to keep the rf hardware block as short as possible
+                let first = mag.iter().position(|m| *m != 0.0).ok_or(PulseqError::Parse(format!("rf {} magnitude shape is empty",block.rf)))?;
+                let last = mag.iter().rposition(|m| *m != 0.0).unwrap();
+                let phase:Vec<f32> = phase[first..=last].iter().map(|p| p*360.0).collect();
+                let pulse = ArbitraryWaveform::new_with_phase(mag[first..=last].to_vec(),phase,self.rf_raster);
+                let label = format!("pulseq_rf_{}",b+1);
+                let rf_event = RfEvent::new(&label,rf_uid,pulse,RfStateType::Static(hz_to_rf_dac(rf.amplitude)?),RfStateType::Static(rad_to_phase(rf.phase)));
+                rf_uid += 1;
+                // rendered pulses start with a zero sample, so the first non-zero sample is one raster period in
+                let delay_us = rf.delay_us + first.saturating_sub(1) as f32*self.rf_raster*1E6;
+                events.push(place(rf_event.as_reference(),block_start + _utils::us_to_clock(delay_us.round() as i32)));
+            }
+
+            if block.adc != 0 {
+                let adc = self.adc.get(&block.adc).ok_or(PulseqError::Parse(format!("adc {} not found",block.adc)))?;
+                if adc.freq != 0.0 {return Err(PulseqError::Unsupported(String::from("adc frequency offsets")))}
+                let sample_rate = scanner.receiver_bandwidths.iter()
+                    .find(|bw| (100.0*bw.sample_period_clocks as f32 - adc.dwell_ns).abs() < 1.0)
+                    .map(|bw| SpectralWidth::from_hertz(bw.hertz))
+                    .ok_or(PulseqError::Unsupported(format!("adc dwell time of {} ns",adc.dwell_ns)))?;
+                let label = format!("pulseq_acq_{}",b+1);
+                let acq = AcqEvent::new(&label,sample_rate,adc.n_samples,0,RfStateType::Static(rad_to_phase(adc.phase)));
+                events.push(place(acq.as_reference(),block_start + _utils::us_to_clock(adc.delay_us.round() as i32)));
+            }
+
+            if (0..3).any(|c| active(b,c)) {
+                let dac = (0..3).map(|c| if active(b,c) {grad_dac(b,c).map(Some)} else {Ok(None)})
+                    .collect::<Result<Vec<Option<Vec<i16>>>,PulseqError>>()?;
+                // the shape of each channel comes from the repetition with the strongest gradient, so views
+                // with zero amplitude don't zero out the shape. The matrix dac sets amplitude and polarity
+                let waveforms = (0..3).map(|c| match &dac[c] {
+                    Some(d) => {
+                        let strongest = (0..n_reps).filter(|r| rep_blocks[*r][b].grad[c] != 0).max_by_key(|r| d[*r].unsigned_abs()).unwrap();
+                        self.grad_shape(rep_blocks[strongest][b].grad[c]).map(Some)
+                    }
+                    None => Ok(None)
+                }).collect::<Result<Vec<Option<(Vec<f32>,f32)>>,PulseqError>>()?;
+                // all channels of a gradient event start together, so channels with longer delays are padded
+                let min_delay = waveforms.iter().flatten().map(|w| w.1).fold(f32::MAX,f32::min);
+                let frames:Vec<Option<ArbitraryWaveform>> = waveforms.iter().map(|w| w.as_ref().map(|(samples,delay)| {
+                    let n_pad = ((delay - min_delay)/(self.grad_raster*1E6)).round() as usize;
+                    let mut padded = vec![0.0;n_pad];
+                    padded.extend(samples);
+                    ArbitraryWaveform::new_gradient(padded,self.grad_raster)
+                })).collect();
+
+                let label = format!("pulseq_grad_{}",b+1);
+                let mat_label = format!("pulseq_mat{}",b+1);
+                let varies = |d:&Option<Vec<i16>>| d.as_ref().map(|d| d.iter().any(|v| *v != d[0])).unwrap_or(false);
+                let matrix = match dac.iter().any(varies) {
+                    false => {
+                        let first = |c:usize| dac[c].as_ref().map(|d| d[0]);
+                        Matrix::new_static(&mat_label,DacValues::new(first(0),first(1),first(2)),(false,false,false),false,&tracker)
+                    }
+                    true => {
+                        if varies(&dac[0]) {return Err(PulseqError::Unsupported(format!("read gradients that change between repetitions (block {})",b+1)))}
+                        let lut = lut.clone().unwrap();
+                        // fit a scale for each channel to the look-up table index
+                        let mut scale = [None,Some(0.0),Some(0.0)];
+                        let mut offset = [None,Some(0),Some(0)];
+                        for c in 1..3 {
+                            match &dac[c] {
+                                Some(d) if varies(&dac[c]) => {
+                                    let idx = lut_index[c-1].as_ref().unwrap();
+                                    let num:f32 = d.iter().zip(idx.iter()).map(|(d,i)| *d as f32 * *i as f32).sum();
+                                    let den:f32 = idx.iter().map(|i| (*i as f32).powi(2)).sum();
+                                    let s = num/den;
+                                    let tol = 0.01*d.iter().map(|d| d.abs()).max().unwrap() as f32 + 2.0;
+                                    if d.iter().zip(idx.iter()).any(|(d,i)| (*d as f32 - s * *i as f32).abs() > tol) {
+                                        return Err(PulseqError::Unsupported(format!("gradient {} channel {} does not scale with the phase encoding table",label,c)))
+                                    }
+                                    scale[c] = Some(s);
+                                }
+                                Some(d) => offset[c] = Some(d[0]),
+                                None => {}
+                            }
+                        }
+                        let driver = MatrixDriver::new(DriverVar::Repetition,MatrixDriverType::PhaseEncode(EncodeStrategy::LUT(Dimension::_3D,lut)),None);
+                        let transform = LinTransform::new((None,scale[1],scale[2]),(None,offset[1],offset[2]));
+                        let default_dac = DacValues::new(dac[0].as_ref().map(|d| d[0]),None,None);
+                        Matrix::new_driven(&mat_label,driver,transform,default_dac,(false,false,false),false,&tracker)
+                    }
+                };
+                let grad = GradEvent::new((frames[0].clone(),frames[1].clone(),frames[2].clone()),&matrix,GradEventType::NonBlocking,&label);
+                events.push(place(grad.as_reference(),block_start + _utils::us_to_clock(min_delay.round() as i32)));
+            }
+            block_start += self.block_clocks(block.duration);
+        }
+        if events.is_empty() {return Err(PulseqError::Parse(String::from("no events found")))}
+        Ok(PulseqImport {
+            event_queue:EventQueue::new(&events),
+            n_repetitions:n_reps as u32,
+            rep_time:_utils::clock_to_sec(block_start),
+            lut,
+        })
+    }
+}
+
+#[test]
+fn test(){
+    use crate::event_block::{Event, EventPlacementType, GradEventType};
+    use crate::gradient_event::GradEvent;
+    use crate::gradient_matrix::{DacValues, DriverVar, EncodeStrategy, Dimension, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+    use crate::pulse::{Hardpulse, Trapezoid};
+    use crate::rf_event::RfEvent;
+    use crate::rf_state::{PhaseCycleStrategy, RfStateType};
+    use crate::acq_event::{AcqEvent, SpectralWidth};
+    use crate::execution::ExecutionBlock;
+
+    let n_views = 4;
+    let tracker = Matrix::new_tracker();
+    let driver = MatrixDriver::new(DriverVar::Repetition,MatrixDriverType::PhaseEncode(EncodeStrategy::FullySampled(Dimension::_2D,n_views,None)),None);
+    let pe_matrix = Matrix::new_driven("pe",driver,LinTransform::new((None,Some(-100.0),None),(None,None,None)),DacValues::new(None,None,None),(false,false,false),false,&tracker);
+    let ro_matrix = Matrix::new_static("ro",DacValues::new(Some(1000),None,None),(false,false,false),false,&tracker);
+
+    let excitation = RfEvent::new("excitation",1,Hardpulse::new(100E-6),RfStateType::Static(400),RfStateType::Adjustable(0,Some(PhaseCycleStrategy::CycleCPMG(1))));
+    let phase_encode = GradEvent::new((None,Some(Trapezoid::new(100E-6,500E-6)),None),&pe_matrix,GradEventType::Blocking,"phase_encode");
+    let readout = GradEvent::new((Some(Trapezoid::new(100E-6,1.28E-3)),None,None),&ro_matrix,GradEventType::NonBlocking,"readout");
+    let acq = AcqEvent::new("acq",SpectralWidth::SW100kH,128,0,RfStateType::Adjustable(0,Some(PhaseCycleStrategy::CycleCPMG(1))));
+
+    let e = Event::new(excitation.as_reference(),EventPlacementType::Origin);
+    let pe = Event::new(phase_encode.as_reference(),EventPlacementType::After(e.clone(),0));
+    let ro = Event::new(readout.as_reference(),EventPlacementType::After(pe.clone(),0));
+    let a = Event::new(acq.as_reference(),EventPlacementType::ExactFromOrigin(ro.borrow().center()));
+    let q = EventQueue::new(&vec![e,pe,ro,a]);
+
+    let seq = Pulseq::new(&q,PulseqParams{
+        name:String::from("test"),
+        n_repetitions:n_views as u32,
+        n_averages:2,
+        rep_time:50E-3,
+        view_acceleration:1,
+        waveform_sample_period_us:2,
+        phase_unit:PhaseUnit::Min,
+        rf_dac_seconds:50E-3,
+    },&ScannerProfile::civm9p4t()).unwrap();
+    // every view must be rendered with the same number of blocks and last the full rep time
+    assert_eq!(seq.blocks.len()%(n_views*2),0);
+    assert!((seq.total_duration() - 50E-3*(2*n_views) as f32).abs() < 1E-6);
+    // phase encodes and cpmg phase cycling result in unique events per view
+    assert_eq!(seq.rf.entries.len(),2);
+    assert_eq!(seq.adc.entries.len(),2);
+    println!("{}",seq.print());
+
+    // a rep time that can't fit the events is an error, not a panic
+    let short = Pulseq::new(&q,PulseqParams{
+        name:String::from("test_short"),
+        n_repetitions:n_views as u32,
+        n_averages:1,
+        rep_time:1E-3,
+        view_acceleration:1,
+        waveform_sample_period_us:2,
+        phase_unit:PhaseUnit::Min,
+        rf_dac_seconds:50E-3,
+    },&ScannerProfile::civm9p4t());
+    match short {
+        Err(PulseqError::RepTime{min}) => assert!(min > 1E-3),
+        _ => panic!("expected a rep time error")
+    }
+}
+
+#[test]
+fn import_test(){
+    use crate::event_block::{Event, EventPlacementType, GradEventType};
+    use crate::gradient_event::GradEvent;
+    use crate::gradient_matrix::{DacValues, DriverVar, EncodeStrategy, Dimension, LinTransform, Matrix, MatrixDriver, MatrixDriverType};
+    use crate::pulse::{Hardpulse, Trapezoid};
+    use crate::rf_event::RfEvent;
+    use crate::rf_state::RfStateType;
+    use crate::acq_event::{AcqEvent, SpectralWidth};
+    use crate::execution::ExecutionBlock;
+    use crate::ppl::{GradClock, Orientation, PPL};
+
+    let n_views = 8;
+    let tracker = Matrix::new_tracker();
+    let driver = MatrixDriver::new(DriverVar::Repetition,MatrixDriverType::PhaseEncode(EncodeStrategy::FullySampled(Dimension::_2D,n_views,None)),None);
+    let pe_matrix = Matrix::new_driven("pe",driver,LinTransform::new((None,Some(-100.0),None),(None,None,None)),DacValues::new(None,None,None),(false,false,false),false,&tracker);
+    let ro_matrix = Matrix::new_static("ro",DacValues::new(Some(1000),None,None),(false,false,false),false,&tracker);
+
+    let excitation = RfEvent::new("excitation",1,Hardpulse::new(100E-6),RfStateType::Static(400),RfStateType::Static(0));
+    let phase_encode = GradEvent::new((None,Some(Trapezoid::new(100E-6,500E-6)),None),&pe_matrix,GradEventType::Blocking,"phase_encode");
+    let readout = GradEvent::new((Some(Trapezoid::new(100E-6,1.28E-3)),None,None),&ro_matrix,GradEventType::NonBlocking,"readout");
+    let acq = AcqEvent::new("acq",SpectralWidth::SW100kH,128,0,RfStateType::Static(0));
+
+    let e = Event::new(excitation.as_reference(),EventPlacementType::Origin);
+    let pe = Event::new(phase_encode.as_reference(),EventPlacementType::After(e.clone(),0));
+    let ro = Event::new(readout.as_reference(),EventPlacementType::After(pe.clone(),0));
+    let a = Event::new(acq.as_reference(),EventPlacementType::ExactFromOrigin(ro.borrow().center()));
+    let q = EventQueue::new(&vec![e,pe,ro,a]);
+
+    let seq = Pulseq::new(&q,PulseqParams{
+        name:String::from("import_test"),
+        n_repetitions:n_views as u32,
+        n_averages:1,
+        rep_time:50E-3,
+        view_acceleration:1,
+        waveform_sample_period_us:2,
+        phase_unit:PhaseUnit::Min,
+        rf_dac_seconds:50E-3,
+    },&ScannerProfile::civm9p4t()).unwrap();
+    let path = std::env::temp_dir().join("pulseq_import_test.seq");
+    seq.write(&path);
+
+    let mut import = PulseqReader::open(&path).unwrap().event_queue(50E-3,PhaseUnit::Min,&ScannerProfile::civm9p4t()).unwrap();
+    assert_eq!(import.n_repetitions,n_views as u32);
+    assert!((import.rep_time - 50E-3).abs() < 1E-6);
+    let lut = import.lut.clone().expect("phase encoding should be driven by a look-up table");
+    assert_eq!(lut.len(),2*n_views);
+
+    // exporting the imported queue must reproduce the waveforms and timing of the original export
+    let round_trip = Pulseq::new(&import.event_queue,PulseqParams{
+        name:String::from("import_test_round_trip"),
+        n_repetitions:import.n_repetitions,
+        n_averages:1,
+        rep_time:import.rep_time,
+        view_acceleration:1,
+        waveform_sample_period_us:2,
+        phase_unit:PhaseUnit::Min,
+        rf_dac_seconds:50E-3,
+    },&ScannerProfile::civm9p4t()).unwrap();
+    let round_trip_path = std::env::temp_dir().join("pulseq_import_test_round_trip.seq");
+    round_trip.write(&round_trip_path);
+    assert!((round_trip.total_duration() - seq.total_duration()).abs() < 1E-6);
+    let original = waveform_timeline(&PulseqReader::open(&path).unwrap());
+    let imported = waveform_timeline(&PulseqReader::open(&round_trip_path).unwrap());
+    assert_eq!(original.len(),imported.len());
+    for (o,i) in original.iter().zip(imported.iter()) {
+        assert_eq!((&o.0,o.1),(&i.0,i.1),"waveforms start at different times");
+        assert_eq!(o.2.len(),i.2.len(),"{} at {} us has a different length",o.0,o.1);
+        let max = o.2.iter().fold(0.0f32,|acc,x| acc.max(x.abs()));
+        assert!(o.2.iter().zip(i.2.iter()).all(|(o,i)| (o - i).abs() <= 0.01*max),"{} at {} us has a different shape",o.0,o.1);
+    }
+
+    let ppl = PPL::new(&mut import.event_queue,import.n_repetitions,1,1,import.rep_time,0.0,&ScannerProfile::civm9p4t(),"","",Orientation::CivmStandard,GradClock::CPS20,PhaseUnit::Min,1,false);
+    println!("{}",ppl.print());
+
+    // malformed and unsupported files are errors
+    assert_eq!(PulseqReader::parse("[VERSION]\nmajor 1\nminor 3\n").err(),Some(PulseqError::Version(1,3)));
+    assert!(matches!(PulseqReader::parse("[VERSION]\nmajor 1\nminor 4\n[BLOCKS]\n1 10 0 x 0 0 0 0\n").err(),Some(PulseqError::Parse(_))));
+    assert!(matches!(PulseqReader::open(&std::env::temp_dir().join("missing.seq")).err(),Some(PulseqError::Io(_))));
+}
+
+#[test]
+fn center_out_import_test(){
+    // center-out phase encoding where the k=0 view leaves out its zero-area phase encode, as pypulseq does
+    let pe_ids = [0,1,2,3];
+    let mut blocks = vec![];
+    for (view,id) in pe_ids.iter().enumerate() {
+        let n = 4*view;
+        blocks.push(format!("{} 10 1 0 0 0 0 0",n+1));
+        blocks.push(format!("{} 30 0 0 {} 0 0 0",n+2,id));
+        blocks.push(format!("{} 150 0 4 0 0 1 0",n+3));
+        blocks.push(format!("{} 4810 0 0 0 0 0 0",n+4));
+    }
+    let text = [
+        "[VERSION]\nmajor 1\nminor 4\nrevision 1",
+        "[DEFINITIONS]\nBlockDurationRaster 1e-05\nGradientRasterTime 1e-05\nRadiofrequencyRasterTime 1e-06\nAdcRasterTime 1e-07",
+        &format!("[BLOCKS]\n{}",blocks.join("\n")),
+        "[RF]\n1 2500 1 2 0 0 0 0",
+        "[TRAP]\n1 1e6 100 100 100 0\n2 -1e6 100 100 100 0\n3 2e6 100 100 100 0\n4 5e6 100 1300 100 0",
+        "[ADC]\n1 128 10000 100 0 0",
+        "[SHAPES]\nshape_id 1\nnum_samples 100\n1\n0\n0\n97\nshape_id 2\nnum_samples 100\n0\n0\n98",
+    ].join("\n\n");
+    let import = PulseqReader::parse(&text).unwrap().event_queue(50E-3,PhaseUnit::Min,&ScannerProfile::civm9p4t()).unwrap();
+    assert_eq!(import.n_repetitions,4);
+    assert!((import.rep_time - 50E-3).abs() < 1E-6);
+    assert_eq!(import.lut,Some(vec![0,0,1,0,-1,0,2,0]));
+
+    // every view plays its phase encode with the amplitude and polarity of the file
+    let seq = Pulseq::new(&import.event_queue,PulseqParams{
+        name:String::from("center_out"),
+        n_repetitions:import.n_repetitions,
+        n_averages:1,
+        rep_time:import.rep_time,
+        view_acceleration:1,
+        waveform_sample_period_us:10,
+        phase_unit:PhaseUnit::Min,
+        rf_dac_seconds:50E-3,
+    },&ScannerProfile::civm9p4t()).unwrap();
+    let path = std::env::temp_dir().join("pulseq_center_out_test.seq");
+    seq.write(&path);
+    let peaks:Vec<f32> = waveform_timeline(&PulseqReader::open(&path).unwrap()).iter().filter(|w| w.0 == "grad1")
+        .map(|w| w.2.iter().fold(0.0f32,|acc,x| if x.abs() > acc.abs() {*x} else {acc})).collect();
+    assert_eq!(peaks.len(),3);
+    for (peak,expected) in peaks.iter().zip([1E6,-1E6,2E6]) {
+        assert!((peak - expected).abs() < 0.01*expected.abs(),"phase encode of {} hz/m played as {} hz/m",expected,peak);
+    }
+}
+
+/* every waveform of a pulseq file as (kind,start time in us,samples) with leading and trailing zeros
+removed, in order of start time. Adc events are described by their sample count and dwell time */
+#[cfg(test)]
+fn waveform_timeline(reader:&PulseqReader) -> Vec<(String,i64,Vec<f32>)> {
+    let trimmed = |samples:Vec<f32>,start_us:f32,raster_us:f32| -> Option<(i64,Vec<f32>)> {
+        let first = samples.iter().position(|x| *x != 0.0)?;
+        let last = samples.iter().rposition(|x| *x != 0.0).unwrap();
+        Some(((start_us + first as f32*raster_us).round() as i64,samples[first..=last].to_vec()))
+    };
+    let mut timeline = Vec::<(String,i64,Vec<f32>)>::new();
+    let mut block_start_us = 0.0;
+    for block in reader.blocks.iter() {
+        if block.rf != 0 {
+            let rf = &reader.rf[&block.rf];
+            let mag:Vec<f32> = reader.shape(rf.mag_id).unwrap().iter().map(|m| m*rf.amplitude).collect();
+            if let Some((t,samples)) = trimmed(mag,block_start_us + rf.delay_us,reader.rf_raster*1E6) {
+                timeline.push((String::from("rf"),t,samples));
+            }
+        }
+        for (c,id) in block.grad.iter().enumerate() {
+            if *id == 0 {continue}
+            let (shape,delay_us) = reader.grad_shape(*id).unwrap();
+            let amplitude = match &reader.gradients[id] {
+                PulseqGrad::Arbitrary{amplitude,..} => *amplitude,
+                PulseqGrad::Trapezoid{amplitude,..} => *amplitude,
+            };
+            let samples = shape.iter().map(|x| x*amplitude).collect();
+            if let Some((t,samples)) = trimmed(samples,block_start_us + delay_us,reader.grad_raster*1E6) {
+                timeline.push((format!("grad{}",c),t,samples));
+            }
+        }
+        if block.adc != 0 {
+            let adc = &reader.adc[&block.adc];
+            timeline.push((String::from("adc"),(block_start_us + adc.delay_us).round() as i64,vec![adc.n_samples as f32,adc.dwell_ns]));
+        }
+        block_start_us += block.duration as f32*reader.block_raster*1E6;
+    }
+    timeline.sort_by(|a,b| (a.1,&a.0).cmp(&(b.1,&b.0)));
+    timeline
+}
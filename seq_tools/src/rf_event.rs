@@ -1,15 +1,15 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 use crate::rf_frame::RfFrame;
-use crate::rf_state::{PhaseCycleStrategy, RfDriver, RfDriverType, RfState, RfStateType};
+use crate::rf_state::{PhaseCycleStrategy, RfDriver, RfDriverType, RfState, RfStateError, RfStateType};
 use crate::command_string::CommandString;
 use crate::pulse::{CompositeHardpulse, Pulse};
 use crate::_utils;
-use crate::execution::{ExecutionBlock, WaveformData, PlotTrace, BlockExecution, EventType};
+use crate::execution::{ExecutionBlock, WaveformData, PlotTrace, BlockExecution, EventType, RfRole};
 use crate::ppl_function;
 use crate::pulse_function::render_function_vector;
 use crate::_utils::us_to_clock;
-use crate::ppl::Adjustment;
+use crate::ppl::{Adjustment, SLICE_LOOP_COUNTER_VAR};
 use crate::seqframe::{RF_SEQ_FILE_LABEL, SeqFrame};
 
 //const TIME_BLOCK:i32 = 150; // clock cycles (100ns)
@@ -22,7 +22,10 @@ pub struct RfEvent<RF> where RF:RfFrame{
     rf_frame:RF,
     rf_state:RfState,
     label:String,
-    uid:u8
+    uid:u8,
+    // transmit frequency offset (Hz) for each iteration of the slice loop
+    frequency_offsets:Option<Vec<f32>>,
+    role:RfRole,
 }
 
 impl<RF> RfEvent<RF> where RF:RfFrame {
@@ -32,12 +35,30 @@ impl<RF> RfEvent<RF> where RF:RfFrame {
             rf_state:RfState::new(label,rf_power,rf_phase),
             label:label.to_owned(),
             uid,
+            frequency_offsets:None,
+            role:RfRole::Excitation,
         }
     }
     pub fn set_rf_phase(&mut self,rf_phase:RfStateType) {
         let power = self.rf_state.power();
         self.rf_state = RfState::new(&self.label,power,rf_phase);
     }
+    /** Shift the transmit frequency by a different offset for each slice, in slice loop order */
+    pub fn set_frequency_offsets(&mut self,offsets_hz:Vec<f32>) {
+        if self.uid == 0 {panic!("rf event {} needs a uid above 0 to have its own frequency buffers",self.label)}
+        self.frequency_offsets = Some(offsets_hz);
+    }
+    /** Mark the pulse as a refocusing or preparation pulse. Pulses are excitations by default */
+    pub fn set_role(&mut self,role:RfRole) {
+        self.role = role;
+    }
+    fn freq_buffer_var(&self) -> String {
+        format!("{}_freq_buf",self.label)
+    }
+    // buffer 0 holds the base frequency, so every event gets its own range of buffers by uid
+    fn first_freq_buffer(&self,n_offsets:usize) -> usize {
+        self.uid as usize*n_offsets
+    }
     pub fn pulse_duration_us(&self) -> i32 {
         _utils::sec_to_us(self.rf_frame.duration())
     }
@@ -65,21 +86,31 @@ impl<RF: 'static> ExecutionBlock for RfEvent<RF> where RF:RfFrame + Clone{
         TIME_BLOCK + RFSTART_PREDELAY + us_to_clock(self.pulse_duration_us()) + RFSTART_POSTDELAY
     }
     fn block_execution(&self,post_delay:i32) -> BlockExecution {
-        let cmd_str = vec![
+        let mut cmd_str = vec![
             ppl_function::start_timer(),
             ppl_function::resync(),
             ppl_function::set_phase_with_var(&self.rf_state.phase_var()),
+        ];
+        // the buffer switch is covered by the time block
+        if self.frequency_offsets.is_some() {
+            cmd_str.push(ppl_function::select_freq_buffer(&self.freq_buffer_var()));
+        }
+        cmd_str.extend(vec![
             ppl_function::wait_timer(TIME_BLOCK),
             ppl_function::rf_start(self.uid, self.pulse_duration_us() as u16, &self.rf_state.power_var(), _utils::clock_to_us(RFSTART_PREDELAY) as u16),
-        ];
+        ]);
         let cmd_str = CommandString::new_hardware_exec(&cmd_str.join("\n"));
         BlockExecution::new(cmd_str,post_delay)
     }
     fn block_declaration(&self) -> CommandString {
-        let cmd_str = vec![
+        let mut cmd_str = vec![
             self.rf_state.declare_power_var().unwrap_or("".to_string()),
             self.rf_state.declare_phase_var(),
-        ].join("\n");
+        ];
+        if self.frequency_offsets.is_some() {
+            cmd_str.push(format!("int {};",self.freq_buffer_var()));
+        }
+        let cmd_str = cmd_str.join("\n");
         CommandString::new_declare(&cmd_str)
     }
     fn block_calculation(&self) -> Option<CommandString> {
@@ -91,16 +122,23 @@ impl<RF: 'static> ExecutionBlock for RfEvent<RF> where RF:RfFrame + Clone{
             }
             None => {}
         }
+        if let Some(offsets) = &self.frequency_offsets {
+            cmds.push(format!("{} = {} + {};",self.freq_buffer_var(),self.first_freq_buffer(offsets.len()),SLICE_LOOP_COUNTER_VAR));
+        }
         Some(CommandString::new_calculation(&cmds.join("\n")))
     }
     fn block_initialization(&self) -> CommandString {
-        CommandString::new_calculation(
-            &vec![
-                self.rf_state.init_phase_var(),
-                self.rf_state.init_power_var().unwrap_or("".to_string()),
-                self.init_list(),
-            ].join("\n")
-        )
+        let mut cmds = vec![
+            self.rf_state.init_phase_var(),
+            self.rf_state.init_power_var().unwrap_or("".to_string()),
+            self.init_list(),
+        ];
+        if let Some(offsets) = &self.frequency_offsets {
+            let first = self.first_freq_buffer(offsets.len());
+            offsets.iter().enumerate().for_each(|(i,offset)| cmds.push(ppl_function::set_offset_freq(first + i,offset.round() as i32)));
+            cmds.push(ppl_function::select_freq_buffer("0"));
+        }
+        CommandString::new_calculation(&cmds.join("\n"))
     }
     fn block_constant_initialization(&self) -> Option<CommandString> {
         None
@@ -147,25 +185,27 @@ impl<RF: 'static> ExecutionBlock for RfEvent<RF> where RF:RfFrame + Clone{
     }
 
     fn render_magnitude(&self, time_step_us: usize, driver_value: u32) -> WaveformData {
-        let power = self.rf_state.power();
-        match power {
-            RfStateType::Static(dac) => {
-                let phase = render_function_vector(self.rf_frame.phase_function(time_step_us));
-                let amplitude_plot = self.rf_frame.render_magnitude(time_step_us,dac);
-                let t = amplitude_plot.x.clone();
-                let phase_plot = PlotTrace::new(t,phase);
-                WaveformData::Rf(amplitude_plot,phase_plot)
-            }
-            _=> {
-                //todo!(get dac values for driven rf state instead of rendering normalized)
-                println!("dynamic rf power rendering not yet implemented!!!");
-                let phase = render_function_vector(self.rf_frame.phase_function(time_step_us));
-                let amplitude_plot = self.rf_frame.render_normalized(time_step_us);
-                let t = amplitude_plot.x.clone();
-                let phase_plot = PlotTrace::new(t,phase);
-                WaveformData::Rf(amplitude_plot,phase_plot)
-            }
-        }
+        let dac = self.rf_state.power_value(driver_value).expect("rf event must have a power field. What happened??");
+        let phase = render_function_vector(self.rf_frame.phase_function(time_step_us));
+        let amplitude_plot = self.rf_frame.render_magnitude(time_step_us,dac);
+        let t = amplitude_plot.x.clone();
+        let phase_plot = PlotTrace::new(t,phase);
+        WaveformData::Rf(amplitude_plot,phase_plot)
+    }
+    fn phase_value(&self, driver_value: u32) -> Result<Option<i16>,RfStateError> {
+        self.rf_state.phase_value(driver_value).map(Some)
+    }
+    fn rf_dac_value(&self, driver_value: u32) -> Option<i16> {
+        self.rf_state.power_value(driver_value)
+    }
+    fn rf_power_abs(&self, magnitude: f32) -> Option<f32> {
+        Some(self.rf_frame.power_abs(magnitude))
+    }
+    fn rf_role(&self) -> Option<RfRole> {
+        Some(self.role)
+    }
+    fn scale_rf_power(&mut self,scale:f32) {
+        self.rf_state.scale_power(scale);
     }
 }
 
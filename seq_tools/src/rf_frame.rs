@@ -1,9 +1,11 @@
-use crate::pulse::{Pulse,Trapezoid, Hardpulse, CompositeHardpulse};
+use crate::pulse::{Pulse,Trapezoid, Hardpulse, CompositeHardpulse, ArbitraryWaveform, GaussianPulse, HammingSincPulse, SlrPulse, HyperbolicSecant};
 use crate::seqframe::{self, SeqFrame, FrameType, SeqFrameExpression};
 use crate::_utils;
-use crate::pulse_function::{Function,FunctionParams};
+use crate::pulse_function::{Function,FunctionParams,linear_runs};
 
 pub const RF_MAX_DAC:i16 = 2047;
+// largest error of a phase segment against the phase it describes
+pub const PHASE_TOLERANCE_DEG:f32 = 0.5;
 
 // this is like inheritance, forcing whatever implements GradFrame to also implement Pulse
 pub trait RfFrame:Pulse {
@@ -56,6 +58,48 @@ impl RfFrame for CompositeHardpulse {
     }
 }
 
+impl RfFrame for ArbitraryWaveform {
+    fn phase_function(&self,sample_period_us:usize) -> Vec<Function>{
+        match self.phase(sample_period_us) {
+            Some(mut phase) => {
+                // the phase is held over the zero magnitude end points of the pulse
+                if self.end_points() {
+                    phase.insert(0,phase[0]);
+                    phase.push(phase[phase.len()-1]);
+                }
+                phase_runs(&phase)
+            }
+            None => vec![Function::Plateau(FunctionParams::new(self.n_samples(sample_period_us),0.0))]
+        }
+    }
+    fn phase_expression(&self, sample_period_us:usize) -> Vec<seqframe::Expression>{
+        let dac_val = 90;// degrees
+        self.phase_function(sample_period_us).iter().map(|func| func.expression(dac_val)).collect()
+    }
+}
+
+/** Phase segments normalized to 90 degrees. Sampled phases are ramped between segment ends so a
+smoothly modulated phase takes a handful of expressions instead of one per sample */
+fn phase_runs(phase_deg:&[f32]) -> Vec<Function> {
+    let p:Vec<f32> = phase_deg.iter().map(|deg| deg/90.0).collect();
+    linear_runs(&p,PHASE_TOLERANCE_DEG/90.0,2.0)
+}
+
+impl RfFrame for GaussianPulse {}
+
+impl RfFrame for HammingSincPulse {}
+
+impl RfFrame for SlrPulse {}
+
+impl RfFrame for HyperbolicSecant {
+    fn phase_function(&self,sample_period_us:usize) -> Vec<Function>{
+        phase_runs(&self.phase_deg(sample_period_us))
+    }
+    fn phase_expression(&self, sample_period_us:usize) -> Vec<seqframe::Expression>{
+        let dac_val = 90;// degrees
+        self.phase_function(sample_period_us).iter().map(|func| func.expression(dac_val)).collect()
+    }
+}
 
 #[test]
 fn test(){
@@ -68,4 +112,16 @@ fn test(){
     println!("{}",s.1.serialize());
     println!("{}",hs.0.serialize());
     println!("{}",hs.1.serialize());
-}
\ No newline at end of file
+
+    // the frequency sweep of an adiabatic pulse is described by a few ramps between phase wraps
+    let sech = HyperbolicSecant::new(8E-3,5.3,5.0);
+    let phase_deg = sech.phase_deg(2);
+    let phase = sech.phase_function(2);
+    assert!(phase.len() < 50,"{} phase expressions for {} samples",phase.len(),phase_deg.len());
+    assert_eq!(sech.phase_expression(2).len(),phase.len());
+    let rendered = crate::pulse_function::render_function_vector(phase);
+    assert_eq!(rendered.len(),phase_deg.len());
+    rendered.iter().zip(phase_deg.iter()).for_each(|(r,deg)|{
+        assert!((90.0*r - deg).abs() <= PHASE_TOLERANCE_DEG + 1E-3,"rendered {} for {}",90.0*r,deg);
+    });
+}
@@ -0,0 +1,198 @@
+/*
+    RF power estimation. The rf dac of every pulse in an event queue is turned into B1 and flip angle with
+    the rf_dac_seconds calibration (the dac*seconds of a 90 degree hard pulse). Power is averaged over the
+    repetition time and over every view so that power ramps are accounted for. Estimates for several
+    experiments can be combined to get the time-averaged power of a whole protocol.
+ */
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use serde_json;
+use crate::_utils;
+use crate::event_block::EventQueue;
+use crate::execution::{EventType, WaveformData};
+use crate::grad_cal::GAMMA_BAR;
+use crate::rf_frame::RF_MAX_DAC;
+
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct RfPowerLimits {
+    // time-averaged B1 over the scan
+    pub max_b1_rms_ut:f32,
+    // time-averaged power as a fraction of the full scale amplifier output
+    pub max_mean_power:f32,
+    // fraction of the repetition time the amplifier is on
+    pub max_duty_cycle:f32,
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct RfPulsePower {
+    pub label:String,
+    // largest dac over all views
+    pub dac:i16,
+    pub duration:f32,
+    pub flip_angle_deg:f32,
+    pub b1_peak_ut:f32,
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct RfPowerEstimate {
+    pub pulses:Vec<RfPulsePower>,
+    pub scan_time:f32,
+    pub duty_cycle:f32,
+    pub mean_power:f32,
+    pub b1_rms_ut:f32,
+    pub b1_peak_ut:f32,
+}
+
+#[derive(Clone,Debug)]
+pub enum RfPowerError {
+    B1Rms(f32,f32),
+    MeanPower(f32,f32),
+    DutyCycle(f32,f32),
+}
+
+impl fmt::Display for RfPowerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RfPowerError::B1Rms(value,max) => write!(f,"time-averaged B1 exceeded: {:.3} uT > {:.3} uT",value,max),
+            RfPowerError::MeanPower(value,max) => write!(f,"time-averaged rf power exceeded: {:.4} > {:.4} of full scale",value,max),
+            RfPowerError::DutyCycle(value,max) => write!(f,"rf duty cycle exceeded: {:.4} > {:.4}",value,max),
+        }
+    }
+}
+
+fn dac_to_b1_ut(dac:f32,rf_dac_seconds:f32) -> f32 {
+    // a 90 degree hard pulse has an area of 1/4 cycle
+    let hz = dac*0.25/rf_dac_seconds;
+    1E6*hz/GAMMA_BAR
+}
+
+impl RfPowerEstimate {
+    pub fn new(event_queue:&EventQueue,n_repetitions:u32,n_averages:u16,view_acceleration:u16,rep_time:f32,rf_dac_seconds:f32,time_step_us:usize) -> Self {
+        let dt = _utils::us_to_sec(time_step_us as i32);
+        let mut pulses = Vec::<RfPulsePower>::new();
+        let mut energy_per_rep = 0.0;
+        let mut on_time = 0.0;
+        for event in event_queue.events().iter() {
+            let e = event.borrow();
+            if e.execution.kind() != EventType::Rf {continue}
+            // pulse shapes don't change over views, so the normalized waveform is only rendered once
+            let y = match e.execution.render_normalized(time_step_us) {
+                WaveformData::Rf(amplitude,_) => amplitude.y,
+                _=> continue
+            };
+            let peak = y.iter().fold(0.0f32,|acc,y| acc.max(y.abs()));
+            let energy:f32 = y.iter().map(|y| y*y).sum::<f32>()*dt;
+            let area = e.execution.rf_power_abs(1.0).expect("rf events must have a pulse area");
+            let mut max_dac = 0i16;
+            let mut dac_squared = 0.0f64;
+            for rep in 0..n_repetitions {
+                let dac = e.execution.rf_dac_value(rep*view_acceleration as u32).unwrap_or(0);
+                if dac.abs() > max_dac.abs() {max_dac = dac}
+                dac_squared += (dac as f64).powi(2);
+            }
+            energy_per_rep += energy*(dac_squared/n_repetitions as f64) as f32;
+            let duration = _utils::clock_to_sec(e.execution.time_to_end() - e.execution.time_to_start());
+            on_time += duration;
+            pulses.push(RfPulsePower{
+                label:e.unique_label(),
+                dac:max_dac,
+                duration,
+                flip_angle_deg:90.0*area*max_dac.abs() as f32/rf_dac_seconds,
+                b1_peak_ut:dac_to_b1_ut(peak*max_dac.abs() as f32,rf_dac_seconds),
+            });
+        }
+        Self {
+            b1_peak_ut:pulses.iter().fold(0.0f32,|acc,p| acc.max(p.b1_peak_ut)),
+            pulses,
+            scan_time:rep_time*n_repetitions as f32*n_averages as f32,
+            duty_cycle:on_time/rep_time,
+            mean_power:energy_per_rep/(rep_time*(RF_MAX_DAC as f32).powi(2)),
+            b1_rms_ut:dac_to_b1_ut((energy_per_rep/rep_time).sqrt(),rf_dac_seconds),
+        }
+    }
+    /** Time-weighted combination of estimates from experiments run back to back */
+    pub fn combine(estimates:&[RfPowerEstimate]) -> Self {
+        let scan_time:f32 = estimates.iter().map(|e| e.scan_time).sum();
+        let weighted = |f:&dyn Fn(&RfPowerEstimate) -> f32| estimates.iter().map(|e| f(e)*e.scan_time).sum::<f32>()/scan_time;
+        Self {
+            pulses:estimates.iter().flat_map(|e| e.pulses.clone()).collect(),
+            scan_time,
+            duty_cycle:weighted(&|e| e.duty_cycle),
+            mean_power:weighted(&|e| e.mean_power),
+            b1_rms_ut:weighted(&|e| e.b1_rms_ut.powi(2)).sqrt(),
+            b1_peak_ut:estimates.iter().fold(0.0f32,|acc,e| acc.max(e.b1_peak_ut)),
+        }
+    }
+    pub fn to_file(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+}
+
+impl RfPowerLimits {
+    pub fn civm9p4t() -> Self {
+        Self {
+            max_b1_rms_ut:25.0,
+            max_mean_power:0.01,
+            max_duty_cycle:0.1,
+        }
+    }
+    pub fn to_file(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn from_file(file_path:&Path) -> Self {
+        let mut f = File::open(file_path).unwrap_or_else(|_| panic!("cannot open file {:?}",file_path));
+        let mut in_str = String::new();
+        f.read_to_string(&mut in_str).expect("trouble reading file");
+        serde_json::from_str(&in_str).expect("cannot deserialize struct")
+    }
+    pub fn check(&self,estimate:&RfPowerEstimate) -> Result<(),RfPowerError> {
+        if estimate.b1_rms_ut > self.max_b1_rms_ut {
+            return Err(RfPowerError::B1Rms(estimate.b1_rms_ut,self.max_b1_rms_ut))
+        }
+        if estimate.mean_power > self.max_mean_power {
+            return Err(RfPowerError::MeanPower(estimate.mean_power,self.max_mean_power))
+        }
+        if estimate.duty_cycle > self.max_duty_cycle {
+            return Err(RfPowerError::DutyCycle(estimate.duty_cycle,self.max_duty_cycle))
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test(){
+    use crate::event_block::{Event, EventPlacementType};
+    use crate::execution::ExecutionBlock;
+    use crate::pulse::Hardpulse;
+    use crate::rf_event::RfEvent;
+    use crate::rf_state::RfStateType;
+
+    let rf_dac_seconds = 50E-3;
+    // 90 and 180 degree hard pulses
+    let excitation = RfEvent::new("excitation",1,Hardpulse::new(100E-6),RfStateType::Static(500),RfStateType::Static(0));
+    let refocus = RfEvent::new("refocus",2,Hardpulse::new(100E-6),RfStateType::Adjustable(1000,None),RfStateType::Static(0));
+    let e = Event::new(excitation.as_reference(),EventPlacementType::Origin);
+    let r = Event::new(refocus.as_reference(),EventPlacementType::After(e.clone(),1000));
+    let q = EventQueue::new(&vec![e,r]);
+
+    let estimate = RfPowerEstimate::new(&q,128,2,1,100E-3,rf_dac_seconds,2);
+    assert!((estimate.pulses[0].flip_angle_deg - 90.0).abs() < 0.1);
+    assert!((estimate.pulses[1].flip_angle_deg - 180.0).abs() < 0.1);
+    assert!((estimate.duty_cycle - 2E-3).abs() < 1E-5);
+    assert!((estimate.scan_time - 25.6).abs() < 1E-3);
+    assert!(RfPowerLimits::civm9p4t().check(&estimate).is_ok());
+
+    // running the same experiment with a much shorter rep time
+    let fast = RfPowerEstimate::new(&q,128,2,1,1E-3,rf_dac_seconds,2);
+    assert!(RfPowerLimits::civm9p4t().check(&fast).is_err());
+    let protocol = RfPowerEstimate::combine(&[estimate.clone(),fast.clone()]);
+    assert!(protocol.mean_power > estimate.mean_power && protocol.mean_power < fast.mean_power);
+}
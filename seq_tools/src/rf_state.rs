@@ -1,7 +1,13 @@
 use crate::gradient_matrix::{LUT_INDEX_VAR_NAME, LUT_TEMPVAL_VAR_NAME_1, LUT_TEMPVAL_VAR_NAME_2, LONG_TEMPVAL_VAR_NAME, DriverVar};
 use crate::command_string::{CommandString,Command};
+use std::fmt;
 use crate::ppl::{Adjustment, VIEW_LOOP_COUNTER_VAR};
 
+// phase dac units in a full cycle (400 = 90 deg)
+pub const PHASE_UNITS_PER_CYCLE:i32 = 1600;
+// quadratic rf spoiling seed with the most uniform spoiling for most tissues (Zur et al. 1991)
+pub const RF_SPOIL_SEED_DEG:f32 = 117.0;
+
 #[derive(Clone,Debug)]
 pub struct RfState {
     label:String,// power and phase vars are derived from label
@@ -27,9 +33,30 @@ pub enum RfDriverType{
 
 #[derive(Clone,Debug)]
 pub enum PhaseCycleStrategy{
-    LUTNinetyTwoSeventy(usize,Option<usize>),
+    // the table holds the (phase,slice) encoding coordinates the scanner steps through
+    LUTNinetyTwoSeventy(usize,Option<usize>,Vec<i16>),
     FullySampledNinetyTwoSeventy(usize,Option<usize>),
-    CycleCPMG(usize)
+    CycleCPMG(usize),
+    // the phase increment grows by the seed (deg) every repetition
+    QuadraticSpoil(f32)
+}
+
+/** Why a state can't be evaluated outside of the pulse program */
+#[derive(Clone,Debug,PartialEq)]
+pub enum RfStateError {
+    // lut index and lut length
+    LutIndex(usize,usize),
+    // description of the state
+    NotImplemented(String),
+}
+
+impl fmt::Display for RfStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RfStateError::LutIndex(index,len) => write!(f,"lut index {} is out of range for a lut of length {}",index,len),
+            RfStateError::NotImplemented(state) => write!(f,"{} can't be evaluated",state),
+        }
+    }
 }
 
 #[derive(Clone,Debug)]
@@ -39,6 +66,28 @@ pub enum RfStateType {
     Driven(RfDriver)
 }
 
+impl PhaseCycleStrategy {
+    fn spoil_seed(seed_deg:f32) -> i32 {
+        ((seed_deg*PHASE_UNITS_PER_CYCLE as f32/360.0).round() as i32).rem_euclid(PHASE_UNITS_PER_CYCLE)
+    }
+    /** Quadratic spoiling phase seed*n*(n+1)/2 of repetition n. n*(n+1) is reduced first so the
+    pulse program never overflows */
+    fn quadratic_spoil(counter:&str,seed_deg:f32) -> String {
+        let cycle = PHASE_UNITS_PER_CYCLE;
+        [
+            format!("{} = ({})%{}L;",LONG_TEMPVAL_VAR_NAME,counter,2*cycle),
+            format!("{} = (({}*(({}+1L)%{}L))%{}L)/2L;",LONG_TEMPVAL_VAR_NAME,LONG_TEMPVAL_VAR_NAME,LONG_TEMPVAL_VAR_NAME,2*cycle,2*cycle),
+            format!("{} = ({}*{}L)%{}L;",LONG_TEMPVAL_VAR_NAME,LONG_TEMPVAL_VAR_NAME,Self::spoil_seed(seed_deg),cycle),
+        ].join("\n")
+    }
+    fn quadratic_spoil_value(counter:i32,seed_deg:f32) -> i16 {
+        let cycle = 2*PHASE_UNITS_PER_CYCLE as i64;
+        let n = counter as i64%cycle;
+        let triangle = ((n*((n + 1)%cycle))%cycle)/2;
+        ((triangle*Self::spoil_seed(seed_deg) as i64)%PHASE_UNITS_PER_CYCLE as i64) as i16
+    }
+}
+
 impl RfDriver {
     pub fn new(driver_variable:DriverVar,driver_type:RfDriverType,echo_index:Option<usize>) -> RfDriver {
         RfDriver{
@@ -144,6 +193,12 @@ impl RfState {
                             PhaseCycleStrategy::CycleCPMG(acceleration) => {
                                 format!("{} = {} + 800*(({}/{})%2);", self.phase_var(),self.adjust_phase_var(), VIEW_LOOP_COUNTER_VAR, acceleration)
                             }
+                            PhaseCycleStrategy::QuadraticSpoil(seed) => {
+                                [
+                                    PhaseCycleStrategy::quadratic_spoil(VIEW_LOOP_COUNTER_VAR,*seed),
+                                    format!("{} = {} + {};",self.phase_var(),self.adjust_phase_var(),LONG_TEMPVAL_VAR_NAME)
+                                ].join("\n")
+                            }
                             _=> panic!("phase cycle strategy no yet implemented for user adjustments")
                         }
                     }
@@ -154,7 +209,7 @@ impl RfState {
                 match &driver.kind {
                     RfDriverType::PhaseCycle3D(strategy) => {
                         match &strategy{
-                            PhaseCycleStrategy::LUTNinetyTwoSeventy(size_1,size_2) => {
+                            PhaseCycleStrategy::LUTNinetyTwoSeventy(size_1,size_2,_) => {
                                 let size_2 = size_2.unwrap_or(*size_1);
                                 let out_str = vec![
                                     format!("{} = 2L*({}+{});",LUT_INDEX_VAR_NAME,&driver.driver_var,driver.echo_index),
@@ -177,14 +232,22 @@ impl RfState {
                             PhaseCycleStrategy::CycleCPMG(acceleration) => {
                                 format!("{} = 400*(2*(({}/{}+{})%2)+1);",self.phase_var(),&driver.driver_var,acceleration,driver.echo_index)
                             }
-                            _=> "phase cycle strategy not implemented yet".to_owned()
+                            PhaseCycleStrategy::QuadraticSpoil(seed) => self.set_spoil_phase(driver,*seed),
                         }
                     }
+                    // spoiling doesn't depend on the encoding dimension
+                    RfDriverType::PhaseCycle2D(PhaseCycleStrategy::QuadraticSpoil(seed)) => self.set_spoil_phase(driver,*seed),
                     _=> "driver not implemented yet".to_owned()
                 }
             }
         }
     }
+    fn set_spoil_phase(&self,driver:&RfDriver,seed_deg:f32) -> String {
+        [
+            PhaseCycleStrategy::quadratic_spoil(&format!("{}+{}L",driver.driver_var,driver.echo_index),seed_deg),
+            format!("{} = {};",self.phase_var(),LONG_TEMPVAL_VAR_NAME)
+        ].join("\n")
+    }
     pub fn set_power(&self) -> Option<String> {
         match &self.power {
             Some(RfStateType::Static(dac)) => {
@@ -204,6 +267,91 @@ impl RfState {
             None => None
         }
     }
+    /** Evaluates the phase state for a driver value. This mirrors the calculation done by set_phase */
+    pub fn phase_value(&self,driver_value:u32) -> Result<i16,RfStateError> {
+        let dv = driver_value as i32;
+        match &self.phase {
+            RfStateType::Static(phase) => Ok(*phase),
+            RfStateType::Adjustable(init,strategy) => {
+                match strategy {
+                    Some(PhaseCycleStrategy::CycleCPMG(acceleration)) => {
+                        Ok((*init as i32 + 800*((dv/(*acceleration as i32))%2)) as i16)
+                    }
+                    Some(PhaseCycleStrategy::QuadraticSpoil(seed)) => {
+                        Ok(init + PhaseCycleStrategy::quadratic_spoil_value(dv,*seed))
+                    }
+                    Some(strategy) => Err(RfStateError::NotImplemented(format!("adjustable phase with {:?}",strategy))),
+                    None => Ok(*init)
+                }
+            }
+            RfStateType::Driven(driver) => {
+                let echo = driver.echo_index as i32;
+                match &driver.kind {
+                    RfDriverType::PhaseCycle3D(strategy) => {
+                        match &strategy {
+                            PhaseCycleStrategy::FullySampledNinetyTwoSeventy(size_1,size_2) => {
+                                let size_1 = *size_1 as i32;
+                                let size_2 = size_2.unwrap_or(size_1 as usize) as i32;
+                                let t1 = ((dv + echo)%size_1) - size_1/2;
+                                let t2 = ((dv + echo)/size_2) - size_2/2;
+                                Ok((2*((t1 + t2 + (size_1/2) + (size_2/2) + 2)%2) + 1) as i16)
+                            }
+                            PhaseCycleStrategy::CycleCPMG(acceleration) => {
+                                Ok((400*(2*((dv/(*acceleration as i32) + echo)%2) + 1)) as i16)
+                            }
+                            PhaseCycleStrategy::LUTNinetyTwoSeventy(size_1,size_2,lut) => {
+                                let size_2 = size_2.unwrap_or(*size_1) as i32;
+                                let lut_idx = 2*(dv + echo) as usize;
+                                let (t1,t2) = match (lut.get(lut_idx),lut.get(lut_idx + 1)) {
+                                    (Some(t1),Some(t2)) => (*t1 as i32,*t2 as i32),
+                                    _=> return Err(RfStateError::LutIndex(lut_idx + 1,lut.len()))
+                                };
+                                Ok((2*((t1 + t2 + (*size_1 as i32/2) + (size_2/2) + 2)%2) + 1) as i16)
+                            }
+                            PhaseCycleStrategy::QuadraticSpoil(seed) => {
+                                Ok(PhaseCycleStrategy::quadratic_spoil_value(dv + echo,*seed))
+                            }
+                        }
+                    }
+                    RfDriverType::PhaseCycle2D(PhaseCycleStrategy::QuadraticSpoil(seed)) => {
+                        Ok(PhaseCycleStrategy::quadratic_spoil_value(dv + echo,*seed))
+                    }
+                    kind => Err(RfStateError::NotImplemented(format!("phase driven by {:?}",kind)))
+                }
+            }
+        }
+    }
+    /** Evaluates the power state for a driver value. This mirrors the calculation done by set_power */
+    pub fn power_value(&self,driver_value:u32) -> Option<i16> {
+        match &self.power {
+            Some(RfStateType::Static(dac)) => Some(*dac),
+            Some(RfStateType::Adjustable(dac,_)) => Some(*dac),
+            Some(RfStateType::Driven(driver)) => {
+                match &driver.kind {
+                    RfDriverType::PowerRamp(dac_per_driver_val,offset) => {
+                        Some((driver_value as i32*(*dac_per_driver_val as i32) + *offset as i32) as i16)
+                    }
+                    _=> panic!("rf driver type not yet implemented")
+                }
+            }
+            None => None
+        }
+    }
+    /** Scale the power dac. Adjustable powers keep the scaled value as their starting point */
+    pub fn scale_power(&mut self,scale:f32) {
+        let scaled = |dac:i16| (dac as f32*scale).round() as i16;
+        self.power = match self.power.take() {
+            Some(RfStateType::Static(dac)) => Some(RfStateType::Static(scaled(dac))),
+            Some(RfStateType::Adjustable(dac,strategy)) => Some(RfStateType::Adjustable(scaled(dac),strategy)),
+            Some(RfStateType::Driven(mut driver)) => {
+                if let RfDriverType::PowerRamp(dac_per_driver_val,offset) = driver.kind {
+                    driver.kind = RfDriverType::PowerRamp(scaled(dac_per_driver_val),scaled(offset));
+                }
+                Some(RfStateType::Driven(driver))
+            }
+            None => None
+        };
+    }
     pub fn adjust_power_var(&self) -> String {
         format!("{}_adj",self.power_var())
     }
@@ -213,4 +361,29 @@ impl RfState {
     pub fn power(&self) -> RfStateType {
         self.power.clone().expect("rf event must have a power field. What happened??")
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test(){
+    // the phase increment between repetitions grows by the seed every repetition
+    let driver = RfDriver::new(DriverVar::Repetition,RfDriverType::PhaseCycle2D(PhaseCycleStrategy::QuadraticSpoil(RF_SPOIL_SEED_DEG)),None);
+    let state = RfState::new_phase_only("excitation",RfStateType::Driven(driver));
+    let seed = PhaseCycleStrategy::spoil_seed(RF_SPOIL_SEED_DEG);
+    assert_eq!(seed,520);
+    for n in [1,2,3,1000,3199,3200,3201,499_999] {
+        let increment = |n:u32| (state.phase_value(n).unwrap() as i32 - state.phase_value(n - 1).unwrap() as i32).rem_euclid(PHASE_UNITS_PER_CYCLE);
+        assert_eq!(increment(n),(seed*n as i32).rem_euclid(PHASE_UNITS_PER_CYCLE),"repetition {}",n);
+        assert_eq!((increment(n + 1) - increment(n)).rem_euclid(PHASE_UNITS_PER_CYCLE),seed);
+    }
+    assert!(state.set_phase().contains(VIEW_LOOP_COUNTER_VAR));
+
+    // lut phase cycling is evaluated from the same table the pulse program reads
+    let lut = vec![-2,3,0,0,1,1];
+    let driver = RfDriver::new(DriverVar::Repetition,RfDriverType::PhaseCycle3D(PhaseCycleStrategy::LUTNinetyTwoSeventy(4,None,lut)),None);
+    let state = RfState::new_phase_only("refocus",RfStateType::Driven(driver));
+    assert_eq!(state.phase_value(0),Ok(3));
+    assert_eq!(state.phase_value(1),Ok(1));
+    assert_eq!(state.phase_value(2),Ok(1));
+    assert_eq!(state.phase_value(3),Err(RfStateError::LutIndex(7,6)));
+    let driver = RfDriver::new(DriverVar::Repetition,RfDriverType::PowerRamp(1,0),None);
+    assert!(RfState::new_phase_only("ramp",RfStateType::Driven(driver)).phase_value(0).is_err());
+}
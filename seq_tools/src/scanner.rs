@@ -0,0 +1,206 @@
+/*
+    Scanner hardware profile. Gradient calibration, base frequency, gradient limits and the paths to
+    vendor include files are properties of the scanner a sequence is built for. The civm 9.4T system is
+    the default. Another profile can be loaded from a json file pointed to by the SCANNER_PROFILE
+    environment variable, which is read once on first use.
+ */
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use crate::grad_cal;
+use crate::grad_limits::GradHardwareProfile;
+use crate::rf_power::RfPowerLimits;
+
+pub const SCANNER_PROFILE_ENV_VAR:&str = "SCANNER_PROFILE";
+
+static ACTIVE_PROFILE:OnceLock<ScannerProfile> = OnceLock::new();
+
+/** A digitizer setting. The sample period is in 100 ns clock ticks and the dwell code is the value the
+ppr passes to the digitizer for that period */
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct ReceiverBandwidth {
+    pub hertz:i32,
+    pub sample_period_clocks:i32,
+    pub dwell_code:u16,
+    // delay required after sampling is complete, but before control is returned
+    pub post_delay_clocks:i32,
+    pub label:String,
+}
+
+impl ReceiverBandwidth {
+    pub fn new(sample_period_clocks:i32,dwell_code:u16,post_delay_clocks:i32) -> Self {
+        let hertz = (1E7/sample_period_clocks as f64).round() as i32;
+        Self {
+            hertz,
+            sample_period_clocks,
+            dwell_code,
+            post_delay_clocks,
+            label:format!("{:.1} KHz {} µs",hertz as f32/1E3,sample_period_clocks as f32/10.0),
+        }
+    }
+    fn with_label(mut self,label:&str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+    /** Sample rate the digitizer actually runs at */
+    pub fn sample_rate(&self) -> f32 {
+        1E7/self.sample_period_clocks as f32
+    }
+    pub fn ppr_string(&self) -> String {
+        format!("{}, {}, \"{}\"",self.sample_period_clocks,self.dwell_code,self.label)
+    }
+}
+
+/** Paths to vendor include and seq files referenced by the generated ppl */
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct ScannerIncludes {
+    pub civm_include:String,
+    pub std_fn:String,
+    pub grad_fn:String,
+    pub rf_fn:String,
+    pub std_rf_seq:String,
+    pub std_grad_seq:String,
+    pub lut_include:String,
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize,PartialEq)]
+pub struct ScannerProfile {
+    pub name:String,
+    // label of the observe frequency shown in the ppr
+    pub nucleus:String,
+    pub base_freq_hz:f32,
+    // main field strength in tesla
+    pub field_strength_t:f32,
+    // gradient strength at full scale dac (hz/mm). These must match the parfilio file values
+    pub grad_max_read:u32,
+    pub grad_max_phase:u32,
+    pub grad_max_slice:u32,
+    pub grad_limits:GradHardwareProfile,
+    pub rf_limits:RfPowerLimits,
+    // dac-seconds of a 90 degree hard pulse the starting rf powers of the sequences are written for
+    pub reference_rf_dac_seconds:f32,
+    // digitizer settings the receiver supports
+    pub receiver_bandwidths:Vec<ReceiverBandwidth>,
+    pub includes:ScannerIncludes,
+}
+
+impl ScannerProfile {
+    pub fn civm9p4t() -> Self {
+        Self {
+            name:String::from("civm9p4t"),
+            nucleus:String::from("9.4T 1H"),
+            base_freq_hz:30171576.0,
+            field_strength_t:9.4,
+            grad_max_read:grad_cal::GRAD_MAX_READ,
+            grad_max_phase:grad_cal::GRAD_MAX_PHASE,
+            grad_max_slice:grad_cal::GRAD_MAX_SLICE,
+            grad_limits:GradHardwareProfile::civm9p4t(),
+            rf_limits:RfPowerLimits::civm9p4t(),
+            // a dac of 400 for 140 µs
+            reference_rf_dac_seconds:56E-3,
+            // the dwell code steps down by one for every 2.5 µs of sample period
+            receiver_bandwidths:vec![
+                ReceiverBandwidth::new(50,25,3582).with_label("200  KHz   5 µs"),
+                ReceiverBandwidth::new(75,24,3582).with_label("133  KHz 7.5 µs"),
+                ReceiverBandwidth::new(100,23,3582).with_label("100  KHz  10 µs"),
+                ReceiverBandwidth::new(125,22,3582).with_label("80.0 KHz 12.5 µs"),
+                ReceiverBandwidth::new(150,21,3582).with_label("66.7 KHz  15 µs"),
+                ReceiverBandwidth::new(200,19,3582).with_label("50.0 KHz  20 µs"),
+                ReceiverBandwidth::new(250,17,3582).with_label("40.0 KHz  25 µs"),
+                ReceiverBandwidth::new(400,11,3582).with_label("25.0 KHz  40 µs"),
+            ],
+            includes:ScannerIncludes {
+                civm_include:String::from(r"C:\workstation\SequenceTools\CivmSequenceTools_v1.0\civm_var_20_long.PPH"),
+                std_fn:String::from(r"stdfn_15.pph"),
+                grad_fn:String::from(r"m3040_20.pph"),
+                rf_fn:String::from(r"m3031_15.pph"),
+                std_rf_seq:String::from(r"c:\smis\seqlib\RFstd.seq"),
+                std_grad_seq:String::from(r"c:\smis\seqlib\g3040_15.seq"),
+                lut_include:String::from(r"C:\smis\include\lututils.pph"),
+            }
+        }
+    }
+    /** The profile used when none is passed explicitly. Loaded from SCANNER_PROFILE if it is set */
+    pub fn active() -> &'static ScannerProfile {
+        ACTIVE_PROFILE.get_or_init(|| match env::var(SCANNER_PROFILE_ENV_VAR) {
+            Ok(path) => ScannerProfile::from_file(Path::new(&path)),
+            Err(_) => ScannerProfile::civm9p4t()
+        })
+    }
+    /** Proton resonance frequency at the field strength. The base frequency is the synthesizer setting,
+    so chemical shifts (ppm) are taken from this instead */
+    pub fn larmor_hz(&self) -> f32 {
+        grad_cal::GAMMA_BAR*self.field_strength_t
+    }
+    /** All channels are calibrated to the weakest channel so a dac value means the same strength
+    on every axis */
+    pub fn grad_min(&self) -> u32 {
+        self.grad_max_read.min(self.grad_max_phase).min(self.grad_max_slice)
+    }
+    // hz/mm -> dac
+    pub fn grad_to_dac(&self,grad_hz_per_mm:f32) -> i16 {
+        let grad_min = self.grad_min();
+        let fraction = grad_hz_per_mm/grad_min as f32;
+        // full scale is the strongest gradient that can be played
+        if fraction > 1.0 {panic!("max gradient strength exceeded. {} hz/mm > {} hz/mm",grad_hz_per_mm,grad_min)}
+        let dac = i16::MAX as f32 * fraction;
+        dac as i16
+    }
+    // dac -> hz/mm (signed)
+    pub fn dac_to_hz_per_mm(&self,grad_dac:i16) -> f32 {
+        self.grad_min() as f32 * (grad_dac as f32 / i16::MAX as f32)
+    }
+    /** Digitizer setting for a sample rate. The rate must be one of the supported bandwidths to
+    within a clock tick of sample period */
+    pub fn receiver_bandwidth(&self,hertz:i32) -> &ReceiverBandwidth {
+        let period = (1E7/hertz as f64).round() as i32;
+        self.receiver_bandwidths.iter().find(|bw| bw.sample_period_clocks == period).unwrap_or_else(||{
+            let supported:Vec<String> = self.receiver_bandwidths.iter().map(|bw| bw.hertz.to_string()).collect();
+            panic!("receiver bandwidth of {} hz is not supported by {}. Supported bandwidths are {} hz",hertz,self.name,supported.join(", "))
+        })
+    }
+    pub fn to_file(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn from_file(file_path:&Path) -> Self {
+        let mut f = File::open(file_path).unwrap_or_else(|_| panic!("cannot open file {:?}",file_path));
+        let mut in_str = String::new();
+        f.read_to_string(&mut in_str).expect("trouble reading file");
+        serde_json::from_str(&in_str).unwrap_or_else(|e| panic!("cannot deserialize scanner profile {:?}: {}",file_path,e))
+    }
+}
+
+#[test]
+fn test(){
+    let p = ScannerProfile::civm9p4t();
+    assert_eq!(p.grad_min(),grad_cal::GRAD_MIN);
+    assert_eq!(p.grad_to_dac(1000.0),grad_cal::grad_to_dac(1000.0));
+    assert_eq!(p.grad_to_dac(p.grad_min() as f32),i16::MAX);
+
+    // a stronger gradient set needs less dac for the same strength
+    let mut strong = p.clone();
+    strong.name = String::from("strong");
+    strong.grad_max_read *= 2;
+    strong.grad_max_phase *= 2;
+    strong.grad_max_slice *= 2;
+    assert!(strong.grad_to_dac(1000.0) < p.grad_to_dac(1000.0));
+    assert!((strong.dac_to_hz_per_mm(strong.grad_to_dac(1000.0)) - 1000.0).abs() <= strong.dac_to_hz_per_mm(1));
+
+    // narrower bandwidths come from the profile table
+    assert_eq!(p.receiver_bandwidth(50_000).ppr_string(),"200, 19, \"50.0 KHz  20 µs\"");
+    strong.receiver_bandwidths.push(ReceiverBandwidth::new(500,7,3582));
+    let bw = strong.receiver_bandwidth(20_000);
+    assert_eq!(bw.ppr_string(),"500, 7, \"20.0 KHz 50 µs\"");
+    assert_eq!(p.receiver_bandwidth(133_333).sample_period_clocks,75);
+
+    let path = std::env::temp_dir().join("scanner_profile_test.json");
+    strong.to_file(&path);
+    assert_eq!(ScannerProfile::from_file(&path),strong);
+}
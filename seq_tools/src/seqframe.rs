@@ -120,6 +120,42 @@ impl SeqFrameExpression for Function {
                     text: format!("{}*sinc(PI*{}*((Ñ-({}/2))/({}/2)))", dac, lobe_val, p.n_samples, p.n_samples)
                 }
             }
+            Function::Gaussian(sigma,p) => {
+                let dac = ((dac_scale as f32)*p.max_value) as i16;
+                let c = (p.n_samples - 1) as f32/2.0;
+                let s = sigma*p.n_samples as f32;
+                Expression {
+                    n_samples:p.n_samples,
+                    text: format!("{}*exp(-((Ñ-{})*(Ñ-{}))/{})", dac, c, c, 2.0*s*s)
+                }
+            }
+            Function::HammingSinc(n_lobes,p) => {
+                let dac = ((dac_scale as f32)*p.max_value) as i16;
+                let lobes = if n_lobes.is_multiple_of(2) {n_lobes+1} else {*n_lobes};
+                let lobe_val = lobes.div_ceil(2);
+                Expression {
+                    n_samples:p.n_samples,
+                    text: format!("{}*(0.54+0.46*cos(PI*((Ñ-({}/2))/({}/2))))*sinc(PI*{}*((Ñ-({}/2))/({}/2)))",
+                                  dac, p.n_samples, p.n_samples, lobe_val, p.n_samples, p.n_samples)
+                }
+            }
+            Function::Sech(extent,p) => {
+                let dac = ((dac_scale as f32)*p.max_value) as i16;
+                let c = (p.n_samples - 1) as f32/2.0;
+                let x = format!("({}*((Ñ-{})/{}))",extent,c,c);
+                Expression {
+                    n_samples:p.n_samples,
+                    text: format!("{}*2/(exp({})+exp(-{}))", dac, x, x)
+                }
+            }
+            Function::Ramp(start,p) => {
+                let start = ((dac_scale as f32)*start) as i16;
+                let dac = ((dac_scale as f32)*p.max_value) as i16;
+                Expression {
+                    n_samples:p.n_samples,
+                    text: format!("ramp({},{})",start,dac)
+                }
+            }
         }
     }
 }
@@ -0,0 +1,225 @@
+/*
+    Headless timing diagram of a placed event queue for a single view. Waveforms are rendered with the
+    driver value of the view and drawn as rows of rf amplitude, rf phase, the three gradient channels
+    and the adc. Rows are normalized to their own peak, so the diagram shows timing and shape rather
+    than absolute strength. The same data is written to json for scripting and review diffs.
+    Times are in seconds relative to the origin of the event queue.
+ */
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use serde::Serialize;
+use serde_json;
+use crate::_utils;
+use crate::event_block::{EventGraph, EventQueue};
+use crate::execution::{EventType, PlotTrace, RfRole, WaveformData};
+
+const ROW_LABELS:[&str;6] = ["rf amp","rf phase","read","phase","slice","adc"];
+const WIDTH:f32 = 1400.0;
+const ROW_HEIGHT:f32 = 90.0;
+const MARGIN_LEFT:f32 = 90.0;
+const MARGIN_RIGHT:f32 = 30.0;
+const MARGIN_TOP:f32 = 50.0;
+const MARGIN_BOTTOM:f32 = 50.0;
+
+#[derive(Clone,Debug,Serialize)]
+pub struct TimingDiagram {
+    pub driver_value:u32,
+    pub t_start:f32,
+    pub t_end:f32,
+    // center of the first excitation pulse
+    pub excitation_center:Option<f32>,
+    // centers of every acquisition
+    pub echo_centers:Vec<f32>,
+    pub echo_time:Option<f32>,
+    pub rep_time:Option<f32>,
+    pub events:Vec<EventGraph>,
+}
+
+impl TimingDiagram {
+    pub fn new(event_queue:&EventQueue,driver_value:u32,time_step_us:usize,rep_time:Option<f32>) -> Self {
+        let centers = |kind:fn(&EventType) -> bool| -> Vec<f32> {
+            event_queue.events().iter().filter(|e| kind(&e.borrow().execution.kind()))
+                .map(|e| _utils::clock_to_sec(e.borrow().center())).collect()
+        };
+        // preparation pulses can come before the excitation, so it is found by role
+        let excitation_center = event_queue.events().iter().find(|e| e.borrow().execution.rf_role() == Some(RfRole::Excitation))
+            .map(|e| _utils::clock_to_sec(e.borrow().center()));
+        let echo_centers = centers(|k| matches!(k,EventType::Acq(..)));
+        let echo_time = match (excitation_center,echo_centers.first()) {
+            (Some(ex),Some(echo)) => Some(echo - ex),
+            _=> None
+        };
+        let events = event_queue.graphs_dynamic(time_step_us,driver_value);
+        let t_start = events.iter().map(|e| e.block_interval.0).fold(f32::MAX,f32::min);
+        let t_end = events.iter().map(|e|{
+            let wave_end = waveform_traces(e).iter().map(|(_,trace)| e.waveform_start + trace.x.last().copied().unwrap_or(0.0))
+                .fold(f32::MIN,f32::max);
+            e.block_interval.1.max(wave_end)
+        }).fold(f32::MIN,f32::max);
+        Self {
+            driver_value,
+            t_start,
+            t_end,
+            excitation_center,
+            echo_centers,
+            echo_time,
+            rep_time,
+            events
+        }
+    }
+    pub fn to_json(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn to_svg(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        f.write_all(self.svg().as_bytes()).expect("trouble writing to file");
+    }
+    fn x(&self,t:f32) -> f32 {
+        let span = (self.t_end - self.t_start).max(f32::EPSILON);
+        MARGIN_LEFT + (t - self.t_start)/span*(WIDTH - MARGIN_LEFT - MARGIN_RIGHT)
+    }
+    pub fn svg(&self) -> String {
+        let n_rows = ROW_LABELS.len();
+        let height = MARGIN_TOP + ROW_HEIGHT*n_rows as f32 + MARGIN_BOTTOM;
+        let plot_bottom = MARGIN_TOP + ROW_HEIGHT*n_rows as f32;
+        let row_center = |row:usize| MARGIN_TOP + ROW_HEIGHT*(row as f32 + 0.5);
+        let mut s = vec![
+            format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{height}\" font-family=\"sans-serif\" font-size=\"11\">"),
+            format!("<rect width=\"{WIDTH}\" height=\"{height}\" fill=\"white\"/>"),
+        ];
+
+        // peak of each row for normalization
+        let mut peaks = [0.0f32;6];
+        self.events.iter().for_each(|e| waveform_traces(e).iter().for_each(|(row,trace)|{
+            peaks[*row] = trace.y.iter().fold(peaks[*row],|m,y| m.max(y.abs()));
+        }));
+
+        for (row,label) in ROW_LABELS.iter().enumerate() {
+            let y = row_center(row);
+            s.push(format!("<text x=\"8\" y=\"{:.1}\" dominant-baseline=\"middle\">{}</text>",y,label));
+            s.push(format!("<line x1=\"{MARGIN_LEFT}\" y1=\"{y:.1}\" x2=\"{:.1}\" y2=\"{y:.1}\" stroke=\"#bbbbbb\" stroke-width=\"0.5\"/>",WIDTH - MARGIN_RIGHT));
+        }
+
+        for e in self.events.iter() {
+            let traces = waveform_traces(e);
+            let Some((first_row,_)) = traces.first() else {continue};
+            // block interval of the event behind its first row
+            let (x0,x1) = (self.x(e.block_interval.0),self.x(e.block_interval.1));
+            let top = MARGIN_TOP + ROW_HEIGHT*(*first_row as f32);
+            s.push(format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#e8eef8\" stroke=\"#9aaccc\" stroke-width=\"0.5\"/>",
+                           x0,top + 2.0,(x1 - x0).max(0.5),ROW_HEIGHT - 4.0));
+            s.push(format!("<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"9\" fill=\"#334466\">{}</text>",x0 + 2.0,top + 12.0,escape(&e.label)));
+            for (row,trace) in traces.iter() {
+                let peak = peaks[*row].max(f32::EPSILON);
+                let points:Vec<String> = trace.x.iter().zip(trace.y.iter()).map(|(t,y)|{
+                    format!("{:.1},{:.1}",self.x(e.waveform_start + t),row_center(*row) - y/peak*0.4*ROW_HEIGHT)
+                }).collect();
+                s.push(format!("<polyline points=\"{}\" fill=\"none\" stroke=\"black\" stroke-width=\"1\"/>",points.join(" ")));
+            }
+        }
+
+        // te and tr markers span all rows
+        let marker = |t:f32,color:&str,label:String| -> Vec<String> {
+            let x = self.x(t);
+            vec![
+                format!("<line x1=\"{x:.1}\" y1=\"{MARGIN_TOP}\" x2=\"{x:.1}\" y2=\"{plot_bottom:.1}\" stroke=\"{color}\" stroke-dasharray=\"4,3\"/>"),
+                format!("<text x=\"{:.1}\" y=\"{:.1}\" fill=\"{color}\">{}</text>",x + 3.0,MARGIN_TOP - 6.0,label),
+            ]
+        };
+        if let Some(ex) = self.excitation_center {
+            s.extend(marker(ex,"#c03030",String::from("rf")));
+            if let Some(te) = self.echo_time {
+                s.extend(marker(ex + te,"#c03030",format!("TE = {:.3} ms",te*1E3)));
+            }
+            self.echo_centers.iter().skip(1).for_each(|echo| s.extend(marker(*echo,"#d08080",String::new())));
+            if let Some(tr) = self.rep_time {
+                match ex + tr <= self.t_end {
+                    true => s.extend(marker(ex + tr,"#3030c0",format!("TR = {:.3} ms",tr*1E3))),
+                    false => s.push(format!("<text x=\"{:.1}\" y=\"20\" text-anchor=\"end\" fill=\"#3030c0\">TR = {:.3} ms</text>",WIDTH - MARGIN_RIGHT,tr*1E3))
+                }
+            }
+        }
+
+        // time axis in ms
+        let span_ms = (self.t_end - self.t_start)*1E3;
+        let tick = nice_tick(span_ms/10.0);
+        let mut t_ms = (self.t_start*1E3/tick).ceil()*tick;
+        while t_ms <= self.t_end*1E3 {
+            let x = self.x(t_ms*1E-3);
+            s.push(format!("<line x1=\"{x:.1}\" y1=\"{plot_bottom:.1}\" x2=\"{x:.1}\" y2=\"{:.1}\" stroke=\"black\"/>",plot_bottom + 5.0));
+            s.push(format!("<text x=\"{x:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>",plot_bottom + 18.0,format_tick(t_ms,tick)));
+            t_ms += tick;
+        }
+        s.push(format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">time (ms), view driver = {}</text>",
+                       (WIDTH + MARGIN_LEFT)/2.0,height - 10.0,self.driver_value));
+        s.push(String::from("</svg>"));
+        s.join("\n")
+    }
+}
+
+/** Traces of an event paired with the diagram row they are drawn on */
+fn waveform_traces(graph:&EventGraph) -> Vec<(usize,PlotTrace)> {
+    match &graph.wave_data {
+        WaveformData::Rf(amp,phase) => vec![(0,amp.clone()),(1,phase.clone())],
+        WaveformData::Grad(r,p,s) => [r,p,s].iter().enumerate()
+            .filter_map(|(c,trace)| trace.as_ref().map(|t| (c + 2,t.clone()))).collect(),
+        WaveformData::Acq(trace) => vec![(5,trace.clone())]
+    }
+}
+
+// 1, 2 or 5 times a power of 10
+fn nice_tick(approx:f32) -> f32 {
+    let mag = 10f32.powf(approx.max(1E-6).log10().floor());
+    let r = approx/mag;
+    let step = if r < 1.5 {1.0} else if r < 3.5 {2.0} else if r < 7.5 {5.0} else {10.0};
+    step*mag
+}
+
+fn format_tick(t_ms:f32,tick:f32) -> String {
+    let decimals = (-tick.log10().floor()).max(0.0) as usize;
+    format!("{:.*}",decimals,t_ms)
+}
+
+fn escape(s:&str) -> String {
+    s.replace('&',"&amp;").replace('<',"&lt;").replace('>',"&gt;")
+}
+
+#[test]
+fn test(){
+    use crate::acq_event::{AcqEvent, SpectralWidth};
+    use crate::event_block::{Event, EventPlacementType, GradEventType};
+    use crate::execution::ExecutionBlock;
+    use crate::gradient_event::GradEvent;
+    use crate::gradient_matrix::{DacValues, Matrix};
+    use crate::pulse::{Hardpulse, Trapezoid};
+    use crate::rf_event::RfEvent;
+    use crate::rf_state::RfStateType;
+
+    let tracker = Matrix::new_tracker();
+    let acq = AcqEvent::new("acq",SpectralWidth::SW100kH,64,0,RfStateType::Static(0));
+    let (sample_time,ro_dac) = acq.readout_event(20.0);
+    let ro_matrix = Matrix::new_static("ro",DacValues::new(Some(ro_dac),None,None),(false,false,false),false,&tracker);
+    let readout = GradEvent::new((Some(Trapezoid::new(100E-6,sample_time)),None,None),&ro_matrix,GradEventType::NonBlocking,"readout");
+    let excitation = RfEvent::new("excitation",1,Hardpulse::new(100E-6),RfStateType::Static(500),RfStateType::Static(0));
+    // echo time is measured from the excitation, not the inversion ahead of it
+    let mut inversion = RfEvent::new("inversion",2,Hardpulse::new(100E-6),RfStateType::Static(500),RfStateType::Static(0));
+    inversion.set_role(RfRole::Preparation);
+    let ex = Event::new(excitation.as_reference(),EventPlacementType::Origin);
+    let inv = Event::new(inversion.as_reference(),EventPlacementType::Before(ex.clone(),1000));
+    let ro = Event::new(readout.as_reference(),EventPlacementType::After(ex.clone(),1000));
+    let ac = Event::new(acq.as_reference(),EventPlacementType::ExactFromOrigin(ro.borrow().center()));
+    let q = EventQueue::new(&vec![inv,ex,ro.clone(),ac]);
+
+    let diagram = TimingDiagram::new(&q,0,2,Some(100E-3));
+    let te = diagram.echo_time.expect("an rf and an acq event should give an echo time");
+    assert!((te - _utils::clock_to_sec(ro.borrow().center())).abs() < 1E-6);
+    let svg = diagram.svg();
+    assert!(svg.starts_with("<svg") && svg.ends_with("</svg>"));
+    assert!(svg.contains("TE = ") && svg.contains("TR = ") && svg.contains("readout"));
+    diagram.to_svg(&std::env::temp_dir().join("timing_diagram_test.svg"));
+    diagram.to_json(&std::env::temp_dir().join("timing_diagram_test.json"));
+}
@@ -0,0 +1,151 @@
+/*
+    K-space trajectory of a placed event queue. Gradient waveforms are rendered for a driver value
+    and integrated into k(t), which is then sampled at the acquisition times of every acq event.
+    Rf events act on k by their role: an excitation zeroes k at its center, a refocusing pulse negates
+    it, and preparation pulses (inversion, saturation) leave it alone.
+    K-space positions are in cycles/m along the read, phase and slice channels.
+ */
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use serde::Serialize;
+use serde_json;
+use crate::_utils;
+use crate::event_block::EventQueue;
+use crate::execution::{EventType, RfRole, WaveformData};
+use crate::moments::GradientGrid;
+
+#[derive(Clone,Debug,Serialize)]
+pub struct ReadoutTrajectory {
+    pub label:String,
+    // sample times relative to the start of the event queue
+    pub t:Vec<f32>,
+    pub k:Vec<[f32;3]>,
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct KSpaceTrajectory {
+    pub driver_value:u32,
+    pub readouts:Vec<ReadoutTrajectory>,
+}
+
+impl KSpaceTrajectory {
+    pub fn new(event_queue:&EventQueue,driver_value:u32,time_step_us:usize) -> Self {
+        let events = event_queue.events();
+        let grid = GradientGrid::new(event_queue,driver_value,time_step_us);
+        let (t0,dt,n) = (grid.t0,grid.dt,grid.g.len());
+        // k-space operations of rf events (grid index, role)
+        let rf_ops:Vec<(usize,RfRole)> = events.iter().filter_map(|e|{
+            let e = e.borrow();
+            e.execution.rf_role().map(|role| (grid.index(_utils::clock_to_sec(e.center())),role))
+        }).collect();
+
+        // integrate the gradients (hz/mm -> hz/m), applying rf operations as they occur
+        let mut k = vec![[0.0f32;3];n];
+        for i in 1..n {
+            let (prev,g) = (k[i-1],grid.g[i-1]);
+            k[i] = [0,1,2].map(|c| prev[c] + 1000.0*g[c]*dt);
+            for (_,role) in rf_ops.iter().filter(|(idx,_)| *idx == i) {
+                match role {
+                    RfRole::Excitation => k[i] = [0.0;3],
+                    RfRole::Refocus => k[i] = k[i].map(|x| -x),
+                    RfRole::Preparation => {}
+                }
+            }
+        }
+
+        let readouts = events.iter().filter_map(|event|{
+            let e = event.borrow();
+            let n_samples = match e.execution.kind() {
+                EventType::Acq(_,n_samples,_) => n_samples,
+                _=> return None
+            };
+            let graph = e.event_graph_dynamic(time_step_us,driver_value);
+            let period = match &graph.wave_data {
+                WaveformData::Acq(trace) if trace.x.len() > 1 => trace.x[1] - trace.x[0],
+                _=> 0.0
+            };
+            let t:Vec<f32> = (0..n_samples).map(|i| graph.waveform_start + i as f32*period).collect();
+            // linear interpolation of k between grid points
+            let ks = t.iter().map(|t|{
+                let f = ((t - t0)/dt).max(0.0);
+                let i = (f.floor() as usize).min(n - 2);
+                let w = f - i as f32;
+                [0,1,2].map(|c| k[i][c]*(1.0 - w) + k[i+1][c]*w)
+            }).collect();
+            Some(ReadoutTrajectory {
+                label:e.unique_label(),
+                t:t.iter().map(|t| t - t0).collect(),
+                k:ks
+            })
+        }).collect();
+        Self {
+            driver_value,
+            readouts
+        }
+    }
+    pub fn to_json(&self,file_path:&Path) {
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        let str = serde_json::to_string_pretty(&self).expect("cannot serialize struct");
+        f.write_all(str.as_bytes()).expect("trouble writing to file");
+    }
+    pub fn to_csv(&self,file_path:&Path) {
+        let mut s = String::from("readout,sample,t,k_read,k_phase,k_slice\n");
+        for r in self.readouts.iter() {
+            for (i,(t,k)) in r.t.iter().zip(r.k.iter()).enumerate() {
+                s.push_str(&format!("{},{},{},{},{},{}\n",r.label,i,t,k[0],k[1],k[2]));
+            }
+        }
+        let mut f = File::create(file_path).unwrap_or_else(|_| panic!("cannot create file {:?}",file_path));
+        f.write_all(s.as_bytes()).expect("trouble writing to file");
+    }
+}
+
+#[test]
+fn test(){
+    use crate::acq_event::{AcqEvent, SpectralWidth};
+    use crate::event_block::{Event, EventPlacementType, GradEventType};
+    use crate::execution::ExecutionBlock;
+    use crate::gradient_event::GradEvent;
+    use crate::gradient_matrix::{DacValues, Matrix};
+    use crate::pulse::{Hardpulse, Trapezoid};
+    use crate::rf_event::RfEvent;
+    use crate::rf_state::RfStateType;
+
+    let fov_mm = 20.0;
+    let n_samples = 128;
+    let tracker = Matrix::new_tracker();
+    let acq = AcqEvent::new("acq",SpectralWidth::SW100kH,n_samples,0,RfStateType::Static(0));
+    let (sample_time,ro_dac) = acq.readout_event(fov_mm);
+    let ramp = 100E-6;
+    // prewinder balances the readout area up to the echo center
+    let ro_matrix = Matrix::new_static("ro",DacValues::new(Some(ro_dac),None,None),(false,false,false),false,&tracker);
+    let pre_matrix = Matrix::new_static("pre",DacValues::new(Some(-ro_dac),None,None),(false,false,false),false,&tracker);
+    let readout = GradEvent::new((Some(Trapezoid::new(ramp,sample_time)),None,None),&ro_matrix,GradEventType::NonBlocking,"readout");
+    let prewind = GradEvent::new((Some(Trapezoid::new(ramp,sample_time/2.0 - ramp/2.0)),None,None),&pre_matrix,GradEventType::Blocking,"prewind");
+    let excitation = RfEvent::new("excitation",1,Hardpulse::new(100E-6),RfStateType::Static(500),RfStateType::Static(0));
+    // a preparation pulse and spoiler ahead of the excitation must not shift the echo
+    let mut inversion = RfEvent::new("inversion",2,Hardpulse::new(100E-6),RfStateType::Static(500),RfStateType::Static(0));
+    inversion.set_role(RfRole::Preparation);
+    let spoil = GradEvent::new((Some(Trapezoid::new(ramp,sample_time)),None,None),&ro_matrix,GradEventType::Blocking,"spoil");
+
+    let ex = Event::new(excitation.as_reference(),EventPlacementType::Origin);
+    let sp = Event::new(spoil.as_reference(),EventPlacementType::Before(ex.clone(),0));
+    let inv = Event::new(inversion.as_reference(),EventPlacementType::Before(sp.clone(),0));
+    let pre = Event::new(prewind.as_reference(),EventPlacementType::After(ex.clone(),0));
+    let ro = Event::new(readout.as_reference(),EventPlacementType::After(pre.clone(),0));
+    let ac = Event::new(acq.as_reference(),EventPlacementType::ExactFromOrigin(ro.borrow().center()));
+    let q = EventQueue::new(&vec![inv,sp,ex,pre,ro,ac]);
+
+    let traj = KSpaceTrajectory::new(&q,0,2);
+    assert_eq!(traj.readouts.len(),1);
+    let k = &traj.readouts[0].k;
+    assert_eq!(k.len(),n_samples as usize);
+    // the readout should traverse 1/resolution centered on k = 0
+    let dk = 1.0/(fov_mm*1E-3);
+    let extent = k[k.len()-1][0] - k[0][0];
+    assert!((extent - (n_samples as f32 - 1.0)*dk).abs() < 2.0*dk,"extent = {} cycles/m",extent);
+    let center = k[k.len()/2][0];
+    assert!(center.abs() < 2.0*dk,"k at echo center = {} cycles/m",center);
+}
@@ -1,4 +1,5 @@
 pub mod matmath;
 pub mod spin_operators;
 pub mod spin;
-pub mod unit;
\ No newline at end of file
+pub mod unit;
+pub mod slice_profile;
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::f32::consts::PI;
+use seq_tools::pulse::{Pulse, SliceSelective};
+use seq_tools::rf_frame::RfFrame;
+use seq_tools::pulse_function::render_function_vector;
+use crate::matmath::Vector;
+use crate::spin::{Event, Spin, SpinSystem};
+
+// simulation time step of the pulse
+const TIME_STEP_US:usize = 2;
+// field of view (in slice thicknesses) and isochromat count used when sizing a slice
+const SLICE_FOV:f32 = 6.0;
+const SLICE_ISOCHROMATS:usize = 601;
+
+/*
+    Slice profile of a pulse from a Bloch simulation of isochromats spread along the slice axis. Unlike
+    the fft bandwidth of the waveform this holds for large flip angles, adiabatic and composite pulses.
+    Fields are in Hz so the spins use a gyromagnetic ratio of 2pi rad/cycle, positions are in mm and
+    relaxation is ignored.
+ */
+#[derive(Clone,Debug)]
+pub struct SliceProfile {
+    pub position_mm:Vec<f32>,
+    pub mz:Vec<f32>,
+    pub mxy:Vec<f32>,
+    pub phase_deg:Vec<f32>,
+    pub grad_strength_hzpmm:f32,
+}
+
+impl SliceProfile {
+    pub fn simulate<P>(pulse:&P,b1_peak_hz:f32,grad_strength_hzpmm:f32,fov_mm:f32,n_isochromats:usize) -> Self
+        where P:SliceSelective + RfFrame {
+        assert!(n_isochromats > 1,"need at least 2 isochromats");
+        let amplitude = pulse.render(TIME_STEP_US);
+        let phase:Vec<f32> = render_function_vector(pulse.phase_function(TIME_STEP_US)).iter().map(|p| p*90.0).collect();
+        assert_eq!(amplitude.len(),phase.len(),"rf amplitude and phase must have the same number of samples");
+        let position_mm:Vec<f32> = (0..n_isochromats).map(|i| fov_mm*(i as f32/(n_isochromats - 1) as f32 - 0.5)).collect();
+        let mut system = SpinSystem::new();
+        position_mm.iter().for_each(|x|{
+            let mut spin = Spin::new();
+            spin.r = Vector::new(0.0,0.0,*x);
+            spin.t1 = f32::INFINITY;
+            spin.t2 = f32::INFINITY;
+            spin.gamma = 2.0*PI;
+            system.spins.push(spin);
+        });
+        let dt = TIME_STEP_US as f32*1E-6;
+        amplitude.iter().zip(phase.iter()).for_each(|(a,p)|{
+            let b1 = a*b1_peak_hz;
+            let rf = Vector::new(b1*p.to_radians().cos(),b1*p.to_radians().sin(),0.0);
+            system.apply(&Event{gradient:Vector::new(0.0,0.0,grad_strength_hzpmm),rf,duration:dt});
+        });
+        Self {
+            mz:system.spins.iter().map(|s| s.m.z).collect(),
+            mxy:system.spins.iter().map(|s| (s.m.x*s.m.x + s.m.y*s.m.y).sqrt()).collect(),
+            phase_deg:system.spins.iter().map(|s| s.m.y.atan2(s.m.x).to_degrees()).collect(),
+            position_mm,
+            grad_strength_hzpmm,
+        }
+    }
+    /** Profile at the gradient strength that selects a slice of the given thickness. The fft bandwidth of the
+    pulse gives a first guess that is corrected by the simulated thickness, which scales inversely with the
+    gradient. Pulses over 90 degrees are measured by their inversion profile */
+    pub fn for_thickness<P>(pulse:&P,flip_angle_deg:f32,slice_thickness_mm:f32) -> Self
+        where P:SliceSelective + RfFrame {
+        let b1 = Self::b1_peak_hz_for_flip(pulse,flip_angle_deg);
+        let simulate = |grad:f32| Self::simulate(pulse,b1,grad,SLICE_FOV*slice_thickness_mm,SLICE_ISOCHROMATS);
+        let guess = simulate(pulse.grad_strength_hzpmm(slice_thickness_mm));
+        let thickness = match flip_angle_deg > 90.0 {
+            true => guess.inversion_thickness_mm(),
+            false => guess.slice_thickness_mm()
+        };
+        simulate(guess.grad_strength_hzpmm*thickness/slice_thickness_mm)
+    }
+    /** Peak B1 in Hz that gives the flip angle, based on the net area of the pulse */
+    pub fn b1_peak_hz_for_flip(pulse:&dyn Pulse,flip_angle_deg:f32) -> f32 {
+        (flip_angle_deg/360.0)/pulse.power_net(1.0)
+    }
+    /** Full-width half-max of the transverse magnetization */
+    pub fn slice_thickness_mm(&self) -> f32 {
+        fwhm(&self.position_mm,&self.mxy)
+    }
+    /** Full-width half-max of the inverted longitudinal magnetization. Use for inversion and refocusing pulses */
+    pub fn inversion_thickness_mm(&self) -> f32 {
+        let inverted:Vec<f32> = self.mz.iter().map(|mz| (1.0 - mz)/2.0).collect();
+        fwhm(&self.position_mm,&inverted)
+    }
+    pub fn bandwidth_hz(&self) -> f32 {
+        self.slice_thickness_mm()*self.grad_strength_hzpmm
+    }
+    /** Gradient area (Hz*s/mm) needed after the pulse to remove the linear phase across the slice. This
+    is normally negative, meaning opposite to the slice select gradient */
+    pub fn rephase_area(&self) -> f32 {
+        // weighted linear fit of the unwrapped phase over the slice
+        let max = self.mxy.iter().fold(0.0f32,|acc,m| acc.max(*m));
+        let phase = unwrap(&self.phase_deg.iter().map(|p| p.to_radians()).collect::<Vec<f32>>());
+        let points:Vec<(f32,f32,f32)> = (0..phase.len()).filter(|i| self.mxy[*i] > max/2.0)
+            .map(|i| (self.position_mm[i],phase[i],self.mxy[i])).collect();
+        let w:f32 = points.iter().map(|p| p.2).sum();
+        let mean_x = points.iter().map(|p| p.0*p.2).sum::<f32>()/w;
+        let mean_y = points.iter().map(|p| p.1*p.2).sum::<f32>()/w;
+        let cov:f32 = points.iter().map(|p| p.2*(p.0 - mean_x)*(p.1 - mean_y)).sum();
+        let var:f32 = points.iter().map(|p| p.2*(p.0 - mean_x).powi(2)).sum();
+        // a gradient area A rotates the transverse magnetization at x by -2pi*A*x
+        (cov/var)/(2.0*PI)
+    }
+}
+
+fn fwhm(x:&[f32],y:&[f32]) -> f32 {
+    let max = y.iter().fold(0.0f32,|acc,y| acc.max(*y));
+    let above:Vec<usize> = (0..y.len()).filter(|i| y[*i] >= max/2.0).collect();
+    let (first,last) = (above[0],above[above.len()-1]);
+    // linear interpolation of the half max crossings
+    let cross = |i:usize,j:usize| x[i] + (x[j] - x[i])*(max/2.0 - y[i])/(y[j] - y[i]);
+    let left = if first > 0 {cross(first-1,first)} else {x[first]};
+    let right = if last < y.len()-1 {cross(last,last+1)} else {x[last]};
+    right - left
+}
+
+fn unwrap(phase:&[f32]) -> Vec<f32> {
+    let mut out = Vec::<f32>::with_capacity(phase.len());
+    let mut offset = 0.0;
+    for (i,p) in phase.iter().enumerate() {
+        if i > 0 {
+            let d = p - phase[i-1];
+            if d > PI {offset -= 2.0*PI}
+            else if d < -PI {offset += 2.0*PI}
+        }
+        out.push(p + offset);
+    }
+    out
+}
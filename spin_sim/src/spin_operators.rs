@@ -4,6 +4,10 @@ use crate::matmath::{Vector,Matrix};
 pub fn rot_op(bvec:&Vector,tau:f32,gamma:f32) -> Matrix{
     let mut result = Matrix::zeros();
     let norm = bvec.mag();
+    // no field means no rotation
+    if norm == 0.0 {
+        return Matrix::identity();
+    }
     let n = (1.0/norm)*(*bvec);
     let phi = tau*gamma*norm;
     let cosphi = phi.cos();